@@ -1,10 +1,18 @@
 use crate::camera_controller::OrbitCamera;
+use crate::geometry::{mesh::MeshFormat, Mesh};
 use crate::gui::GuiState;
 use crate::renderer::RenderOutput;
-use crate::scene::{Scene, SceneSphere, SceneTris};
+use crate::scene::bvh::{wide, Node};
+use crate::scene::{apng_writer, render_png, render_rgb};
+use crate::scene::{Animation, Material, Scene, SceneFile, SceneSphere, SceneTris, Timeline};
+use glam::Vec3;
 use rand::Rng;
+use std::f32::consts::PI;
+use std::io::BufWriter;
+use std::mem::size_of;
+use std::path::Path;
 use std::sync::Arc;
-use std::{i8, time::Instant};
+use std::time::Instant;
 use winit::application::ApplicationHandler;
 use winit::event::{StartCause, WindowEvent};
 use winit::event_loop::ActiveEventLoop;
@@ -14,9 +22,158 @@ pub struct App {
     scene: Option<Box<dyn Scene>>,
     window: Option<Arc<Window>>,
     scene_id: i8,
+    scene_file: Option<String>,
+    /// Path and contents of the mesh file to show, parsed only when its tree is not cached.
+    mesh: Option<(String, Vec<u8>)>,
     start_time_stamp: Instant,
     camera: Option<OrbitCamera>,
     gui: Option<GuiState>,
+    flags: u32,
+}
+
+/// Prints `Tree::stats` of the tree over the mesh at `path` as the viewer builds it, floor
+/// included, the memory and frame time of its nodes at each width, then the stats of the
+/// tree as sorted on the CPU.
+fn print_stats(path: &str) -> Result<(), String> {
+    const FRAMES: u32 = 8;
+    let source = std::fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+    let material = Material::new_lambertian(Vec3::splat(0.8));
+    let mesh = Mesh::load(path, &source, material).map_err(|e| format!("{path}: {e}"))?;
+    let output = RenderOutput::Headless(640, 480);
+    let mut scene = pollster::block_on(SceneTris::from_mesh(mesh, output));
+    scene.init().map_err(|e| e.to_string())?;
+    println!("{path}, as rendered\n{}", scene.tris_bvh.stats());
+    println!("width  node memory  frame at 640x480");
+    for width in std::iter::once(2).chain(wide::WIDTHS) {
+        let memory = match width {
+            2 => scene.tris_bvh.nodes.len() * size_of::<Node>(),
+            _ => scene.tris_bvh.collapse(width).len() * size_of::<u32>(),
+        };
+        scene.tris_bvh.width = width;
+        scene.write_tree_data().map_err(|e| e.to_string())?;
+        scene.draw();
+        let _ = scene.get_device().poll(wgpu::MaintainBase::Wait);
+        let start = Instant::now();
+        for _ in 0..FRAMES {
+            scene.draw();
+            let _ = scene.get_device().poll(wgpu::MaintainBase::Wait);
+        }
+        let frame = start.elapsed() / FRAMES;
+        println!("{width:<6} {:>7} KiB  {frame:.1?}", memory / 1024);
+    }
+    println!();
+    scene.tris_bvh.build();
+    println!("{path}, sorted on the CPU\n{}", scene.tris_bvh.stats());
+    Ok(())
+}
+
+/// The material meshes opened from files are shown in.
+fn mesh_material() -> Material {
+    Material::new_lambertian(Vec3::splat(0.8)).with_vertex_colors()
+}
+
+/// The scene of the mesh at `path` as the viewer shows it, rendering off screen.
+fn headless_mesh_scene(path: &str) -> Result<SceneTris, String> {
+    const WIDTH: u32 = 1024;
+    const HEIGHT: u32 = 768;
+    let source = std::fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+    let output = RenderOutput::Headless(WIDTH, HEIGHT);
+    let scene = SceneTris::load_mesh(path, &source, mesh_material(), output);
+    let mut scene = pollster::block_on(scene).map_err(|e| format!("{path}: {e}"))?;
+    scene.init().map_err(|e| e.to_string())?;
+    Ok(scene)
+}
+
+/// Renders every frame of `animation` over `scene` with `samples` samples per pixel into
+/// `dir` as `0000.png`, `0001.png` and so on, and with `apng` all of them into
+/// `animation.png` too.
+fn render_frames(
+    scene: &mut SceneTris,
+    animation: &Animation,
+    dir: &str,
+    samples: u32,
+    apng: bool,
+) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|e| format!("{dir}: {e}"))?;
+    let timeline = &animation.timeline;
+    let mut animated = None;
+    if apng && timeline.frames > 0 {
+        let path = Path::new(dir).join("animation.png");
+        let file = std::fs::File::create(&path)
+            .map_err(|e| e.to_string())
+            .map(BufWriter::new)
+            .and_then(|file| {
+                apng_writer(file, &scene.renderer, timeline.frames, timeline.fps)
+                    .map_err(|e| e.to_string())
+            });
+        animated = Some(file.map_err(|e| format!("{}: {e}", path.display()))?);
+    }
+    for frame in 0..timeline.frames {
+        animation.draw_frame(scene, frame, samples);
+        let image = render_png(&mut scene.renderer).map_err(|e| e.to_string())?;
+        let file = Path::new(dir).join(format!("{frame:04}.png"));
+        std::fs::write(&file, image).map_err(|e| format!("{}: {e}", file.display()))?;
+        println!("{}", file.display());
+        if let Some(writer) = &mut animated {
+            let image = render_rgb(&mut scene.renderer);
+            writer.write_image_data(&image).map_err(|e| e.to_string())?;
+        }
+    }
+    if let Some(writer) = animated {
+        writer.finish().map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Renders the timeline at `timeline_path` over the mesh at `path`, see `render_frames`.
+fn render_sequence(
+    path: &str,
+    timeline_path: &str,
+    dir: &str,
+    samples: u32,
+    apng: bool,
+) -> Result<(), String> {
+    let timeline = std::fs::read_to_string(timeline_path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| Timeline::parse(&text).map_err(|e| e.to_string()))
+        .map_err(|e| format!("{timeline_path}: {e}"))?;
+    let mut scene = headless_mesh_scene(path)?;
+    let animation = Animation::new(timeline, &scene.tris_bvh)
+        .map_err(|e| format!("{timeline_path}: {e}"))?;
+    render_frames(&mut scene, &animation, dir, samples, apng)
+}
+
+/// Renders `frames` frames of the camera going once round the mesh at `path`, a little
+/// above it, see `render_frames`. The orbit is centred on the bounds of the mesh and
+/// wide enough to keep them in view, the floor is left out as it reaches far past them.
+/// Fails without frames or triangles to render.
+fn render_turntable(
+    path: &str,
+    dir: &str,
+    frames: u32,
+    samples: u32,
+    apng: bool,
+) -> Result<(), String> {
+    if frames == 0 {
+        return Err("frames: a turntable needs at least one".into());
+    }
+    let mut scene = headless_mesh_scene(path)?;
+    let (min, max) = scene
+        .mesh_bounds()
+        .ok_or_else(|| format!("{path}: no triangles to turn round"))?;
+    let config = &scene.renderer.config;
+    let mut orbit = OrbitCamera::new(config.width as f32 / config.height as f32);
+    orbit.target = (min + max) * 0.5;
+    orbit.radius = 0.5 * min.distance(max) / (0.5 * orbit.fov).sin();
+    orbit.theta = 0.5 * PI;
+    orbit.phi = 0.4 * PI;
+    let animation = Animation::new(orbit.turntable(frames), &scene.tris_bvh)?;
+    render_frames(&mut scene, &animation, dir, samples, apng)
+}
+
+/// `arg` as a count of `what`, or `default` if it is missing.
+fn count(arg: Option<&&str>, default: u32, what: &str) -> Result<u32, String> {
+    arg.map_or(Ok(default), |s| s.parse().map_err(|e| format!("{what}: {e}")))
 }
 
 impl Default for App {
@@ -24,36 +181,117 @@ impl Default for App {
         Self {
             start_time_stamp: Instant::now(),
             scene_id: 0,
+            scene_file: None,
+            mesh: None,
             scene: None,
             window: None,
             camera: None,
             gui: None,
+            flags: 0,
         }
     }
 }
 
 impl App {
     pub fn parse_args(&mut self, args: Vec<String>) {
+        // `stats <mesh>` prints how good the tree over the mesh is, `render <mesh> <timeline>
+        // <dir>` renders an animation of it and `turntable <mesh> <dir>` one round it, all
+        // without a window. `--apng` gathers the frames rendered into an animated PNG.
+        let command = args.get(1).map(String::as_str);
+        if matches!(command, Some("stats" | "render" | "turntable")) {
+            let apng = args.iter().any(|s| s == "--apng");
+            let rest: Vec<&str> = args[2..]
+                .iter()
+                .map(String::as_str)
+                .filter(|&s| s != "--apng")
+                .collect();
+            let done = match (command, rest.as_slice()) {
+                (Some("stats"), [path]) => print_stats(path),
+                (Some("render"), [path, timeline, dir, samples @ ..]) if samples.len() <= 1 => {
+                    count(samples.first(), 64, "samples")
+                        .and_then(|samples| render_sequence(path, timeline, dir, samples, apng))
+                }
+                (Some("turntable"), [path, dir, counts @ ..]) if counts.len() <= 2 => {
+                    count(counts.first(), 120, "frames").and_then(|frames| {
+                        let samples = count(counts.get(1), 256, "samples")?;
+                        render_turntable(path, dir, frames, samples, apng)
+                    })
+                }
+                (Some("stats"), _) => Err("usage: wgsl_toy stats <mesh>".into()),
+                (Some("render"), _) => {
+                    Err("usage: wgsl_toy render <mesh> <timeline> <dir> [samples] [--apng]".into())
+                }
+                _ => {
+                    Err("usage: wgsl_toy turntable <mesh> <dir> [frames] [samples] [--apng]".into())
+                }
+            };
+            if let Err(e) = done {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+            std::process::exit(0);
+        }
         let mut rng = rand::thread_rng();
-        let j = rng.gen_range(1..=7);
+        let j = rng.gen_range(1..=16);
         let i = args.get(1).map_or(j, |s| s.parse::<i8>().unwrap_or(j));
         self.scene_id = i;
+        // anything that is not a scene number is taken as a file path
+        if let Some(path) = args.get(1).filter(|s| s.parse::<i8>().is_err()) {
+            if let Err(e) = self.open(path) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    /// Reads a mesh, in any format `Mesh::load` knows, or otherwise a scene file, to be shown
+    /// by the next `build_scene`, which parses the mesh unless its tree is cached.
+    fn open(&mut self, path: &str) -> Result<(), String> {
+        let source = std::fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+        let is_scene = path.ends_with(".scene");
+        if !is_scene && MeshFormat::detect(path, &source).is_some() {
+            self.mesh = Some((path.to_string(), source));
+            self.scene_file = None;
+        } else {
+            let source = String::from_utf8(source).map_err(|e| format!("{path}: {e}"))?;
+            SceneFile::parse(&source).map_err(|e| format!("{path}: {e}"))?;
+            self.scene_file = Some(source);
+            self.mesh = None;
+        }
+        Ok(())
     }
-    async fn build_scene(&mut self) {
+    /// Replaces the scene, keeping the current one when the new one does not fit the device.
+    async fn build_scene(&mut self) -> Result<(), String> {
         let Some(window) = self.window.as_ref() else {
-            return;
+            return Ok(());
         };
         let render_output = RenderOutput::Window(window.clone());
-        let mut scene: Box<dyn Scene> = match self.scene_id {
-            2 => Box::new(SceneSphere::new(render_output).await),
-            3 => Box::new(SceneTris::new_quad(render_output).await),
-            4 => Box::new(SceneTris::new_cube(render_output).await),
-            5 => Box::new(SceneTris::new_suzane(render_output).await),
-            6 => Box::new(SceneTris::new_lucy(render_output).await),
-            7 => Box::new(SceneTris::new_dragon(render_output).await),
-            _ => Box::new(SceneSphere::new_simple(render_output).await),
+        let mut scene: Box<dyn Scene> = if let Some(source) = &self.scene_file {
+            let scene = SceneSphere::from_scene_file(source, render_output).await;
+            Box::new(scene.expect("scene file is checked in open"))
+        } else if let Some((path, source)) = &self.mesh {
+            let scene = SceneTris::load_mesh(path, source, mesh_material(), render_output).await;
+            Box::new(scene.map_err(|e| format!("{path}: {e}"))?)
+        } else {
+            match self.scene_id {
+                2 => Box::new(SceneSphere::new(render_output).await),
+                3 => Box::new(SceneTris::new_quad(render_output).await),
+                4 => Box::new(SceneTris::new_cube(render_output).await),
+                5 => Box::new(SceneTris::new_suzane(render_output).await),
+                6 => Box::new(SceneTris::new_lucy(render_output).await),
+                7 => Box::new(SceneTris::new_dragon(render_output).await),
+                8 => Box::new(SceneTris::new_bumpy(render_output).await),
+                9 => Box::new(SceneTris::new_fence(render_output).await),
+                10 => Box::new(SceneSphere::new_primitives(render_output).await),
+                11 => Box::new(SceneSphere::new_sdf(render_output).await),
+                12 => Box::new(SceneSphere::new_csg(render_output).await),
+                13 => Box::new(SceneSphere::new_hair(render_output).await),
+                14 => Box::new(SceneTris::new_subdivided(render_output).await),
+                15 => Box::new(SceneTris::new_points(render_output).await),
+                16 => Box::new(SceneSphere::new_cover(render_output).await),
+                _ => Box::new(SceneSphere::new_simple(render_output).await),
+            }
         };
-        scene.init();
+        scene.init().map_err(|e| e.to_string())?;
         self.scene = Some(scene);
         let size = window.inner_size();
         let aspect_ratio = size.width as f32 / size.height as f32;
@@ -76,6 +314,7 @@ impl App {
             
             self.gui = Some(gui);
         }
+        Ok(())
     }
 }
 
@@ -87,7 +326,10 @@ impl ApplicationHandler for App {
                 .unwrap(),
         );
         self.window = Some(window);
-        pollster::block_on(self.build_scene());
+        if let Err(e) = pollster::block_on(self.build_scene()) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
     }
     fn new_events(&mut self, _event_loop: &ActiveEventLoop, cause: StartCause) {
         if cause == StartCause::Poll {
@@ -95,6 +337,16 @@ impl ApplicationHandler for App {
             if let Some(scene) = self.scene.as_mut() {
                 scene.set_time(time);
             }
+            if let Some(path) = self.gui.as_mut().and_then(|gui| gui.open_requested.take()) {
+                let built = self
+                    .open(&path)
+                    .and_then(|()| pollster::block_on(self.build_scene()));
+                if let Err(e) = built {
+                    if let Some(gui) = self.gui.as_mut() {
+                        gui.open_error = Some(e);
+                    }
+                }
+            }
             if let Some(window) = self.window.as_ref() {
                 window.request_redraw();
             }
@@ -137,6 +389,14 @@ impl ApplicationHandler for App {
                             }
                             scene.update_camera(camera.to_uniform());
                         }
+                        if let Some(gui) = &self.gui {
+                            let flags = gui.render_flags();
+                            if flags != self.flags {
+                                self.flags = flags;
+                                scene.set_flags(flags);
+                                scene.reset_frame_count();
+                            }
+                        }
                         
                         // Draw scene with or without GUI
                         if let (Some(gui), Some(window)) = (&mut self.gui, &self.window) {
@@ -1,7 +1,9 @@
 use glam::{Vec3, Vec4};
+use crate::scene::{Interpolation, Timeline};
 use winit::event::{ElementState, MouseButton, MouseScrollDelta};
 use winit::dpi::PhysicalPosition;
 
+#[derive(Clone)]
 pub struct OrbitCamera {
     pub position: Vec3,
     pub target: Vec3,
@@ -69,6 +71,29 @@ impl OrbitCamera {
         self.has_moved = true;
     }
     
+    /// A timeline of `frames` frames going once round `target` at the current radius and
+    /// height, from the current angle, which renders as a turntable of the scene.
+    pub fn turntable(&self, frames: u32) -> Timeline {
+        let mut timeline = Timeline {
+            frames,
+            shutter: 0.0,
+            ..Timeline::default()
+        };
+        let mut orbit = self.clone();
+        for frame in 0..frames {
+            orbit.theta = self.theta + std::f32::consts::TAU * frame as f32 / frames as f32;
+            orbit.update_position();
+            let time = timeline.time(frame);
+            let keys = &mut timeline.camera;
+            keys.eye.key(time, orbit.position, Interpolation::Linear);
+            keys.target.key(time, self.target, Interpolation::Linear);
+            keys.focal_length.key(time, self.radius, Interpolation::Linear);
+            keys.blur.key(time, 0.0, Interpolation::Linear);
+            keys.fov.key(time, self.fov.to_degrees(), Interpolation::Linear);
+        }
+        timeline
+    }
+    
     pub fn handle_mouse_input(&mut self, state: ElementState, button: MouseButton) {
         if button == MouseButton::Left {
             self.is_dragging = state == ElementState::Pressed;
@@ -144,4 +169,28 @@ pub struct CameraUniform {
     pub focal_blur_amount: f32,
     pub fov: f32,
     pub _padding: f32,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turntable() {
+        let mut orbit = OrbitCamera::new(1.0);
+        orbit.target = Vec3::new(0.0, 1.0, 0.0);
+        orbit.update_position();
+        let timeline = orbit.turntable(4);
+        assert_eq!((timeline.frames, timeline.shutter), (4, 0.0));
+        let eye = |frame| timeline.camera.eye.sample(timeline.time(frame)).unwrap();
+        assert_eq!(eye(0), orbit.position);
+        // a quarter turn a frame, at the height and distance of the orbit
+        for frame in 0..4 {
+            assert!((eye(frame).distance(orbit.target) - orbit.radius).abs() < 1e-5);
+            assert!((eye(frame).y - orbit.position.y).abs() < 1e-5);
+        }
+        let (a, b) = (eye(0) - orbit.target, eye(1) - orbit.target);
+        assert!((a.x * b.x + a.z * b.z).abs() < 1e-5);
+        assert!(timeline.camera_at(0.0).is_some());
+    }
+}
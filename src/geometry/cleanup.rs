@@ -0,0 +1,241 @@
+use glam::Vec3;
+use std::collections::HashMap;
+
+use crate::geometry::Mesh;
+
+impl Mesh {
+    /// Merges vertices closer than `epsilon`, keeping the attributes of the first one. Zero
+    /// merges exact duplicates only. Returns how many vertices were removed.
+    pub fn weld(&mut self, epsilon: f32) -> usize {
+        // exact duplicates still share a cell when `epsilon` is zero
+        let cell = epsilon.max(1e-6);
+        let key = |p: Vec3| (p / cell).floor().to_array().map(|c| c as i64);
+        let mut grid: HashMap<[i64; 3], Vec<u32>> = HashMap::new();
+        let mut remap = Vec::with_capacity(self.vertices.len());
+        for i in 0..self.vertices.len() as u32 {
+            let p = self.position(i);
+            let [x, y, z] = key(p);
+            let mut found = None;
+            'search: for dx in -1..=1 {
+                for dy in -1..=1 {
+                    for dz in -1..=1 {
+                        let Some(cell) =
+                            grid.get(&[x.wrapping_add(dx), y.wrapping_add(dy), z.wrapping_add(dz)])
+                        else {
+                            continue;
+                        };
+                        found = cell
+                            .iter()
+                            .copied()
+                            .find(|&j| self.position(j).distance(p) <= epsilon);
+                        if found.is_some() {
+                            break 'search;
+                        }
+                    }
+                }
+            }
+            remap.push(found.unwrap_or_else(|| {
+                grid.entry([x, y, z]).or_default().push(i);
+                i
+            }));
+        }
+        for index in self.indices.iter_mut() {
+            *index = remap[*index as usize];
+        }
+        self.remove_unused_vertices()
+    }
+
+    /// Sets the normal of every corner to the area weighted mean of the faces around its
+    /// vertex that meet its own face at less than `max_angle` radians, so creases sharper
+    /// than that stay sharp. Zero gives flat shading and π smooths everything. Vertex
+    /// normals take the mean of all faces.
+    pub fn compute_normals(&mut self, max_angle: f32) {
+        // twice the area long
+        let faces: Vec<Vec3> = self
+            .indices
+            .chunks_exact(3)
+            .map(|t| {
+                let p = [0, 1, 2].map(|v| self.position(t[v]));
+                (p[1] - p[0]).cross(p[2] - p[0])
+            })
+            .collect();
+        let mut around = vec![Vec::new(); self.vertices.len()];
+        for (k, t) in self.indices.chunks_exact(3).enumerate() {
+            for &i in t {
+                around[i as usize].push(k);
+            }
+        }
+        let cos_max = max_angle.cos();
+        for (j, &i) in self.indices.iter().enumerate() {
+            let own = faces[j / 3].normalize_or_zero();
+            let normal: Vec3 = around[i as usize]
+                .iter()
+                .map(|&k| faces[k])
+                .filter(|f| f.normalize_or_zero().dot(own) >= cos_max - 1e-6)
+                .sum();
+            self.corners[j].normal = normal.try_normalize().unwrap_or(own);
+        }
+        for (i, faces_around) in around.iter().enumerate() {
+            let normal: Vec3 = faces_around.iter().map(|&k| faces[k]).sum();
+            self.vertices[i].normal = normal
+                .try_normalize()
+                .unwrap_or(Vec3::Z)
+                .extend(1.0)
+                .to_array();
+        }
+        self.generate_tangents();
+    }
+
+    /// Drops triangles with a repeated vertex or no area, and the vertices left unused.
+    /// Returns how many triangles were removed.
+    pub fn remove_degenerates(&mut self) -> usize {
+        let before = self.indices.len() / 3;
+        let mut indices = Vec::with_capacity(self.indices.len());
+        let mut corners = Vec::with_capacity(self.corners.len());
+        for (t, c) in self
+            .indices
+            .chunks_exact(3)
+            .zip(self.corners.chunks_exact(3))
+        {
+            let p = [0, 1, 2].map(|v| self.position(t[v]));
+            let longest = [p[1] - p[0], p[2] - p[1], p[0] - p[2]]
+                .map(Vec3::length_squared)
+                .into_iter()
+                .fold(0.0, f32::max);
+            let area = (p[1] - p[0]).cross(p[2] - p[0]).length();
+            if t[0] == t[1] || t[1] == t[2] || t[2] == t[0] || area <= 1e-7 * longest {
+                continue;
+            }
+            indices.extend_from_slice(t);
+            corners.extend_from_slice(c);
+        }
+        self.indices = indices;
+        self.corners = corners;
+        self.remove_unused_vertices();
+        before - self.indices.len() / 3
+    }
+
+    /// Smallest and largest corner of the box around the vertices.
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        let positions = (0..self.vertices.len() as u32).map(|i| self.position(i));
+        positions.fold((Vec3::MAX, Vec3::MIN), |(min, max), p| {
+            (min.min(p), max.max(p))
+        })
+    }
+
+    pub fn translate(&mut self, offset: Vec3) {
+        for vertex in self.vertices.iter_mut() {
+            let p = Vec3::from_slice(&vertex.position) + offset;
+            vertex.position = p.extend(1.0).to_array();
+        }
+    }
+
+    /// Scales about the origin. Normals and tangents are unchanged by a uniform scale.
+    pub fn scale(&mut self, factor: f32) {
+        for vertex in self.vertices.iter_mut() {
+            let p = Vec3::from_slice(&vertex.position) * factor;
+            vertex.position = p.extend(1.0).to_array();
+        }
+    }
+
+    /// Centres the mesh on the origin and scales it so its longest side is one, so meshes
+    /// from any tool fit the same unit box.
+    pub fn normalize(&mut self) {
+        let (min, max) = self.bounds();
+        self.translate(-0.5 * (min + max));
+        self.scale(1.0 / (max - min).max_element().max(f32::EPSILON));
+    }
+
+    /// Drops vertices no triangle refers to, returns how many.
+    pub(crate) fn remove_unused_vertices(&mut self) -> usize {
+        let mut remap = vec![u32::MAX; self.vertices.len()];
+        for &i in &self.indices {
+            remap[i as usize] = 0;
+        }
+        let mut vertices = Vec::with_capacity(self.vertices.len());
+        for (i, vertex) in self.vertices.iter().enumerate() {
+            if remap[i] == 0 {
+                remap[i] = vertices.len() as u32;
+                vertices.push(*vertex);
+            }
+        }
+        for index in self.indices.iter_mut() {
+            *index = remap[*index as usize];
+        }
+        let removed = self.vertices.len() - vertices.len();
+        self.vertices = vertices;
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Material;
+
+    fn cube() -> Mesh {
+        Mesh::load_obj(
+            include_bytes!("../assets/cube.obj"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn weld() {
+        // two triangles of a quad, each with its own copy of the shared edge
+        let source =
+            b"v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 0 0.001\nv 1 1 0\nv 0 1 0\nf 1 2 3\nf 4 5 6\n";
+        let mut mesh = Mesh::load_obj(source, Material::new_lambertian(Vec3::ONE)).unwrap();
+        assert_eq!(mesh.weld(0.0), 1);
+        assert_eq!(mesh.vertices.len(), 5);
+        assert_eq!(mesh.weld(0.01), 1);
+        assert_eq!(mesh.indices, [0, 1, 2, 0, 2, 3]);
+        assert_eq!(mesh.corners.len(), 6);
+    }
+
+    #[test]
+    fn normals() {
+        let mut mesh = cube();
+        let (min, max) = mesh.bounds();
+        let center = 0.5 * (min + max);
+        mesh.compute_normals(30f32.to_radians());
+        for (t, c) in mesh
+            .indices
+            .chunks_exact(3)
+            .zip(mesh.corners.chunks_exact(3))
+        {
+            let p = [0, 1, 2].map(|v| mesh.position(t[v]));
+            let face = (p[1] - p[0]).cross(p[2] - p[0]).normalize();
+            for corner in c {
+                assert!(corner.normal.abs_diff_eq(face, 1e-5));
+            }
+        }
+        mesh.compute_normals(std::f32::consts::PI);
+        for (corner, &i) in mesh.corners.iter().zip(&mesh.indices) {
+            let diagonal = (mesh.position(i) - center).normalize();
+            // each cube face is two triangles, so the mean leans to the split faces
+            assert!(corner.normal.dot(diagonal) > 0.9);
+        }
+    }
+
+    #[test]
+    fn degenerates() {
+        let source = b"v 0 0 0\nv 1 0 0\nv 0 1 0\nv 2 0 0\nf 1 2 3\nf 1 2 4\nf 1 1 3\n";
+        let mut mesh = Mesh::load_obj(source, Material::new_lambertian(Vec3::ONE)).unwrap();
+        assert_eq!(mesh.remove_degenerates(), 2);
+        assert_eq!(mesh.indices, [0, 1, 2]);
+        assert_eq!(mesh.vertices.len(), 3);
+    }
+
+    #[test]
+    fn normalize() {
+        let mut mesh = cube();
+        mesh.scale(3.0);
+        mesh.translate(Vec3::new(5.0, -1.0, 2.0));
+        mesh.normalize();
+        let (min, max) = mesh.bounds();
+        assert!(min.abs_diff_eq(Vec3::splat(-0.5), 1e-5));
+        assert!(max.abs_diff_eq(Vec3::splat(0.5), 1e-5));
+    }
+}
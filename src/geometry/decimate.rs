@@ -0,0 +1,315 @@
+//! Edge collapse decimation driven by quadric error metrics, after Garland and Heckbert
+//! 1997. Each vertex carries the sum of the squared distances to the planes of the faces
+//! around it, and the edge whose collapse adds the least error goes first.
+use glam::{DMat3, DVec3};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use crate::geometry::Mesh;
+
+/// Weight of the planes pinning open edges, relative to the faces, so outlines and holes
+/// keep their shape.
+const BOUNDARY_WEIGHT: f64 = 100.0;
+
+/// Symmetric 4x4 matrix measuring the squared distance to a set of planes, upper triangle
+/// in rows.
+#[derive(Copy, Clone, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    fn plane(normal: DVec3, point: DVec3, weight: f64) -> Self {
+        let [a, b, c] = normal.to_array();
+        let d = -normal.dot(point);
+        Self([
+            a * a,
+            a * b,
+            a * c,
+            a * d,
+            b * b,
+            b * c,
+            b * d,
+            c * c,
+            c * d,
+            d * d,
+        ])
+        .scaled(weight)
+    }
+    fn scaled(self, weight: f64) -> Self {
+        Self(self.0.map(|q| q * weight))
+    }
+    fn add(self, other: Self) -> Self {
+        Self(std::array::from_fn(|i| self.0[i] + other.0[i]))
+    }
+    fn error(&self, p: DVec3) -> f64 {
+        let q = &self.0;
+        let (x, y, z) = (p.x, p.y, p.z);
+        q[0] * x * x
+            + q[4] * y * y
+            + q[7] * z * z
+            + 2.0 * (q[1] * x * y + q[2] * x * z + q[5] * y * z)
+            + 2.0 * (q[3] * x + q[6] * y + q[8] * z)
+            + q[9]
+    }
+    /// The point of least error, unless the planes leave it undetermined, as along a flat
+    /// region or a straight crease.
+    fn minimum(&self) -> Option<DVec3> {
+        let q = &self.0;
+        let a = DMat3::from_cols_array(&[q[0], q[1], q[2], q[1], q[4], q[5], q[2], q[5], q[7]]);
+        let scale = q[0] + q[4] + q[7];
+        if a.determinant().abs() <= 1e-9 * scale * scale * scale {
+            return None;
+        }
+        Some(-(a.inverse() * DVec3::new(q[3], q[6], q[8])))
+    }
+}
+
+/// A candidate collapse of `b` into `a`, valid while both vertices are at `stamps`.
+struct Collapse {
+    cost: f64,
+    a: u32,
+    b: u32,
+    position: DVec3,
+    stamps: [u32; 2],
+}
+
+// ordered so the cheapest collapse tops the heap
+impl Ord for Collapse {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Collapse {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Collapse {}
+
+struct Decimator {
+    positions: Vec<DVec3>,
+    quadrics: Vec<Quadric>,
+    stamps: Vec<u32>,
+    triangles: Vec<[u32; 3]>,
+    removed: Vec<bool>,
+    /// Triangles around each vertex, including removed ones until they are pruned.
+    around: Vec<Vec<usize>>,
+}
+
+impl Decimator {
+    fn collapse(&self, a: u32, b: u32) -> Collapse {
+        let q = self.quadrics[a as usize].add(self.quadrics[b as usize]);
+        let (pa, pb) = (self.positions[a as usize], self.positions[b as usize]);
+        let position = q.minimum().unwrap_or_else(|| {
+            [pa, pb, 0.5 * (pa + pb)]
+                .into_iter()
+                .min_by(|x, y| q.error(*x).total_cmp(&q.error(*y)))
+                .unwrap()
+        });
+        Collapse {
+            cost: q.error(position).max(0.0),
+            a,
+            b,
+            position,
+            stamps: [self.stamps[a as usize], self.stamps[b as usize]],
+        }
+    }
+
+    /// Whether moving `v` to `position` turns over one of its triangles that survive the
+    /// collapse of the edge to `other`.
+    fn flips(&self, v: u32, other: u32, position: DVec3) -> bool {
+        self.around[v as usize].iter().any(|&k| {
+            let t = self.triangles[k];
+            if self.removed[k] || t.contains(&other) {
+                return false;
+            }
+            let p = t.map(|i| self.positions[i as usize]);
+            let moved = t.map(|i| {
+                if i == v {
+                    position
+                } else {
+                    self.positions[i as usize]
+                }
+            });
+            let before = (p[1] - p[0]).cross(p[2] - p[0]);
+            let after = (moved[1] - moved[0]).cross(moved[2] - moved[0]);
+            before.dot(after) <= 0.0
+        })
+    }
+}
+
+impl Mesh {
+    /// Collapses edges in order of least quadric error until at most `target` triangles are
+    /// left, or no collapse is possible without turning a triangle over. Corners keep their
+    /// normals and UVs, so creases and seams survive, and tangents are regenerated.
+    pub fn decimate(&mut self, target: usize) {
+        let mut live = self.indices.len() / 3;
+        if live <= target {
+            return;
+        }
+        let n = self.vertices.len();
+        let triangles: Vec<[u32; 3]> = self
+            .indices
+            .chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]])
+            .collect();
+        let mut d = Decimator {
+            positions: (0..n as u32).map(|i| self.position(i).as_dvec3()).collect(),
+            quadrics: vec![Quadric::default(); n],
+            stamps: vec![0; n],
+            removed: vec![false; triangles.len()],
+            around: vec![Vec::new(); n],
+            triangles,
+        };
+        let mut edges: HashMap<(u32, u32), Vec<usize>> = HashMap::new();
+        for (k, t) in d.triangles.iter().enumerate() {
+            let p = t.map(|i| d.positions[i as usize]);
+            let normal = (p[1] - p[0]).cross(p[2] - p[0]);
+            // weighted by area
+            let q = Quadric::plane(normal.normalize_or_zero(), p[0], 0.5 * normal.length());
+            for v in 0..3 {
+                let i = t[v] as usize;
+                d.quadrics[i] = d.quadrics[i].add(q);
+                d.around[i].push(k);
+                let (a, b) = (t[v], t[(v + 1) % 3]);
+                edges.entry((a.min(b), a.max(b))).or_default().push(k);
+            }
+        }
+        for (&(a, b), faces) in &edges {
+            if let [k] = faces[..] {
+                let t = d.triangles[k];
+                let p = t.map(|i| d.positions[i as usize]);
+                let normal = (p[1] - p[0]).cross(p[2] - p[0]);
+                let (pa, pb) = (d.positions[a as usize], d.positions[b as usize]);
+                let side = (pb - pa).cross(normal).normalize_or_zero();
+                let q = Quadric::plane(side, pa, BOUNDARY_WEIGHT * pa.distance_squared(pb));
+                d.quadrics[a as usize] = d.quadrics[a as usize].add(q);
+                d.quadrics[b as usize] = d.quadrics[b as usize].add(q);
+            }
+        }
+        let mut heap: BinaryHeap<_> = edges.keys().map(|&(a, b)| d.collapse(a, b)).collect();
+        while live > target {
+            let Some(c) = heap.pop() else {
+                break;
+            };
+            let (a, b) = (c.a as usize, c.b as usize);
+            if c.stamps != [d.stamps[a], d.stamps[b]] {
+                continue;
+            }
+            if d.flips(c.a, c.b, c.position) || d.flips(c.b, c.a, c.position) {
+                continue;
+            }
+            d.positions[a] = c.position;
+            d.quadrics[a] = d.quadrics[a].add(d.quadrics[b]);
+            d.stamps[a] += 1;
+            // never matches a stamp again, `b` is gone
+            d.stamps[b] = u32::MAX;
+            for k in std::mem::take(&mut d.around[b]) {
+                if d.removed[k] {
+                    continue;
+                }
+                if d.triangles[k].contains(&c.a) {
+                    d.removed[k] = true;
+                    live -= 1;
+                } else {
+                    d.triangles[k] = d.triangles[k].map(|i| if i == c.b { c.a } else { i });
+                    d.around[a].push(k);
+                }
+            }
+            let removed = &d.removed;
+            d.around[a].retain(|&k| !removed[k]);
+            let mut neighbours: Vec<u32> = d.around[a]
+                .iter()
+                .flat_map(|&k| d.triangles[k])
+                .filter(|&i| i != c.a)
+                .collect();
+            neighbours.sort_unstable();
+            neighbours.dedup();
+            for i in neighbours {
+                heap.push(d.collapse(c.a, i));
+            }
+        }
+        let mut indices = Vec::with_capacity(3 * live);
+        let mut corners = Vec::with_capacity(3 * live);
+        for (k, t) in d.triangles.iter().enumerate() {
+            if !d.removed[k] {
+                indices.extend_from_slice(t);
+                corners.extend_from_slice(&self.corners[3 * k..3 * k + 3]);
+            }
+        }
+        for (vertex, p) in self.vertices.iter_mut().zip(&d.positions) {
+            vertex.position = p.as_vec3().extend(1.0).to_array();
+        }
+        self.indices = indices;
+        self.corners = corners;
+        self.remove_unused_vertices();
+        self.generate_tangents();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Material;
+    use glam::Vec3;
+
+    #[test]
+    fn sphere() {
+        let mut mesh = Mesh::load_obj(
+            include_bytes!("../assets/ico_sphere.obj"),
+            Material::new_metal(Vec3::ONE, 0.0),
+        )
+        .unwrap();
+        mesh.subdivide(2);
+        let (min, max) = mesh.bounds();
+        let center = 0.5 * (min + max);
+        let radius = 0.5 * (max - min).x;
+        let before = mesh.indices.len() / 3;
+        mesh.decimate(before / 8);
+        let after = mesh.indices.len() / 3;
+        assert!(
+            after <= before / 8 && after > before / 10,
+            "{before} -> {after}"
+        );
+        assert_eq!(mesh.corners.len(), mesh.indices.len());
+        for i in 0..mesh.vertices.len() as u32 {
+            let r = mesh.position(i).distance(center);
+            assert!((r - radius).abs() < 0.05 * radius, "{r} vs {radius}");
+        }
+        // still closed, every edge shared by two triangles
+        let mut edges = HashMap::new();
+        for t in mesh.indices.chunks_exact(3) {
+            for v in 0..3 {
+                let (a, b) = (t[v], t[(v + 1) % 3]);
+                *edges.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+            }
+        }
+        assert!(edges.values().all(|&n| n == 2));
+    }
+
+    #[test]
+    fn plane_keeps_outline() {
+        let mut mesh = Mesh::load_obj(
+            include_bytes!("../assets/quad.obj"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap();
+        mesh.subdivide(3);
+        let (min, max) = mesh.bounds();
+        let before = mesh.indices.len() / 3;
+        mesh.decimate(before / 4);
+        assert!(mesh.indices.len() / 3 <= before / 4);
+        // flat, so only the boundary planes cost anything and the outline stays
+        let (new_min, new_max) = mesh.bounds();
+        assert!(new_min.abs_diff_eq(min, 1e-3) && new_max.abs_diff_eq(max, 1e-3));
+        let flat = (max - min).min_element();
+        assert!(flat < 1e-5);
+    }
+}
@@ -1,62 +1,388 @@
+use crate::geometry::ply::{self, PlyError};
+use crate::geometry::stl;
 use crate::{geometry::Vertex, scene::Material};
+use glam::{Vec2, Vec3, Vec4};
+use std::collections::HashMap;
+use std::fmt;
 use std::io::BufReader;
+use std::path::Path;
 
+/// Why a mesh could not be loaded.
+#[derive(Debug)]
+pub enum MeshError {
+    Io(std::io::Error),
+    Obj(tobj::LoadError),
+    Ply(PlyError),
+    Stl(String),
+    /// Neither the file name nor the contents tell the format.
+    UnknownFormat,
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Obj(e) => write!(f, "OBJ: {e}"),
+            Self::Ply(e) => write!(f, "PLY: {e}"),
+            Self::Stl(e) => write!(f, "STL: {e}"),
+            Self::UnknownFormat => write!(f, "unknown mesh format"),
+        }
+    }
+}
+
+impl std::error::Error for MeshError {}
+
+impl From<std::io::Error> for MeshError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<PlyError> for MeshError {
+    fn from(e: PlyError) -> Self {
+        Self::Ply(e)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MeshFormat {
+    Obj,
+    Ply,
+    Stl,
+}
+
+impl MeshFormat {
+    /// The format named by the extension of `path`, otherwise guessed from the contents.
+    pub fn detect(path: &str, source: &[u8]) -> Option<Self> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("obj") => Some(Self::Obj),
+            Some("ply") => Some(Self::Ply),
+            Some("stl") => Some(Self::Stl),
+            _ if source.starts_with(b"ply") => Some(Self::Ply),
+            _ if stl::is_binary(source) || source.starts_with(b"solid") => Some(Self::Stl),
+            _ if source.split(|&b| b == b'\n').any(|l| l.starts_with(b"v ")) => Some(Self::Obj),
+            _ => None,
+        }
+    }
+}
+
+/// Attributes of one triangle corner, OBJ indexes normals and UVs separately from positions.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Corner {
+    pub normal: Vec3,
+    pub uv: Vec2,
+    /// Tangent along increasing U, `w` is the sign of the bitangent.
+    pub tangent: Vec4,
+}
+
+#[derive(Clone)]
 pub struct Mesh {
     pub vertices: Vec<Vertex>,
     pub indices: Vec<u32>,
+    /// One entry per element of `indices`.
+    pub corners: Vec<Corner>,
     pub material: Material,
 }
 
 impl Mesh {
-    pub fn load_obj(source: &[u8], material: Material) -> Self {
+    pub fn load_obj(source: &[u8], material: Material) -> Result<Self, MeshError> {
         let mut reader = BufReader::new(source);
-        if let Ok((models, _materials)) = tobj::load_obj_buf(
+        let (models, _materials) = tobj::load_obj_buf(
             &mut reader,
             &tobj::LoadOptions {
                 // single_index: true,
                 ..Default::default()
             },
             |_matpath| Err(tobj::LoadError::GenericFailure),
-        ) {
-            let mut vertices = Vec::new();
-            let mut indices = Vec::new();
-            for model in models {
-                let mesh = model.mesh;
-                // println!("pos: {:?}", mesh.positions.len());
-                // println!("index: {:?}", mesh.normal_indices.len());
-                // println!("index: {:?}", mesh.indices.len());
-                let offset = vertices.len() as u32;
-                let n = mesh.positions.len();
-                for i in 0..n / 3 {
-                    let i = i * 3;
-                    let pos = [
-                        mesh.positions[i],
-                        mesh.positions[i + 1],
-                        mesh.positions[i + 2],
-                    ];
-                    let nor = if mesh.normals.len() <= i + 2 {
-                        [0.0, 0.0, 1.0]
-                    } else {
-                        [mesh.normals[i], mesh.normals[i + 1], mesh.normals[i + 2]]
+        )
+        .map_err(MeshError::Obj)?;
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        let mut corners = Vec::new();
+        for model in models {
+            let mesh = model.mesh;
+            // println!("pos: {:?}", mesh.positions.len());
+            // println!("index: {:?}", mesh.normal_indices.len());
+            // println!("index: {:?}", mesh.indices.len());
+            let offset = vertices.len() as u32;
+            let n = mesh.positions.len();
+            for i in 0..n / 3 {
+                let i = i * 3;
+                let pos = [
+                    mesh.positions[i],
+                    mesh.positions[i + 1],
+                    mesh.positions[i + 2],
+                ];
+                let nor = if mesh.normals.len() <= i + 2 {
+                    [0.0, 0.0, 1.0]
+                } else {
+                    [mesh.normals[i], mesh.normals[i + 1], mesh.normals[i + 2]]
+                };
+                let mut vertex = Vertex::new(pos, nor, 0xffffffff);
+                // the `v x y z r g b` extension, gamma encoded like PLY colours
+                if let Some(rgb) = mesh.vertex_color.get(i..i + 3) {
+                    let rgb = Vec3::from_slice(rgb).clamp(Vec3::ZERO, Vec3::ONE).powf(2.2);
+                    vertex.color = rgb.extend(1.0).to_array();
+                }
+                vertices.push(vertex);
+            }
+            for (k, t) in mesh.indices.chunks_exact(3).enumerate() {
+                let p: [Vec3; 3] = std::array::from_fn(|v| {
+                    Vec3::from_slice(&mesh.positions[3 * t[v] as usize..])
+                });
+                let face_normal = (p[1] - p[0]).cross(p[2] - p[0]).normalize_or_zero();
+                for j in 3 * k..3 * k + 3 {
+                    let normal = match mesh.normal_indices.get(j) {
+                        Some(&i) => Vec3::from_slice(&mesh.normals[3 * i as usize..]),
+                        None => face_normal,
+                    };
+                    let uv = match mesh.texcoord_indices.get(j) {
+                        Some(&i) => Vec2::from_slice(&mesh.texcoords[2 * i as usize..]),
+                        None => Vec2::ZERO,
                     };
-                    let col = 0xffff00ff;
-                    vertices.push(Vertex::new(pos, nor, col));
+                    corners.push(Corner {
+                        normal,
+                        uv,
+                        tangent: Vec4::ZERO,
+                    });
                 }
-                for i in mesh.indices {
-                    indices.push(offset + i);
+            }
+            for i in mesh.indices {
+                indices.push(offset + i);
+            }
+        }
+        let mut ret = Self {
+            vertices,
+            indices,
+            corners,
+            material,
+        };
+        ret.generate_tangents();
+        Ok(ret)
+    }
+
+    pub fn load_obj_file(path: impl AsRef<Path>, material: Material) -> Result<Self, MeshError> {
+        Self::load_obj(&std::fs::read(path)?, material)
+    }
+
+    /// Reads any format `load` knows from disk.
+    pub fn load_file(path: impl AsRef<Path>, material: Material) -> Result<Self, MeshError> {
+        let path = path.as_ref();
+        let source = std::fs::read(path)?;
+        Self::load(&path.to_string_lossy(), &source, material)
+    }
+
+    /// Loads OBJ, PLY or STL, telling them apart with `MeshFormat::detect`.
+    pub fn load(path: &str, source: &[u8], material: Material) -> Result<Self, MeshError> {
+        match MeshFormat::detect(path, source) {
+            Some(MeshFormat::Obj) => Self::load_obj(source, material),
+            Some(MeshFormat::Ply) => Ok(Self::load_ply(source, material)?),
+            Some(MeshFormat::Stl) => Self::load_stl(source, material),
+            None => Err(MeshError::UnknownFormat),
+        }
+    }
+
+    /// Reads the `vertex` element of a PLY file, positions with optional normals, colours
+    /// and UVs, and the polygons of `face` split into fans of triangles. Without normals the
+    /// mesh is flat shaded.
+    pub fn load_ply(source: &[u8], material: Material) -> Result<Self, PlyError> {
+        let mut vertices = Vec::new();
+        let mut uvs = Vec::new();
+        let mut indices = Vec::new();
+        let mut columns = None;
+        ply::read(source, |element, row| match element.name.as_str() {
+            "vertex" => {
+                let columns = match &mut columns {
+                    Some(columns) => columns,
+                    None => {
+                        // all single values, so each row holds one of every column
+                        let find = |names: &[&[&str]]| -> Result<Option<Vec<usize>>, PlyError> {
+                            names.iter().map(|n| element.scalar_property(n)).collect()
+                        };
+                        let color = find(&[&["red", "r"], &["green", "g"], &["blue", "b"]])?
+                            .map(|c| (element.properties[c[0]].unit(), c));
+                        columns.insert((
+                            find(&[&["x"], &["y"], &["z"]])?,
+                            find(&[&["nx"], &["ny"], &["nz"]])?,
+                            color,
+                            element.scalar_property(&["alpha", "a"])?,
+                            find(&[&["u", "s", "texture_u"], &["v", "t", "texture_v"]])?,
+                        ))
+                    }
+                };
+                let value = |i: usize| element.values(row, i)[0] as f32;
+                let Some(position) = &columns.0 else {
+                    return Ok(());
+                };
+                let position =
+                    Vec3::new(value(position[0]), value(position[1]), value(position[2]));
+                let normal = match &columns.1 {
+                    Some(n) => Vec3::new(value(n[0]), value(n[1]), value(n[2])).normalize_or_zero(),
+                    None => Vec3::Z,
+                };
+                let color = match &columns.2 {
+                    Some((unit, c)) => {
+                        let unit = *unit as f32;
+                        let rgb = Vec3::new(value(c[0]), value(c[1]), value(c[2])) / unit;
+                        let alpha = columns.3.map_or(1.0, |a| value(a) / unit);
+                        rgb.clamp(Vec3::ZERO, Vec3::ONE).powf(2.2).extend(alpha)
+                    }
+                    None => Vec4::ONE,
+                };
+                vertices.push(Vertex {
+                    position: position.extend(1.0).to_array(),
+                    normal: normal.extend(1.0).to_array(),
+                    color: color.to_array(),
+                });
+                if let Some(uv) = &columns.4 {
+                    uvs.push(Vec2::new(value(uv[0]), value(uv[1])));
                 }
+                Ok(())
             }
-            Self {
-                vertices,
-                indices,
-                material,
+            "face" => {
+                let Some(list) = element.property(&["vertex_indices", "vertex_index"]) else {
+                    return Ok(());
+                };
+                let polygon = element.values(row, list);
+                if polygon.iter().any(|&v| v < 0.0) {
+                    return Err(PlyError("face refers to a negative vertex index".to_string()));
+                }
+                for i in 2..polygon.len() {
+                    indices.extend([polygon[0], polygon[i - 1], polygon[i]].map(|v| v as u32));
+                }
+                Ok(())
             }
-        } else {
-            Self {
-                vertices: Vec::new(),
-                indices: Vec::new(),
-                material,
+            _ => Ok(()),
+        })?;
+        if columns.as_ref().is_some_and(|c| c.0.is_none()) {
+            return Err(PlyError("vertices have no `x y z`".to_string()));
+        }
+        if indices.iter().any(|&i| i as usize >= vertices.len()) {
+            return Err(PlyError("face refers to a missing vertex".to_string()));
+        }
+        let smooth = columns.is_some_and(|c| c.1.is_some());
+        Ok(Self::from_triangles(
+            vertices, indices, &uvs, smooth, material,
+        ))
+    }
+
+    /// Reads binary or ASCII STL. Facets are welded where their corners meet exactly and
+    /// flat shaded, STL has no normals worth keeping.
+    pub fn load_stl(source: &[u8], material: Material) -> Result<Self, MeshError> {
+        let facets = stl::read(source).map_err(MeshError::Stl)?;
+        let mut welded = HashMap::new();
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for corner in facets.iter().flatten() {
+            let index = *welded
+                .entry(corner.to_array().map(f32::to_bits))
+                .or_insert_with(|| {
+                    vertices.push(Vertex::new(corner.to_array(), [0.0, 0.0, 1.0], 0xffffffff));
+                    vertices.len() as u32 - 1
+                });
+            indices.push(index);
+        }
+        Ok(Self::from_triangles(
+            vertices,
+            indices,
+            &[],
+            false,
+            material,
+        ))
+    }
+
+    /// Fills in the corners of indexed triangles, with the vertex normal when `smooth` or
+    /// the face normal otherwise, and UVs from `uvs` when there is one per vertex.
+    fn from_triangles(
+        vertices: Vec<Vertex>,
+        indices: Vec<u32>,
+        uvs: &[Vec2],
+        smooth: bool,
+        material: Material,
+    ) -> Self {
+        let mut mesh = Self {
+            vertices,
+            indices,
+            corners: Vec::new(),
+            material,
+        };
+        let has_uvs = uvs.len() == mesh.vertices.len();
+        for t in mesh.indices.chunks_exact(3) {
+            let p = [0, 1, 2].map(|v| mesh.position(t[v]));
+            let face_normal = (p[1] - p[0]).cross(p[2] - p[0]).normalize_or_zero();
+            for &i in t {
+                let normal = match smooth {
+                    true => Vec4::from_array(mesh.vertices[i as usize].normal).truncate(),
+                    false => face_normal,
+                };
+                mesh.corners.push(Corner {
+                    normal,
+                    uv: if has_uvs { uvs[i as usize] } else { Vec2::ZERO },
+                    tangent: Vec4::ZERO,
+                });
+            }
+        }
+        mesh.generate_tangents();
+        mesh
+    }
+
+    pub(crate) fn position(&self, index: u32) -> Vec3 {
+        Vec4::from_array(self.vertices[index as usize].position).truncate()
+    }
+
+    /// Per corner tangents following MikkTSpace: each triangle's UV derivative is projected
+    /// onto the corner normal, weighted by the corner angle and summed over every corner
+    /// sharing the same position, normal and UV. Meshes without UVs get an arbitrary frame.
+    pub fn generate_tangents(&mut self) {
+        let key = |mesh: &Self, j: usize| {
+            let c = &mesh.corners[j];
+            (
+                mesh.indices[j],
+                c.normal.to_array().map(f32::to_bits),
+                c.uv.to_array().map(f32::to_bits),
+            )
+        };
+        let mut sums: HashMap<_, (Vec3, Vec3)> = HashMap::new();
+        for k in 0..self.indices.len() / 3 {
+            let j = [3 * k, 3 * k + 1, 3 * k + 2];
+            let p = j.map(|j| self.position(self.indices[j]));
+            let uv = j.map(|j| self.corners[j].uv);
+            let (e1, e2) = (p[1] - p[0], p[2] - p[0]);
+            let (d1, d2) = (uv[1] - uv[0], uv[2] - uv[0]);
+            let det = d1.x * d2.y - d2.x * d1.y;
+            if det.abs() < f32::EPSILON {
+                continue;
             }
+            let sdir = (e1 * d2.y - e2 * d1.y) / det;
+            let tdir = (e2 * d1.x - e1 * d2.x) / det;
+            for v in 0..3 {
+                let a = p[(v + 1) % 3] - p[v];
+                let b = p[(v + 2) % 3] - p[v];
+                let angle = a.angle_between(b);
+                if !angle.is_finite() {
+                    continue;
+                }
+                let n = self.corners[j[v]].normal;
+                let t = (sdir - n * n.dot(sdir)).normalize_or_zero();
+                let sum = sums.entry(key(self, j[v])).or_default();
+                sum.0 += t * angle;
+                sum.1 += tdir * angle;
+            }
+        }
+        for j in 0..self.corners.len() {
+            let n = self.corners[j].normal;
+            let (t, b) = sums.get(&key(self, j)).copied().unwrap_or_default();
+            let t = (t - n * n.dot(t)).normalize_or_zero();
+            self.corners[j].tangent = if t == Vec3::ZERO {
+                n.any_orthonormal_vector().extend(1.0)
+            } else {
+                let sign = if n.cross(t).dot(b) < 0.0 { -1.0 } else { 1.0 };
+                t.extend(sign)
+            };
         }
     }
 }
@@ -72,9 +398,11 @@ mod tests {
         let mesh = Mesh::load_obj(
             include_bytes!("../assets/cube.obj"),
             Material::new_lambertian(Vec3::new(0.5, 0.5, 0.5)),
-        );
+        )
+        .unwrap();
         assert_eq!(mesh.vertices.len(), 8);
         assert_eq!(mesh.indices.len(), 36);
+        assert_eq!(mesh.corners.len(), 36);
     }
 
     #[test]
@@ -82,8 +410,128 @@ mod tests {
         let mesh = Mesh::load_obj(
             include_bytes!("../assets/suzanne.obj"),
             Material::new_lambertian(Vec3::new(0.5, 0.5, 0.5)),
-        );
+        )
+        .unwrap();
         assert_eq!(mesh.vertices.len(), 515);
         assert_eq!(mesh.indices.len(), 2937);
     }
+
+    #[test]
+    fn errors() {
+        let material = Material::new_lambertian(Vec3::ONE);
+        let error = Mesh::load_obj_file("src/assets/missing.obj", material).err();
+        assert!(matches!(error, Some(MeshError::Io(_))));
+        let error = Mesh::load_obj(b"v 0 0 zero\nf 1 1 1\n", material).err();
+        assert!(matches!(error, Some(MeshError::Obj(_))));
+        let mesh = Mesh::load_file("src/assets/cube.obj", material).unwrap();
+        assert_eq!(mesh.indices.len(), 36);
+    }
+
+    #[test]
+    fn ply() {
+        // a quad with colours and UVs, split into two triangles
+        let source = b"ply\nformat ascii 1.0\nelement vertex 4\n\
+            property float x\nproperty float y\nproperty float z\n\
+            property float s\nproperty float t\n\
+            property uchar red\nproperty uchar green\nproperty uchar blue\n\
+            element face 1\nproperty list uchar int vertex_indices\nend_header\n\
+            0 0 0 0 0 255 0 0\n1 0 0 1 0 0 255 0\n1 1 0 1 1 0 0 255\n0 1 0 0 1 255 255 255\n\
+            4 0 1 2 3\n";
+        let mesh = Mesh::load_ply(source, Material::new_lambertian(Vec3::ONE)).unwrap();
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.indices, [0, 1, 2, 0, 2, 3]);
+        assert_eq!(mesh.vertices[1].color, [0.0, 1.0, 0.0, 1.0]);
+        assert_eq!(mesh.corners[2].uv, Vec2::ONE);
+        assert_eq!(mesh.corners[0].normal, Vec3::Z);
+        assert!(mesh.corners[0]
+            .tangent
+            .abs_diff_eq(Vec4::new(1.0, 0.0, 0.0, 1.0), 1e-5));
+        let source = b"ply\nformat ascii 1.0\nelement vertex 1\n\
+            property float x\nproperty float y\nproperty float z\n\
+            element face 1\nproperty list uchar int vertex_index\nend_header\n0 0 0\n3 0 1 2\n";
+        let error = Mesh::load_ply(source, Material::new_lambertian(Vec3::ONE)).err();
+        assert_eq!(
+            error,
+            Some(PlyError("face refers to a missing vertex".into()))
+        );
+        let source = b"ply\nformat ascii 1.0\nelement vertex 3\n\
+            property float x\nproperty float y\nproperty float z\n\
+            element face 1\nproperty list uchar int vertex_index\nend_header\n\
+            0 0 0\n1 0 0\n1 1 0\n3 0 -1 2\n";
+        let error = Mesh::load_ply(source, Material::new_lambertian(Vec3::ONE)).err();
+        assert_eq!(
+            error,
+            Some(PlyError("face refers to a negative vertex index".into()))
+        );
+        // an empty list where a coordinate should be
+        let source = b"ply\nformat ascii 1.0\nelement vertex 1\n\
+            property list uchar float x\nproperty float y\nproperty float z\n\
+            end_header\n0 0 0\n";
+        let error = Mesh::load_ply(source, Material::new_lambertian(Vec3::ONE)).err();
+        assert_eq!(
+            error,
+            Some(PlyError("property `x` of element `vertex` is a list".into()))
+        );
+    }
+
+    #[test]
+    fn stl() {
+        let source = b"solid quad\n\
+            facet normal 0 0 0\nouter loop\nvertex 0 0 0\nvertex 1 0 0\nvertex 1 1 0\nendloop\nendfacet\n\
+            facet normal 0 0 0\nouter loop\nvertex 0 0 0\nvertex 1 1 0\nvertex 0 1 0\nendloop\nendfacet\n\
+            endsolid quad\n";
+        let mesh = Mesh::load("quad.STL", source, Material::new_lambertian(Vec3::ONE)).unwrap();
+        // the shared diagonal is welded
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.indices.len(), 6);
+        for corner in &mesh.corners {
+            assert_eq!(corner.normal, Vec3::Z);
+        }
+        assert!(Mesh::load(
+            "quad.stl",
+            b"solid\nvertex 0 0",
+            Material::new_lambertian(Vec3::ONE)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn detect() {
+        let cube = include_bytes!("../assets/cube.obj");
+        assert_eq!(MeshFormat::detect("cube.obj", b""), Some(MeshFormat::Obj));
+        assert_eq!(
+            MeshFormat::detect("scan", b"ply\nformat"),
+            Some(MeshFormat::Ply)
+        );
+        assert_eq!(
+            MeshFormat::detect("part", b"solid part"),
+            Some(MeshFormat::Stl)
+        );
+        assert_eq!(MeshFormat::detect("model", cube), Some(MeshFormat::Obj));
+        assert_eq!(MeshFormat::detect("notes.txt", b"hello"), None);
+        let mesh = Mesh::load("model", cube, Material::new_lambertian(Vec3::ONE)).unwrap();
+        assert_eq!(mesh.indices.len(), 36);
+    }
+
+    #[test]
+    fn tangents() {
+        let source = b"v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+            vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\nvn 0 0 1\n\
+            f 1/1/1 2/2/1 3/3/1\nf 1/1/1 3/3/1 4/4/1\n";
+        let mesh = Mesh::load_obj(source, Material::new_lambertian(Vec3::ONE)).unwrap();
+        for corner in mesh.corners {
+            assert!(corner
+                .tangent
+                .abs_diff_eq(Vec4::new(1.0, 0.0, 0.0, 1.0), 1e-5));
+        }
+        // mirrored U flips the handedness
+        let source = b"v 0 0 0\nv 1 0 0\nv 1 1 0\n\
+            vt 1 0\nvt 0 0\nvt 0 1\nvn 0 0 1\nf 1/1/1 2/2/1 3/3/1\n";
+        let mesh = Mesh::load_obj(source, Material::new_lambertian(Vec3::ONE)).unwrap();
+        for corner in mesh.corners {
+            assert!(corner
+                .tangent
+                .abs_diff_eq(Vec4::new(-1.0, 0.0, 0.0, -1.0), 1e-5));
+        }
+    }
 }
@@ -1,4 +1,11 @@
 pub mod mesh;
+pub mod ply;
+pub mod point_cloud;
+pub mod stl;
+mod cleanup;
+mod decimate;
+mod subdivision;
 pub mod vertex;
 pub use mesh::Mesh;
+pub use point_cloud::PointCloud;
 pub use vertex::Vertex;
@@ -0,0 +1,335 @@
+//! Reader for Stanford PLY files, ASCII or binary in either byte order. Elements are handed
+//! to the caller row by row, so millions of vertices never sit in memory twice.
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlyError(pub String);
+
+impl fmt::Display for PlyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for PlyError {}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Format {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Result<Self, PlyError> {
+        Ok(match name {
+            "char" | "int8" => Self::I8,
+            "uchar" | "uint8" => Self::U8,
+            "short" | "int16" => Self::I16,
+            "ushort" | "uint16" => Self::U16,
+            "int" | "int32" => Self::I32,
+            "uint" | "uint32" => Self::U32,
+            "float" | "float32" => Self::F32,
+            "double" | "float64" => Self::F64,
+            _ => return Err(PlyError(format!("unknown property type `{name}`"))),
+        })
+    }
+    fn size(self) -> usize {
+        match self {
+            Self::I8 | Self::U8 => 1,
+            Self::I16 | Self::U16 => 2,
+            Self::I32 | Self::U32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+    fn decode(self, bytes: &[u8], format: Format) -> f64 {
+        macro_rules! decode {
+            ($t:ty) => {{
+                let bytes = bytes.try_into().unwrap();
+                match format {
+                    Format::BigEndian => <$t>::from_be_bytes(bytes) as f64,
+                    _ => <$t>::from_le_bytes(bytes) as f64,
+                }
+            }};
+        }
+        match self {
+            Self::I8 => decode!(i8),
+            Self::U8 => decode!(u8),
+            Self::I16 => decode!(i16),
+            Self::U16 => decode!(u16),
+            Self::I32 => decode!(i32),
+            Self::U32 => decode!(u32),
+            Self::F32 => decode!(f32),
+            Self::F64 => decode!(f64),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Property {
+    pub name: String,
+    kind: Scalar,
+    /// Type of the length prefix of list properties.
+    list: Option<Scalar>,
+}
+
+impl Property {
+    /// The value standing for full intensity in a colour channel of this type, 255 for
+    /// bytes and 1 for floats.
+    pub fn unit(&self) -> f64 {
+        match self.kind {
+            Scalar::U8 | Scalar::I8 => 255.0,
+            Scalar::U16 | Scalar::I16 => 65535.0,
+            _ => 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Element {
+    pub name: String,
+    pub count: usize,
+    pub properties: Vec<Property>,
+}
+
+impl Element {
+    /// Index of the first property called any of `names`, files disagree on spelling.
+    pub fn property(&self, names: &[&str]) -> Option<usize> {
+        self.properties
+            .iter()
+            .position(|p| names.contains(&p.name.as_str()))
+    }
+    /// As `property`, but an error if the property found is a list rather than one value.
+    pub fn scalar_property(&self, names: &[&str]) -> Result<Option<usize>, PlyError> {
+        match self.property(names) {
+            Some(i) if self.properties[i].list.is_some() => Err(PlyError(format!(
+                "property `{}` of element `{}` is a list",
+                self.properties[i].name, self.name
+            ))),
+            found => Ok(found),
+        }
+    }
+    /// Values of property `index` in `row`, one for a scalar or the items of a list.
+    pub fn values<'a>(&self, row: &'a [f64], index: usize) -> &'a [f64] {
+        let mut start = 0;
+        for property in &self.properties[..index] {
+            start += match property.list {
+                Some(_) => 1 + row[start] as usize,
+                None => 1,
+            };
+        }
+        match self.properties[index].list {
+            Some(_) => &row[start + 1..start + 1 + row[start] as usize],
+            None => &row[start..start + 1],
+        }
+    }
+}
+
+/// Parses the header of `source` and calls `visit` for every row of every element in file
+/// order, with the values of its properties in declaration order. Lists are flattened to
+/// their length followed by the items, see `Element::values`. An error from `visit` ends
+/// the read.
+pub fn read(
+    source: &[u8],
+    mut visit: impl FnMut(&Element, &[f64]) -> Result<(), PlyError>,
+) -> Result<(), PlyError> {
+    const END: &[u8] = b"end_header";
+    let end = source
+        .windows(END.len())
+        .position(|w| w == END)
+        .filter(|_| source.starts_with(b"ply"))
+        .ok_or_else(|| PlyError("not a PLY file".to_string()))?;
+    let header = std::str::from_utf8(&source[..end])
+        .map_err(|_| PlyError("header is not text".to_string()))?;
+    // the body starts after the line ending of `end_header`
+    let mut body = &source[end + END.len()..];
+    body = body.strip_prefix(b"\r").unwrap_or(body);
+    body = body.strip_prefix(b"\n").unwrap_or(body);
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in header.lines().skip(1) {
+        let words: Vec<_> = line.split_whitespace().collect();
+        match words[..] {
+            [] | ["comment", ..] | ["obj_info", ..] => {}
+            ["format", kind, _] => {
+                format = Some(match kind {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::LittleEndian,
+                    "binary_big_endian" => Format::BigEndian,
+                    _ => return Err(PlyError(format!("unknown format `{kind}`"))),
+                })
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| PlyError(format!("bad count for element `{name}`")))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count, kind, name] => {
+                let element = elements
+                    .last_mut()
+                    .ok_or_else(|| PlyError(format!("property `{name}` outside an element")))?;
+                element.properties.push(Property {
+                    name: name.to_string(),
+                    kind: Scalar::parse(kind)?,
+                    list: Some(Scalar::parse(count)?),
+                });
+            }
+            ["property", kind, name] => {
+                let element = elements
+                    .last_mut()
+                    .ok_or_else(|| PlyError(format!("property `{name}` outside an element")))?;
+                element.properties.push(Property {
+                    name: name.to_string(),
+                    kind: Scalar::parse(kind)?,
+                    list: None,
+                });
+            }
+            _ => return Err(PlyError(format!("unexpected header line `{line}`"))),
+        }
+    }
+    let format = format.ok_or_else(|| PlyError("missing format".to_string()))?;
+    let mut reader = Reader::new(body, format)?;
+    let mut row = Vec::new();
+    for element in &elements {
+        let truncated = || PlyError(format!("data ends inside element `{}`", element.name));
+        for _ in 0..element.count {
+            row.clear();
+            for property in &element.properties {
+                match property.list {
+                    Some(count) => {
+                        let n = reader.next(count)?.ok_or_else(truncated)?;
+                        row.push(n);
+                        for _ in 0..n as usize {
+                            row.push(reader.next(property.kind)?.ok_or_else(truncated)?);
+                        }
+                    }
+                    None => row.push(reader.next(property.kind)?.ok_or_else(truncated)?),
+                }
+            }
+            visit(element, &row)?;
+        }
+    }
+    Ok(())
+}
+
+enum Reader<'a> {
+    Ascii(std::str::SplitAsciiWhitespace<'a>),
+    Binary(&'a [u8], Format),
+}
+
+impl<'a> Reader<'a> {
+    fn new(body: &'a [u8], format: Format) -> Result<Self, PlyError> {
+        Ok(match format {
+            Format::Ascii => Self::Ascii(
+                std::str::from_utf8(body)
+                    .map_err(|_| PlyError("ASCII data is not text".to_string()))?
+                    .split_ascii_whitespace(),
+            ),
+            _ => Self::Binary(body, format),
+        })
+    }
+    fn next(&mut self, kind: Scalar) -> Result<Option<f64>, PlyError> {
+        match self {
+            Self::Ascii(words) => words
+                .next()
+                .map(|word| {
+                    word.parse()
+                        .map_err(|_| PlyError(format!("expected a number, found `{word}`")))
+                })
+                .transpose(),
+            Self::Binary(bytes, format) => {
+                let Some((value, rest)) = bytes.split_at_checked(kind.size()) else {
+                    return Ok(None);
+                };
+                *bytes = rest;
+                Ok(Some(kind.decode(value, *format)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(source: &[u8]) -> Result<Vec<(String, Vec<f64>)>, PlyError> {
+        let mut rows = Vec::new();
+        read(source, |element, row| {
+            rows.push((element.name.clone(), row.to_vec()));
+            Ok(())
+        })?;
+        Ok(rows)
+    }
+
+    #[test]
+    fn ascii() {
+        let source = b"ply\nformat ascii 1.0\ncomment made by hand\n\
+            element vertex 2\nproperty float x\nproperty uchar red\n\
+            element face 1\nproperty list uchar int vertex_indices\nend_header\n\
+            0.5 255\n-1 0\n3 0 1 1\n";
+        let rows = rows(source).unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0], ("vertex".to_string(), vec![0.5, 255.0]));
+        assert_eq!(rows[2], ("face".to_string(), vec![3.0, 0.0, 1.0, 1.0]));
+    }
+
+    #[test]
+    fn binary() {
+        let header = "ply\r\nformat binary_big_endian 1.0\r\nelement face 1\r\n\
+            property uchar flags\r\nproperty list uchar uint vertex_index\r\n\
+            property short s\r\nend_header\r\n";
+        let mut source = header.as_bytes().to_vec();
+        source.extend([7, 2]);
+        source.extend(5u32.to_be_bytes());
+        source.extend(9u32.to_be_bytes());
+        source.extend((-2i16).to_be_bytes());
+        let mut seen = false;
+        read(&source, |element, row| {
+            assert_eq!(row, [7.0, 2.0, 5.0, 9.0, -2.0]);
+            let list = element
+                .property(&["vertex_indices", "vertex_index"])
+                .unwrap();
+            assert_eq!(element.values(row, list), [5.0, 9.0]);
+            assert_eq!(element.values(row, 2), [-2.0]);
+            assert_eq!(element.scalar_property(&["s"]), Ok(Some(2)));
+            assert_eq!(
+                element.scalar_property(&["vertex_index"]),
+                Err(PlyError("property `vertex_index` of element `face` is a list".into()))
+            );
+            seen = true;
+            Ok(())
+        })
+        .unwrap();
+        assert!(seen);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(rows(b"solid cube"), Err(PlyError("not a PLY file".into())));
+        let source = b"ply\nformat binary_little_endian 1.0\nelement vertex 2\n\
+            property float x\nend_header\n\0\0\0\0";
+        assert_eq!(
+            rows(source),
+            Err(PlyError("data ends inside element `vertex`".into()))
+        );
+        let source = b"ply\nformat ascii 1.0\nelement vertex 1\nproperty half x\nend_header\n";
+        assert_eq!(
+            rows(source),
+            Err(PlyError("unknown property type `half`".into()))
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use glam::Vec3;
+
+use crate::geometry::ply::{self, PlyError};
+use crate::scene::Material;
+
+/// Scanned points drawn as small disks facing their normals, or as spheres when the scan
+/// has no normals, with their colour as albedo.
+pub struct PointCloud {
+    pub positions: Vec<Vec3>,
+    /// Empty when the points are spheres.
+    pub normals: Vec<Vec3>,
+    /// Linear RGB multiplied into the material's albedo, empty to use the albedo alone.
+    pub colors: Vec<Vec3>,
+    pub radius: f32,
+    pub material: Material,
+}
+
+impl PointCloud {
+    /// Reads the `vertex` element of a PLY file, `x y z` with optional `nx ny nz` and
+    /// `red green blue`. Colours are gamma encoded, as scanners write them. The radius is
+    /// guessed from the point density, see `estimate_radius`.
+    pub fn load_ply(source: &[u8], material: Material) -> Result<Self, PlyError> {
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut colors = Vec::new();
+        let mut columns = None;
+        ply::read(source, |element, row| {
+            if element.name != "vertex" {
+                return Ok(());
+            }
+            let columns = match columns {
+                Some(columns) => columns,
+                None => {
+                    let find = |names: [&str; 3]| -> Result<_, PlyError> {
+                        let [x, y, z] = names.map(|name| element.scalar_property(&[name]));
+                        Ok(match [x?, y?, z?] {
+                            [Some(x), Some(y), Some(z)] => Some([x, y, z]),
+                            _ => None,
+                        })
+                    };
+                    let color = match find(["red", "green", "blue"])? {
+                        Some(c) => Some(c),
+                        None => find(["r", "g", "b"])?,
+                    };
+                    let color = color.map(|c| (c, element.properties[c[0]].unit()));
+                    *columns.insert((find(["x", "y", "z"])?, find(["nx", "ny", "nz"])?, color))
+                }
+            };
+            let value = |i: usize| element.values(row, i)[0] as f32;
+            let vec3 = |c: [usize; 3]| Vec3::new(value(c[0]), value(c[1]), value(c[2]));
+            let Some(position) = columns.0 else {
+                return Ok(());
+            };
+            positions.push(vec3(position));
+            if let Some(normal) = columns.1 {
+                normals.push(vec3(normal).normalize_or_zero());
+            }
+            if let Some((color, unit)) = columns.2 {
+                colors.push(
+                    (vec3(color) / unit as f32)
+                        .clamp(Vec3::ZERO, Vec3::ONE)
+                        .powf(2.2),
+                );
+            }
+            Ok(())
+        })?;
+        if columns.is_some_and(|c| c.0.is_none()) {
+            return Err(PlyError("vertices have no `x y z`".to_string()));
+        }
+        let mut cloud = Self {
+            positions,
+            normals,
+            colors,
+            radius: 0.0,
+            material,
+        };
+        cloud.radius = cloud.estimate_radius();
+        Ok(cloud)
+    }
+    pub fn with_radius(mut self, radius: f32) -> Self {
+        self.radius = radius;
+        self
+    }
+    /// Half the spacing of the points if they were spread evenly over the surface of their
+    /// bounding box, about enough for neighbouring disks to close the gaps of a scan.
+    pub fn estimate_radius(&self) -> f32 {
+        let min = self.positions.iter().fold(Vec3::MAX, |a, &p| a.min(p));
+        let max = self.positions.iter().fold(Vec3::MIN, |a, &p| a.max(p));
+        let size = (max - min).max(Vec3::ZERO);
+        let area = 2.0 * (size.x * size.y + size.y * size.z + size.z * size.x);
+        0.5 * (area / self.positions.len().max(1) as f32).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load() {
+        let cloud = PointCloud::load_ply(
+            include_bytes!("../assets/dragon_points.ply"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap();
+        assert_eq!(cloud.positions.len(), 100000);
+        assert_eq!(cloud.normals.len(), 100000);
+        assert_eq!(cloud.colors.len(), 100000);
+        assert!(cloud.radius > 0.01 && cloud.radius < 0.03);
+        let source = b"ply\nformat ascii 1.0\nelement vertex 2\n\
+            property double x\nproperty double y\nproperty double z\n\
+            property float r\nproperty float g\nproperty float b\nend_header\n\
+            0 0 0 1 0.5 0\n2 1 0 0 0 2\n";
+        let cloud = PointCloud::load_ply(source, Material::new_lambertian(Vec3::ONE)).unwrap();
+        assert!(cloud.normals.is_empty());
+        assert_eq!(cloud.colors[0], Vec3::new(1.0, 0.5f32.powf(2.2), 0.0));
+        assert_eq!(cloud.colors[1], Vec3::Z);
+        assert!((cloud.radius - 0.5 * 2f32.sqrt()).abs() < 1e-6);
+        let source = b"ply\nformat ascii 1.0\nelement vertex 1\nproperty float u\nend_header\n0\n";
+        assert!(PointCloud::load_ply(source, Material::new_lambertian(Vec3::ONE)).is_err());
+        let source = b"ply\nformat ascii 1.0\nelement vertex 1\n\
+            property float x\nproperty float y\nproperty list uchar float z\nend_header\n0 0 0\n";
+        assert_eq!(
+            PointCloud::load_ply(source, Material::new_lambertian(Vec3::ONE)).err(),
+            Some(PlyError("property `z` of element `vertex` is a list".into()))
+        );
+    }
+}
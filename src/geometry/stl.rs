@@ -0,0 +1,89 @@
+//! Reader for STL files, binary or ASCII. Facet normals are ignored since exporters often
+//! leave them zero, the winding gives the same information.
+use glam::Vec3;
+
+/// Whether `source` is laid out as a binary STL, an 80 byte header and a count followed by
+/// exactly that many 50 byte facets. Some exporters start binary headers with `solid` too.
+pub fn is_binary(source: &[u8]) -> bool {
+    match source.get(80..84) {
+        Some(count) => {
+            let count = u32::from_le_bytes(count.try_into().unwrap()) as usize;
+            source.len() == 84 + 50 * count
+        }
+        None => false,
+    }
+}
+
+/// The corners of every facet.
+pub fn read(source: &[u8]) -> Result<Vec<[Vec3; 3]>, String> {
+    if is_binary(source) {
+        let facets = source[84..]
+            .chunks_exact(50)
+            .map(|facet| {
+                let float =
+                    |i: usize| f32::from_le_bytes(facet[4 * i..4 * i + 4].try_into().unwrap());
+                // skip the normal in the first three floats
+                [1, 2, 3].map(|v| Vec3::new(float(3 * v), float(3 * v + 1), float(3 * v + 2)))
+            })
+            .collect();
+        return Ok(facets);
+    }
+    let text = std::str::from_utf8(source).map_err(|_| "not an STL file".to_string())?;
+    let mut words = text.split_whitespace();
+    if words.next() != Some("solid") {
+        return Err("not an STL file".to_string());
+    }
+    let mut corners = Vec::new();
+    while let Some(word) = words.next() {
+        if word != "vertex" {
+            continue;
+        }
+        let mut number = || {
+            let word = words.next().unwrap_or_default();
+            word.parse::<f32>()
+                .map_err(|_| format!("expected a number after `vertex`, found `{word}`"))
+        };
+        corners.push(Vec3::new(number()?, number()?, number()?));
+    }
+    if corners.len() % 3 != 0 {
+        return Err("facet with other than three vertices".to_string());
+    }
+    Ok(corners
+        .chunks_exact(3)
+        .map(|c| [c[0], c[1], c[2]])
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii() {
+        let source = b"solid tri\nfacet normal 0 0 1\nouter loop\n\
+            vertex 0 0 0\nvertex 1 0 0\nvertex 0 1 0\nendloop\nendfacet\nendsolid tri\n";
+        assert_eq!(read(source), Ok(vec![[Vec3::ZERO, Vec3::X, Vec3::Y]]));
+        let source = b"solid bad\nfacet normal 0 0 1\nouter loop\nvertex 0 0 x\n";
+        assert_eq!(
+            read(source),
+            Err("expected a number after `vertex`, found `x`".into())
+        );
+        assert!(read(b"ply\n").is_err());
+    }
+
+    #[test]
+    fn binary() {
+        // a header starting with `solid` must not fool the detection
+        let mut source = b"solid but binary".to_vec();
+        source.resize(80, 0);
+        source.extend(1u32.to_le_bytes());
+        for x in [
+            0.0f32, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0,
+        ] {
+            source.extend(x.to_le_bytes());
+        }
+        source.extend([0, 0]);
+        assert!(is_binary(&source));
+        assert_eq!(read(&source), Ok(vec![[Vec3::ZERO, Vec3::X, Vec3::Y]]));
+    }
+}
@@ -0,0 +1,243 @@
+use glam::{Vec2, Vec3, Vec4};
+use std::collections::HashMap;
+
+use crate::geometry::mesh::Corner;
+use crate::geometry::{Mesh, Vertex};
+use crate::scene::Image;
+
+impl Mesh {
+    /// Refines the mesh `levels` times with Loop subdivision, each level splitting every
+    /// triangle in four and pulling the vertices towards the smooth limit surface. Open
+    /// edges follow the boundary rules so holes keep their outline. UVs are interpolated
+    /// linearly and normals are recomputed from the refined surface.
+    pub fn subdivide(&mut self, levels: u32) {
+        if levels == 0 {
+            return;
+        }
+        for _ in 0..levels {
+            self.subdivide_once();
+        }
+        self.smooth_normals();
+        self.generate_tangents();
+    }
+
+    fn subdivide_once(&mut self) {
+        // the corners opposite each edge, keyed by its sorted ends
+        let mut edges: HashMap<(u32, u32), Vec<u32>> = HashMap::new();
+        for t in self.indices.chunks_exact(3) {
+            for v in 0..3 {
+                let (a, b) = (t[v], t[(v + 1) % 3]);
+                edges
+                    .entry((a.min(b), a.max(b)))
+                    .or_default()
+                    .push(t[(v + 2) % 3]);
+            }
+        }
+        let n = self.vertices.len();
+        let mut ring = vec![Vec::new(); n];
+        let mut boundary = vec![Vec::new(); n];
+        for (&(a, b), opposite) in &edges {
+            ring[a as usize].push(b);
+            ring[b as usize].push(a);
+            if opposite.len() == 1 {
+                boundary[a as usize].push(b);
+                boundary[b as usize].push(a);
+            }
+        }
+        let sum = |ids: &[u32]| ids.iter().map(|&i| self.position(i)).sum::<Vec3>();
+        let mut vertices: Vec<_> = (0..n)
+            .map(|i| {
+                let p = self.position(i as u32);
+                let p = match (boundary[i].len(), ring[i].len()) {
+                    (2, _) => 0.75 * p + 0.125 * sum(&boundary[i]),
+                    // non-manifold corners and isolated vertices stay put
+                    (1.., _) | (_, 0) => p,
+                    (_, k) => {
+                        let beta = if k == 3 {
+                            3.0 / 16.0
+                        } else {
+                            3.0 / (8.0 * k as f32)
+                        };
+                        (1.0 - k as f32 * beta) * p + beta * sum(&ring[i])
+                    }
+                };
+                with_position(self.vertices[i], p)
+            })
+            .collect();
+        let mut split = HashMap::new();
+        let mut edge_vertex = |a: u32, b: u32| {
+            let key = (a.min(b), a.max(b));
+            *split.entry(key).or_insert_with(|| {
+                let (pa, pb) = (self.position(a), self.position(b));
+                let p = match edges[&key][..] {
+                    [c, d] => 0.375 * (pa + pb) + 0.125 * (self.position(c) + self.position(d)),
+                    _ => 0.5 * (pa + pb),
+                };
+                let (ca, cb) = (
+                    self.vertices[a as usize].color,
+                    self.vertices[b as usize].color,
+                );
+                let mut vertex = with_position(self.vertices[a as usize], p);
+                vertex.color = std::array::from_fn(|i| 0.5 * (ca[i] + cb[i]));
+                vertices.push(vertex);
+                vertices.len() as u32 - 1
+            })
+        };
+        let mut indices = Vec::with_capacity(4 * self.indices.len());
+        let mut corners = Vec::with_capacity(4 * self.corners.len());
+        for (t, c) in self
+            .indices
+            .chunks_exact(3)
+            .zip(self.corners.chunks_exact(3))
+        {
+            let m = [0, 1, 2].map(|v| edge_vertex(t[v], t[(v + 1) % 3]));
+            let mc = [0, 1, 2].map(|v| midpoint(&c[v], &c[(v + 1) % 3]));
+            indices.extend([
+                t[0], m[0], m[2], m[0], t[1], m[1], m[2], m[1], t[2], m[0], m[1], m[2],
+            ]);
+            corners.extend([
+                c[0], mc[0], mc[2], mc[0], c[1], mc[1], mc[2], mc[1], c[2], mc[0], mc[1], mc[2],
+            ]);
+        }
+        self.vertices = vertices;
+        self.indices = indices;
+        self.corners = corners;
+    }
+
+    /// Moves every vertex along its smoothed normal by `height(position, uv)`, then
+    /// recomputes normals and tangents. A vertex on a UV seam is displaced by the mean of
+    /// the heights at its UVs so the surface does not tear. Only existing vertices move, so
+    /// subdivide first for detail finer than the input mesh.
+    pub fn displace(&mut self, height: impl Fn(Vec3, Vec2) -> f32) {
+        let normals = self.smooth_normals();
+        let mut sums = vec![(0.0, 0.0); self.vertices.len()];
+        for (corner, &i) in self.corners.iter().zip(&self.indices) {
+            let sum = &mut sums[i as usize];
+            sum.0 += height(self.position(i), corner.uv);
+            sum.1 += 1.0;
+        }
+        for (i, (h, count)) in sums.into_iter().enumerate() {
+            if count > 0.0 {
+                let p = self.position(i as u32) + normals[i] * h / count;
+                self.vertices[i] = with_position(self.vertices[i], p);
+            }
+        }
+        self.smooth_normals();
+        self.generate_tangents();
+    }
+
+    /// Displaces by the red channel of `map` at each UV, black staying in place and white
+    /// moving out by `scale`. See `displace`.
+    pub fn displace_map(&mut self, map: &Image, scale: f32) {
+        self.displace(|_, uv| map.sample(uv).x * scale)
+    }
+
+    /// Sets each vertex normal, and the normal of every corner on it, to the area weighted
+    /// mean of the faces around it, and returns them.
+    fn smooth_normals(&mut self) -> Vec<Vec3> {
+        let mut normals = vec![Vec3::ZERO; self.vertices.len()];
+        for t in self.indices.chunks_exact(3) {
+            let p = [0, 1, 2].map(|v| self.position(t[v]));
+            // twice the area long
+            let normal = (p[1] - p[0]).cross(p[2] - p[0]);
+            for &i in t {
+                normals[i as usize] += normal;
+            }
+        }
+        for (vertex, normal) in self.vertices.iter_mut().zip(normals.iter_mut()) {
+            *normal = normal.normalize_or_zero();
+            vertex.normal = normal.extend(1.0).to_array();
+        }
+        for (corner, &i) in self.corners.iter_mut().zip(&self.indices) {
+            corner.normal = normals[i as usize];
+        }
+        normals
+    }
+}
+
+fn with_position(vertex: Vertex, position: Vec3) -> Vertex {
+    Vertex {
+        position: position.extend(1.0).to_array(),
+        ..vertex
+    }
+}
+
+fn midpoint(a: &Corner, b: &Corner) -> Corner {
+    Corner {
+        normal: (a.normal + b.normal).normalize_or_zero(),
+        uv: (a.uv + b.uv) * 0.5,
+        tangent: Vec4::ZERO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Material;
+
+    fn ico_sphere() -> (Mesh, Vec3, f32) {
+        let mesh = Mesh::load_obj(
+            include_bytes!("../assets/ico_sphere.obj"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap();
+        let n = mesh.vertices.len() as u32;
+        let center = (0..n).map(|i| mesh.position(i)).sum::<Vec3>() / n as f32;
+        let radius = mesh.position(0).distance(center);
+        (mesh, center, radius)
+    }
+
+    #[test]
+    fn loop_subdivision() {
+        let (mut mesh, center, radius) = ico_sphere();
+        mesh.subdivide(2);
+        assert_eq!(mesh.indices.len(), 80 * 16 * 3);
+        assert_eq!(mesh.corners.len(), mesh.indices.len());
+        // closed mesh: V - E + F = 2 with E = 3F / 2
+        assert_eq!(mesh.vertices.len(), 2 + 80 * 16 / 2);
+        // the limit surface of a sphere stays close to round and inside it
+        for i in 0..mesh.vertices.len() as u32 {
+            let r = mesh.position(i).distance(center) / radius;
+            assert!(r > 0.85 && r <= 1.0 + 1e-5, "radius {r}");
+        }
+        for (corner, &i) in mesh.corners.iter().zip(&mesh.indices) {
+            assert!(corner.normal.dot((mesh.position(i) - center).normalize()) > 0.99);
+        }
+    }
+
+    #[test]
+    fn boundary() {
+        // an open square stays flat, its outline smoothed by the boundary rules
+        let mut mesh = Mesh::load_obj(
+            include_bytes!("../assets/quad.obj"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap();
+        mesh.subdivide(1);
+        assert_eq!(mesh.indices.len(), 2 * 4 * 3);
+        assert_eq!(mesh.vertices.len(), 9);
+        let z = mesh.position(0).z;
+        for i in 0..9 {
+            assert!((mesh.position(i).z - z).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn displace() {
+        let (mut mesh, center, radius) = ico_sphere();
+        mesh.displace(|_, _| 0.5);
+        for i in 0..mesh.vertices.len() as u32 {
+            assert!((mesh.position(i).distance(center) - radius - 0.5).abs() < 1e-4);
+        }
+        let raised = Image::from_fn(2, 2, |_, _| [255, 0, 0, 255]);
+        let mut mesh = Mesh::load_obj(
+            include_bytes!("../assets/quad.obj"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap();
+        let p = mesh.position(0);
+        mesh.displace_map(&raised, 0.25);
+        let normal = mesh.corners[0].normal;
+        assert!(mesh.position(0).abs_diff_eq(p + normal * 0.25, 1e-6));
+    }
+}
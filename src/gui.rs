@@ -5,6 +5,8 @@ use wgpu::{CommandEncoder, Device, Queue, TextureFormat, TextureView};
 use winit::event::WindowEvent;
 use winit::window::Window;
 
+use crate::renderer::{FLAG_HEATMAP, FLAG_SPECTRAL};
+
 pub struct GuiState {
     state: EguiState,
     renderer: egui_wgpu::Renderer,
@@ -13,6 +15,12 @@ pub struct GuiState {
     pub camera_theta: f32,
     pub camera_phi: f32,
     pub fov: f32,
+    pub spectral: bool,
+    pub heatmap: bool,
+    pub open_path: String,
+    /// Set when "Open" is clicked, taken by the app which reports back in `open_error`.
+    pub open_requested: Option<String>,
+    pub open_error: Option<String>,
 }
 
 impl GuiState {
@@ -35,6 +43,11 @@ impl GuiState {
             camera_theta: 0.0,
             camera_phi: 0.0,
             fov: 60.0,
+            spectral: false,
+            heatmap: false,
+            open_path: String::new(),
+            open_requested: None,
+            open_error: None,
         }
     }
 
@@ -50,6 +63,11 @@ impl GuiState {
         let mut camera_theta = self.camera_theta;
         let mut camera_phi = self.camera_phi;
         let mut fov = self.fov;
+        let mut spectral = self.spectral;
+        let mut heatmap = self.heatmap;
+        let mut open_path = std::mem::take(&mut self.open_path);
+        let mut open_clicked = false;
+        let open_error = self.open_error.clone();
         
         let output = self.state.egui_ctx().run(input, |ctx| {
             egui::Window::new("Debug Panel")
@@ -74,6 +92,28 @@ impl GuiState {
                     
                     ui.separator();
                     
+                    ui.heading("Rendering");
+                    
+                    ui.checkbox(&mut spectral, "Spectral (dispersion)");
+                    ui.checkbox(&mut heatmap, "BVH traversal heatmap")
+                        .on_hover_text("Steps per camera ray, magenta where traversal gave up");
+                    
+                    ui.separator();
+                    
+                    ui.heading("File");
+                    
+                    ui.horizontal(|ui| {
+                        ui.text_edit_singleline(&mut open_path)
+                            .on_hover_text("OBJ, PLY, STL or .scene");
+                        open_clicked = ui.button("Open").clicked();
+                    });
+                    
+                    if let Some(error) = &open_error {
+                        ui.colored_label(egui::Color32::RED, error);
+                    }
+                    
+                    ui.separator();
+                    
                     ui.label(egui::RichText::new("Controls:").strong());
                     ui.label("Left Mouse: Orbit camera");
                     ui.label("Scroll: Zoom in/out");
@@ -94,10 +134,28 @@ impl GuiState {
         self.camera_theta = camera_theta;
         self.camera_phi = camera_phi;
         self.fov = fov;
+        self.spectral = spectral;
+        self.heatmap = heatmap;
+        if open_clicked && !open_path.is_empty() {
+            self.open_requested = Some(open_path.clone());
+            self.open_error = None;
+        }
+        self.open_path = open_path;
         
         output
     }
 
+    pub fn render_flags(&self) -> u32 {
+        let mut flags = 0;
+        if self.spectral {
+            flags |= FLAG_SPECTRAL;
+        }
+        if self.heatmap {
+            flags |= FLAG_HEATMAP;
+        }
+        flags
+    }
+
     pub fn render(
         &mut self,
         device: &Device,
@@ -117,13 +175,13 @@ impl GuiState {
         let paint_jobs = self.state.egui_ctx().tessellate(output.shapes, output.pixels_per_point);
 
         for (id, image_delta) in &output.textures_delta.set {
-            self.renderer.update_texture(device, queue, *id, &image_delta);
+            self.renderer.update_texture(device, queue, *id, image_delta);
         }
 
         self.renderer.update_buffers(device, queue, encoder, &paint_jobs, &screen_descriptor);
 
         {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            let render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("egui render pass"),
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
                     view: surface_view,
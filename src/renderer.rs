@@ -1,4 +1,9 @@
-use std::{borrow::Cow, cmp::max, mem::size_of, sync::Arc};
+use std::{
+    borrow::Cow,
+    cmp::{max, min},
+    mem::size_of,
+    sync::Arc,
+};
 use wgpu::{
     BindGroupDescriptor, BindGroupEntry, BindGroupLayoutDescriptor,
     BindGroupLayoutEntry, BindingType, BufferBindingType, BufferDescriptor,
@@ -16,6 +21,47 @@ use crate::scene::Camera;
 use crate::camera_controller::CameraUniform;
 
 const MAX_IMAGE_BUFFER_SIZE: usize = 4096 * 2048;
+/// Trace a few wavelengths per path instead of RGB, see `flags` in the shaders.
+pub const FLAG_SPECTRAL: u32 = 1;
+/// Show how many BVH traversal steps camera rays take instead of shading, in triangle
+/// scenes, magenta where traversal gave up.
+pub const FLAG_HEATMAP: u32 = 2;
+
+/// A scene needs a custom buffer larger than the device can bind.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BufferTooLarge {
+    pub buffer: usize,
+    pub size: u64,
+    pub limit: u64,
+}
+
+impl std::fmt::Display for BufferTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "scene buffer {} needs {} MiB but the device binds at most {} MiB",
+            self.buffer,
+            self.size.div_ceil(1 << 20),
+            self.limit >> 20
+        )
+    }
+}
+
+impl std::error::Error for BufferTooLarge {}
+
+/// `limits` raised to what `adapter` allows for compute shaders, which the WebGL 2
+/// defaults leave out, so `Lbvh` can run where the adapter supports it.
+fn with_compute(limits: Limits, adapter: &Limits) -> Limits {
+    Limits {
+        max_compute_workgroup_storage_size: adapter.max_compute_workgroup_storage_size,
+        max_compute_invocations_per_workgroup: adapter.max_compute_invocations_per_workgroup,
+        max_compute_workgroup_size_x: adapter.max_compute_workgroup_size_x,
+        max_compute_workgroup_size_y: adapter.max_compute_workgroup_size_y,
+        max_compute_workgroup_size_z: adapter.max_compute_workgroup_size_z,
+        max_compute_workgroups_per_dimension: adapter.max_compute_workgroups_per_dimension,
+        ..limits
+    }
+}
 
 pub struct Buffers {
     pub buffers: Vec<wgpu::Buffer>,
@@ -40,6 +86,8 @@ pub struct Renderer {
     pub buffers: Vec<Buffers>,
     pub frame_count: u32,
     render_pipeline: RenderPipeline,
+    /// Kept to rebuild bind groups when a buffer grows.
+    layouts: Vec<wgpu::BindGroupLayout>,
     current_frame: Option<wgpu::SurfaceTexture>,
 }
 impl Renderer {
@@ -67,11 +115,14 @@ impl Renderer {
                     .unwrap();
                 let mut limits =
                     Limits::downlevel_webgl2_defaults().using_resolution(adapter.limits());
-                let max_storage_buffer_size = 256 << 20;
+                let max_storage_buffer_size =
+                    min(256 << 20, adapter.limits().max_storage_buffer_binding_size);
                 limits.max_buffer_size =
                     max(limits.max_buffer_size, max_storage_buffer_size as u64);
                 limits.max_storage_buffer_binding_size = max_storage_buffer_size;
-                limits.max_storage_buffers_per_shader_stage = 4;
+                limits.max_storage_buffers_per_shader_stage =
+                    min(8, adapter.limits().max_storage_buffers_per_shader_stage);
+                let limits = with_compute(limits, &adapter.limits());
                 let (device, queue) = adapter
                     .request_device(
                         &DeviceDescriptor {
@@ -101,11 +152,14 @@ impl Renderer {
                 };
                 let mut limits =
                     Limits::downlevel_webgl2_defaults().using_resolution(adapter.limits());
-                let max_storage_buffer_size = 256 << 20;
+                let max_storage_buffer_size =
+                    min(256 << 20, adapter.limits().max_storage_buffer_binding_size);
                 limits.max_buffer_size =
                     max(limits.max_buffer_size, max_storage_buffer_size as u64);
                 limits.max_storage_buffer_binding_size = max_storage_buffer_size;
-                limits.max_storage_buffers_per_shader_stage = 4;
+                limits.max_storage_buffers_per_shader_stage =
+                    min(8, adapter.limits().max_storage_buffers_per_shader_stage);
+                let limits = with_compute(limits, &adapter.limits());
                 let (device, queue) = adapter
                     .request_device(
                         &DeviceDescriptor {
@@ -145,6 +199,7 @@ impl Renderer {
                 (MAX_IMAGE_BUFFER_SIZE * size_of::<u32>()) as u64,
             ), // image data
             (BufferBindingType::Uniform, size_of::<Camera>() as u64),  // camera
+            (BufferBindingType::Uniform, size_of::<u32>() as u64),     // flags
         ];
         let buffers = [builtin_buffer, custom_buffers];
         let bind_group_layouts: Vec<wgpu::BindGroupLayout> = buffers
@@ -170,7 +225,8 @@ impl Renderer {
                 })
             })
             .collect();
-        let bind_group_layouts: Vec<_> = bind_group_layouts.iter().collect();
+        let layouts = bind_group_layouts;
+        let bind_group_layouts: Vec<_> = layouts.iter().collect();
         println!("creating pipeline layout");
         let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
             bind_group_layouts: &bind_group_layouts,
@@ -262,6 +318,7 @@ impl Renderer {
             target,
             queue,
             render_pipeline,
+            layouts,
             buffers,
             frame_count: 0,
             current_frame: None,
@@ -321,6 +378,10 @@ impl Renderer {
         let buffer = &self.buffers[0].buffers[1];
         self.queue.write_buffer(buffer, 0, bytemuck::bytes_of(&[n]));
     }
+    pub fn set_flags(&mut self, flags: u32) {
+        let buffer = &self.buffers[0].buffers[5];
+        self.queue.write_buffer(buffer, 0, bytemuck::bytes_of(&flags));
+    }
     pub fn set_camera(&mut self, camera: &Camera) {
         let buffer = &self.buffers[0].buffers[4];
         self.queue
@@ -347,9 +408,78 @@ impl Renderer {
         );
     }
 
-    pub fn write_buffer(&mut self, data: &[u8], buffer: usize) {
+    /// Writes `data` at the start of custom buffer `buffer`. A buffer too small for `data`
+    /// is replaced by one rounded up to a power of two, so growing scenes reallocate
+    /// rarely, and the bind group is rebuilt around it. Fails, writing nothing, when
+    /// `data` is more than the device can bind.
+    pub fn write_buffer(&mut self, data: &[u8], buffer: usize) -> Result<(), BufferTooLarge> {
+        self.reserve_buffer(buffer, data.len() as u64)?;
+        let buffer = &self.buffers[1].buffers[buffer];
+        self.queue.write_buffer(buffer, 0, data);
+        Ok(())
+    }
+
+    /// Grows custom buffer `buffer` as `write_buffer` does to hold at least `size` bytes,
+    /// for buffers filled on the GPU.
+    pub fn reserve_buffer(&mut self, buffer: usize, size: u64) -> Result<(), BufferTooLarge> {
+        if size > self.buffers[1].buffers[buffer].size() {
+            let limit = self.max_buffer_size(buffer);
+            if size > limit {
+                return Err(BufferTooLarge {
+                    buffer,
+                    size,
+                    limit,
+                });
+            }
+            self.grow_buffer(buffer, size.next_power_of_two().min(limit));
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at byte `offset` of custom buffer `buffer`, which must already hold it,
+    /// to update part of what `write_buffer` wrote.
+    pub fn write_buffer_at(&self, data: &[u8], buffer: usize, offset: u64) {
         let buffer = &self.buffers[1].buffers[buffer];
-        self.queue.write_buffer(buffer, 0, data)
+        self.queue.write_buffer(buffer, offset, data)
+    }
+
+    /// The most bytes custom buffer `buffer` can grow to on this device.
+    pub fn max_buffer_size(&self, buffer: usize) -> u64 {
+        let limits = self.device.limits();
+        let binding = if self.buffers[1].buffers[buffer]
+            .usage()
+            .contains(BufferUsages::STORAGE)
+        {
+            limits.max_storage_buffer_binding_size
+        } else {
+            limits.max_uniform_buffer_binding_size
+        };
+        limits.max_buffer_size.min(binding as u64)
+    }
+
+    fn grow_buffer(&mut self, index: usize, size: u64) {
+        let old = &self.buffers[1].buffers[index];
+        let buffer = self.device.create_buffer(&BufferDescriptor {
+            usage: old.usage(),
+            size,
+            mapped_at_creation: false,
+            label: None,
+        });
+        self.buffers[1].buffers[index] = buffer;
+        let entries: Vec<_> = self.buffers[1]
+            .buffers
+            .iter()
+            .enumerate()
+            .map(|(binding, buffer)| BindGroupEntry {
+                binding: binding as u32,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect();
+        self.buffers[1].group = self.device.create_bind_group(&BindGroupDescriptor {
+            layout: &self.layouts[1],
+            entries: entries.as_slice(),
+            label: None,
+        });
     }
 
     pub fn draw(&mut self) {
@@ -0,0 +1,289 @@
+//! Built trees on disk, so a large mesh is neither parsed nor built again on the next
+//! launch. A cache file starts with a magic number, the format version and the key it was
+//! written for, a hash of everything that went into the tree, followed by the arrays of
+//! the tree in native byte order. Files of another version or key are ignored and
+//! overwritten.
+use bytemuck::Pod;
+use std::io;
+use std::mem::size_of;
+use std::path::PathBuf;
+
+use crate::scene::bvh::tree::{links, node_count};
+use crate::scene::bvh::{Tree, EMPTY, LEAF};
+use crate::scene::{Texture, MAX_MOTIONS};
+
+const MAGIC: [u8; 8] = *b"wgsltree";
+/// Bump whenever the file layout, or the layout of anything stored in it, changes.
+const VERSION: u32 = 5;
+
+/// FNV-1a of `parts` and their lengths, which unlike `DefaultHasher` is the same in every
+/// build, so caches outlive the binary that wrote them.
+pub fn key(parts: &[&[u8]]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for part in parts {
+        for &byte in (part.len() as u64).to_le_bytes().iter().chain(part.iter()) {
+            hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// The cache file for `key`, in the user's cache directory.
+pub fn path(key: u64) -> PathBuf {
+    let home = |var: &str, dir: &str| std::env::var_os(var).map(|h| PathBuf::from(h).join(dir));
+    let base = home("XDG_CACHE_HOME", "")
+        .or_else(|| home("LOCALAPPDATA", ""))
+        .or_else(|| home("HOME", ".cache"))
+        .unwrap_or_else(std::env::temp_dir);
+    base.join("wgsl_toy").join(format!("{key:016x}.tree"))
+}
+
+/// The tree cached under `key`, if there is a valid one.
+pub fn load(key: u64) -> Option<Tree> {
+    Tree::from_cache(&std::fs::read(path(key)).ok()?, key)
+}
+
+/// Caches `tree` under `key`, written aside and renamed so a reader never sees half a file.
+pub fn save(tree: &Tree, key: u64) -> io::Result<()> {
+    let path = path(key);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let partial = path.with_extension("partial");
+    std::fs::write(&partial, tree.to_cache(key))?;
+    std::fs::rename(partial, path)
+}
+
+impl Tree {
+    /// The cache file contents for a tree built from what `key` hashes.
+    pub fn to_cache(&self, key: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&key.to_le_bytes());
+        let mut array = |data: &[u8]| {
+            bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(data);
+        };
+        array(bytemuck::cast_slice(&self.sizes));
+        array(bytemuck::cast_slice(&self.nodes));
+        array(bytemuck::cast_slice(&self.triangles));
+        array(bytemuck::cast_slice(&self.attributes));
+        array(bytemuck::cast_slice(&self.materials));
+        array(bytemuck::cast_slice(&self.motions));
+        array(bytemuck::cast_slice(&self.textures));
+        array(bytemuck::cast_slice(&self.texels));
+        array(bytemuck::cast_slice(&self.slots));
+        array(bytemuck::cast_slice(&self.point_sizes));
+        array(bytemuck::cast_slice(&self.point_nodes));
+        array(bytemuck::cast_slice(&self.points));
+        bytes
+    }
+
+    /// The tree in `bytes` written by `to_cache`, unless they are of another version or
+    /// key, or cut short.
+    pub fn from_cache(bytes: &[u8], key: u64) -> Option<Tree> {
+        let mut reader = Reader { bytes };
+        if reader.take(8)? != MAGIC
+            || reader.take(4)? != VERSION.to_le_bytes()
+            || reader.take(8)? != key.to_le_bytes()
+        {
+            return None;
+        }
+        let mut tree = Tree::new();
+        tree.sizes = reader.array::<u32>()?.try_into().ok()?;
+        tree.nodes = reader.array()?;
+        tree.triangles = reader.array()?;
+        tree.attributes = reader.array()?;
+        tree.materials = reader.array()?;
+        tree.motions = reader.array()?;
+        tree.textures = reader.array()?;
+        tree.texels = reader.array()?;
+        tree.slots = reader.array()?;
+        tree.point_sizes = reader.array::<u32>()?.try_into().ok()?;
+        tree.point_nodes = reader.array()?;
+        tree.points = reader.array()?;
+        if !tree.is_consistent() || !reader.bytes.is_empty() {
+            return None;
+        }
+        tree.restore();
+        Some(tree)
+    }
+
+    /// Whether the arrays read by `from_cache` fit together and index each other in range,
+    /// so a garbled file of the right length is rebuilt rather than read out of bounds.
+    fn is_consistent(&self) -> bool {
+        let m = self.triangles.len();
+        let mut seen = vec![false; m];
+        let permutation = self.slots.iter().all(|&slot| match seen.get_mut(slot as usize) {
+            Some(seen) if !*seen => {
+                *seen = true;
+                true
+            }
+            _ => false,
+        });
+        let materials = self.materials.len() as u32;
+        let textures = self.textures.len() as u32;
+        let texels = self.texels.len() as u64;
+        let motions = self.motions.len() as u32;
+        self.attributes.len() == m
+            && self.slots.len() == m
+            && permutation
+            && self.nodes.len() == node_count(m)
+            && self.is_linked()
+            && self.sizes == [self.nodes.len() as u32, m as u32]
+            && self.point_nodes.len() == self.points.len().next_power_of_two()
+            && self.point_sizes == [self.point_nodes.len() as u32, self.points.len() as u32]
+            && self.triangles.iter().all(|t| t.material < materials)
+            && self.points.iter().all(|p| p.material < materials)
+            && self.materials.iter().all(|m| m.texture_indices().all(|t| t < textures))
+            && self.textures.iter().filter_map(Texture::texels).all(|r| r.end <= texels)
+            && self.motions.len() <= MAX_MOTIONS
+            && self.materials.iter().all(|m| m.motion().is_none_or(|i| i < motions))
+    }
+
+    /// Whether the links of `nodes` make the tree `Tree::nodes` describes, each node
+    /// splitting its slots and linking the halves where they must be, so walking them
+    /// ends. `nodes` must have the right length.
+    pub(crate) fn is_linked(&self) -> bool {
+        let m = self.triangles.len();
+        if m < 2 {
+            let first = if m == 0 { EMPTY } else { LEAF };
+            return self.nodes[0].children() == [first, EMPTY];
+        }
+        let mut stack = vec![(0, 0, m - 1)];
+        while let Some((j, a, b)) = stack.pop() {
+            let [left, right] = self.nodes[j].children();
+            let split = (left & !LEAF) as usize;
+            if split < a || split >= b || [left, right] != links(a, split, b) {
+                return false;
+            }
+            if left & LEAF == 0 {
+                stack.push((split, a, split));
+            }
+            if right & LEAF == 0 {
+                stack.push((split + 1, split + 1, b));
+            }
+        }
+        true
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Option<&[u8]> {
+        let (taken, rest) = self.bytes.split_at_checked(len)?;
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    /// An array as `to_cache` writes it, copied out since the file bytes are unaligned.
+    fn array<T: Pod>(&mut self) -> Option<Vec<T>> {
+        let len = u64::from_le_bytes(self.take(8)?.try_into().ok()?) as usize;
+        let data = self.take(len)?;
+        if !len.is_multiple_of(size_of::<T>()) {
+            return None;
+        }
+        let mut array = vec![T::zeroed(); len / size_of::<T>()];
+        bytemuck::cast_slice_mut(&mut array).copy_from_slice(data);
+        Some(array)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Mesh, PointCloud};
+    use crate::scene::{Image, Material, Motion};
+    use glam::Vec3;
+
+    #[test]
+    fn round_trip() {
+        let mesh = Mesh::load_obj(
+            include_bytes!("../../assets/suzanne.obj"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap();
+        let mut tree: Tree = mesh.into();
+        tree.build();
+        let key = key(&[b"suzanne"]);
+        let bytes = tree.to_cache(key);
+        // everything down to what `refit` keeps
+        let cached = Tree::from_cache(&bytes, key).unwrap();
+        assert_eq!(format!("{tree:?}"), format!("{cached:?}"));
+
+        assert!(Tree::from_cache(&bytes, key ^ 1).is_none());
+        assert!(Tree::from_cache(&bytes[..bytes.len() - 1], key).is_none());
+        let mut other_version = bytes.clone();
+        other_version[8] += 1;
+        assert!(Tree::from_cache(&other_version, key).is_none());
+        assert_ne!(super::key(&[b"ab", b"c"]), super::key(&[b"a", b"bc"]));
+    }
+
+    #[test]
+    fn garbled() {
+        let mesh = Mesh::load_obj(
+            include_bytes!("../../assets/cube.obj"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap();
+        let mut tree: Tree = mesh.into();
+        tree.build();
+        let key = key(&[b"cube"]);
+        // arrays of the right length whose indices point nowhere, or twice at one slot
+        let mut repeated_slot = Tree::from_cache(&tree.to_cache(key), key).unwrap();
+        repeated_slot.slots[0] = repeated_slot.slots[1];
+        assert!(Tree::from_cache(&repeated_slot.to_cache(key), key).is_none());
+        let mut missing_slot = Tree::from_cache(&tree.to_cache(key), key).unwrap();
+        missing_slot.slots[0] = 12;
+        assert!(Tree::from_cache(&missing_slot.to_cache(key), key).is_none());
+        let mut missing_material = Tree::from_cache(&tree.to_cache(key), key).unwrap();
+        missing_material.triangles[3].material = 1;
+        assert!(Tree::from_cache(&missing_material.to_cache(key), key).is_none());
+        let mut missing_texture = Tree::from_cache(&tree.to_cache(key), key).unwrap();
+        missing_texture.materials[0] = Material::new_lambertian(Vec3::ONE).with_albedo_texture(0);
+        assert!(Tree::from_cache(&missing_texture.to_cache(key), key).is_none());
+        let mut missing_motion = Tree::from_cache(&tree.to_cache(key), key).unwrap();
+        missing_motion.materials[0].set_motion(Some(0));
+        assert!(Tree::from_cache(&missing_motion.to_cache(key), key).is_none());
+        missing_motion.motions.push(Motion::default());
+        assert!(Tree::from_cache(&missing_motion.to_cache(key), key).is_some());
+        let image = Image { width: 2, height: 2, texels: vec![0; 4] };
+        let mut missing_texels = Tree::from_cache(&tree.to_cache(key), key).unwrap();
+        missing_texels.add_image(&image);
+        assert!(Tree::from_cache(&missing_texels.to_cache(key), key).is_some());
+        missing_texels.texels.pop();
+        assert!(Tree::from_cache(&missing_texels.to_cache(key), key).is_none());
+        let mut missing_point_nodes = Tree::from_cache(&tree.to_cache(key), key).unwrap();
+        missing_point_nodes.add_point_cloud(PointCloud {
+            positions: vec![Vec3::ZERO; 3],
+            normals: Vec::new(),
+            colors: Vec::new(),
+            radius: 0.1,
+            material: Material::new_lambertian(Vec3::ONE),
+        });
+        missing_point_nodes.build();
+        assert!(Tree::from_cache(&missing_point_nodes.to_cache(key), key).is_some());
+        missing_point_nodes.point_nodes.pop();
+        missing_point_nodes.point_sizes[0] -= 1;
+        assert!(Tree::from_cache(&missing_point_nodes.to_cache(key), key).is_none());
+        let mut broken_link = Tree::from_cache(&tree.to_cache(key), key).unwrap();
+        let [left, right] = broken_link.nodes[0].children();
+        broken_link.nodes[0].set_children([right, left]);
+        assert!(Tree::from_cache(&broken_link.to_cache(key), key).is_none());
+        // cut anywhere or scribbled over anywhere, the file is read or refused but never
+        // panics
+        let bytes = tree.to_cache(key);
+        for len in 0..bytes.len() {
+            assert!(Tree::from_cache(&bytes[..len], key).is_none());
+        }
+        for i in 20..bytes.len() {
+            let mut scribbled = bytes.clone();
+            scribbled[i] ^= 0xa5;
+            let _ = Tree::from_cache(&scribbled, key);
+        }
+    }
+}
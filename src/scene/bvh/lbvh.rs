@@ -0,0 +1,274 @@
+//! Linear BVH construction on the GPU, after Karras 2012. Triangles are sorted along a
+//! Morton curve through their centroids by a radix sort in compute shaders, then every
+//! internal node finds its slots and splits them where their codes first differ, all at
+//! once, linking its children in the layout of `Tree::nodes`. Equal codes are told apart
+//! by their slots. The bounds are fitted bottom up a depth per dispatch, depths being
+//! found by climbing the parents the links leave behind.
+use bytemuck::{Pod, Zeroable};
+use glam::{Vec3, Vec4, Vec4Swizzles};
+use std::borrow::Cow;
+use std::sync::mpsc::channel;
+use wgpu::util::DeviceExt;
+use wgpu::{
+    BindGroup, BindGroupDescriptor, BindGroupEntry, Buffer, BufferDescriptor, BufferUsages,
+    CommandEncoder, ComputePassDescriptor, ComputePipeline, ComputePipelineDescriptor, Device,
+    Queue, ShaderModuleDescriptor, ShaderSource,
+};
+
+use crate::scene::bvh::{Node, Triangle, TriangleAttributes};
+
+const WORKGROUP: u32 = 256;
+/// Bits sorted per radix pass, sixteen digits.
+const DIGIT_BITS: u32 = 4;
+
+#[repr(C)]
+#[derive(Copy, Clone, Default, Pod, Zeroable)]
+struct Params {
+    bounds_min: Vec4,
+    bounds_scale: Vec4,
+    count: u32,
+    blocks: u32,
+    shift: u32,
+    level: u32,
+}
+
+/// Where `Lbvh::build` writes the tree, buffers of the triangle shader.
+pub struct LbvhOutput<'a> {
+    pub nodes: &'a Buffer,
+    pub triangles: &'a Buffer,
+    pub attributes: &'a Buffer,
+}
+
+pub struct Lbvh {
+    morton: ComputePipeline,
+    count_digits: ComputePipeline,
+    scan: ComputePipeline,
+    scatter: ComputePipeline,
+    gather: ComputePipeline,
+    hierarchy: ComputePipeline,
+    depths: ComputePipeline,
+    fit: ComputePipeline,
+}
+
+impl Lbvh {
+    pub fn new(device: &Device) -> Self {
+        let module = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("lbvh"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("../../shaders/lbvh.wgsl"))),
+        });
+        let pipeline = |entry_point: &str| {
+            device.create_compute_pipeline(&ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: None,
+                module: &module,
+                entry_point: Some(entry_point),
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+        Self {
+            morton: pipeline("morton"),
+            count_digits: pipeline("count_digits"),
+            scan: pipeline("scan"),
+            scatter: pipeline("scatter"),
+            gather: pipeline("gather"),
+            hierarchy: pipeline("hierarchy"),
+            depths: pipeline("depths"),
+            fit: pipeline("fit"),
+        }
+    }
+
+    /// Whether `device` can build a tree over `triangles` triangles, which takes compute
+    /// shaders and a workgroup per 256 triangles.
+    pub fn supports(device: &Device, triangles: usize) -> bool {
+        let limits = device.limits();
+        limits.max_compute_invocations_per_workgroup >= WORKGROUP
+            && limits.max_compute_workgroup_size_x >= WORKGROUP
+            && limits.max_compute_workgroup_storage_size >= 4 * (16 + 2 * WORKGROUP)
+            && limits.max_compute_workgroups_per_dimension as usize
+                >= triangles.div_ceil(WORKGROUP as usize)
+    }
+
+    /// Sorts `triangles` and `attributes` into `output`, with their face normal in
+    /// `custom`, and fills its nodes, which must have room for one less than the two
+    /// triangles at least. Reads back the index in `triangles` of the triangle in each
+    /// slot and the nodes, none if the read fails.
+    pub fn build(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        triangles: &[Triangle],
+        attributes: &[TriangleAttributes],
+        output: LbvhOutput,
+    ) -> Option<(Vec<u32>, Vec<Node>)> {
+        let m = triangles.len() as u32;
+        assert!(m >= 2);
+        let blocks = m.div_ceil(WORKGROUP);
+        let centers = triangles.iter().map(|t| (t.a + t.b + t.c).xyz() / 3.0);
+        let (min, max) = centers.fold((Vec3::MAX, Vec3::MIN), |(min, max), c| {
+            (min.min(c), max.max(c))
+        });
+        let extent = (max - min).max(Vec3::splat(f32::EPSILON));
+        let base = Params {
+            bounds_min: min.extend(0.0),
+            bounds_scale: (1.0 / extent).extend(0.0),
+            count: m,
+            blocks,
+            ..Default::default()
+        };
+        let init = |contents: &[u8], usage| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents,
+                usage,
+            })
+        };
+        let storage = |size: u64| {
+            device.create_buffer(&BufferDescriptor {
+                label: None,
+                size: size.max(4),
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            })
+        };
+        let params = |params: Params| init(bytemuck::bytes_of(&params), BufferUsages::UNIFORM);
+        let triangles_in = init(bytemuck::cast_slice(triangles), BufferUsages::STORAGE);
+        let attributes_in = init(bytemuck::cast_slice(attributes), BufferUsages::STORAGE);
+        let keys = [storage(4 * m as u64), storage(4 * m as u64)];
+        let values = [storage(4 * m as u64), storage(4 * m as u64)];
+        let histogram = storage(4 * 16 * blocks as u64);
+
+        let mut encoder = device.create_command_encoder(&Default::default());
+        let base_params = params(base);
+        let group = self.bind(
+            device,
+            &self.morton,
+            &[
+                (0, &base_params),
+                (1, &triangles_in),
+                (5, &keys[0]),
+                (6, &values[0]),
+            ],
+        );
+        dispatch(&mut encoder, &self.morton, &group, blocks);
+        // an even number of passes, so the sorted keys end up back in the first buffers
+        for pass in 0..32 / DIGIT_BITS as usize {
+            let (from, to) = (pass % 2, 1 - pass % 2);
+            let params = params(Params {
+                shift: pass as u32 * DIGIT_BITS,
+                ..base
+            });
+            let group = self.bind(
+                device,
+                &self.count_digits,
+                &[(0, &params), (3, &keys[from]), (7, &histogram)],
+            );
+            dispatch(&mut encoder, &self.count_digits, &group, blocks);
+            let group = self.bind(device, &self.scan, &[(0, &params), (7, &histogram)]);
+            dispatch(&mut encoder, &self.scan, &group, 1);
+            let group = self.bind(
+                device,
+                &self.scatter,
+                &[
+                    (0, &params),
+                    (3, &keys[from]),
+                    (4, &values[from]),
+                    (5, &keys[to]),
+                    (6, &values[to]),
+                    (7, &histogram),
+                ],
+            );
+            dispatch(&mut encoder, &self.scatter, &group, blocks);
+        }
+        let group = self.bind(
+            device,
+            &self.gather,
+            &[
+                (0, &base_params),
+                (1, &triangles_in),
+                (2, &attributes_in),
+                (4, &values[0]),
+                (8, output.triangles),
+                (9, output.attributes),
+            ],
+        );
+        dispatch(&mut encoder, &self.gather, &group, blocks);
+        // the sorted keys stay in the first buffers, the second are free for the parents
+        // and depths of the nodes
+        let (parents, depths) = (&values[1], &keys[1]);
+        let group = self.bind(
+            device,
+            &self.hierarchy,
+            &[(0, &base_params), (3, &keys[0]), (10, output.nodes), (11, parents)],
+        );
+        dispatch(&mut encoder, &self.hierarchy, &group, blocks);
+        let group = self.bind(device, &self.depths, &[(0, &base_params), (11, parents), (12, depths)]);
+        dispatch(&mut encoder, &self.depths, &group, blocks);
+        // every level down lengthens the prefix shared, of the 30 bits of the codes and
+        // then of the slots
+        let levels = 30 + (32 - (m - 1).leading_zeros());
+        for level in (0..levels).rev() {
+            let params = params(Params { level, ..base });
+            let group = self.bind(
+                device,
+                &self.fit,
+                &[(0, &params), (8, output.triangles), (10, output.nodes), (12, depths)],
+            );
+            dispatch(&mut encoder, &self.fit, &group, blocks);
+        }
+
+        // the nodes first, their size keeps the order after them aligned
+        let nodes_size = ((m - 1) as usize * size_of::<Node>()) as u64;
+        let readback = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: nodes_size + 4 * m as u64,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        encoder.copy_buffer_to_buffer(output.nodes, 0, &readback, 0, nodes_size);
+        encoder.copy_buffer_to_buffer(&values[0], 0, &readback, nodes_size, 4 * m as u64);
+        queue.submit(Some(encoder.finish()));
+        let slice = readback.slice(..);
+        let (tx, rx) = channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            tx.send(result.is_ok()).unwrap()
+        });
+        let _ = device.poll(wgpu::MaintainBase::Wait);
+        let built = rx.recv().unwrap_or(false).then(|| {
+            let data = slice.get_mapped_range();
+            let (nodes, order) = data.split_at(nodes_size as usize);
+            let nodes: Vec<Node> = bytemuck::pod_collect_to_vec(nodes);
+            (bytemuck::cast_slice(order).to_vec(), nodes)
+        });
+        readback.unmap();
+        built
+    }
+
+    /// Binds `buffers` by binding number for `pipeline`, which takes only those it uses.
+    fn bind(
+        &self,
+        device: &Device,
+        pipeline: &ComputePipeline,
+        buffers: &[(u32, &Buffer)],
+    ) -> BindGroup {
+        let entries: Vec<_> = buffers
+            .iter()
+            .map(|&(binding, buffer)| BindGroupEntry {
+                binding,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect();
+        device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &entries,
+        })
+    }
+}
+
+fn dispatch(encoder: &mut CommandEncoder, pipeline: &ComputePipeline, group: &BindGroup, x: u32) {
+    let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+    pass.set_pipeline(pipeline);
+    pass.set_bind_group(0, group, &[]);
+    pass.dispatch_workgroups(x, 1, 1);
+}
@@ -1,6 +1,15 @@
+pub mod cache;
+pub mod lbvh;
 pub mod node;
+pub mod point;
+pub mod stats;
 pub mod tree;
 pub mod triangle;
-pub use node::Node;
-pub use tree::Tree;
+pub mod wide;
+pub use lbvh::{Lbvh, LbvhOutput};
+pub use node::{Node, EMPTY, LEAF};
+pub use point::Point;
+pub use stats::Stats;
+pub use tree::{build_nodes, Changes, Tree};
 pub use triangle::Triangle;
+pub use triangle::TriangleAttributes;
@@ -1,11 +1,20 @@
 use bytemuck::{Pod, Zeroable};
-use glam::Vec4;
+use glam::{Vec3, Vec4};
+
+/// Marks a child link of a triangle tree node as the slot of a triangle rather than a node.
+pub const LEAF: u32 = 1 << 31;
+/// A child link to nothing, only in the root of a tree over fewer than two triangles.
+pub const EMPTY: u32 = u32::MAX;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
 pub struct Node {
-    bound_min: Vec4,
-    bound_max: Vec4,
+    bound_min: Vec3,
+    /// Links to the children in a triangle tree, see `Tree::nodes`. The implicit trees of
+    /// `build_nodes` leave them alone.
+    left: u32,
+    bound_max: Vec3,
+    right: u32,
 }
 
 #[repr(C)]
@@ -20,8 +29,10 @@ pub struct CompactNode {
 impl Default for Node {
     fn default() -> Self {
         Self {
-            bound_min: Vec4::MAX,
-            bound_max: Vec4::MIN,
+            bound_min: Vec3::MAX,
+            left: EMPTY,
+            bound_max: Vec3::MIN,
+            right: EMPTY,
         }
     }
 }
@@ -39,7 +50,39 @@ impl Default for CompactNode {
 
 impl Node {
     pub fn union(&mut self, vertex: Vec4) {
-        self.bound_min = self.bound_min.min(vertex);
-        self.bound_max = self.bound_max.max(vertex);
+        self.bound_min = self.bound_min.min(vertex.truncate());
+        self.bound_max = self.bound_max.max(vertex.truncate());
+    }
+    pub fn merge(&mut self, other: &Node) {
+        self.bound_min = self.bound_min.min(other.bound_min);
+        self.bound_max = self.bound_max.max(other.bound_max);
+    }
+    /// The corners of the box.
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        (self.bound_min, self.bound_max)
+    }
+    /// The links to the children, each a node, `LEAF` and a slot, or `EMPTY`.
+    pub fn children(&self) -> [u32; 2] {
+        [self.left, self.right]
+    }
+    pub fn set_children(&mut self, [left, right]: [u32; 2]) {
+        self.left = left;
+        self.right = right;
+    }
+    /// Surface area of the box, zero for an empty node.
+    pub fn area(&self) -> f32 {
+        let size = self.bound_max - self.bound_min;
+        if size.min_element() < 0.0 {
+            return 0.0;
+        }
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+    /// Whether the boxes share more than their boundary. Boxes flat along the same axis,
+    /// as around triangles in one axis aligned plane, overlap where they meet in it.
+    pub fn overlaps(&self, other: &Node) -> bool {
+        let min = self.bound_min.max(other.bound_min);
+        let max = self.bound_max.min(other.bound_max);
+        let flat = |node: &Node| (node.bound_max - node.bound_min).cmpeq(Vec3::ZERO);
+        (min.cmplt(max) | (flat(self) & flat(other) & min.cmpeq(max))).all()
     }
 }
\ No newline at end of file
@@ -0,0 +1,25 @@
+use bytemuck::{Pod, Zeroable};
+use glam::{Vec3, Vec4};
+
+/// One splat of a point cloud, a disk facing `normal` or a sphere when `normal` is zero.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Pod, Zeroable)]
+pub struct Point {
+    pub position: Vec3,
+    pub radius: f32,
+    pub normal: Vec3,
+    pub material: u32,
+    /// Multiplied into the material's albedo.
+    pub color: Vec4,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        // must match `struct Point` in the triangle shader
+        assert_eq!(std::mem::size_of::<Point>(), 48);
+    }
+}
@@ -0,0 +1,142 @@
+//! Quality of a built `Tree`, printed by `wgsl_toy stats <mesh>`.
+use std::fmt;
+
+use crate::scene::bvh::{Tree, EMPTY, LEAF};
+
+/// What the surface area heuristic charges for testing a node and a triangle. Both are a
+/// step of the traversal loop in the triangle shader, so the cost counts steps.
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    pub triangles: usize,
+    pub nodes: usize,
+    /// Expected cost of a ray that hits the root box, by the surface area heuristic.
+    pub sah_cost: f32,
+    /// Triangles by the depth of their leaf, the root being depth 0.
+    pub depths: Vec<usize>,
+    /// Nodes by how many of their two children are triangles.
+    pub leaf_sizes: Vec<usize>,
+    /// Share of nodes with two children whose boxes overlap.
+    pub overlap_ratio: f32,
+}
+
+impl Tree {
+    /// Measures the tree over the triangles, all zero until built.
+    pub fn stats(&self) -> Stats {
+        let m = self.triangles.len();
+        if !self.is_built() {
+            return Stats::default();
+        }
+        let mut depths = Vec::new();
+        let mut leaf_sizes = vec![0; 3];
+        let root = self.nodes[0].area();
+        let mut sah_cost = TRAVERSAL_COST;
+        let (mut pairs, mut overlapping) = (0, 0);
+        for span in self.spans(self.root(), 0..m) {
+            let links = self.nodes[span.node].children();
+            let leaves = links.iter().filter(|&&link| link != EMPTY && link & LEAF != 0).count();
+            leaf_sizes[leaves] += 1;
+            if leaves > 0 {
+                depths.resize(depths.len().max(span.depth + 2), 0);
+                depths[span.depth + 1] += leaves;
+            }
+            let tests: f32 = links
+                .iter()
+                .map(|&link| match link {
+                    EMPTY => 0.0,
+                    _ if link & LEAF != 0 => INTERSECTION_COST,
+                    _ => TRAVERSAL_COST,
+                })
+                .sum();
+            if root > 0.0 {
+                sah_cost += self.nodes[span.node].area() / root * tests;
+            }
+            if let [Some(a), Some(b)] = links.map(|link| self.child_bounds(link)) {
+                pairs += 1;
+                if a.overlaps(&b) {
+                    overlapping += 1;
+                }
+            }
+        }
+        Stats {
+            triangles: m,
+            nodes: self.nodes.len(),
+            sah_cost,
+            depths,
+            leaf_sizes,
+            overlap_ratio: overlapping as f32 / pairs.max(1) as f32,
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "triangles       {}", self.triangles)?;
+        writeln!(f, "nodes           {}", self.nodes)?;
+        writeln!(f, "SAH cost        {:.2}", self.sah_cost)?;
+        writeln!(f, "overlapping     {:.1}%", 100.0 * self.overlap_ratio)?;
+        writeln!(f, "leaf depth      triangles")?;
+        for (depth, &count) in self.depths.iter().enumerate().filter(|(_, &c)| c > 0) {
+            writeln!(f, "  {depth:<13} {count}")?;
+        }
+        writeln!(f, "leaf children   nodes")?;
+        for (size, count) in self.leaf_sizes.iter().enumerate() {
+            writeln!(f, "  {size:<13} {count}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Mesh;
+    use crate::scene::Material;
+    use glam::Vec3;
+
+    #[test]
+    fn suzanne() {
+        let mesh = Mesh::load_obj(
+            include_bytes!("../../assets/suzanne.obj"),
+            Material::new_lambertian(Vec3::new(0.5, 0.5, 0.5)),
+        )
+        .unwrap();
+        let mut tree: Tree = mesh.into();
+        assert_eq!(tree.stats(), Stats::default());
+        tree.build();
+        let stats = tree.stats();
+        assert_eq!((stats.triangles, stats.nodes), (979, 978));
+        assert_eq!(stats.depths.iter().sum::<usize>(), 979);
+        // full left subtrees of 512, 256, 128 and 64, then of 16, 2 and 1
+        assert_eq!(stats.depths, vec![0, 0, 0, 0, 0, 0, 1, 2, 0, 16, 960]);
+        // 979 triangles pair up under 489 nodes, the last one alone
+        assert_eq!(stats.leaf_sizes, vec![488, 1, 489]);
+        assert!(stats.overlap_ratio > 0.0 && stats.overlap_ratio <= 1.0);
+        // a ray tests the root, its children and at least one leaf
+        assert!(stats.sah_cost > 3.0 && stats.sah_cost < 979.0);
+    }
+
+    #[test]
+    fn overlap() {
+        let mesh = Mesh::load_obj(
+            include_bytes!("../../assets/quad.obj"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap();
+        let mut apart = Tree::new();
+        let mut stacked = Tree::new();
+        for i in 0..4 {
+            let mut moved = mesh.clone();
+            moved.translate(Vec3::new(10.0 * i as f32, 0.0, 0.0));
+            apart.add_mesh(moved);
+            stacked.add_mesh(mesh.clone());
+        }
+        apart.build();
+        stacked.build();
+        assert_eq!(stacked.stats().overlap_ratio, 1.0);
+        assert!(apart.stats().overlap_ratio < stacked.stats().overlap_ratio);
+        assert!(apart.stats().sah_cost < stacked.stats().sah_cost);
+    }
+}
@@ -1,18 +1,67 @@
 use std::collections::VecDeque;
-use glam::Vec4Swizzles;
+use glam::{Vec3, Vec4, Vec4Swizzles};
 use std::cmp::Ordering;
+use std::ops::Range;
 
-use crate::geometry::Mesh;
-use crate::scene::bvh::Node;
+use crate::geometry::{Mesh, PointCloud};
+use crate::scene::bvh::{Node, EMPTY, LEAF};
+use crate::scene::bvh::Point;
+use crate::scene::bvh::triangle::pack_color;
 use crate::scene::bvh::Triangle;
+use crate::scene::bvh::TriangleAttributes;
 use crate::scene::material::Material;
+use crate::scene::{Image, Motion, Texture, TooManyMotions, MAX_MOTIONS};
+
+/// Quality a subtree may lose to refitting, as growth of its surface area since it was
+/// sorted, before `Tree::refit` sorts it again.
+const REBUILD_RATIO: f32 = 2.0;
+
+/// Parts of a tree changed since they were last uploaded, in elements.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Changes {
+    /// Of `triangles` and `attributes` alike.
+    pub triangles: Option<Range<usize>>,
+    /// Of `nodes`, a range per run of nodes touched.
+    pub nodes: Vec<Range<usize>>,
+    /// Of `materials` or `motions`, which are uploaded whole.
+    pub materials: bool,
+}
 
 #[derive(Debug, Default)]
 pub struct Tree {
     pub sizes: [u32; 2],
+    /// The binary tree over the triangle slots, laid out as Karras 2012 does. Node 0 is
+    /// the root, over every slot, and the node over slots `a..=b` split after slot `s`
+    /// links to node `s`, over `a..=s`, or to the triangle in slot `a` when that is all,
+    /// and to node `s + 1`, over `s + 1..=b`, or to the triangle in slot `b`. So `m`
+    /// triangles take `m - 1` nodes, see `Node::children`, and one at least.
     pub nodes: Vec<Node>,
     pub triangles: Vec<Triangle>,
+    pub attributes: Vec<TriangleAttributes>,
     pub materials: Vec<Material>,
+    /// How the meshes set moving move, each material saying which is its mesh's, see
+    /// `set_motion`.
+    pub motions: Vec<Motion>,
+    pub textures: Vec<Texture>,
+    pub texels: Vec<u32>,
+    /// Point clouds get a tree of their own, sized like `sizes`.
+    pub point_sizes: [u32; 2],
+    pub point_nodes: Vec<Node>,
+    pub points: Vec<Point>,
+    /// Index in `triangles` of every triangle in the order they were added, since `build`
+    /// sorts them. Empty until built.
+    pub slots: Vec<u32>,
+    /// Filled by `update_mesh` and `refit`, taken by `SceneTris::write_tree_changes`.
+    pub changes: Changes,
+    /// Children per node of the tree the shader traverses, 2 for `nodes` as they are or
+    /// one of `wide::WIDTHS` for the tree `collapse` makes of them, 8 unless set.
+    pub width: usize,
+    /// The inverse of `slots`.
+    order: Vec<u32>,
+    /// Surface area of every node when its subtree was last sorted.
+    built_areas: Vec<f32>,
+    /// Triangles moved since the last `refit`.
+    unfitted: Option<Range<usize>>,
 }
 
 impl From<Mesh> for Tree {
@@ -27,73 +76,528 @@ impl Tree {
     pub fn new() -> Self {
         Self {
             triangles: Vec::new(),
+            attributes: Vec::new(),
             nodes: Vec::new(),
             materials: Vec::new(),
+            motions: Vec::new(),
+            textures: Vec::new(),
+            texels: Vec::new(),
             sizes: [0, 0],
+            point_sizes: [0, 0],
+            point_nodes: Vec::new(),
+            points: Vec::new(),
+            slots: Vec::new(),
+            changes: Changes::default(),
+            width: 8,
+            order: Vec::new(),
+            built_areas: Vec::new(),
+            unfitted: None,
         }
     }
 
     pub fn build(&mut self) {
-        let mut q = VecDeque::new();
-        let m = self.triangles.len();
-        let n = m.next_power_of_two();
-        q.push_back((0, n, 0));
-        while let Some((i, j, depth)) = q.pop_front() {
-            let l = i;
-            let r = std::cmp::min(j, m);
-            if l + 1 >= r {
-                continue;
-            }
-            self.triangles[l..r].sort_by(|a, b| {
-                a.custom[depth % 3]
-                    .partial_cmp(&b.custom[depth % 3])
-                    .unwrap_or(Ordering::Equal)
-            });
-            let mid = (i + j) / 2;
-            q.push_back((i, mid, depth + 1));
-            q.push_back((mid, j, depth + 1));
-        }
-
-        self.nodes = vec![Node::default(); n];
-        for (i, t) in self.triangles.iter().enumerate() {
-            let mut j = (i + n) / 2;
-            while j > 0 {
-                self.nodes[j].union(t.a);
-                self.nodes[j].union(t.b);
-                self.nodes[j].union(t.c);
-                j /= 2;
-            }
+        // sorts the indices, the triangles and their attributes follow in `build_with_order`
+        let mut items: Vec<_> = self.triangles.iter().copied().zip(0..).collect();
+        sort_halves(&mut items, |(t, _)| centroid3(t), 0);
+        self.build_with_order(items.into_iter().map(|(_, i)| i).collect());
+    }
+
+    /// Builds the tree over the triangles in `order`, the index of the triangle going in
+    /// each slot, halving the slots at every node as `build` sorts them.
+    pub fn build_with_order(&mut self, order: Vec<u32>) {
+        let m = order.len();
+        self.arrange(order);
+        self.nodes = vec![Node::default(); node_count(m)];
+        match m {
+            0 => self.nodes[0].set_children([EMPTY, EMPTY]),
+            1 => self.nodes[0].set_children([LEAF, EMPTY]),
+            _ => self.link_halves(self.root()),
+        }
+        self.fit_nodes();
+        self.finish_build();
+    }
+
+    /// As `build_with_order`, with `nodes` already linked and fitted over the triangles in
+    /// that order, as `Lbvh` builds them.
+    pub fn build_with_nodes(&mut self, order: Vec<u32>, nodes: Vec<Node>) {
+        assert_eq!(nodes.len(), node_count(order.len()));
+        self.arrange(order);
+        self.nodes = nodes;
+        self.finish_build();
+    }
+
+    /// Puts the triangles and their attributes in the slots `order` gives them.
+    fn arrange(&mut self, order: Vec<u32>) {
+        self.triangles = order.iter().map(|&i| self.triangles[i as usize]).collect();
+        self.attributes = order.iter().map(|&i| self.attributes[i as usize]).collect();
+        self.slots = vec![0; order.len()];
+        for (slot, &i) in order.iter().enumerate() {
+            self.slots[i as usize] = slot as u32;
         }
+        self.order = order;
         for t in self.triangles.iter_mut() {
-            let normal = (t.b - t.a).xyz().cross((t.c - t.a).xyz());
-            t.custom = normal.normalize();
+            t.custom = normal(t);
+        }
+    }
+
+    /// What follows from the triangle nodes, and the points, once those are in place.
+    fn finish_build(&mut self) {
+        self.built_areas = self.nodes.iter().map(Node::area).collect();
+        self.changes = Changes::default();
+        self.unfitted = None;
+        self.sizes = [self.nodes.len() as u32, self.triangles.len() as u32];
+        self.point_nodes = build_nodes(
+            &mut self.points,
+            |p| p.position,
+            |p| ((p.position - p.radius).extend(0.0), (p.position + p.radius).extend(0.0)),
+        );
+        self.point_sizes = [self.point_nodes.len() as u32, self.points.len() as u32];
+    }
+
+    /// Recomputes what `refit` keeps beside `slots` and `nodes` once they are set
+    /// directly, as `cache` does. `slots` must be a permutation of the triangle indices.
+    pub(crate) fn restore(&mut self) {
+        self.order = vec![0; self.slots.len()];
+        for (i, &slot) in self.slots.iter().enumerate() {
+            self.order[slot as usize] = i as u32;
         }
-        self.sizes = [n as u32, m as u32];
+        self.built_areas = self.nodes.iter().map(Node::area).collect();
     }
 
-    pub fn add_mesh(&mut self, mesh: Mesh) {
+    /// Registers a texture for materials to reference, returns its index.
+    pub fn add_texture(&mut self, texture: Texture) -> u32 {
+        self.textures.push(texture);
+        self.textures.len() as u32 - 1
+    }
+
+    /// Appends the texels of `image` and registers a UV mapped texture reading them.
+    pub fn add_image(&mut self, image: &Image) -> u32 {
+        let offset = self.texels.len() as u32;
+        self.texels.extend_from_slice(&image.texels);
+        self.add_texture(Texture::image(offset, image.width, image.height))
+    }
+
+    pub fn add_point_cloud(&mut self, cloud: PointCloud) {
         let material = self.materials.len() as u32;
-        self.materials.push(mesh.material);
-        self.triangles.extend(mesh.indices.chunks_exact(3).map(|t| {
-            let a = glam::Vec4::from_array(mesh.vertices[t[0] as usize].position);
-            let b = glam::Vec4::from_array(mesh.vertices[t[1] as usize].position);
-            let c = glam::Vec4::from_array(mesh.vertices[t[2] as usize].position);
-            let center3x = (a + b + c).xyz();
-            Triangle {
-                a,
-                b,
-                c,
-                material,
-                custom: center3x,
-            }
+        self.materials.push(cloud.material);
+        self.points.extend(cloud.positions.iter().enumerate().map(|(i, &position)| Point {
+            position,
+            radius: cloud.radius,
+            normal: cloud.normals.get(i).copied().unwrap_or_default(),
+            material,
+            color: cloud.colors.get(i).copied().unwrap_or(Vec3::ONE).extend(1.0),
         }));
     }
+
+    /// Whether `build` ran since the last triangles were added.
+    pub fn is_built(&self) -> bool {
+        !self.nodes.is_empty() && self.slots.len() == self.triangles.len()
+    }
+
+    /// Returns the index of the first triangle of `mesh` among those added, which
+    /// `update_mesh` takes.
+    pub fn add_mesh(&mut self, mesh: Mesh) -> u32 {
+        let first = self.triangles.len() as u32;
+        let material = self.materials.len() as u32;
+        // standing still, whatever tree the material was taken from
+        let mut still = mesh.material;
+        still.set_motion(None);
+        self.materials.push(still);
+        for (t, a) in mesh_triangles(&mesh, material) {
+            self.triangles.push(t);
+            self.attributes.push(a);
+        }
+        first
+    }
+
+    /// Adds `mesh` as `add_mesh` does, moving over the frame as `motion` says. Nodes bound
+    /// the triangles wherever they are over the frame. Fails, adding nothing, once
+    /// `MAX_MOTIONS` meshes move.
+    pub fn add_moving_mesh(&mut self, mesh: Mesh, motion: Motion) -> Result<u32, TooManyMotions> {
+        if !motion.is_static() && self.motions.len() >= MAX_MOTIONS {
+            return Err(TooManyMotions);
+        }
+        let material = self.materials.len() as u32;
+        let first = self.add_mesh(mesh);
+        self.set_motion(material, motion)?;
+        Ok(first)
+    }
+
+    /// Moves the mesh made of material `material` over the frame from now on. The nodes
+    /// bound it once its triangles are updated and refitted, see `update_triangles`. A
+    /// mesh takes one of the `MAX_MOTIONS` motions the first time it moves and keeps it,
+    /// failing if none is left.
+    pub fn set_motion(&mut self, material: u32, motion: Motion) -> Result<(), TooManyMotions> {
+        let material = &mut self.materials[material as usize];
+        match material.motion() {
+            Some(index) => self.motions[index as usize] = motion,
+            None if motion.is_static() => return Ok(()),
+            None if self.motions.len() >= MAX_MOTIONS => return Err(TooManyMotions),
+            None => {
+                material.set_motion(Some(self.motions.len() as u32));
+                self.motions.push(motion);
+            }
+        }
+        self.changes.materials = true;
+        Ok(())
+    }
+
+    /// Replaces material `index`, as animated materials do from frame to frame, keeping
+    /// how its mesh moves.
+    pub fn set_material(&mut self, index: u32, mut material: Material) {
+        let old = &mut self.materials[index as usize];
+        material.set_motion(old.motion());
+        *old = material;
+        self.changes.materials = true;
+    }
+
+    /// Whether any mesh moves, which `Lbvh` does not bound.
+    pub fn is_moving(&self) -> bool {
+        self.motions.iter().any(|m| !m.is_static())
+    }
+
+    /// Replaces the triangles added from `first` on, see `add_mesh`, with those of `mesh`,
+    /// which must list as many in the same order, as an animated mesh does from frame to
+    /// frame. The tree must be built, and its bounds catch up in `refit`.
+    pub fn update_mesh(&mut self, first: u32, mesh: &Mesh) {
+        self.update_triangles(first, mesh_triangles(mesh, 0));
+    }
+
+    /// Replaces triangles from `first` on, in the order they were added, as `update_mesh`
+    /// does, keeping their materials.
+    pub fn update_triangles(
+        &mut self,
+        first: u32,
+        triangles: impl IntoIterator<Item = (Triangle, TriangleAttributes)>,
+    ) {
+        for (k, (t, a)) in triangles.into_iter().enumerate() {
+            let slot = self.slots[first as usize + k] as usize;
+            self.triangles[slot] = Triangle {
+                custom: normal(&t),
+                material: self.triangles[slot].material,
+                ..t
+            };
+            self.attributes[slot] = a;
+            grow(&mut self.unfitted, slot..slot + 1);
+            grow(&mut self.changes.triangles, slot..slot + 1);
+        }
+    }
+
+    /// Brings the bounds of the nodes above triangles moved by `update_mesh` up to date,
+    /// leaving the rest of the tree alone. Subtrees whose area grew by more than
+    /// `REBUILD_RATIO` since they were sorted are sorted again, returns how many.
+    pub fn refit(&mut self) -> usize {
+        let Some(moved) = self.unfitted.take() else {
+            return 0;
+        };
+        let spans = self.spans(self.root(), moved);
+        for span in spans.iter().rev() {
+            self.nodes[span.node] = self.fit(span.node);
+        }
+        // top down, so a subtree sorted again is not also sorted in parts
+        let mut degraded: Vec<Span> = Vec::new();
+        for &span in &spans {
+            let inside = degraded.iter().any(|d| d.contains(&span));
+            let area = self.nodes[span.node].area();
+            if !inside && area > REBUILD_RATIO * self.built_areas[span.node] {
+                degraded.push(span);
+            }
+        }
+        for &span in &degraded {
+            self.rebuild(span);
+        }
+        // the nodes inside those sorted again are new, their ancestors need refitting
+        for span in spans.iter().rev() {
+            if !degraded.iter().any(|d| d.contains(span)) {
+                self.nodes[span.node] = self.fit(span.node);
+            }
+            self.changes.nodes.push(span.node..span.node + 1);
+        }
+        self.changes.nodes = coalesce(std::mem::take(&mut self.changes.nodes));
+        degraded.len()
+    }
+
+    /// The box of what `link` leads to, a node or a triangle, if anything, see
+    /// `Node::children`.
+    pub fn child_bounds(&self, link: u32) -> Option<Node> {
+        if link & LEAF == 0 {
+            return Some(self.nodes[link as usize]);
+        }
+        let (min, max) = self.swept_bounds(self.triangles.get((link & !LEAF) as usize)?);
+        let mut node = Node::default();
+        node.union(min);
+        node.union(max);
+        Some(node)
+    }
+
+    /// The root, over every slot.
+    pub(crate) fn root(&self) -> Span {
+        Span {
+            node: 0,
+            first: 0,
+            last: self.triangles.len().saturating_sub(1),
+            depth: 0,
+        }
+    }
+
+    /// The nodes under `top`, itself included, over any of `slots`, parents before their
+    /// children.
+    pub(crate) fn spans(&self, top: Span, slots: Range<usize>) -> Vec<Span> {
+        let mut spans = Vec::new();
+        let mut stack = vec![top];
+        while let Some(span) = stack.pop() {
+            spans.push(span);
+            let [left, right] = self.nodes[span.node].children();
+            let split = (left & !LEAF) as usize;
+            let depth = span.depth + 1;
+            if right & LEAF == 0 && slots.start <= span.last && split + 1 < slots.end {
+                stack.push(Span { node: right as usize, first: split + 1, last: span.last, depth });
+            }
+            if left & LEAF == 0 && slots.start <= split && span.first < slots.end {
+                stack.push(Span { node: left as usize, first: span.first, last: split, depth });
+            }
+        }
+        spans
+    }
+
+    /// Fits every node over its children, bottom up, keeping the links.
+    pub(crate) fn fit_nodes(&mut self) {
+        let m = self.triangles.len();
+        for span in self.spans(self.root(), 0..m).into_iter().rev() {
+            self.nodes[span.node] = self.fit(span.node);
+        }
+    }
+
+    /// Node `j` recomputed from its children.
+    fn fit(&self, j: usize) -> Node {
+        let links = self.nodes[j].children();
+        let mut node = Node::default();
+        for child in links.iter().filter_map(|&link| self.child_bounds(link)) {
+            node.merge(&child);
+        }
+        node.set_children(links);
+        node
+    }
+
+    /// Links the nodes under `span` as `sort_halves` splits its slots.
+    fn link_halves(&mut self, span: Span) {
+        let mut stack = vec![(span.node, span.first, span.last)];
+        while let Some((j, a, b)) = stack.pop() {
+            let split = a + half(b - a + 1) - 1;
+            self.nodes[j].set_children(links(a, split, b));
+            if split > a {
+                stack.push((split, a, split));
+            }
+            if split + 1 < b {
+                stack.push((split + 1, split + 1, b));
+            }
+        }
+    }
+
+    /// The box of `t` over the frame, see `add_moving_mesh`.
+    fn swept_bounds(&self, t: &Triangle) -> (Vec4, Vec4) {
+        let (min, max) = bounds(t);
+        let motion = self.materials.get(t.material as usize).and_then(Material::motion);
+        match motion.map(|m| self.motions[m as usize]) {
+            Some(motion) if !motion.is_static() => {
+                let (lo, hi) = motion.sweep(min.xyz(), max.xyz());
+                (lo.extend(min.w), hi.extend(max.w))
+            }
+            _ => (min, max),
+        }
+    }
+
+    /// Sorts the triangles under `span` again as `build` would, then relinks and refits
+    /// the nodes of its subtree. Those keep their indices, `first..last` or one past.
+    fn rebuild(&mut self, span: Span) {
+        let Span { first, last, depth, .. } = span;
+        let mut items: Vec<_> = (first..=last)
+            .map(|s| (self.triangles[s], self.attributes[s], self.order[s]))
+            .collect();
+        sort_halves(&mut items, |(t, _, _)| centroid3(t), depth);
+        for (slot, (t, a, i)) in (first..).zip(items) {
+            self.triangles[slot] = t;
+            self.attributes[slot] = a;
+            self.order[slot] = i;
+            self.slots[i as usize] = slot as u32;
+        }
+        grow(&mut self.changes.triangles, first..last + 1);
+        self.link_halves(span);
+        for inner in self.spans(span, first..last + 1).into_iter().rev() {
+            self.nodes[inner.node] = self.fit(inner.node);
+            self.built_areas[inner.node] = self.nodes[inner.node].area();
+        }
+        self.changes.nodes.push(first..(last + 1).min(self.nodes.len()));
+    }
+}
+
+/// A node with the slots `first..=last` under it, `depth` levels below the root.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Span {
+    pub node: usize,
+    pub first: usize,
+    pub last: usize,
+    pub depth: usize,
+}
+
+impl Span {
+    /// Whether `other` is in the subtree under this node, this node included.
+    fn contains(&self, other: &Span) -> bool {
+        self.first <= other.first && other.last <= self.last && self.depth <= other.depth
+    }
+}
+
+/// Nodes a tree over `m` triangles takes, see `Tree::nodes`.
+pub(crate) fn node_count(m: usize) -> usize {
+    m.saturating_sub(1).max(1)
+}
+
+/// The links of the node over slots `a..=b` split after slot `split`, see `Tree::nodes`.
+pub(crate) fn links(a: usize, split: usize, b: usize) -> [u32; 2] {
+    let left = if split == a { LEAF | a as u32 } else { split as u32 };
+    let right = if split + 1 == b { LEAF | b as u32 } else { split as u32 + 1 };
+    [left, right]
+}
+
+/// Slots under the left child of a node over `len`, as `build` splits them, so every left
+/// subtree is full.
+fn half(len: usize) -> usize {
+    len.next_power_of_two() / 2
+}
+
+/// Sorts ranges into runs, joining those that touch.
+fn coalesce(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges.sort_by_key(|r| r.start);
+    let mut runs: Vec<Range<usize>> = Vec::new();
+    for r in ranges {
+        match runs.last_mut() {
+            Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+            _ => runs.push(r),
+        }
+    }
+    runs
+}
+
+/// Triangles and attributes of `mesh`, all made of `material`.
+fn mesh_triangles(
+    mesh: &Mesh,
+    material: u32,
+) -> impl Iterator<Item = (Triangle, TriangleAttributes)> + '_ {
+    let corners = mesh
+        .corners
+        .chunks_exact(3)
+        .zip(mesh.indices.chunks_exact(3));
+    corners.map(move |(corner, t)| {
+        let position = |v: usize| Vec4::from_array(mesh.vertices[t[v] as usize].position);
+        let (a, b, c) = (position(0), position(1), position(2));
+        let triangle = Triangle {
+            a,
+            b,
+            c,
+            material,
+            custom: (a + b + c).xyz(),
+        };
+        let color = |v: usize| {
+            let color = Vec4::from_array(mesh.vertices[t[v] as usize].color);
+            f32::from_bits(pack_color(color))
+        };
+        let attributes = TriangleAttributes {
+            normals: [0, 1, 2].map(|v| corner[v].normal.extend(color(v))),
+            tangents: [0, 1, 2].map(|v| corner[v].tangent),
+            uvs: [0, 1, 2].map(|v| corner[v].uv),
+            _padding: [0.0; 2],
+        };
+        (triangle, attributes)
+    })
+}
+
+/// Three times the centroid, the sort key of triangles.
+fn centroid3(t: &Triangle) -> Vec3 {
+    (t.a + t.b + t.c).xyz()
+}
+
+fn bounds(t: &Triangle) -> (Vec4, Vec4) {
+    (t.a.min(t.b).min(t.c), t.a.max(t.b).max(t.c))
+}
+
+fn normal(t: &Triangle) -> Vec3 {
+    (t.b - t.a).xyz().cross((t.c - t.a).xyz()).normalize()
+}
+
+fn grow(range: &mut Option<Range<usize>>, other: Range<usize>) {
+    *range = Some(match range.take() {
+        Some(r) => r.start.min(other.start)..r.end.max(other.end),
+        None => other,
+    });
+}
+
+/// Sorts `items` along alternating axes of `key` into the leaves of an implicit binary
+/// tree and returns its nodes. Node `i` has children `2i` and `2i + 1`, item `j` is leaf
+/// `n + j` where `n`, the number of nodes, is the item count rounded up to a power of two.
+pub fn build_nodes<T>(
+    items: &mut [T],
+    key: impl Fn(&T) -> Vec3,
+    bounds: impl Fn(&T) -> (Vec4, Vec4),
+) -> Vec<Node> {
+    let n = items.len().next_power_of_two();
+    sort_leaves(items, key, n, 0);
+    let mut nodes = vec![Node::default(); n];
+    for (i, item) in items.iter().enumerate() {
+        let (min, max) = bounds(item);
+        let mut j = (i + n) / 2;
+        while j > 0 {
+            nodes[j].union(min);
+            nodes[j].union(max);
+            j /= 2;
+        }
+    }
+    nodes
+}
+
+/// Sorts `items`, the slots under a node `depth` levels down, along alternating axes of
+/// `key`, halving them at every level as `Tree::build` links them.
+fn sort_halves<T>(items: &mut [T], key: impl Fn(&T) -> Vec3, depth: usize) {
+    let mut stack = vec![(0, items.len(), depth)];
+    while let Some((start, end, depth)) = stack.pop() {
+        if end - start < 2 {
+            continue;
+        }
+        items[start..end].sort_by(|a, b| {
+            key(a)[depth % 3]
+                .partial_cmp(&key(b)[depth % 3])
+                .unwrap_or(Ordering::Equal)
+        });
+        let mid = start + half(end - start);
+        stack.push((start, mid, depth + 1));
+        stack.push((mid, end, depth + 1));
+    }
+}
+
+/// Sorts `items`, the leaves under a node `depth` levels down whose subtree has room for
+/// `span`, the way `build_nodes` sorts the whole tree.
+fn sort_leaves<T>(items: &mut [T], key: impl Fn(&T) -> Vec3, span: usize, depth: usize) {
+    let mut q = VecDeque::new();
+    let m = items.len();
+    q.push_back((0, span, depth));
+    while let Some((i, j, depth)) = q.pop_front() {
+        let l = i;
+        let r = std::cmp::min(j, m);
+        if l + 1 >= r {
+            continue;
+        }
+        items[l..r].sort_by(|a, b| {
+            key(a)[depth % 3]
+                .partial_cmp(&key(b)[depth % 3])
+                .unwrap_or(Ordering::Equal)
+        });
+        let mid = (i + j) / 2;
+        q.push_back((i, mid, depth + 1));
+        q.push_back((mid, j, depth + 1));
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use glam::Vec3;
-
     use super::*;
 
     #[test]
@@ -101,13 +605,31 @@ mod tests {
         let mesh = Mesh::load_obj(
             include_bytes!("../../assets/cube.obj"),
             Material::new_lambertian(Vec3::new(0.5, 0.5, 0.5)),
-        );
+        )
+        .unwrap();
         let mut tree: Tree = mesh.into();
         tree.build();
-        assert_eq!(tree.sizes, [16, 12]);
-        assert_eq!(tree.nodes.len(), 16);
+        assert_eq!(tree.sizes, [11, 12]);
+        assert_eq!(tree.nodes.len(), 11);
         assert_eq!(tree.triangles.len(), 12);
+        assert_eq!(tree.attributes.len(), 12);
         assert_eq!(tree.materials.len(), 1);
+        // flat shaded cube, so attributes still line up with their triangle after sorting
+        for (t, a) in tree.triangles.iter().zip(&tree.attributes) {
+            for n in a.normals {
+                assert!(n.truncate().abs_diff_eq(t.custom, 1e-5));
+            }
+        }
+    }
+
+    #[test]
+    fn vertex_colors() {
+        let source = b"v 0 0 0 1 0 0\nv 1 0 0 0 1 0\nv 0 1 0 0 0 1\nf 1 2 3\n";
+        let mesh = Mesh::load_obj(source, Material::new_lambertian(Vec3::ONE)).unwrap();
+        let tree: Tree = mesh.into();
+        let colors = tree.attributes[0].normals.map(|n| n.w.to_bits());
+        assert_eq!(colors, [0xff0000ff, 0xff00ff00, 0xffff0000]);
+        assert_eq!(pack_color(Vec4::new(0.5, 0.0, 1.0, 0.5)), 0x80ff00ba);
     }
 
     #[test]
@@ -115,12 +637,115 @@ mod tests {
         let mesh = Mesh::load_obj(
             include_bytes!("../../assets/suzanne.obj"),
             Material::new_lambertian(Vec3::new(0.5, 0.5, 0.5)),
-        );
+        )
+        .unwrap();
         let mut tree: Tree = mesh.into();
         tree.build();
-        assert_eq!(tree.sizes, [1024, 979]);
-        assert_eq!(tree.nodes.len(), 1024);
+        assert_eq!(tree.sizes, [978, 979]);
+        assert_eq!(tree.nodes.len(), 978);
         assert_eq!(tree.triangles.len(), 979);
         assert_eq!(tree.materials.len(), 1);
     }
+
+    /// Checks the nodes still link up, every node bounds its children and `slots` finds
+    /// the triangles of `mesh`, added from `first` on.
+    fn check(tree: &Tree, first: u32, mesh: &Mesh) {
+        assert!(tree.is_linked());
+        for j in 0..tree.nodes.len() {
+            assert_eq!(format!("{:?}", tree.nodes[j]), format!("{:?}", tree.fit(j)));
+        }
+        for (i, t) in mesh.indices.chunks_exact(3).enumerate() {
+            let triangle = &tree.triangles[tree.slots[first as usize + i] as usize];
+            assert_eq!(triangle.a.truncate(), mesh.position(t[0]));
+        }
+    }
+
+    #[test]
+    fn refit() {
+        let mut mesh = Mesh::load_obj(
+            include_bytes!("../../assets/suzanne.obj"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap();
+        let mut tree = Tree::new();
+        tree.add_mesh(
+            Mesh::load_obj(b"v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n", mesh.material).unwrap(),
+        );
+        let first = tree.add_mesh(mesh.clone());
+        assert_eq!(first, 1);
+        tree.build();
+        // a small move only refits
+        mesh.translate(Vec3::new(0.01, 0.0, 0.0));
+        tree.update_mesh(first, &mesh);
+        assert_eq!(tree.refit(), 0);
+        check(&tree, first, &mesh);
+        assert!(tree.changes.triangles.is_some());
+        // every node is over some of the head, so one run of them all
+        assert_eq!(tree.changes.nodes, vec![0..tree.nodes.len()]);
+        // pulling half the head away spoils the sort, so some subtrees are sorted again
+        for i in 0..mesh.vertices.len() as u32 {
+            if mesh.position(i).x > 0.0 {
+                mesh.vertices[i as usize].position[1] += 5.0;
+            }
+        }
+        tree.update_mesh(first, &mesh);
+        assert!(tree.refit() > 0);
+        check(&tree, first, &mesh);
+        // the lone triangle is where it was
+        let lone = &tree.triangles[tree.slots[0] as usize];
+        assert_eq!(lone.b.truncate(), Vec3::X);
+        assert_eq!(lone.material, 0);
+    }
+
+    #[test]
+    fn motions() {
+        let source = b"v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n";
+        let triangle = Mesh::load_obj(source, Material::new_lambertian(Vec3::ONE)).unwrap();
+        let step = Motion::linear(Vec3::new(2.0, 0.0, 0.0));
+        let mut tree = Tree::new();
+        tree.add_mesh(triangle.clone());
+        assert_eq!(tree.add_moving_mesh(triangle.clone(), step), Ok(1));
+        tree.build();
+        assert_eq!(tree.nodes[0].bounds(), (Vec3::ZERO, Vec3::new(3.0, 1.0, 0.0)));
+        // meshes standing still take no motion, however many there are
+        for _ in 0..MAX_MOTIONS {
+            tree.add_mesh(triangle.clone());
+        }
+        assert_eq!(tree.motions, [step]);
+        for material in 2..MAX_MOTIONS as u32 + 1 {
+            tree.set_motion(material, step).unwrap();
+        }
+        // a mesh set moving again keeps its motion, a new one finds none left
+        tree.set_motion(1, Motion::default()).unwrap();
+        assert_eq!(tree.motions[0], Motion::default());
+        assert_eq!(tree.set_motion(0, step), Err(TooManyMotions));
+        assert_eq!(tree.add_moving_mesh(triangle.clone(), step), Err(TooManyMotions));
+        assert_eq!(tree.materials.len(), MAX_MOTIONS + 2);
+        assert!(tree.add_moving_mesh(triangle, Motion::default()).is_ok());
+        // and so does one whose material is replaced
+        tree.set_material(2, Material::new_metal(Vec3::ONE, 0.0));
+        assert_eq!(tree.materials[2].motion(), Some(1));
+    }
+
+    #[test]
+    fn points() {
+        let mut tree = Tree::new();
+        let cloud = PointCloud {
+            positions: vec![Vec3::ZERO, Vec3::X, Vec3::Y],
+            normals: Vec::new(),
+            colors: vec![Vec3::X; 3],
+            radius: 0.5,
+            material: Material::new_lambertian(Vec3::ONE),
+        };
+        tree.add_point_cloud(cloud);
+        tree.build();
+        assert_eq!(tree.point_sizes, [4, 3]);
+        assert_eq!(tree.sizes, [1, 0]);
+        assert_eq!(tree.points[0].color, Vec4::new(1.0, 0.0, 0.0, 1.0));
+        // the root bounds every point grown by its radius
+        let mut root = Node::default();
+        root.union(Vec4::new(-0.5, -0.5, -0.5, 0.0));
+        root.union(Vec4::new(1.5, 1.5, 0.5, 0.0));
+        assert_eq!(format!("{:?}", tree.point_nodes[1]), format!("{root:?}"));
+    }
 }
@@ -1,5 +1,6 @@
 use bytemuck::Pod;
 use bytemuck::Zeroable;
+use glam::Vec2;
 use glam::Vec3;
 use glam::Vec4;
 
@@ -13,6 +14,25 @@ pub struct Triangle {
     pub material: u32,
 }
 
+/// Shading data for the triangle at the same index, only read once the closest hit is known.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Pod, Zeroable)]
+pub struct TriangleAttributes {
+    /// `w` holds the bits of the vertex colour, see `pack_color`.
+    pub normals: [Vec4; 3],
+    pub tangents: [Vec4; 3],
+    pub uvs: [Vec2; 3],
+    pub _padding: [f32; 2],
+}
+
+/// Packs a linear colour as sRGB RGBA8, the shader unpacks it with `unpack4x8unorm`. The
+/// result is only meant to travel as the bits of an `f32`.
+pub fn pack_color(color: Vec4) -> u32 {
+    let srgb = color.truncate().clamp(Vec3::ZERO, Vec3::ONE).powf(1.0 / 2.2);
+    let bytes = srgb.extend(color.w.clamp(0.0, 1.0)) * 255.0;
+    u32::from_le_bytes(bytes.round().to_array().map(|c| c as u8))
+}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, Pod, Zeroable)]
 pub struct CompactTriangle {
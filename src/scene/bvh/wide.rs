@@ -0,0 +1,188 @@
+//! Wide trees for the triangle shader, collapsed from the binary tree of `Tree` into nodes
+//! of 4 or 8 children. A node holds the boxes of its children, not its own, quantized to a
+//! byte per side on a grid over their union whose cells are powers of two on each axis, so
+//! the shader decodes them exactly and tests all children with a single read.
+//!
+//! Collapsing starts from the two children of a binary node and keeps opening the one
+//! with the largest box that is a node until there are `width`, so every wide node but
+//! those near few triangles is full. Nodes are stored breadth first from the root, each
+//! `stride(width)` vec4 words: the grid origin and the biased exponents of its cell sizes,
+//! the low and high corners of the children as rows of `width` bytes for low x, y and z
+//! then high x, y and z, and a link per child as binary nodes have, the index of a wide
+//! node, `LEAF` and a triangle slot or `EMPTY`. Empty children have a low x above their
+//! high x.
+use std::collections::VecDeque;
+
+use glam::Vec3;
+
+use crate::scene::bvh::{Node, Tree, EMPTY, LEAF};
+
+/// Widths `Tree::collapse` supports besides the binary tree itself.
+pub const WIDTHS: [usize; 2] = [4, 8];
+
+/// Size of a node of a tree `width` wide, in vec4 words.
+pub fn stride(width: usize) -> usize {
+    1 + (6 * width).div_ceil(16) + width / 4
+}
+
+impl Tree {
+    /// The tree as nodes `width` wide, 4 or 8, in the layout described in `wide`.
+    pub fn collapse(&self, width: usize) -> Vec<u32> {
+        assert!(WIDTHS.contains(&width));
+        let root = self.nodes.first().map_or([EMPTY, EMPTY], Node::children);
+        let mut queue = VecDeque::from([root]);
+        let mut count = 1;
+        let mut words = Vec::new();
+        while let Some(links) = queue.pop_front() {
+            let mut children = links.to_vec();
+            while children.len() < width {
+                let area = |&i: &usize| self.nodes[children[i] as usize].area();
+                let nodes = (0..children.len()).filter(|&i| children[i] & LEAF == 0);
+                let Some(i) = nodes.max_by(|a, b| area(a).total_cmp(&area(b))) else {
+                    break;
+                };
+                let link = children[i] as usize;
+                children.splice(i..i + 1, self.nodes[link].children());
+            }
+            children.resize(width, EMPTY);
+            let boxes: Vec<_> = children.iter().map(|&link| self.child_bounds(link)).collect();
+            let links: Vec<_> = children
+                .iter()
+                .map(|&link| {
+                    if link & LEAF != 0 {
+                        return link;
+                    }
+                    queue.push_back(self.nodes[link as usize].children());
+                    count += 1;
+                    count - 1
+                })
+                .collect();
+            quantize(&boxes, &links, &mut words);
+        }
+        words
+    }
+}
+
+/// Appends the node holding `children`, linked as `links` say, see `wide`.
+fn quantize(children: &[Option<Node>], links: &[u32], words: &mut Vec<u32>) {
+    let width = children.len();
+    let mut union = Node::default();
+    for child in children.iter().flatten() {
+        union.merge(child);
+    }
+    let (origin, max) = union.bounds();
+    let origin = if children.iter().any(Option::is_some) {
+        origin
+    } else {
+        Vec3::ZERO
+    };
+    // the smallest cells that reach `max` in 255 steps
+    let exponents = [0, 1, 2].map(|axis| {
+        let extent = max[axis] - origin[axis];
+        let guess = (extent / 255.0).log2().ceil() + 127.0;
+        let mut e = guess.clamp(1.0, 254.0) as u32;
+        while e < 254 && origin[axis] + 255.0 * cell(e) < max[axis] {
+            e += 1;
+        }
+        e
+    });
+    let mut bytes = vec![0u8; 6 * width];
+    for (c, child) in children.iter().enumerate() {
+        let Some(child) = child else {
+            bytes[c] = 255;
+            continue;
+        };
+        let (min, max) = child.bounds();
+        for axis in 0..3 {
+            let (o, s) = (origin[axis], cell(exponents[axis]));
+            // rounded outwards, checked as the shader decodes
+            let mut lo = ((min[axis] - o) / s).floor().clamp(0.0, 255.0) as u32;
+            while lo > 0 && o + lo as f32 * s > min[axis] {
+                lo -= 1;
+            }
+            let mut hi = ((max[axis] - o) / s).ceil().clamp(0.0, 255.0) as u32;
+            while hi < 255 && o + (hi as f32) * s < max[axis] {
+                hi += 1;
+            }
+            bytes[axis * width + c] = lo as u8;
+            bytes[(3 + axis) * width + c] = hi as u8;
+        }
+    }
+    let start = words.len();
+    words.extend(origin.to_array().map(f32::to_bits));
+    words.push(exponents[0] | exponents[1] << 8 | exponents[2] << 16);
+    words.extend(
+        bytes
+            .chunks(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+    );
+    words.resize(start + 4 * (stride(width) - width / 4), 0);
+    words.extend(links);
+}
+
+/// The cell size of biased exponent `e`, exactly as the shader makes it.
+fn cell(e: u32) -> f32 {
+    f32::from_bits(e << 23)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Mesh;
+    use crate::scene::Material;
+
+    /// The box of child `c` of node `node` as the shader decodes it, and the cell size.
+    fn decode(words: &[u32], width: usize, node: usize, c: usize) -> Option<(Vec3, Vec3, Vec3)> {
+        let words = &words[4 * stride(width) * node..];
+        let origin = Vec3::from_array([0, 1, 2].map(|i| f32::from_bits(words[i])));
+        let scale = Vec3::from_array([0, 1, 2].map(|i| cell(words[3] >> (8 * i) & 255)));
+        let byte = |b: usize| (words[4 + b / 4] >> (8 * (b % 4)) & 255) as f32;
+        let lo = Vec3::from_array([0, 1, 2].map(|axis| byte(axis * width + c)));
+        let hi = Vec3::from_array([3, 4, 5].map(|axis| byte(axis * width + c)));
+        (lo.x <= hi.x).then(|| (origin + lo * scale, origin + hi * scale, scale))
+    }
+
+    #[test]
+    fn collapse() {
+        let mesh = Mesh::load_obj(
+            include_bytes!("../../assets/suzanne.obj"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap();
+        let mut tree: Tree = mesh.into();
+        tree.build();
+        for width in WIDTHS {
+            let words = tree.collapse(width);
+            let size = 4 * stride(width);
+            assert_eq!(words.len() % size, 0);
+            let nodes = words.len() / size;
+            // 978 binary nodes open into few wide ones, most of them full
+            assert!(nodes < 2 * 979 / (width - 1));
+            // every triangle is reached once, and sits in its box a cell from it at most
+            let mut reached = vec![0; tree.triangles.len()];
+            for node in 0..nodes {
+                for c in 0..width {
+                    let link = words[size * node + size - width + c];
+                    let decoded = decode(&words, width, node, c);
+                    if link == EMPTY {
+                        assert!(decoded.is_none());
+                        continue;
+                    }
+                    let (lo, hi, cell) = decoded.unwrap();
+                    if link & LEAF == 0 {
+                        assert!(node < link as usize && (link as usize) < nodes);
+                        continue;
+                    }
+                    let slot = (link & !LEAF) as usize;
+                    reached[slot] += 1;
+                    let t = &tree.triangles[slot];
+                    let (min, max) = (t.a.min(t.b).min(t.c), t.a.max(t.b).max(t.c));
+                    assert!(lo.cmple(min.truncate()).all() && hi.cmpge(max.truncate()).all());
+                    let excess = (hi - lo) - (max - min).truncate();
+                    assert!(excess.cmple(2.0 * cell).all());
+                }
+            }
+            assert!(reached.iter().all(|&r| r == 1));
+        }
+    }
+}
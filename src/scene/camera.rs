@@ -9,6 +9,8 @@ pub struct Camera {
     up: Vec4,
     right: Vec4,
     params: Vec4,
+    /// Opening and closing time of the shutter in xy, see `with_shutter`.
+    shutter: Vec4,
 }
 
 impl Camera {
@@ -24,6 +26,14 @@ impl Camera {
             up: up.extend(1.0),
             right: right.extend(1.0),
             params: Vec4::new(focal_length, focal_blur_amount, fov, 0.0),
+            shutter: Vec4::ZERO,
         }
     }
+    /// Keeps the shutter open from `open` to `close` over the frame objects move in, from
+    /// 0 to 1 (see `Motion`), so every path sees them at a random time in between and
+    /// moving objects blur. Closed by default, showing everything as it is at time 0.
+    pub fn with_shutter(mut self, open: f32, close: f32) -> Self {
+        self.shutter = Vec4::new(open, close.max(open), 0.0, 0.0);
+        self
+    }
 }
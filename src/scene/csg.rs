@@ -0,0 +1,211 @@
+use bytemuck::{Pod, Zeroable};
+use glam::{Quat, Vec3, Vec4};
+
+pub const CSG_SPHERE: u32 = 1;
+pub const CSG_BOX: u32 = 2;
+pub const CSG_UNION: u32 = 3;
+pub const CSG_INTERSECTION: u32 = 4;
+pub const CSG_DIFFERENCE: u32 = 5;
+
+/// Operand stack size of the shader's CSG evaluator.
+pub const MAX_CSG_DEPTH: usize = 8;
+
+/// One step of a CSG tree flattened in postfix order, leaves push the ray's span through
+/// them and operators combine the top two.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable, Default, PartialEq)]
+pub struct CsgNode {
+    a: Vec4,
+    b: Vec4,
+    c: Vec4,
+    kind: u32,
+    _padding: [u32; 3],
+}
+
+/// Boolean combination of solids, intersected span by span along the ray.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Csg {
+    Sphere(Vec3, f32),
+    /// Center, half extents and rotation.
+    Box(Vec3, Vec3, Quat),
+    Union(Box<Csg>, Box<Csg>),
+    Intersection(Box<Csg>, Box<Csg>),
+    /// The first solid minus the second.
+    Difference(Box<Csg>, Box<Csg>),
+}
+
+impl Csg {
+    pub fn sphere(center: Vec3, radius: f32) -> Self {
+        Self::Sphere(center, radius)
+    }
+    pub fn aabb(min: Vec3, max: Vec3) -> Self {
+        Self::Box((min + max) * 0.5, (max - min).abs() * 0.5, Quat::IDENTITY)
+    }
+    pub fn oriented_box(center: Vec3, half_extents: Vec3, rotation: Quat) -> Self {
+        Self::Box(center, half_extents.abs(), rotation.normalize())
+    }
+    pub fn union(self, other: Self) -> Self {
+        Self::Union(Box::new(self), Box::new(other))
+    }
+    pub fn intersection(self, other: Self) -> Self {
+        Self::Intersection(Box::new(self), Box::new(other))
+    }
+    pub fn difference(self, other: Self) -> Self {
+        Self::Difference(Box::new(self), Box::new(other))
+    }
+    /// Reads a prefix expression such as `(difference (box -1 -1 -1 1 1 1) (sphere 0 0 0 1.3))`.
+    /// Spheres take a center and radius, boxes their min and max corners.
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let source = source.replace('(', " ( ").replace(')', " ) ");
+        let mut tokens = source.split_whitespace();
+        let csg = Self::parse_tokens(&mut tokens)?;
+        match tokens.next() {
+            Some(token) => Err(format!("unexpected `{token}` after solid")),
+            None => Ok(csg),
+        }
+    }
+    fn parse_tokens<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Result<Self, String> {
+        match tokens.next() {
+            Some("(") => {}
+            Some(token) => return Err(format!("expected `(`, found `{token}`")),
+            None => return Err("missing solid".to_string()),
+        }
+        let name = tokens.next().ok_or("missing solid name")?;
+        let number = |tokens: &mut dyn Iterator<Item = &'a str>| {
+            let token = tokens.next().ok_or(format!("missing number in `{name}`"))?;
+            match token.parse::<f32>() {
+                Ok(x) if x.is_finite() => Ok(x),
+                _ => Err(format!("expected a number in `{name}`, found `{token}`")),
+            }
+        };
+        let vec3 = |tokens: &mut dyn Iterator<Item = &'a str>| -> Result<Vec3, String> {
+            Ok(Vec3::new(number(tokens)?, number(tokens)?, number(tokens)?))
+        };
+        let csg = match name {
+            "sphere" => Self::sphere(vec3(tokens)?, number(tokens)?),
+            "box" => Self::aabb(vec3(tokens)?, vec3(tokens)?),
+            "union" | "intersection" | "difference" => {
+                let a = Self::parse_tokens(tokens)?;
+                let b = Self::parse_tokens(tokens)?;
+                match name {
+                    "union" => a.union(b),
+                    "intersection" => a.intersection(b),
+                    _ => a.difference(b),
+                }
+            }
+            _ => return Err(format!("unknown solid `{name}`")),
+        };
+        match tokens.next() {
+            Some(")") => Ok(csg),
+            Some(token) => Err(format!("expected `)` to close `{name}`, found `{token}`")),
+            None => Err(format!("missing `)` to close `{name}`")),
+        }
+    }
+    /// Axis aligned bounds of the solid.
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        match self {
+            Self::Sphere(center, radius) => (*center - *radius, *center + *radius),
+            Self::Box(center, half, rotation) => {
+                // extent of the rotated box along each world axis
+                let axes = [Vec3::X, Vec3::Y, Vec3::Z].map(|axis| *rotation * axis);
+                let extent =
+                    axes[0].abs() * half.x + axes[1].abs() * half.y + axes[2].abs() * half.z;
+                (*center - extent, *center + extent)
+            }
+            Self::Union(a, b) => {
+                let (a, b) = (a.bounds(), b.bounds());
+                (a.0.min(b.0), a.1.max(b.1))
+            }
+            Self::Intersection(a, b) => {
+                let (a, b) = (a.bounds(), b.bounds());
+                (a.0.max(b.0), a.1.min(b.1))
+            }
+            Self::Difference(a, _) => a.bounds(),
+        }
+    }
+    /// Operand stack entries needed to evaluate the flattened tree.
+    pub fn depth(&self) -> usize {
+        match self {
+            Self::Sphere(..) | Self::Box(..) => 1,
+            Self::Union(a, b) | Self::Intersection(a, b) | Self::Difference(a, b) => {
+                a.depth().max(b.depth() + 1)
+            }
+        }
+    }
+    /// Appends the tree to `nodes` in postfix order.
+    pub fn flatten(&self, nodes: &mut Vec<CsgNode>) {
+        let node = |kind, a, b, c| CsgNode {
+            a,
+            b,
+            c,
+            kind,
+            _padding: [0; 3],
+        };
+        match self {
+            Self::Sphere(center, radius) => {
+                nodes.push(node(CSG_SPHERE, center.extend(*radius), Vec4::ZERO, Vec4::ZERO))
+            }
+            Self::Box(center, half, rotation) => nodes.push(node(
+                CSG_BOX,
+                center.extend(0.0),
+                half.extend(0.0),
+                Vec4::from(*rotation),
+            )),
+            Self::Union(a, b) | Self::Intersection(a, b) | Self::Difference(a, b) => {
+                a.flatten(nodes);
+                b.flatten(nodes);
+                let kind = match self {
+                    Self::Union(..) => CSG_UNION,
+                    Self::Intersection(..) => CSG_INTERSECTION,
+                    _ => CSG_DIFFERENCE,
+                };
+                nodes.push(node(kind, Vec4::ZERO, Vec4::ZERO, Vec4::ZERO));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        // must match `struct CsgNode` in the sphere shader
+        assert_eq!(std::mem::size_of::<CsgNode>(), 64);
+    }
+
+    #[test]
+    fn lens() {
+        let lens = Csg::parse("(intersection (sphere 0 0 -0.8 1) (sphere 0 0 0.8 1))").unwrap();
+        assert_eq!(
+            lens,
+            Csg::sphere(Vec3::new(0.0, 0.0, -0.8), 1.0)
+                .intersection(Csg::sphere(Vec3::new(0.0, 0.0, 0.8), 1.0))
+        );
+        let (min, max) = lens.bounds();
+        assert!(min.abs_diff_eq(Vec3::new(-1.0, -1.0, -0.2), 1e-6));
+        assert!(max.abs_diff_eq(Vec3::new(1.0, 1.0, 0.2), 1e-6));
+        let mut nodes = Vec::new();
+        lens.flatten(&mut nodes);
+        let kinds: Vec<_> = nodes.iter().map(|n| n.kind).collect();
+        assert_eq!(kinds, [CSG_SPHERE, CSG_SPHERE, CSG_INTERSECTION]);
+        assert_eq!(lens.depth(), 2);
+    }
+
+    #[test]
+    fn rotated_bounds() {
+        let rotation = Quat::from_rotation_y(std::f32::consts::FRAC_PI_4);
+        let (min, max) = Csg::oriented_box(Vec3::ZERO, Vec3::ONE, rotation).bounds();
+        let r = 2f32.sqrt();
+        assert!(min.abs_diff_eq(Vec3::new(-r, -1.0, -r), 1e-5));
+        assert!(max.abs_diff_eq(Vec3::new(r, 1.0, r), 1e-5));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Csg::parse("(cube 1)"), Err("unknown solid `cube`".into()));
+        assert!(Csg::parse("(sphere 0 0 0)").is_err());
+        assert!(Csg::parse("(union (sphere 0 0 0 1))").is_err());
+    }
+}
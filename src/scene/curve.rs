@@ -0,0 +1,152 @@
+use bytemuck::{Pod, Zeroable};
+use glam::{Vec3, Vec4};
+use std::f32::consts::SQRT_2;
+
+use crate::scene::{Material, ParseError};
+
+pub const TUBE: u32 = 1;
+pub const RIBBON: u32 = 2;
+
+/// Finest split of a curve when intersecting it, into `2^MAX_DEPTH` line segments.
+const MAX_DEPTH: u32 = 5;
+
+/// Cubic Bézier curve swept by a radius that varies linearly from start to end, kept in
+/// their own BVH and intersected in the sphere shader.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct Curve {
+    points: [Vec4; 4],
+    /// Ribbon normals at both ends, unused by tubes.
+    normals: [Vec4; 2],
+    radius: [f32; 2],
+    kind: u32,
+    /// The shader walks the curve in `2^depth` straight segments.
+    depth: u32,
+    material: Material,
+}
+
+impl Curve {
+    fn new(kind: u32, points: [Vec3; 4], radius: [f32; 2], normals: [Vec3; 2], material: Material) -> Self {
+        let mut curve = Self {
+            points: points.map(|p| p.extend(0.0)),
+            normals: normals.map(|n| n.normalize_or_zero().extend(0.0)),
+            radius: radius.map(f32::abs),
+            kind,
+            depth: 0,
+            material,
+        };
+        curve.depth = curve.subdivision_depth();
+        curve
+    }
+    /// Round fibre through the Bézier control points `points`, `radius` at its start and end.
+    /// The curve faces every ray and is shaded as a cylinder, which is cheap enough for hair.
+    pub fn tube(points: [Vec3; 4], radius: [f32; 2], material: Material) -> Self {
+        Self::new(TUBE, points, radius, [Vec3::ZERO; 2], material)
+    }
+    /// Flat strip of half width `width` facing `normals[0]` at its start and turning to
+    /// `normals[1]` at its end, grass blades for example.
+    pub fn ribbon(points: [Vec3; 4], width: [f32; 2], normals: [Vec3; 2], material: Material) -> Self {
+        Self::new(RIBBON, points, width, normals, material)
+    }
+    /// Tubes along the Catmull-Rom spline through `vertices`, positions in xyz with the
+    /// radius at that vertex in w.
+    pub fn strand(vertices: &[Vec4], material: Material) -> Vec<Self> {
+        let last = vertices.len().saturating_sub(1);
+        (0..last)
+            .map(|i| {
+                let p = |j: usize| vertices[j.min(last)].truncate();
+                let (p0, p1, p2, p3) = (p(i.saturating_sub(1)), p(i), p(i + 1), p(i + 2));
+                let points = [p1, p1 + (p2 - p0) / 6.0, p2 - (p3 - p1) / 6.0, p2];
+                Self::tube(points, [vertices[i].w, vertices[i + 1].w], material)
+            })
+            .collect()
+    }
+    /// Reads hair from a strand file, one strand per line given as `x y z radius` for each
+    /// of its vertices, at least two. `#` starts a comment.
+    pub fn parse_strands(source: &str, material: Material) -> Result<Vec<Self>, ParseError> {
+        let mut curves = Vec::new();
+        for (i, line) in source.lines().enumerate() {
+            let error = |message: String| ParseError {
+                line: i + 1,
+                message,
+            };
+            let line = line.split('#').next().unwrap_or_default();
+            let numbers = line
+                .split_whitespace()
+                .map(|word| {
+                    word.parse::<f32>()
+                        .map_err(|_| error(format!("expected a number, found `{word}`")))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if numbers.is_empty() {
+                continue;
+            }
+            if numbers.len() % 4 != 0 || numbers.len() < 8 {
+                return Err(error(
+                    "expected at least two vertices of `x y z radius`".to_string(),
+                ));
+            }
+            let vertices: Vec<_> = numbers.chunks_exact(4).map(Vec4::from_slice).collect();
+            curves.extend(Self::strand(&vertices, material));
+        }
+        Ok(curves)
+    }
+    /// Axis aligned bounds of the control points grown by the radius, which hold the curve.
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        let r = self.radius[0].max(self.radius[1]);
+        let points = self.points.map(Vec4::truncate);
+        let min = points.iter().fold(Vec3::MAX, |a, &p| a.min(p));
+        let max = points.iter().fold(Vec3::MIN, |a, &p| a.max(p));
+        (min - r, max + r)
+    }
+    /// Segments needed for the straight pieces to stay within a tenth of the radius of the
+    /// curve, as in pbrt.
+    fn subdivision_depth(&self) -> u32 {
+        let p = self.points.map(Vec4::truncate);
+        let l0 = (0..2)
+            .map(|i| (p[i] - 2.0 * p[i + 1] + p[i + 2]).abs().max_element())
+            .fold(0.0, f32::max);
+        let epsilon = self.radius[0].max(self.radius[1]) * 0.1;
+        let depth = (SQRT_2 * 6.0 * l0 / (8.0 * epsilon)).log2() * 0.5;
+        // a straight curve gives -inf, which saturates to 0
+        (depth.round() as u32).min(MAX_DEPTH)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        // must match `struct Curve` in the sphere shader
+        assert_eq!(std::mem::size_of::<Curve>(), 224);
+    }
+
+    #[test]
+    fn strands() {
+        let material = Material::new_hair(1.0, 0.0, 0.3, 0.3, 2.0);
+        let curves = Curve::parse_strands(
+            "# a strand\n0 0 0 0.01  0 1 0 0.01  1 2 0 0.005\n\n0 0 0 0.1  0 0 1 0.1\n",
+            material,
+        )
+        .unwrap();
+        assert_eq!(curves.len(), 3);
+        // the spline passes through the vertices
+        assert_eq!(curves[0].points[0], Vec4::ZERO);
+        assert_eq!(curves[0].points[3], Vec4::new(0.0, 1.0, 0.0, 0.0));
+        assert_eq!(curves[1].points[0], curves[0].points[3]);
+        assert_eq!(curves[1].radius, [0.01, 0.005]);
+        assert_eq!(
+            curves[2].bounds(),
+            (Vec3::splat(-0.1), Vec3::new(0.1, 0.1, 1.1))
+        );
+        // thin bent pieces are split finer
+        assert!(curves[0].depth > curves[2].depth);
+        assert!(curves[0].depth <= MAX_DEPTH);
+        let error = Curve::parse_strands("0 0 0 1\n0 0 0 1 0 0 x 1", material).err().unwrap();
+        assert_eq!(error.to_string(), "line 1: expected at least two vertices of `x y z radius`");
+        let error = Curve::parse_strands("0 0 0 1 0 0 x 1", material).err().unwrap();
+        assert_eq!(error.message, "expected a number, found `x`");
+    }
+}
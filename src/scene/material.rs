@@ -1,9 +1,16 @@
 use bytemuck::{Pod, Zeroable};
-use glam::{Vec3, Vec4};
+use glam::{DMat3, DVec3, UVec4, Vec3, Vec4};
 
 pub const LAMBERTIAN: u32 = 1;
 pub const METAL: u32 = 2;
 pub const DIELECTRIC: u32 = 3;
+pub const HAIR: u32 = 4;
+
+pub const NORMAL_MAP: u32 = 1;
+pub const BUMP_MAP: u32 = 2;
+
+/// Bits of `Material::flags`.
+pub const VERTEX_COLORS: u32 = 1;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Pod, Zeroable, Default)]
@@ -11,6 +18,18 @@ pub struct Material {
     albedo: Vec4,
     params: Vec3,
     kind: u32,
+    coating: Vec4,
+    emission: Vec4,
+    textures: UVec4,
+    bump: Vec4,
+    /// Alpha below which rays pass through, zero for opaque materials.
+    cutout: f32,
+    /// Per-material switches, `VERTEX_COLORS` so far.
+    flags: u32,
+    /// One past the index in `Tree::motions` of how the mesh of this material moves, zero
+    /// while it stands still. Set by the tree, see `Tree::set_motion`.
+    motion: u32,
+    _padding: u32,
 }
 
 impl Material {
@@ -19,6 +38,14 @@ impl Material {
             kind: LAMBERTIAN,
             albedo: albedo.extend(1.0),
             params: Vec3::ZERO,
+            coating: Vec4::ZERO,
+            emission: Vec4::ZERO,
+            textures: UVec4::ZERO,
+            bump: Vec4::ZERO,
+            cutout: 0.0,
+            flags: 0,
+            motion: 0,
+            _padding: 0,
         }
     }
     pub fn new_metal(albedo: Vec3, fuzzy: f32) -> Self {
@@ -26,13 +53,337 @@ impl Material {
             kind: METAL,
             albedo: albedo.extend(1.0),
             params: Vec3::splat(fuzzy),
+            coating: Vec4::ZERO,
+            emission: Vec4::ZERO,
+            textures: UVec4::ZERO,
+            bump: Vec4::ZERO,
+            cutout: 0.0,
+            flags: 0,
+            motion: 0,
+            _padding: 0,
         }
     }
     pub fn new_dielectric(ir: f32) -> Self {
+        Self::new_dispersive(ir, 0.0, 0.0)
+    }
+    /// Dielectric whose IOR follows Cauchy's equation `n = a + b/λ² + c/λ⁴`, λ in micrometres.
+    /// Dispersion only shows up in spectral mode, RGB mode uses the IOR at 550nm.
+    pub fn new_dispersive(a: f32, b: f32, c: f32) -> Self {
         Self {
             kind: DIELECTRIC,
             albedo: Vec4::ONE,
-            params: Vec3::splat(ir),
+            params: Vec3::new(a, b, c),
+            coating: Vec4::ZERO,
+            emission: Vec4::ZERO,
+            textures: UVec4::ZERO,
+            bump: Vec4::ZERO,
+            cutout: 0.0,
+            flags: 0,
+            motion: 0,
+            _padding: 0,
+        }
+    }
+    /// Dielectric from Sellmeier coefficients (λ in micrometres), least-squares fitted to
+    /// Cauchy's equation over the visible range so the shader only evaluates one model.
+    pub fn new_sellmeier(b: Vec3, c: Vec3) -> Self {
+        let b = b.as_dvec3();
+        let c = c.as_dvec3();
+        let mut ata = DMat3::ZERO;
+        let mut aty = DVec3::ZERO;
+        for nm in (380..=780).step_by(5) {
+            let l2 = (nm as f64 * 1e-3).powi(2);
+            let terms = b * l2 / (DVec3::splat(l2) - c);
+            let n2 = 1.0 + terms.x + terms.y + terms.z;
+            let row = DVec3::new(1.0, 1.0 / l2, 1.0 / (l2 * l2));
+            ata += DMat3::from_cols(row * row.x, row * row.y, row * row.z);
+            aty += row * n2.sqrt();
+        }
+        let [a, b, c] = (ata.inverse() * aty).as_vec3().to_array();
+        Self::new_dispersive(a, b, c)
+    }
+    /// Hair fibre scattering after Chiang et al. 2016, with R, TT and TRT lobes. The
+    /// pigment is given as eumelanin (brown to black) and pheomelanin (red) concentrations,
+    /// `beta_m` and `beta_n` are the longitudinal and azimuthal roughness in 0..1 and
+    /// `alpha` tilts the cuticle scales by that many degrees. Only shades curves.
+    pub fn new_hair(eumelanin: f32, pheomelanin: f32, beta_m: f32, beta_n: f32, alpha: f32) -> Self {
+        // absorption coefficients of the two pigments, from d'Eon et al. 2011
+        let sigma_a = eumelanin * Vec3::new(0.419, 0.697, 1.37)
+            + pheomelanin * Vec3::new(0.187, 0.4, 1.05);
+        Self {
+            kind: HAIR,
+            albedo: sigma_a.extend(1.0),
+            params: Vec3::new(
+                beta_m.clamp(0.01, 1.0),
+                beta_n.clamp(0.01, 1.0),
+                alpha.to_radians(),
+            ),
+            coating: Vec4::ZERO,
+            emission: Vec4::ZERO,
+            textures: UVec4::ZERO,
+            bump: Vec4::ZERO,
+            cutout: 0.0,
+            flags: 0,
+            motion: 0,
+            _padding: 0,
+        }
+    }
+    /// Coats a metal or dielectric with a thin film of `thickness` nanometres and IOR `ior`,
+    /// giving soap bubble and oil slick interference colours.
+    pub fn with_thin_film(mut self, thickness: f32, ior: f32) -> Self {
+        self.coating = Vec4::new(thickness, ior, 0.0, 0.0);
+        self
+    }
+    /// Replaces the albedo, keeping the alpha in `albedo.w`.
+    pub fn with_albedo(mut self, albedo: Vec3) -> Self {
+        self.albedo = albedo.extend(self.albedo.w);
+        self
+    }
+    /// Light emitted on top of whatever the surface scatters.
+    pub fn with_emission(mut self, emission: Vec3) -> Self {
+        self.emission = emission.extend(0.0);
+        self
+    }
+    /// Multiplies the albedo by the texture at `texture`, an index returned by `add_texture`.
+    pub fn with_albedo_texture(mut self, texture: u32) -> Self {
+        self.textures.x = texture + 1;
+        self
+    }
+    /// Multiplies the metal fuzziness by the red channel of the texture at `texture`.
+    pub fn with_roughness_texture(mut self, texture: u32) -> Self {
+        self.textures.y = texture + 1;
+        self
+    }
+    /// Multiplies the emission by the texture at `texture`.
+    pub fn with_emission_texture(mut self, texture: u32) -> Self {
+        self.textures.z = texture + 1;
+        self
+    }
+    /// Perturbs triangle shading normals with a tangent space normal map, `strength` scales
+    /// its slopes.
+    pub fn with_normal_map(mut self, texture: u32, strength: f32) -> Self {
+        self.textures.w = texture + 1;
+        self.bump = Vec4::new(strength, NORMAL_MAP as f32, 0.0, 0.0);
+        self
+    }
+    /// Perturbs triangle shading normals by the slope of the red channel of `texture`,
+    /// taken along the tangent frame for UV mapped textures and in world space otherwise.
+    pub fn with_bump_map(mut self, texture: u32, strength: f32) -> Self {
+        self.textures.w = texture + 1;
+        self.bump = Vec4::new(strength, BUMP_MAP as f32, 0.0, 0.0);
+        self
+    }
+    /// Sets the alpha kept in `albedo.w`, only used by `with_cutout`.
+    pub fn with_alpha(mut self, alpha: f32) -> Self {
+        self.albedo.w = alpha;
+        self
+    }
+    /// Lets rays pass through triangles wherever alpha is below `threshold`. Alpha is
+    /// `albedo.w` times the alpha of the albedo texture, if any.
+    pub fn with_cutout(mut self, threshold: f32) -> Self {
+        self.cutout = threshold;
+        self
+    }
+    /// Multiplies the albedo, and the alpha seen by `with_cutout`, by the vertex colour
+    /// interpolated at the hit, as scans and photogrammetry meshes carry their colour.
+    pub fn with_vertex_colors(mut self) -> Self {
+        self.flags |= VERTEX_COLORS;
+        self
+    }
+    /// Index in `Tree::motions` of how the mesh of this material moves.
+    pub(crate) fn motion(&self) -> Option<u32> {
+        self.motion.checked_sub(1)
+    }
+    pub(crate) fn set_motion(&mut self, index: Option<u32>) {
+        self.motion = index.map_or(0, |i| i + 1);
+    }
+    /// The textures this material reads, as indices returned by `add_texture`.
+    pub(crate) fn texture_indices(&self) -> impl Iterator<Item = u32> {
+        self.textures.to_array().into_iter().filter(|&t| t != 0).map(|t| t - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluates the triangle shader's `dielectric_ior` and `dielectric_reflectance` for
+    /// each material, cosine and wavelengths, lit from air as in `scatter`. Returns the
+    /// reflectance at each wavelength and the IOR at the first in `w`.
+    fn shade(probes: &[(Material, f32, Vec3)]) -> Vec<Vec4> {
+        use wgpu::util::DeviceExt;
+        use wgpu::BufferUsages;
+
+        const PROBE: &str = "
+@group(0) @binding(100) var<storage, read> probe_materials: array<Material>;
+@group(0) @binding(101) var<storage, read> probe_inputs: array<vec4f>;
+@group(0) @binding(102) var<storage, read_write> probe_outputs: array<vec4f>;
+@compute @workgroup_size(1)
+fn probe(@builtin(global_invocation_id) id: vec3u) {
+  let material = probe_materials[id.x];
+  let lambdas = probe_inputs[id.x].yzw;
+  let n = dielectric_ior(material, lambdas.x);
+  let r = dielectric_reflectance(material, probe_inputs[id.x].x, 1.0/n, true, lambdas.x, lambdas);
+  probe_outputs[id.x] = vec4f(r, n);
+}
+";
+        let instance = wgpu::Instance::default();
+        let adapter = pollster::block_on(instance.request_adapter(&Default::default())).unwrap();
+        let (device, queue) = pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor {
+            required_limits: adapter.limits(),
+            ..Default::default()
+        }))
+        .unwrap();
+        let source = format!("{}{PROBE}", include_str!("../shaders/shader_tris.wgsl"));
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: None,
+            module: &module,
+            entry_point: Some("probe"),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+        let materials: Vec<Material> = probes.iter().map(|&(material, ..)| material).collect();
+        let inputs: Vec<Vec4> = probes
+            .iter()
+            .map(|&(_, cos_theta, lambdas)| Vec4::new(cos_theta, lambdas.x, lambdas.y, lambdas.z))
+            .collect();
+        let init = |contents: &[u8]| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents,
+                usage: BufferUsages::STORAGE,
+            })
+        };
+        let materials = init(bytemuck::cast_slice(&materials));
+        let inputs = init(bytemuck::cast_slice(&inputs));
+        let size = (probes.len() * size_of::<Vec4>()) as u64;
+        let buffer = |usage| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                size,
+                usage,
+                mapped_at_creation: false,
+            })
+        };
+        let outputs = buffer(BufferUsages::STORAGE | BufferUsages::COPY_SRC);
+        let readback = buffer(BufferUsages::MAP_READ | BufferUsages::COPY_DST);
+        let group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 100,
+                    resource: materials.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 101,
+                    resource: inputs.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 102,
+                    resource: outputs.as_entire_binding(),
+                },
+            ],
+        });
+        let mut encoder = device.create_command_encoder(&Default::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&Default::default());
+            pass.set_pipeline(&pipeline);
+            pass.set_bind_group(0, &group, &[]);
+            pass.dispatch_workgroups(probes.len() as u32, 1, 1);
         }
+        encoder.copy_buffer_to_buffer(&outputs, 0, &readback, 0, size);
+        queue.submit(Some(encoder.finish()));
+        readback.slice(..).map_async(wgpu::MapMode::Read, |_| {});
+        let _ = device.poll(wgpu::MaintainBase::Wait);
+        let data = bytemuck::cast_slice(&readback.slice(..).get_mapped_range()).to_vec();
+        data
+    }
+
+    #[test]
+    fn cauchy() {
+        let glass = Material::new_dielectric(1.5);
+        let flint = Material::new_dispersive(1.5046, 0.0042, 0.0);
+        let [blue, red] = [400.0, 700.0].map(Vec3::splat);
+        let probes = [(glass, blue), (glass, red), (flint, blue), (flint, red)];
+        let outputs = shade(&probes.map(|(material, lambdas)| (material, 1.0, lambdas)));
+        let ior: Vec<f32> = outputs.iter().map(|output| output.w).collect();
+        assert_eq!(ior[0], 1.5);
+        assert_eq!(ior[1], 1.5);
+        assert!(ior[2] > ior[3]);
+    }
+
+    #[test]
+    fn sellmeier_bk7() {
+        let material = Material::new_sellmeier(
+            Vec3::new(1.039_612, 0.231_792_3, 1.010_469_5),
+            Vec3::new(0.006_000_699, 0.020_017_914, 103.560_65),
+        );
+        // catalogue values for the Fraunhofer F, d and C lines
+        let lines = [(486.1, 1.5224), (587.6, 1.5168), (656.3, 1.5143)];
+        let probes = lines.map(|(wavelength, _)| (material, 1.0, Vec3::splat(wavelength)));
+        for (output, (_, ior)) in shade(&probes).iter().zip(lines) {
+            assert!((output.w - ior).abs() < 1e-3, "{}", output.w);
+        }
+    }
+
+    #[test]
+    fn thin_film() {
+        let green = Vec3::splat(550.0);
+        let glass = Material::new_dielectric(1.5);
+        // a quarter wave of MgF2 is the classic anti-reflection coating, at half a wave the
+        // film drops out
+        let quarter = 550.0 / (4.0 * 1.38);
+        let colours = Vec3::new(630.0, 532.0, 465.0);
+        let film = |thickness: f32, ior: f32| glass.with_thin_film(thickness, ior);
+        let outputs = shade(&[
+            (glass, 1.0, green),
+            (film(quarter, 1.38), 1.0, green),
+            (film(2.0 * quarter, 1.38), 1.0, green),
+            (film(250.0, 1.33), 0.8, colours),
+            (film(400.0, 1.33), 0.8, colours),
+        ]);
+        assert!((outputs[0].x - 0.04).abs() < 1e-6);
+        let expected = ((1.5 - 1.38 * 1.38) / (1.5 + 1.38 * 1.38) as f32).powi(2);
+        assert!((outputs[1].x - expected).abs() < 1e-5);
+        assert!((outputs[2].x - 0.04).abs() < 1e-5);
+        // the reflection colour moves with the thickness
+        let (thin, thick) = (outputs[3].truncate(), outputs[4].truncate());
+        assert!((thin - thick).abs().max_element() > 0.01);
+    }
+
+    #[test]
+    fn layout() {
+        // must match `struct Material` in the shaders
+        assert_eq!(std::mem::size_of::<Material>(), 112);
+        let material = Material::new_lambertian(Vec3::ONE);
+        assert_eq!(material.albedo.w, 1.0);
+        let material = material.with_alpha(0.25).with_cutout(0.5);
+        assert_eq!(material.albedo.w, 0.25);
+        assert_eq!(material.cutout, 0.5);
+    }
+
+    #[test]
+    fn vertex_colors_and_cutout() {
+        let material = Material::new_lambertian(Vec3::ONE);
+        let a = material.with_vertex_colors().with_cutout(0.5);
+        let b = material.with_cutout(0.5).with_vertex_colors();
+        assert_eq!(bytemuck::bytes_of(&a), bytemuck::bytes_of(&b));
+        assert_eq!(a.flags, VERTEX_COLORS);
+        assert_eq!(a.cutout, 0.5);
+    }
+
+    #[test]
+    fn hair() {
+        let material = Material::new_hair(1.0, 0.5, 0.3, 0.0, 2.0);
+        assert_eq!(material.kind, HAIR);
+        let sigma_a = material.albedo.truncate();
+        assert!(sigma_a.abs_diff_eq(Vec3::new(0.5125, 0.897, 1.895), 1e-5));
+        // zero roughness would make the lobes singular
+        assert_eq!(material.params.y, 0.01);
     }
 }
@@ -1,33 +1,57 @@
 pub mod bvh;
 mod camera;
+mod csg;
+mod curve;
 mod material;
+mod motion;
+mod primitive;
+mod scene_file;
+mod sdf;
 mod scene_sphere;
 mod scene_tris;
 mod sphere;
+mod texture;
+mod timeline;
 pub use camera::Camera;
+pub use csg::{Csg, CsgNode};
+pub use curve::Curve;
 pub use material::Material;
+pub use motion::{Motion, TooManyMotions, MAX_MOTIONS};
+pub use primitive::Primitive;
+pub use scene_file::{ParseError, SceneFile};
+pub use sdf::Sdf;
 pub use scene_sphere::SceneSphere;
 pub use scene_tris::SceneTris;
 pub use sphere::Sphere;
+pub use texture::{Image, Texture};
+pub use texture::{MAPPING_UV, MAPPING_WORLD};
+pub use timeline::{Animation, CameraKeys, Interpolation, Key, MeshKeys, Timeline, Track};
 use crate::camera_controller::CameraUniform;
+use crate::renderer::BufferTooLarge;
 
 pub trait Scene {
-    fn init(&mut self);
+    /// Uploads the scene, failing when it does not fit the device.
+    fn init(&mut self) -> Result<(), BufferTooLarge>;
     fn draw(&mut self);
     fn draw_with_gui(&mut self, gui: &mut crate::gui::GuiState, window: &winit::window::Window);
     fn set_time(&mut self, time: u32);
     fn resize(&mut self, width: u32, height: u32);
     fn update_camera(&mut self, camera: CameraUniform);
     fn reset_frame_count(&mut self);
+    fn set_flags(&mut self, flags: u32);
     fn get_device(&self) -> &wgpu::Device;
     fn get_queue(&self) -> &wgpu::Queue;
     fn get_format(&self) -> wgpu::TextureFormat;
 }
 
 impl Scene for SceneTris {
-    fn init(&mut self) {
+    fn init(&mut self) -> Result<(), BufferTooLarge> {
         self.renderer.set_camera(&self.camera);
-        self.write_tree_data();
+        if self.tris_bvh.is_built() {
+            self.write_tree_data()
+        } else {
+            self.build_tree_on_gpu()
+        }
     }
     fn draw(&mut self) {
         self.renderer.draw()
@@ -54,6 +78,9 @@ impl Scene for SceneTris {
     fn reset_frame_count(&mut self) {
         self.renderer.reset_frame_count()
     }
+    fn set_flags(&mut self, flags: u32) {
+        self.renderer.set_flags(flags)
+    }
     fn get_device(&self) -> &wgpu::Device {
         &self.renderer.device
     }
@@ -66,9 +93,9 @@ impl Scene for SceneTris {
 }
 
 impl Scene for SceneSphere {
-    fn init(&mut self) {
+    fn init(&mut self) -> Result<(), BufferTooLarge> {
         self.renderer.set_camera(&self.camera);
-        self.write_scene_data();
+        self.write_scene_data()
     }
     fn draw(&mut self) {
         self.renderer.draw()
@@ -95,6 +122,9 @@ impl Scene for SceneSphere {
     fn reset_frame_count(&mut self) {
         self.renderer.reset_frame_count()
     }
+    fn set_flags(&mut self, flags: u32) {
+        self.renderer.set_flags(flags)
+    }
     fn get_device(&self) -> &wgpu::Device {
         &self.renderer.device
     }
@@ -107,4 +137,4 @@ impl Scene for SceneSphere {
 }
 
 mod render_ppm;
-pub use render_ppm::render_ppm;
+pub use render_ppm::{apng_writer, render_png, render_ppm, render_rgb};
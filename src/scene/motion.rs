@@ -0,0 +1,115 @@
+use bytemuck::{Pod, Zeroable};
+use glam::{BVec3, Quat, Vec3, Vec4};
+
+/// Most meshes of a triangle scene that can move, the shader reads their motions from a
+/// uniform array of this size. Any number of meshes may stand still.
+pub const MAX_MOTIONS: usize = 256;
+/// Times a moving box is sampled at by `Motion::sweep`.
+const SWEEP_STEPS: usize = 8;
+
+/// How an object moves over a frame, from where it is given at time 0 to time 1: turned
+/// by an angle about an axis through a pivot, then moved along a straight line, both at
+/// a constant rate. The camera shutter picks the part of the frame it sees, see
+/// `Camera::with_shutter`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Pod, Zeroable)]
+pub struct Motion {
+    translation: Vec4,
+    /// The axis in xyz and the angle in w.
+    rotation: Vec4,
+    pivot: Vec4,
+}
+
+impl Motion {
+    /// Moves by `translation` while turning by `angle` radians about `axis` through
+    /// `pivot`. The angle may be more than a full turn.
+    pub fn new(translation: Vec3, axis: Vec3, angle: f32, pivot: Vec3) -> Self {
+        let axis = axis.try_normalize().unwrap_or(Vec3::Y);
+        Self {
+            translation: translation.extend(0.0),
+            rotation: axis.extend(angle),
+            pivot: pivot.extend(0.0),
+        }
+    }
+    /// Moves by `translation` without turning.
+    pub fn linear(translation: Vec3) -> Self {
+        Self::new(translation, Vec3::Y, 0.0, Vec3::ZERO)
+    }
+    /// Whether the object stays where it is, which the shaders skip.
+    pub fn is_static(&self) -> bool {
+        self.translation == Vec4::ZERO && self.rotation.w == 0.0
+    }
+    /// Where `point` is at `time`.
+    pub fn apply(&self, point: Vec3, time: f32) -> Vec3 {
+        let rotation = Quat::from_axis_angle(self.rotation.truncate(), self.rotation.w * time);
+        let pivot = self.pivot.truncate();
+        pivot + rotation * (point - pivot) + self.translation.truncate() * time
+    }
+    /// A box holding the box from `min` to `max` over the whole frame.
+    pub fn sweep(&self, min: Vec3, max: Vec3) -> (Vec3, Vec3) {
+        if self.is_static() {
+            return (min, max);
+        }
+        let corners: [Vec3; 8] = std::array::from_fn(|i| {
+            Vec3::select(BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0), max, min)
+        });
+        let (mut lo, mut hi) = (Vec3::MAX, Vec3::MIN);
+        for step in 0..=SWEEP_STEPS {
+            let time = step as f32 / SWEEP_STEPS as f32;
+            for corner in corners {
+                let p = self.apply(corner, time);
+                lo = lo.min(p);
+                hi = hi.max(p);
+            }
+        }
+        // between samples a point strays from the chord by at most the sagitta of its arc
+        let pivot = self.pivot.truncate();
+        let radius = corners.iter().map(|c| c.distance(pivot)).fold(0.0, f32::max);
+        let step = self.rotation.w.abs() / SWEEP_STEPS as f32;
+        let sagitta = if step < std::f32::consts::PI {
+            radius * (1.0 - (0.5 * step).cos())
+        } else {
+            2.0 * radius
+        };
+        (lo - sagitta, hi + sagitta)
+    }
+}
+
+/// More meshes of a triangle scene were set moving than the shader has room for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TooManyMotions;
+
+impl std::fmt::Display for TooManyMotions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "at most {MAX_MOTIONS} meshes can move")
+    }
+}
+
+impl std::error::Error for TooManyMotions {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn sweep() {
+        assert!(Motion::default().is_static());
+        let (min, max) = (Vec3::splat(-0.5), Vec3::splat(0.5));
+        assert_eq!(Motion::default().sweep(min, max), (min, max));
+        let linear = Motion::linear(Vec3::new(2.0, 0.0, 0.0));
+        assert_eq!(linear.apply(Vec3::ZERO, 0.5), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(linear.sweep(min, max), (min, Vec3::new(2.5, 0.5, 0.5)));
+        // half a turn about a pivot off to the side, every point along the way is inside
+        let turn = Motion::new(Vec3::Z, Vec3::Y, PI, Vec3::new(2.0, 0.0, 0.0));
+        assert!(turn.apply(Vec3::ZERO, 1.0).abs_diff_eq(Vec3::new(4.0, 0.0, 1.0), 1e-5));
+        let (lo, hi) = turn.sweep(min, max);
+        for i in 0..=100 {
+            let time = i as f32 / 100.0;
+            for corner in [min, max, Vec3::new(-0.5, 0.5, 0.5), Vec3::new(0.5, -0.5, -0.5)] {
+                let p = turn.apply(corner, time);
+                assert!(p.cmpge(lo).all() && p.cmple(hi).all(), "{p} at {time}");
+            }
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::scene::csg::MAX_CSG_DEPTH;
+use crate::scene::{Csg, CsgNode, Material};
+use bytemuck::{Pod, Zeroable};
+use glam::{Quat, Vec3, Vec4};
+
+pub const PLANE: u32 = 1;
+pub const BOX: u32 = 2;
+pub const DISK: u32 = 3;
+pub const CYLINDER: u32 = 4;
+pub const QUAD: u32 = 5;
+pub const SDF: u32 = 6;
+pub const CSG: u32 = 7;
+
+/// An analytic shape intersected exactly in the sphere shader. The meaning of `a`, `b`
+/// and `c` depends on `kind`, see the constructors.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct Primitive {
+    a: Vec4,
+    b: Vec4,
+    c: Vec4,
+    kind: u32,
+    _padding: [u32; 3],
+    material: Material,
+}
+
+impl Primitive {
+    fn new(kind: u32, a: Vec4, b: Vec4, c: Vec4, material: Material) -> Self {
+        Self {
+            a,
+            b,
+            c,
+            kind,
+            _padding: [0; 3],
+            material,
+        }
+    }
+    /// Infinite plane through `point`.
+    pub fn plane(point: Vec3, normal: Vec3, material: Material) -> Self {
+        Self::new(
+            PLANE,
+            point.extend(0.0),
+            normal.normalize().extend(0.0),
+            Vec4::ZERO,
+            material,
+        )
+    }
+    /// Axis aligned box spanning `min` to `max`.
+    pub fn aabb(min: Vec3, max: Vec3, material: Material) -> Self {
+        Self::oriented_box((min + max) * 0.5, (max - min) * 0.5, Quat::IDENTITY, material)
+    }
+    /// Box of size `2 * half_extents` centred on `center`, rotated by `rotation`.
+    pub fn oriented_box(center: Vec3, half_extents: Vec3, rotation: Quat, material: Material) -> Self {
+        Self::new(
+            BOX,
+            center.extend(0.0),
+            half_extents.abs().extend(0.0),
+            Vec4::from(rotation.normalize()),
+            material,
+        )
+    }
+    /// Flat disk facing `normal`.
+    pub fn disk(center: Vec3, normal: Vec3, radius: f32, material: Material) -> Self {
+        Self::new(
+            DISK,
+            center.extend(radius),
+            normal.normalize().extend(0.0),
+            Vec4::ZERO,
+            material,
+        )
+    }
+    /// Capped cylinder from `base` to `top`.
+    pub fn cylinder(base: Vec3, top: Vec3, radius: f32, material: Material) -> Self {
+        Self::cone(base, top, radius, radius, material)
+    }
+    /// Capped cone from `base` to `top`, a radius of 0 gives a pointed tip.
+    pub fn cone(base: Vec3, top: Vec3, base_radius: f32, top_radius: f32, material: Material) -> Self {
+        Self::new(
+            CYLINDER,
+            base.extend(base_radius),
+            (top - base).extend(top_radius),
+            Vec4::ZERO,
+            material,
+        )
+    }
+    /// Parallelogram spanned by the edges `u` and `v` from `corner`, facing `u × v`. An
+    /// emissive material makes it a rectangular area light.
+    pub fn quad(corner: Vec3, u: Vec3, v: Vec3, material: Material) -> Self {
+        Self::new(QUAD, corner.extend(0.0), u.extend(0.0), v.extend(0.0), material)
+    }
+    /// Sphere traced signed distance field `index` of the shapes compiled into the shader,
+    /// marched inside `min` to `max`.
+    pub fn sdf(index: u32, min: Vec3, max: Vec3, material: Material) -> Self {
+        let c = Vec4::new(f32::from_bits(index), 0.0, 0.0, 0.0);
+        Self::new(SDF, min.extend(0.0), max.extend(0.0), c, material)
+    }
+    /// Flattens `csg` onto the end of `nodes` and returns the primitive evaluating it.
+    ///
+    /// Panics if the tree needs more than `MAX_CSG_DEPTH` operands at once, see `Csg::depth`.
+    pub fn csg(csg: &Csg, nodes: &mut Vec<CsgNode>, material: Material) -> Self {
+        assert!(csg.depth() <= MAX_CSG_DEPTH, "CSG tree is too deep");
+        let first = nodes.len() as u32;
+        csg.flatten(nodes);
+        let count = nodes.len() as u32 - first;
+        let a = Vec4::new(f32::from_bits(first), f32::from_bits(count), 0.0, 0.0);
+        let (min, max) = csg.bounds();
+        Self::new(CSG, a, min.extend(0.0), max.extend(0.0), material)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        // must match `struct Primitive` in the sphere shader
+        assert_eq!(std::mem::size_of::<Primitive>(), 176);
+    }
+
+    #[test]
+    fn aabb() {
+        let material = Material::new_lambertian(Vec3::ONE);
+        let p = Primitive::aabb(Vec3::new(-1.0, 0.0, 2.0), Vec3::new(1.0, 4.0, 3.0), material);
+        assert_eq!(p.kind, BOX);
+        assert_eq!(p.a, Vec4::new(0.0, 2.0, 2.5, 0.0));
+        assert_eq!(p.b, Vec4::new(1.0, 2.0, 0.5, 0.0));
+        assert_eq!(p.c, Vec4::new(0.0, 0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn cylinder() {
+        let material = Material::new_lambertian(Vec3::ONE);
+        let p = Primitive::cylinder(Vec3::ZERO, Vec3::Y * 2.0, 0.5, material);
+        assert_eq!(p.kind, CYLINDER);
+        assert_eq!(p.a.w, p.b.w);
+        assert_eq!(p.b.truncate(), Vec3::Y * 2.0);
+        let p = Primitive::plane(Vec3::ZERO, Vec3::Y * 3.0, material);
+        assert_eq!(p.b, Vec4::Y);
+    }
+
+    #[test]
+    fn csg() {
+        let material = Material::new_dielectric(1.5);
+        let mut nodes = vec![CsgNode::default()];
+        let lens = Csg::sphere(Vec3::Z, 1.0).intersection(Csg::sphere(-Vec3::Z, 1.0));
+        let p = Primitive::csg(&lens, &mut nodes, material);
+        assert_eq!(p.kind, CSG);
+        assert_eq!((p.a.x.to_bits(), p.a.y.to_bits()), (1, 3));
+        assert_eq!(nodes.len(), 4);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::renderer::Renderer;
-use std::fmt::Write;
+use std::fmt::Write as _;
+use std::io::Write;
 use std::mem::size_of;
 use std::sync::mpsc::channel;
 use wgpu::{BufferDescriptor, BufferUsages};
@@ -26,7 +27,7 @@ fn copy_image_buffer(renderer: &mut Renderer) -> Vec<u8> {
     buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
         tx.send(result.is_ok()).unwrap()
     });
-    device.poll(wgpu::MaintainBase::Wait);
+    let _ = device.poll(wgpu::MaintainBase::Wait);
     let ret = if rx.recv().unwrap_or(false) {
         buffer_slice.get_mapped_range().to_vec()
     } else {
@@ -35,23 +36,52 @@ fn copy_image_buffer(renderer: &mut Renderer) -> Vec<u8> {
     output_buffer.unmap();
     ret
 }
+/// The image accumulated so far as rows of 8-bit RGB, without drawing another frame.
+pub fn render_rgb(renderer: &mut Renderer) -> Vec<u8> {
+    let data = copy_image_buffer(renderer);
+    let data = bytemuck::cast_slice::<u8, f32>(data.as_ref());
+    data.iter().map(|c| (c * 255.0) as u8).collect()
+}
 pub fn render_ppm(renderer: &mut Renderer) -> String {
     let width = renderer.config.width;
     let height = renderer.config.height;
     // Don't draw an extra frame here - assume frames have already been rendered
-    let data = copy_image_buffer(renderer);
+    let data = render_rgb(renderer);
     let mut ret = String::new();
-    let data = bytemuck::cast_slice::<u8, f32>(data.as_ref());
-    let data = data
-        .chunks_exact(3)
-        .map(|a| [a[0], a[1], a[2]])
-        .map(|[r, g, b]| [r * 255.0, g * 255.0, b * 255.0])
-        .map(|[r, g, b]| [r as u8, g as u8, b as u8])
-        .collect::<Vec<[u8; 3]>>();
     writeln!(ret, "P3").unwrap();
     writeln!(ret, "{width} {height} 255").unwrap();
-    for [r, g, b] in data.iter() {
-        write!(ret, "{r} {g} {b} ").unwrap();
+    for rgb in data.chunks_exact(3) {
+        write!(ret, "{} {} {} ", rgb[0], rgb[1], rgb[2]).unwrap();
     }
     ret
 }
+/// The image `render_ppm` reads, as a PNG file.
+pub fn render_png(renderer: &mut Renderer) -> Result<Vec<u8>, png::EncodingError> {
+    let width = renderer.config.width;
+    let height = renderer.config.height;
+    let data = render_rgb(renderer);
+    let mut file = Vec::new();
+    let mut encoder = png::Encoder::new(&mut file, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+    Ok(file)
+}
+/// Starts an animated PNG of `frames` images the size of those of `renderer`, shown at
+/// `fps` frames per second and looping forever. Each image goes in as `render_rgb` reads
+/// it, through `write_image_data`.
+pub fn apng_writer<W: Write>(
+    file: W,
+    renderer: &Renderer,
+    frames: u32,
+    fps: f32,
+) -> Result<png::Writer<W>, png::EncodingError> {
+    let mut encoder = png::Encoder::new(file, renderer.config.width, renderer.config.height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(frames, 0)?;
+    encoder.set_frame_delay((1000.0 / fps).round() as u16, 1000)?;
+    encoder.write_header()
+}
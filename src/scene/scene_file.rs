@@ -0,0 +1,498 @@
+//! Plain text scene description loaded into a `SceneSphere`. One statement per line,
+//! `#` starts a comment, angles are in degrees:
+//!
+//! ```text
+//! camera   FROM(x y z) TO(x y z) FOCAL_LENGTH BLUR FOV
+//! material NAME lambertian R G B
+//! material NAME metal R G B FUZZ
+//! material NAME dielectric IOR
+//! material NAME dielectric cauchy A B C
+//! material NAME dielectric sellmeier B(x y z) C(x y z)
+//! material NAME metal|dielectric ... film THICKNESS IOR
+//! material NAME ... albedo|roughness|emission TEXTURE
+//! texture  NAME checker A(r g b) B(r g b) SCALE
+//! texture  NAME noise A B SCALE OCTAVES
+//! texture  NAME marble A B SCALE TURBULENCE OCTAVES
+//! texture  NAME voronoi A B SCALE
+//! texture  NAME gradient A B DIRECTION OFFSET
+//! material NAME light R G B
+//! material NAME hair EUMELANIN PHEOMELANIN BETA_M BETA_N ALPHA
+//! sphere   CENTER RADIUS MATERIAL
+//! plane    POINT NORMAL MATERIAL
+//! box      MIN MAX MATERIAL
+//! obox     CENTER HALF_EXTENTS ROTATION(x y z) MATERIAL
+//! disk     CENTER NORMAL RADIUS MATERIAL
+//! cylinder BASE TOP RADIUS MATERIAL
+//! cone     BASE TOP BASE_RADIUS TOP_RADIUS MATERIAL
+//! quad     CORNER U V MATERIAL
+//! sdf      MATERIAL EXPRESSION
+//! csg      MATERIAL EXPRESSION
+//! curve    P0 P1 P2 P3 RADIUS0 RADIUS1 MATERIAL
+//! ribbon   P0 P1 P2 P3 WIDTH0 WIDTH1 NORMAL0 NORMAL1 MATERIAL
+//! ```
+//!
+//! Materials must be declared before the shapes using them. Dispersive dielectrics take
+//! Cauchy or Sellmeier coefficients with λ in micrometres, see `Material::new_dispersive`
+//! and `Material::new_sellmeier`. Metals and dielectrics can end with a thin film coating,
+//! its thickness in nanometres, see `Material::with_thin_film`. Any material can then
+//! multiply its albedo, metal fuzz or emission by textures declared before it, see `Texture`.
+//! Curves are cubic Béziers
+//! through four control points, see `Curve`. SDF expressions are nested
+//! prefix forms compiled into the shader, see `Sdf::parse`:
+//!
+//! ```text
+//! sdf gold (smooth_union 0.3 (sphere 0.5) (translate 0 0.6 0 (torus 0.5 0.1)))
+//! ```
+//!
+//! CSG expressions combine exact spheres and boxes, see `Csg::parse`:
+//!
+//! ```text
+//! csg glass (intersection (sphere 0 0 -0.8 1) (sphere 0 0 0.8 1))
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::str::SplitWhitespace;
+
+use glam::{EulerRot, Quat, Vec3};
+
+use crate::scene::csg::MAX_CSG_DEPTH;
+use crate::scene::{Camera, Csg, CsgNode, Curve, Material, Primitive, Sdf, Sphere, Texture};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// 1-based line number.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Default)]
+pub struct SceneFile {
+    pub camera: Option<Camera>,
+    pub spheres: Vec<Sphere>,
+    pub primitives: Vec<Primitive>,
+    /// Distance functions referenced by index from `Primitive::sdf`.
+    pub sdfs: Vec<Sdf>,
+    pub csg_nodes: Vec<CsgNode>,
+    pub curves: Vec<Curve>,
+    /// Textures referenced by index from materials, in the order `add_texture` gives.
+    pub textures: Vec<Texture>,
+}
+
+/// The words of a statement, read by what they stand for so errors can name it. Also
+/// reads the lines of a `Timeline`.
+pub(crate) struct Args<'a> {
+    tokens: SplitWhitespace<'a>,
+    line: usize,
+}
+
+impl<'a> Args<'a> {
+    /// The words of `line`, line number `number`, after any comment.
+    pub(crate) fn new(line: &'a str, number: usize) -> Self {
+        Self {
+            tokens: line.split('#').next().unwrap_or_default().split_whitespace(),
+            line: number,
+        }
+    }
+    pub(crate) fn error(&self, message: String) -> ParseError {
+        ParseError {
+            line: self.line,
+            message,
+        }
+    }
+    pub(crate) fn word(&mut self, what: &str) -> Result<&'a str, ParseError> {
+        self.tokens
+            .next()
+            .ok_or_else(|| self.error(format!("missing {what}")))
+    }
+    /// The next word, if there is one left.
+    pub(crate) fn optional_word(&mut self) -> Option<&'a str> {
+        self.tokens.next()
+    }
+    pub(crate) fn f32(&mut self, what: &str) -> Result<f32, ParseError> {
+        let word = self.word(what)?;
+        self.number(word, what)
+    }
+    /// `word`, already read, as the number `what`.
+    fn number(&self, word: &str, what: &str) -> Result<f32, ParseError> {
+        word.parse()
+            .map_err(|_| self.error(format!("expected a number for {what}, found `{word}`")))
+    }
+    fn u32(&mut self, what: &str) -> Result<u32, ParseError> {
+        let word = self.word(what)?;
+        word.parse()
+            .map_err(|_| self.error(format!("expected a whole number for {what}, found `{word}`")))
+    }
+    pub(crate) fn vec3(&mut self, what: &str) -> Result<Vec3, ParseError> {
+        Ok(Vec3::new(self.f32(what)?, self.f32(what)?, self.f32(what)?))
+    }
+    fn control_points(&mut self) -> Result<[Vec3; 4], ParseError> {
+        Ok([
+            self.vec3("control point")?,
+            self.vec3("control point")?,
+            self.vec3("control point")?,
+            self.vec3("control point")?,
+        ])
+    }
+    fn material(&mut self, materials: &HashMap<&str, Material>) -> Result<Material, ParseError> {
+        let name = self.word("material")?;
+        materials
+            .get(name)
+            .copied()
+            .ok_or_else(|| self.error(format!("unknown material `{name}`")))
+    }
+    fn texture(&mut self, textures: &HashMap<&str, u32>) -> Result<u32, ParseError> {
+        let name = self.word("texture")?;
+        textures
+            .get(name)
+            .copied()
+            .ok_or_else(|| self.error(format!("unknown texture `{name}`")))
+    }
+    fn rest(&mut self) -> String {
+        self.tokens.by_ref().collect::<Vec<_>>().join(" ")
+    }
+    pub(crate) fn end(mut self) -> Result<(), ParseError> {
+        match self.tokens.next() {
+            Some(word) => Err(self.error(format!("unexpected `{word}`"))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl SceneFile {
+    pub fn parse(source: &str) -> Result<Self, ParseError> {
+        let mut scene = Self::default();
+        let mut materials = HashMap::new();
+        let mut textures = HashMap::new();
+        for (i, line) in source.lines().enumerate() {
+            let mut args = Args::new(line, i + 1);
+            let Some(keyword) = args.optional_word() else {
+                continue;
+            };
+            match keyword {
+                "camera" => {
+                    let from = args.vec3("camera position")?;
+                    let to = args.vec3("camera target")?;
+                    let focal_length = args.f32("focal length")?;
+                    let blur = args.f32("focal blur")?;
+                    let fov = args.f32("field of view")?;
+                    scene.camera =
+                        Some(Camera::new(from, to, focal_length, blur, fov.to_radians()));
+                }
+                "material" => {
+                    let name = args.word("material name")?;
+                    let kind = args.word("material kind")?;
+                    let material = match kind {
+                        "lambertian" => Material::new_lambertian(args.vec3("albedo")?),
+                        "metal" => Material::new_metal(args.vec3("albedo")?, args.f32("fuzz")?),
+                        "dielectric" => match args.word("ior")? {
+                            "cauchy" => Material::new_dispersive(
+                                args.f32("cauchy a")?,
+                                args.f32("cauchy b")?,
+                                args.f32("cauchy c")?,
+                            ),
+                            "sellmeier" => Material::new_sellmeier(
+                                args.vec3("sellmeier b")?,
+                                args.vec3("sellmeier c")?,
+                            ),
+                            ior => Material::new_dielectric(args.number(ior, "ior")?),
+                        },
+                        "light" => Material::new_lambertian(Vec3::ZERO)
+                            .with_emission(args.vec3("emission")?),
+                        "hair" => Material::new_hair(
+                            args.f32("eumelanin")?,
+                            args.f32("pheomelanin")?,
+                            args.f32("longitudinal roughness")?,
+                            args.f32("azimuthal roughness")?,
+                            args.f32("scale tilt")?,
+                        ),
+                        kind => return Err(args.error(format!("unknown material kind `{kind}`"))),
+                    };
+                    let mut material = material;
+                    while let Some(word) = args.optional_word() {
+                        material = match word {
+                            "film" if matches!(kind, "metal" | "dielectric") => material
+                                .with_thin_film(args.f32("film thickness")?, args.f32("film ior")?),
+                            "film" => {
+                                return Err(args.error(format!("a {kind} material has no film")))
+                            }
+                            "albedo" => material.with_albedo_texture(args.texture(&textures)?),
+                            "roughness" => {
+                                material.with_roughness_texture(args.texture(&textures)?)
+                            }
+                            "emission" => material.with_emission_texture(args.texture(&textures)?),
+                            word => return Err(args.error(format!("unexpected `{word}`"))),
+                        };
+                    }
+                    materials.insert(name, material);
+                }
+                "texture" => {
+                    let name = args.word("texture name")?;
+                    let kind = args.word("texture kind")?;
+                    let a = args.vec3("colour")?;
+                    let b = args.vec3("colour")?;
+                    let texture = match kind {
+                        "checker" => Texture::checker(a, b, args.f32("scale")?),
+                        "noise" => Texture::noise(a, b, args.f32("scale")?, args.u32("octaves")?),
+                        "marble" => Texture::marble(
+                            a,
+                            b,
+                            args.f32("scale")?,
+                            args.f32("turbulence")?,
+                            args.u32("octaves")?,
+                        ),
+                        "voronoi" => Texture::voronoi(a, b, args.f32("scale")?),
+                        "gradient" => {
+                            Texture::gradient(a, b, args.vec3("direction")?, args.f32("offset")?)
+                        }
+                        kind => return Err(args.error(format!("unknown texture kind `{kind}`"))),
+                    };
+                    textures.insert(name, scene.textures.len() as u32);
+                    scene.textures.push(texture);
+                }
+                "sphere" => {
+                    let center = args.vec3("center")?;
+                    let radius = args.f32("radius")?;
+                    let material = args.material(&materials)?;
+                    scene.spheres.push(Sphere::new(center, radius, material));
+                }
+                "plane" => {
+                    let point = args.vec3("point")?;
+                    let normal = args.vec3("normal")?;
+                    let material = args.material(&materials)?;
+                    scene
+                        .primitives
+                        .push(Primitive::plane(point, normal, material));
+                }
+                "box" => {
+                    let min = args.vec3("min")?;
+                    let max = args.vec3("max")?;
+                    let material = args.material(&materials)?;
+                    scene.primitives.push(Primitive::aabb(min, max, material));
+                }
+                "obox" => {
+                    let center = args.vec3("center")?;
+                    let half_extents = args.vec3("half extents")?;
+                    let [x, y, z] = args.vec3("rotation")?.to_array().map(f32::to_radians);
+                    let rotation = Quat::from_euler(EulerRot::XYZ, x, y, z);
+                    let material = args.material(&materials)?;
+                    scene.primitives.push(Primitive::oriented_box(
+                        center,
+                        half_extents,
+                        rotation,
+                        material,
+                    ));
+                }
+                "disk" => {
+                    let center = args.vec3("center")?;
+                    let normal = args.vec3("normal")?;
+                    let radius = args.f32("radius")?;
+                    let material = args.material(&materials)?;
+                    scene
+                        .primitives
+                        .push(Primitive::disk(center, normal, radius, material));
+                }
+                "cylinder" => {
+                    let base = args.vec3("base")?;
+                    let top = args.vec3("top")?;
+                    let radius = args.f32("radius")?;
+                    let material = args.material(&materials)?;
+                    scene
+                        .primitives
+                        .push(Primitive::cylinder(base, top, radius, material));
+                }
+                "cone" => {
+                    let base = args.vec3("base")?;
+                    let top = args.vec3("top")?;
+                    let base_radius = args.f32("base radius")?;
+                    let top_radius = args.f32("top radius")?;
+                    let material = args.material(&materials)?;
+                    scene.primitives.push(Primitive::cone(
+                        base,
+                        top,
+                        base_radius,
+                        top_radius,
+                        material,
+                    ));
+                }
+                "quad" => {
+                    let corner = args.vec3("corner")?;
+                    let u = args.vec3("edge u")?;
+                    let v = args.vec3("edge v")?;
+                    let material = args.material(&materials)?;
+                    scene.primitives.push(Primitive::quad(corner, u, v, material));
+                }
+                "sdf" => {
+                    let material = args.material(&materials)?;
+                    let sdf = Sdf::parse(&args.rest()).map_err(|e| args.error(e))?;
+                    let (min, max) = sdf.bounds();
+                    let index = scene.sdfs.len() as u32;
+                    scene.sdfs.push(sdf);
+                    scene
+                        .primitives
+                        .push(Primitive::sdf(index, min, max, material));
+                }
+                "csg" => {
+                    let material = args.material(&materials)?;
+                    let csg = Csg::parse(&args.rest()).map_err(|e| args.error(e))?;
+                    if csg.depth() > MAX_CSG_DEPTH {
+                        return Err(args.error("CSG tree is too deep".to_string()));
+                    }
+                    let primitive = Primitive::csg(&csg, &mut scene.csg_nodes, material);
+                    scene.primitives.push(primitive);
+                }
+                "curve" => {
+                    let points = args.control_points()?;
+                    let radius = [args.f32("radius")?, args.f32("radius")?];
+                    let material = args.material(&materials)?;
+                    scene.curves.push(Curve::tube(points, radius, material));
+                }
+                "ribbon" => {
+                    let points = args.control_points()?;
+                    let width = [args.f32("width")?, args.f32("width")?];
+                    let normals = [args.vec3("normal")?, args.vec3("normal")?];
+                    let material = args.material(&materials)?;
+                    scene
+                        .curves
+                        .push(Curve::ribbon(points, width, normals, material));
+                }
+                _ => return Err(args.error(format!("unknown statement `{keyword}`"))),
+            }
+            args.end()?;
+        }
+        Ok(scene)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn primitives() {
+        let scene = SceneFile::parse(include_str!("../assets/primitives.scene")).unwrap();
+        assert!(scene.camera.is_some());
+        assert_eq!(scene.spheres.len(), 2);
+        assert_eq!(scene.primitives.len(), 7);
+        assert_eq!(scene.textures.len(), 1);
+        let scene = SceneFile::parse(include_str!("../assets/sdf.scene")).unwrap();
+        assert_eq!(scene.sdfs.len(), 3);
+        let scene = SceneFile::parse(include_str!("../assets/csg.scene")).unwrap();
+        assert_eq!(scene.primitives.len(), 4);
+        assert_eq!(scene.csg_nodes.len(), 13);
+        let scene = SceneFile::parse(include_str!("../assets/hair.scene")).unwrap();
+        assert_eq!(scene.curves.len(), 6);
+    }
+
+    #[test]
+    fn errors() {
+        let error = SceneFile::parse("material red lambertian 1 0 0\nsphere 0 0 0 1 blue\n")
+            .err()
+            .unwrap();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.message, "unknown material `blue`");
+        let error = SceneFile::parse("# comment\n\nplane 0 0 0 0 x 0 m")
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "line 3: expected a number for normal, found `x`");
+        let error = SceneFile::parse("material m dielectric 1.5 2").err().unwrap();
+        assert_eq!(error.message, "unexpected `2`");
+        let error = SceneFile::parse("material m dielectric 1.5\nsdf m (sphere 1 2)")
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "line 2: expected `)` to close `sphere`, found `2`");
+        assert!(SceneFile::parse("teapot").is_err());
+        let error = SceneFile::parse("material m dielectric cauchy 1.5 0.004").err().unwrap();
+        assert_eq!(error.message, "missing cauchy c");
+        let error = SceneFile::parse("material m dielectric glass").err().unwrap();
+        assert_eq!(error.message, "expected a number for ior, found `glass`");
+    }
+
+    #[test]
+    fn dispersion() {
+        let scene = SceneFile::parse(
+            "material flint dielectric cauchy 1.67 0.00743 0.0001\n\
+             material bk7 dielectric sellmeier 1.039612 0.2317923 1.0104695  \
+             0.006000699 0.020017914 103.56065\n\
+             sphere 0 0 0 1 flint\n\
+             sphere 0 0 0 1 bk7\n",
+        )
+        .unwrap();
+        let flint = Material::new_dispersive(1.67, 0.00743, 0.0001);
+        let bk7 = Material::new_sellmeier(
+            Vec3::new(1.039_612, 0.231_792_3, 1.010_469_5),
+            Vec3::new(0.006_000_699, 0.020_017_914, 103.560_65),
+        );
+        let sphere = |material| Sphere::new(Vec3::ZERO, 1.0, material);
+        assert_eq!(bytemuck::bytes_of(&scene.spheres[0]), bytemuck::bytes_of(&sphere(flint)));
+        assert_eq!(bytemuck::bytes_of(&scene.spheres[1]), bytemuck::bytes_of(&sphere(bk7)));
+    }
+
+    #[test]
+    fn textures() {
+        let scene = SceneFile::parse(
+            "texture tiles checker 0 0 0 1 1 1 2\n\
+             texture veins marble 1 1 1 0.2 0.2 0.3 4 5 6\n\
+             texture sky gradient 0 0 1 1 1 1 0 1 0 0.5\n\
+             material floor lambertian 0.8 0.8 0.8 albedo tiles\n\
+             material stone metal 1 1 1 0.5 albedo veins roughness tiles\n\
+             material lamp light 2 2 2 emission sky\n\
+             sphere 0 0 0 1 floor\n\
+             sphere 0 0 0 1 stone\n\
+             sphere 0 0 0 1 lamp\n",
+        )
+        .unwrap();
+        let textures = [
+            Texture::checker(Vec3::ZERO, Vec3::ONE, 2.0),
+            Texture::marble(Vec3::ONE, Vec3::new(0.2, 0.2, 0.3), 4.0, 5.0, 6),
+            Texture::gradient(Vec3::Z, Vec3::ONE, Vec3::Y, 0.5),
+        ];
+        assert_eq!(bytemuck::cast_slice::<_, u8>(&scene.textures), bytemuck::cast_slice(&textures));
+        let materials = [
+            Material::new_lambertian(Vec3::splat(0.8)).with_albedo_texture(0),
+            Material::new_metal(Vec3::ONE, 0.5)
+                .with_albedo_texture(1)
+                .with_roughness_texture(0),
+            Material::new_lambertian(Vec3::ZERO)
+                .with_emission(Vec3::splat(2.0))
+                .with_emission_texture(2),
+        ];
+        for (sphere, material) in scene.spheres.iter().zip(materials) {
+            let expected = Sphere::new(Vec3::ZERO, 1.0, material);
+            assert_eq!(bytemuck::bytes_of(sphere), bytemuck::bytes_of(&expected));
+        }
+        let error = SceneFile::parse("material m lambertian 1 1 1 albedo tiles").err().unwrap();
+        assert_eq!(error.message, "unknown texture `tiles`");
+        let error = SceneFile::parse("texture t noise 0 0 0 1 1 1 2 1.5").err().unwrap();
+        assert_eq!(error.message, "expected a whole number for octaves, found `1.5`");
+        let error = SceneFile::parse("texture t plaid 0 0 0 1 1 1").err().unwrap();
+        assert_eq!(error.message, "unknown texture kind `plaid`");
+    }
+
+    #[test]
+    fn thin_film() {
+        let scene = SceneFile::parse(
+            "material bubble dielectric 1.0 film 300 1.33\n\
+             material oil metal 0.2 0.2 0.2 0 film 450 1.5\n\
+             sphere 0 0 0 1 bubble\n\
+             sphere 0 0 0 1 oil\n",
+        )
+        .unwrap();
+        let bubble = Material::new_dielectric(1.0).with_thin_film(300.0, 1.33);
+        let oil = Material::new_metal(Vec3::splat(0.2), 0.0).with_thin_film(450.0, 1.5);
+        let sphere = |material| Sphere::new(Vec3::ZERO, 1.0, material);
+        assert_eq!(bytemuck::bytes_of(&scene.spheres[0]), bytemuck::bytes_of(&sphere(bubble)));
+        assert_eq!(bytemuck::bytes_of(&scene.spheres[1]), bytemuck::bytes_of(&sphere(oil)));
+        let error = SceneFile::parse("material m lambertian 1 1 1 film 300 1.33").err().unwrap();
+        assert_eq!(error.message, "a lambertian material has no film");
+        let error = SceneFile::parse("material m dielectric 1.5 film 300").err().unwrap();
+        assert_eq!(error.message, "missing film ior");
+    }
+}
@@ -1,33 +1,182 @@
-use std::cmp::min;
 use std::f32::consts::PI;
 use std::mem::size_of;
 
 use crate::renderer::RenderOutput;
-use crate::renderer::Renderer;
+use crate::renderer::{BufferTooLarge, Renderer};
+use crate::scene::bvh::{build_nodes, Node};
 pub use crate::scene::camera::Camera;
 pub use crate::scene::material::DIELECTRIC;
 pub use crate::scene::material::METAL;
 pub use crate::scene::sphere::Sphere;
+use crate::scene::{
+    sdf, Csg, CsgNode, Curve, Image, Material, ParseError, Primitive, SceneFile, Sdf, Texture,
+};
 use glam::Vec3;
 use rand::prelude::*;
 use wgpu::BufferBindingType;
 
-const MAX_OBJECT_IN_SCENE: u64 = 100;
-
 pub struct SceneSphere {
     pub renderer: Renderer,
     pub camera: Camera,
+    /// Reordered by `write_scene_data` when it builds their BVH.
     pub objects: Vec<Sphere>,
+    pub primitives: Vec<Primitive>,
+    pub csg_nodes: Vec<CsgNode>,
+    /// Reordered by `write_scene_data` when it builds their BVH.
+    pub curves: Vec<Curve>,
+    pub textures: Vec<Texture>,
+    pub texels: Vec<u32>,
 }
 
 impl SceneSphere {
-    pub fn write_scene_data(&mut self) {
-        let data = bytemuck::cast_slice(self.objects.as_slice());
-        let n = min(
-            data.len(),
-            MAX_OBJECT_IN_SCENE as usize * size_of::<Sphere>(),
+    /// Uploads the scene, building the BVHs over curves and spheres. Buffers grow to fit,
+    /// see `Renderer::write_buffer`.
+    pub fn write_scene_data(&mut self) -> Result<(), BufferTooLarge> {
+        self.renderer
+            .write_buffer(bytemuck::cast_slice(&self.textures), 1)?;
+        self.renderer
+            .write_buffer(bytemuck::cast_slice(&self.texels), 2)?;
+        self.renderer
+            .write_buffer(bytemuck::cast_slice(&self.primitives), 3)?;
+        self.renderer
+            .write_buffer(bytemuck::cast_slice(&self.csg_nodes), 4)?;
+        let bounds = |(min, max): (Vec3, Vec3)| (min.extend(0.0), max.extend(0.0));
+        let mut nodes = build_nodes(
+            &mut self.curves,
+            |c| {
+                let (min, max) = c.bounds();
+                min + max
+            },
+            |c| bounds(c.bounds()),
         );
-        self.renderer.write_buffer(&data[0..n], 0)
+        let curve_nodes = nodes.len();
+        nodes.extend(build_nodes(
+            &mut self.objects,
+            |s| {
+                let (min, max) = s.bounds();
+                min + max
+            },
+            |s| bounds(s.bounds()),
+        ));
+        // sphere nodes follow the curve nodes in the one buffer
+        let sizes = [
+            curve_nodes as u32,
+            self.curves.len() as u32,
+            (nodes.len() - curve_nodes) as u32,
+            self.objects.len() as u32,
+        ];
+        self.renderer
+            .write_buffer(bytemuck::cast_slice(&self.objects), 0)?;
+        self.renderer.write_buffer(bytemuck::cast_slice(&sizes), 5)?;
+        self.renderer.write_buffer(bytemuck::cast_slice(&nodes), 6)?;
+        self.renderer
+            .write_buffer(bytemuck::cast_slice(&self.curves), 7)?;
+        Ok(())
+    }
+    /// Adds a CSG solid made of `material`.
+    pub fn add_csg(&mut self, csg: &Csg, material: Material) {
+        let primitive = Primitive::csg(csg, &mut self.csg_nodes, material);
+        self.primitives.push(primitive);
+    }
+    /// Registers a texture for materials to reference, returns its index.
+    pub fn add_texture(&mut self, texture: Texture) -> u32 {
+        self.textures.push(texture);
+        self.textures.len() as u32 - 1
+    }
+    /// Appends the texels of `image` and registers a UV mapped texture reading them.
+    pub fn add_image(&mut self, image: &Image) -> u32 {
+        let offset = self.texels.len() as u32;
+        self.texels.extend_from_slice(&image.texels);
+        self.add_texture(Texture::image(offset, image.width, image.height))
+    }
+    /// `sdfs` are compiled into the shader, so they can only be given at creation. Buffers
+    /// start with room for one element and grow in `write_scene_data`.
+    pub(crate) async fn make_renderer(output: RenderOutput, sdfs: &[Sdf]) -> Renderer {
+        Renderer::new(
+            output,
+            vec![
+                (
+                    BufferBindingType::Storage { read_only: true },
+                    size_of::<Sphere>() as u64,
+                ), // spheres
+                (
+                    BufferBindingType::Storage { read_only: true },
+                    size_of::<Texture>() as u64,
+                ), // textures
+                (
+                    BufferBindingType::Storage { read_only: true },
+                    size_of::<u32>() as u64,
+                ), // texels
+                (
+                    BufferBindingType::Storage { read_only: true },
+                    size_of::<Primitive>() as u64,
+                ), // primitives
+                (
+                    BufferBindingType::Storage { read_only: true },
+                    size_of::<CsgNode>() as u64,
+                ), // csg nodes
+                (BufferBindingType::Uniform, 4 * size_of::<u32>() as u64), // tree sizes
+                (
+                    BufferBindingType::Storage { read_only: true },
+                    size_of::<Node>() as u64,
+                ), // curve and sphere nodes
+                (
+                    BufferBindingType::Storage { read_only: true },
+                    size_of::<Curve>() as u64,
+                ), // curves
+            ],
+            &sdf::compile(include_str!("../shaders/shader_sphere.wgsl"), sdfs),
+        )
+        .await
+    }
+    /// Builds a scene from the text format described in `scene_file`.
+    pub async fn from_scene_file(source: &str, output: RenderOutput) -> Result<Self, ParseError> {
+        let file = SceneFile::parse(source)?;
+        let camera = file.camera.unwrap_or_else(|| {
+            Camera::new(
+                Vec3::new(0.0, 0.2, 1.5),
+                Vec3::new(0.0, 0.1, -3.0),
+                2.2,
+                0.0,
+                PI * 0.3,
+            )
+        });
+        let renderer = Self::make_renderer(output, &file.sdfs).await;
+        Ok(Self {
+            renderer,
+            camera,
+            objects: file.spheres,
+            primitives: file.primitives,
+            csg_nodes: file.csg_nodes,
+            curves: file.curves,
+            textures: file.textures,
+            texels: Vec::new(),
+        })
+    }
+    pub async fn new_primitives(output: RenderOutput) -> Self {
+        Self::from_scene_file(include_str!("../assets/primitives.scene"), output)
+            .await
+            .expect("bundled scene file is valid")
+    }
+    pub async fn new_sdf(output: RenderOutput) -> Self {
+        Self::from_scene_file(include_str!("../assets/sdf.scene"), output)
+            .await
+            .expect("bundled scene file is valid")
+    }
+    pub async fn new_csg(output: RenderOutput) -> Self {
+        Self::from_scene_file(include_str!("../assets/csg.scene"), output)
+            .await
+            .expect("bundled scene file is valid")
+    }
+    pub async fn new_hair(output: RenderOutput) -> Self {
+        let mut scene = Self::from_scene_file(include_str!("../assets/hair.scene"), output)
+            .await
+            .expect("bundled scene file is valid");
+        let ginger = Material::new_hair(0.0, 0.12, 0.3, 0.3, 2.0);
+        let fur = Curve::parse_strands(include_str!("../assets/fur.strands"), ginger)
+            .expect("bundled strand file is valid");
+        scene.curves.extend(fur);
+        scene
     }
     pub async fn new(output: RenderOutput) -> Self {
         let black = Vec3::new(0.06, 0.06, 0.1);
@@ -70,21 +219,73 @@ impl SceneSphere {
                 }
             }
         }
-        let renderer = Renderer::new(
-            output,
-            vec![
-                (
-                    BufferBindingType::Storage { read_only: true },
-                    MAX_OBJECT_IN_SCENE * size_of::<Sphere>() as u64,
-                ), // spheres
-            ],
-            include_str!("../shaders/shader_sphere.wgsl"),
-        )
-        .await;
+        let renderer = Self::make_renderer(output, &[]).await;
+        Self {
+            renderer,
+            camera,
+            objects,
+            primitives: Vec::new(),
+            csg_nodes: Vec::new(),
+            curves: Vec::new(),
+            textures: Vec::new(),
+            texels: Vec::new(),
+        }
+    }
+    /// The cover of "Ray Tracing in One Weekend" with its field of small balls widened to
+    /// about a hundred thousand, which the sphere BVH keeps interactive.
+    pub async fn new_cover(output: RenderOutput) -> Self {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut objects = vec![
+            Sphere::new_lambertian(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Vec3::splat(0.5)),
+            Sphere::new_dielectric(Vec3::new(0.0, 1.0, 0.0), 1.0, 1.5),
+            Sphere::new_lambertian(Vec3::new(-4.0, 1.0, 0.0), 1.0, Vec3::new(0.4, 0.2, 0.1)),
+            Sphere::new_metal(Vec3::new(4.0, 1.0, 0.0), 1.0, Vec3::new(0.7, 0.6, 0.5), 0.0),
+        ];
+        for a in -158..158 {
+            for b in -158..158 {
+                let center = Vec3::new(
+                    a as f32 + 0.9 * rng.gen::<f32>(),
+                    0.2,
+                    b as f32 + 0.9 * rng.gen::<f32>(),
+                );
+                if [-4.0, 0.0, 4.0]
+                    .iter()
+                    .any(|&x| center.distance(Vec3::new(x, 0.2, 0.0)) < 1.1)
+                {
+                    continue;
+                }
+                let sphere = match rng.gen::<f32>() {
+                    p if p < 0.8 => {
+                        let albedo = Vec3::new(rng.gen(), rng.gen(), rng.gen())
+                            * Vec3::new(rng.gen(), rng.gen(), rng.gen());
+                        Sphere::new_lambertian(center, 0.2, albedo)
+                    }
+                    p if p < 0.95 => {
+                        let albedo = Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 0.5 + 0.5;
+                        Sphere::new_metal(center, 0.2, albedo, rng.gen_range(0.0..0.5))
+                    }
+                    _ => Sphere::new_dielectric(center, 0.2, 1.5),
+                };
+                objects.push(sphere);
+            }
+        }
+        let camera = Camera::new(
+            Vec3::new(13.0, 2.0, 3.0),
+            Vec3::ZERO,
+            10.0,
+            0.05,
+            20f32.to_radians(),
+        );
+        let renderer = Self::make_renderer(output, &[]).await;
         Self {
             renderer,
             camera,
             objects,
+            primitives: Vec::new(),
+            csg_nodes: Vec::new(),
+            curves: Vec::new(),
+            textures: Vec::new(),
+            texels: Vec::new(),
         }
     }
     pub async fn new_simple(output: RenderOutput) -> Self {
@@ -109,21 +310,16 @@ impl SceneSphere {
             Sphere::new_metal(Vec3::new(-0.3, -0.4, -0.4), 0.1, blue, 0.9),
             Sphere::new_dielectric(Vec3::new(0.2, -0.38, -0.16), 0.12, 0.1),
         ];
-        let renderer = Renderer::new(
-            output,
-            vec![
-                (
-                    BufferBindingType::Storage { read_only: true },
-                    MAX_OBJECT_IN_SCENE * size_of::<Sphere>() as u64,
-                ), // spheres
-            ],
-            include_str!("../shaders/shader_sphere.wgsl"),
-        )
-        .await;
+        let renderer = Self::make_renderer(output, &[]).await;
         Self {
             renderer,
             camera,
             objects,
+            primitives: Vec::new(),
+            csg_nodes: Vec::new(),
+            curves: Vec::new(),
+            textures: Vec::new(),
+            texels: Vec::new(),
         }
     }
 }
@@ -131,8 +327,10 @@ impl SceneSphere {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::scene::render_ppm;
-    use crate::scene::Scene;
+    use crate::scene::{render_ppm, render_rgb};
+    use crate::scene::{Motion, Scene};
+    use glam::{Vec2, Vec3Swizzles, Vec4};
+    use std::collections::HashSet;
     use std::io::Write;
 
     #[test]
@@ -140,12 +338,77 @@ mod tests {
         let width = 1024;
         let height = 768;
         let mut scene = pollster::block_on(SceneSphere::new(RenderOutput::Headless(width, height)));
-        scene.init();
+        scene.init().unwrap();
         let content = render_ppm(&mut scene.renderer);
         let mut file = std::fs::File::create("globe.ppm").unwrap();
         file.write_all(content.as_bytes()).unwrap();
     }
 
+    #[test]
+    fn cover() {
+        let output = RenderOutput::Headless(WIDTH, HEIGHT);
+        let mut scene = pollster::block_on(SceneSphere::new_cover(output));
+        assert!(scene.objects.len() > 90000);
+        // black emitters in many colours, so each pixel shows the sphere its camera ray
+        // hits and nothing the path meets after it
+        for (i, sphere) in scene.objects.iter_mut().enumerate() {
+            let (min, max) = sphere.bounds();
+            let colour = Vec3::new((i % 5) as f32, (i % 7) as f32, (i % 11) as f32) / 10.0;
+            let material = Material::new_lambertian(Vec3::ZERO).with_emission(colour);
+            *sphere = Sphere::new((min + max) / 2.0, (max.x - min.x) / 2.0, material);
+        }
+        let render = |scene: &mut SceneSphere| {
+            scene.reset_frame_count();
+            scene.set_time(1000);
+            scene.draw();
+            render_rgb(&mut scene.renderer)
+        };
+        let bounds = |s: &Sphere| {
+            let (min, max) = s.bounds();
+            (min.extend(0.0), max.extend(0.0))
+        };
+        scene.init().unwrap();
+        let tree = render(&mut scene);
+        let colours: HashSet<_> = tree.chunks(3).collect();
+        assert!(colours.len() > 50, "{} colours", colours.len());
+        // a tree split along the axes in another order groups the spheres differently, but
+        // finds the same closest hits
+        let mut objects = scene.objects.clone();
+        let mut nodes = vec![Node::default()]; // the empty curve tree
+        let key = |s: &Sphere| (s.bounds().0 + s.bounds().1).zxy();
+        nodes.extend(build_nodes(&mut objects, key, bounds));
+        let moved = objects.iter().zip(&scene.objects);
+        assert!(moved.filter(|(a, b)| a.bounds() != b.bounds()).count() > objects.len() / 2);
+        let renderer = &mut scene.renderer;
+        renderer
+            .write_buffer(bytemuck::cast_slice(&objects), 0)
+            .unwrap();
+        renderer
+            .write_buffer(bytemuck::cast_slice(&nodes), 6)
+            .unwrap();
+        let other = render(&mut scene);
+        let changed = differing(&tree, &other).len();
+        assert!(tree == other, "{changed} pixels differ");
+        // and the same as testing every sphere, here only those near the middle as some
+        // drivers cut loops short after 65535 iterations
+        scene
+            .objects
+            .retain(|s| s.bounds().0.length() < 20.0 || s.bounds().0.y < -1.0);
+        scene.init().unwrap();
+        let tree = render(&mut scene);
+        let mut everything = Node::default();
+        everything.union(Vec4::splat(-1e30));
+        everything.union(Vec4::splat(1e30));
+        let nodes = vec![everything; 1 + scene.objects.len().next_power_of_two()];
+        let renderer = &mut scene.renderer;
+        renderer
+            .write_buffer(bytemuck::cast_slice(&nodes), 6)
+            .unwrap();
+        let brute_force = render(&mut scene);
+        let changed = differing(&tree, &brute_force).len();
+        assert!(tree == brute_force, "{changed} pixels differ");
+    }
+
     #[test]
     fn sphere() {
         let width = 1024;
@@ -153,9 +416,182 @@ mod tests {
         let mut scene = pollster::block_on(SceneSphere::new_simple(RenderOutput::Headless(
             width, height,
         )));
-        scene.init();
+        scene.init().unwrap();
         let content = render_ppm(&mut scene.renderer);
         let mut file = std::fs::File::create("sphere.ppm").unwrap();
         file.write_all(content.as_bytes()).unwrap();
     }
+
+    #[test]
+    fn motion_blur() {
+        let (width, height) = (128, 96);
+        let render = |moving: bool, (open, close): (f32, f32)| {
+            let output = RenderOutput::Headless(width, height);
+            let mut scene = pollster::block_on(SceneSphere::new_simple(output));
+            scene.camera = scene.camera.with_shutter(open, close);
+            if moving {
+                // the black ball rolls to the right, the yellow one swings round the middle one
+                let roll = Motion::new(Vec3::new(0.4, 0.0, 0.0), Vec3::Z, -0.8, Vec3::ZERO);
+                scene.objects[2] = scene.objects[2].with_motion(roll);
+                let swing = Motion::new(Vec3::ZERO, Vec3::Y, 0.6, Vec3::new(0.0, 0.0, -1.0));
+                scene.objects[3] = scene.objects[3].with_motion(swing);
+            }
+            scene.init().unwrap();
+            // the blur only shows once many times in the frame are averaged
+            for i in 0..8 {
+                scene.set_time(1000 + i * 10);
+                scene.draw();
+            }
+            render_rgb(&mut scene.renderer)
+        };
+        let differing = |a: &[u8], b: &[u8]| {
+            let pixels = a.chunks(3).zip(b.chunks(3));
+            pixels.filter(|(a, b)| a.iter().zip(b.iter()).any(|(a, b)| a.abs_diff(*b) > 8)).count()
+        };
+        // a shutter closed at the start sees the spheres where they stand
+        assert_eq!(render(true, (0.0, 0.0)), render(false, (0.0, 0.0)));
+        // an open one smears them along the way, unlike either end of it or a still scene
+        // sampled over the same times
+        let blurred = render(true, (0.0, 1.0));
+        let pixels = (width * height) as usize;
+        assert!(differing(&blurred, &render(false, (0.0, 1.0))) > pixels / 20);
+        assert!(differing(&blurred, &render(true, (1.0, 1.0))) > pixels / 20);
+    }
+
+    #[test]
+    fn checker() {
+        // a black and white checker floor filling the view
+        let source = "camera 0 3 1  0 0 0  3 0 40\n\
+                      texture tiles checker 0 0 0  1 1 1  2\n\
+                      material floor lambertian 1 1 1 albedo tiles\n\
+                      plane 0 0.25 0  0 1 0  floor\n";
+        let output = RenderOutput::Headless(64, 48);
+        let mut scene = pollster::block_on(SceneSphere::from_scene_file(source, output)).unwrap();
+        scene.init().unwrap();
+        for i in 0..8 {
+            scene.set_time(1000 + i * 10);
+            scene.draw();
+        }
+        let rgb = render_rgb(&mut scene.renderer);
+        let pixels = rgb.chunks(3).map(|p| p.iter().copied().max().unwrap());
+        let (dark, bright) = pixels.fold((0, 0), |(dark, bright), value| match value {
+            0..=8 => (dark + 1, bright),
+            64.. => (dark, bright + 1),
+            _ => (dark, bright),
+        });
+        // half the cells are black, edges blend between the two
+        let count = 64 * 48;
+        assert!(dark > count / 3 && bright > count / 3, "{dark} dark, {bright} bright");
+    }
+
+    const WIDTH: u32 = 128;
+    const HEIGHT: u32 = 96;
+
+    /// `source` without its lines starting with `prefix`.
+    fn without(source: &str, prefix: &str) -> String {
+        source.lines().filter(|line| !line.starts_with(prefix)).collect::<Vec<_>>().join("\n")
+    }
+
+    fn load(source: &str) -> SceneSphere {
+        let output = RenderOutput::Headless(WIDTH, HEIGHT);
+        pollster::block_on(SceneSphere::from_scene_file(source, output)).unwrap()
+    }
+
+    fn render_frame(mut scene: SceneSphere) -> Vec<u8> {
+        scene.init().unwrap();
+        for i in 0..8 {
+            scene.set_time(1000 + i * 10);
+            scene.draw();
+        }
+        render_rgb(&mut scene.renderer)
+    }
+
+    /// Column and row of the pixels that tell `a` and `b` apart.
+    fn differing(a: &[u8], b: &[u8]) -> Vec<Vec2> {
+        let pixels = a.chunks(3).zip(b.chunks(3)).enumerate();
+        pixels
+            .filter(|(_, (a, b))| a.iter().zip(b.iter()).any(|(a, b)| a.abs_diff(*b) > 8))
+            .map(|(i, _)| Vec2::new((i as u32 % WIDTH) as f32, (i as u32 / WIDTH) as f32))
+            .collect()
+    }
+
+    fn mean(pixels: &[Vec2]) -> Vec2 {
+        pixels.iter().sum::<Vec2>() / pixels.len() as f32
+    }
+
+    #[test]
+    fn primitives() {
+        let source = include_str!("../assets/primitives.scene");
+        let full = render_frame(load(source));
+        // each primitive shows up where it stands, the box on the left, the oriented box on
+        // the right and the disk at the front
+        let changes = |prefix| differing(&full, &render_frame(load(&without(source, prefix))));
+        let [left, right, front] = ["box ", "obox ", "disk "].map(changes);
+        let pixels = (WIDTH * HEIGHT) as usize;
+        for changed in [&left, &right, &front] {
+            assert!(changed.len() > pixels / 100, "{} pixels", changed.len());
+        }
+        let [left, right, front] = [left, right, front].map(|changed| mean(&changed));
+        let centre = Vec2::new(WIDTH as f32, HEIGHT as f32) / 2.0;
+        assert!(left.x < centre.x - WIDTH as f32 / 4.0, "{left}");
+        assert!(right.x > centre.x + WIDTH as f32 / 4.0, "{right}");
+        assert!(front.y > centre.y, "{front}");
+    }
+
+    #[test]
+    fn sdf() {
+        let source = include_str!("../assets/sdf.scene");
+        let changed = differing(
+            &render_frame(load(source)),
+            &render_frame(load(&without(source, "sdf "))),
+        );
+        // the blobby torus stands on the left, the hollowed box in the middle and the
+        // capsules on the right
+        let third = WIDTH as f32 / 3.0;
+        let pixels = (WIDTH * HEIGHT) as usize;
+        for band in 0..3 {
+            let inside = changed
+                .iter()
+                .filter(|p| (p.x / third) as usize == band)
+                .count();
+            assert!(inside > pixels / 50, "{inside} pixels in third {band}");
+        }
+    }
+
+    #[test]
+    fn csg() {
+        let source = include_str!("../assets/csg.scene");
+        let full = render_frame(load(source));
+        // the drilled cube stands on the right, the cut sphere on the left
+        let changes = |prefix| differing(&full, &render_frame(load(&without(source, prefix))));
+        let [right, left] = ["csg copper", "csg green"].map(changes);
+        let pixels = (WIDTH * HEIGHT) as usize;
+        for changed in [&left, &right] {
+            assert!(changed.len() > pixels / 100, "{} pixels", changed.len());
+        }
+        // and its upper front quarter is missing, where a whole sphere differs from it
+        let sphere = "sphere -1.4 0.5 0 0.5 green";
+        let solid = format!("{}\n{sphere}\n", without(source, "csg green"));
+        let cut = differing(&full, &render_frame(load(&solid)));
+        assert!(cut.len() > pixels / 400, "{} pixels", cut.len());
+        let [left, right, cut] = [left, right, cut].map(|changed| mean(&changed));
+        let centre = WIDTH as f32 / 2.0;
+        assert!(left.x < centre, "{left}");
+        assert!(right.x > centre, "{right}");
+        assert!(cut.y < left.y, "{cut} {left}");
+    }
+
+    #[test]
+    fn hair() {
+        let output = RenderOutput::Headless(WIDTH, HEIGHT);
+        let scene = pollster::block_on(SceneSphere::new_hair(output));
+        assert_eq!(scene.curves.len(), 3000 * 3 + 6);
+        let bald = load(include_str!("../assets/hair.scene"));
+        let changed = differing(&render_frame(scene), &render_frame(bald));
+        // the fur grows all over the ball in the middle of the view
+        let pixels = (WIDTH * HEIGHT) as usize;
+        assert!(changed.len() > pixels / 20, "{} pixels", changed.len());
+        let offset = mean(&changed) - Vec2::new(WIDTH as f32, HEIGHT as f32) / 2.0;
+        assert!(offset.abs().max_element() < WIDTH as f32 / 8.0, "{offset}");
+    }
 }
@@ -1,15 +1,59 @@
-use glam::Vec3;
-use std::cmp::min;
+use glam::{Vec3, Vec4};
 use std::{f32::consts::PI, mem::size_of};
 use wgpu::BufferBindingType;
 
 use crate::renderer::RenderOutput;
-use crate::{geometry::Mesh, renderer::Renderer};
+use crate::geometry::{Mesh, PointCloud};
+use crate::renderer::{BufferTooLarge, Renderer};
 
-use super::{bvh::Node, bvh::Tree, bvh::Triangle, material::Material, Camera};
+use super::{
+    bvh::cache, bvh::Changes, bvh::Lbvh, bvh::LbvhOutput, bvh::Node, bvh::Point, bvh::Tree, bvh::Triangle, bvh::TriangleAttributes,
+    material::Material, Camera, Image, Motion, Texture, MAX_MOTIONS,
+};
 
-const MAX_TRIS: usize = 1000000;
-const MAX_MATS: usize = 1000;
+/// The tree sizes the shader reads and the nodes it traverses, collapsed if the tree is
+/// wide, see `bvh::wide`.
+fn tree_nodes(tree: &Tree) -> ([u32; 4], Vec<u32>) {
+    let [n, m] = tree.sizes;
+    if tree.width == 2 {
+        return ([n, m, 2, 0], bytemuck::cast_slice(&tree.nodes).to_vec());
+    }
+    ([n, m, tree.width as u32, 0], tree.collapse(tree.width))
+}
+
+/// The motions the shader reads, in room for `MAX_MOTIONS` since a uniform holds a fixed
+/// array.
+fn tree_motions(tree: &Tree) -> Vec<u8> {
+    let mut motions = tree.motions.clone();
+    motions.resize(MAX_MOTIONS, Motion::default());
+    bytemuck::cast_slice(&motions).to_vec()
+}
+
+/// The tree over `mesh` fitted for `SceneTris::from_mesh` and the floor, not yet built.
+fn mesh_tree(mut mesh: Mesh) -> Tree {
+    mesh.remove_degenerates();
+    mesh.normalize();
+    mesh.scale(2.0);
+    mesh.translate(Vec3::new(0.0, -mesh.bounds().0.y, 0.0));
+    let mut tree: Tree = mesh.into();
+    let mesh = Mesh::load_obj(
+        include_bytes!("../assets/floor.obj"),
+        Material::new_lambertian(Vec3::new(0.5, 0.5, 0.6)),
+    )
+    .unwrap();
+    tree.add_mesh(mesh);
+    tree
+}
+
+fn mesh_camera() -> Camera {
+    Camera::new(
+        Vec3::new(0.0, 2.2, 4.5),
+        Vec3::new(0.0, 1.0, 0.0),
+        4.6,
+        0.0,
+        PI * 0.3,
+    )
+}
 
 pub struct SceneTris {
     pub renderer: Renderer,
@@ -18,47 +62,149 @@ pub struct SceneTris {
 }
 
 impl SceneTris {
-    pub fn write_tree_data(&mut self) {
-        let data = [
-            (
-                bytemuck::cast_slice(&self.tris_bvh.sizes),
-                2 * size_of::<u32>(),
-            ),
-            (
-                bytemuck::cast_slice(&self.tris_bvh.nodes),
-                MAX_TRIS * size_of::<Node>(),
-            ),
-            (
-                bytemuck::cast_slice(&self.tris_bvh.triangles),
-                MAX_TRIS * size_of::<Triangle>(),
-            ),
-            (
-                bytemuck::cast_slice(&self.tris_bvh.materials),
-                MAX_MATS * size_of::<Material>(),
-            ),
+    /// Uploads the tree, growing buffers to fit, see `Renderer::write_buffer`.
+    pub fn write_tree_data(&mut self) -> Result<(), BufferTooLarge> {
+        self.write_tree_buffers(&[])
+    }
+    /// Builds the tree on the GPU in place of `Tree::build` and uploads the scene, see
+    /// `Lbvh`, which makes large meshes quick to load. The order of the triangles and the
+    /// binary nodes come back for the CPU copy of the tree, which `Tree::update_mesh` and
+    /// the wide nodes start from. Falls
+    /// back to `Tree::build` where compute shaders are missing, and for moving meshes,
+    /// whose bounds over the frame `Lbvh` does not fit.
+    pub fn build_tree_on_gpu(&mut self) -> Result<(), BufferTooLarge> {
+        let m = self.tris_bvh.triangles.len();
+        let device = &self.renderer.device;
+        if m < 2 || !Lbvh::supports(device, m) || self.tris_bvh.is_moving() {
+            self.tris_bvh.build();
+            return self.write_tree_data();
+        }
+        self.renderer.reserve_buffer(1, ((m - 1) * size_of::<Node>()) as u64)?;
+        self.renderer.reserve_buffer(2, (m * size_of::<Triangle>()) as u64)?;
+        self.renderer.reserve_buffer(5, (m * size_of::<TriangleAttributes>()) as u64)?;
+        let buffers = &self.renderer.buffers[1].buffers;
+        let built = Lbvh::new(&self.renderer.device).build(
+            &self.renderer.device,
+            &self.renderer.queue,
+            &self.tris_bvh.triangles,
+            &self.tris_bvh.attributes,
+            LbvhOutput {
+                nodes: &buffers[1],
+                triangles: &buffers[2],
+                attributes: &buffers[5],
+            },
+        );
+        let Some((order, nodes)) = built else {
+            self.tris_bvh.build();
+            return self.write_tree_data();
+        };
+        self.tris_bvh.build_with_nodes(order, nodes);
+        // the triangles and attributes are in place, and the nodes unless collapsed
+        match self.tris_bvh.width {
+            2 => self.write_tree_buffers(&[1, 2, 5]),
+            _ => self.write_tree_buffers(&[2, 5]),
+        }
+    }
+    fn write_tree_buffers(&mut self, skip: &[usize]) -> Result<(), BufferTooLarge> {
+        let tree = &self.tris_bvh;
+        let (sizes, nodes) = tree_nodes(tree);
+        let motions = tree_motions(tree);
+        let data: [&[u8]; 11] = [
+            bytemuck::cast_slice(&sizes),
+            bytemuck::cast_slice(&nodes),
+            bytemuck::cast_slice(&tree.triangles),
+            bytemuck::cast_slice(&tree.materials),
+            bytemuck::cast_slice(&tree.textures),
+            bytemuck::cast_slice(&tree.attributes),
+            bytemuck::cast_slice(&tree.texels),
+            bytemuck::cast_slice(&tree.point_sizes),
+            bytemuck::cast_slice(&tree.point_nodes),
+            bytemuck::cast_slice(&tree.points),
+            &motions,
         ];
-        for (i, (data, size)) in data.into_iter().enumerate() {
-            let n = min(data.len(), size);
-            self.renderer.write_buffer(&data[0..n], i);
+        for (i, data) in data.into_iter().enumerate() {
+            if !skip.contains(&i) {
+                self.renderer.write_buffer(data, i)?;
+            }
         }
+        self.tris_bvh.changes = Changes::default();
+        Ok(())
     }
+    /// Uploads only what `Tree::update_mesh`, `Tree::refit` and the setters of materials and
+    /// motions changed since the last upload, once `write_tree_data` sized the buffers.
+    pub fn write_tree_changes(&mut self) {
+        let tree = &mut self.tris_bvh;
+        let changes = std::mem::take(&mut tree.changes);
+        if changes.materials {
+            self.renderer
+                .write_buffer_at(bytemuck::cast_slice(&tree.materials), 3, 0);
+            self.renderer.write_buffer_at(&tree_motions(tree), 10, 0);
+        }
+        if let Some(range) = changes.triangles {
+            let data = bytemuck::cast_slice(&tree.triangles[range.clone()]);
+            let offset = range.start * size_of::<Triangle>();
+            self.renderer.write_buffer_at(data, 2, offset as u64);
+            let data = bytemuck::cast_slice(&tree.attributes[range.clone()]);
+            let offset = range.start * size_of::<TriangleAttributes>();
+            self.renderer.write_buffer_at(data, 5, offset as u64);
+        }
+        if tree.width > 2 {
+            // any change moves the grid of the wide nodes above, it is cheaper to redo
+            if !changes.nodes.is_empty() {
+                let nodes = tree.collapse(tree.width);
+                self.renderer.write_buffer_at(bytemuck::cast_slice(&nodes), 1, 0);
+            }
+            return;
+        }
+        for range in changes.nodes {
+            let data = bytemuck::cast_slice(&tree.nodes[range.clone()]);
+            let offset = range.start * size_of::<Node>();
+            self.renderer.write_buffer_at(data, 1, offset as u64);
+        }
+    }
+    /// Buffers start with room for one element and grow in `write_tree_data`.
     async fn make_renderer(output: RenderOutput) -> Renderer {
         Renderer::new(
             output,
             vec![
-                (BufferBindingType::Uniform, 2 * size_of::<u32>() as u64), // bvh tree size
+                (BufferBindingType::Uniform, 4 * size_of::<u32>() as u64), // bvh tree size
                 (
                     BufferBindingType::Storage { read_only: true },
-                    (MAX_TRIS * size_of::<Node>()) as u64,
+                    size_of::<Node>() as u64,
                 ), // nodes
                 (
                     BufferBindingType::Storage { read_only: true },
-                    (MAX_TRIS * size_of::<Triangle>()) as u64,
+                    size_of::<Triangle>() as u64,
                 ), // triangles
                 (
                     BufferBindingType::Storage { read_only: true },
-                    (MAX_MATS * size_of::<Material>()) as u64,
+                    size_of::<Material>() as u64,
                 ), // materials
+                (
+                    BufferBindingType::Storage { read_only: true },
+                    size_of::<Texture>() as u64,
+                ), // textures
+                (
+                    BufferBindingType::Storage { read_only: true },
+                    size_of::<TriangleAttributes>() as u64,
+                ), // triangle attributes
+                (
+                    BufferBindingType::Storage { read_only: true },
+                    size_of::<u32>() as u64,
+                ), // texels
+                (BufferBindingType::Uniform, 2 * size_of::<u32>() as u64), // point tree size
+                (
+                    BufferBindingType::Storage { read_only: true },
+                    size_of::<Node>() as u64,
+                ), // point nodes
+                (
+                    BufferBindingType::Storage { read_only: true },
+                    size_of::<Point>() as u64,
+                ), // points
+                (
+                    BufferBindingType::Uniform,
+                    (MAX_MOTIONS * size_of::<Motion>()) as u64,
+                ), // motions
             ],
             include_str!("../shaders/shader_tris.wgsl"),
         )
@@ -68,12 +214,19 @@ impl SceneTris {
         let mesh = Mesh::load_obj(
             include_bytes!("../assets/xyzrgb_dragon_lp_20.obj"),
             Material::new_lambertian(Vec3::new(0.7, 0.7, 0.2)),
-        );
+        )
+        .unwrap();
         let mut tree: Tree = mesh.into();
+        let checker = tree.add_texture(Texture::checker(
+            Vec3::new(0.5, 0.5, 0.6),
+            Vec3::new(0.3, 0.3, 0.4),
+            2.0,
+        ));
         let mesh = Mesh::load_obj(
             include_bytes!("../assets/floor.obj"),
-            Material::new_lambertian(Vec3::new(0.5, 0.5, 0.6)),
-        );
+            Material::new_lambertian(Vec3::ONE).with_albedo_texture(checker),
+        )
+        .unwrap();
         tree.add_mesh(mesh);
         tree.build();
         let renderer = Self::make_renderer(output).await;
@@ -94,12 +247,19 @@ impl SceneTris {
         let mesh = Mesh::load_obj(
             include_bytes!("../assets/lucy_lp_20.obj"),
             Material::new_lambertian(Vec3::new(0.4, 0.3, 0.6)),
-        );
+        )
+        .unwrap();
         let mut tree: Tree = mesh.into();
+        let checker = tree.add_texture(Texture::checker(
+            Vec3::new(0.5, 0.5, 0.6),
+            Vec3::new(0.3, 0.3, 0.4),
+            2.0,
+        ));
         let mesh = Mesh::load_obj(
             include_bytes!("../assets/floor.obj"),
-            Material::new_lambertian(Vec3::new(0.5, 0.5, 0.6)),
-        );
+            Material::new_lambertian(Vec3::ONE).with_albedo_texture(checker),
+        )
+        .unwrap();
         tree.add_mesh(mesh);
         tree.build();
         let renderer = Self::make_renderer(output).await;
@@ -120,27 +280,150 @@ impl SceneTris {
         let mesh = Mesh::load_obj(
             include_bytes!("../assets/suzanne.obj"),
             Material::new_lambertian(Vec3::new(0.3, 0.4, 0.6)),
-        );
+        )
+        .unwrap();
         let mut tree: Tree = mesh.into();
         let mesh = Mesh::load_obj(
             include_bytes!("../assets/ico_sphere.obj"),
             Material::new_dielectric(0.2),
-        );
+        )
+        .unwrap();
         tree.add_mesh(mesh);
         let mesh = Mesh::load_obj(
             include_bytes!("../assets/cube_s.obj"),
             Material::new_metal(Vec3::new(0.5, 0.5, 0.6), 0.2),
-        );
+        )
+        .unwrap();
         tree.add_mesh(mesh);
         let mesh = Mesh::load_obj(
             include_bytes!("../assets/cube_m.obj"),
             Material::new_dielectric(0.1),
-        );
+        )
+        .unwrap();
         tree.add_mesh(mesh);
         let mesh = Mesh::load_obj(
             include_bytes!("../assets/cube_l.obj"),
             Material::new_lambertian(Vec3::new(0.5, 0.5, 0.6)),
+        )
+        .unwrap();
+        tree.add_mesh(mesh);
+        tree.build();
+        let camera = Camera::new(
+            Vec3::new(0.0, 2.2, 4.5),
+            Vec3::new(0.0, 0.0, -4.5),
+            5.6,
+            0.0,
+            PI * 0.3,
         );
+        let renderer = Self::make_renderer(output).await;
+        Self {
+            renderer,
+            camera,
+            tris_bvh: tree,
+        }
+    }
+    pub async fn new_bumpy(output: RenderOutput) -> Self {
+        let mut tree = Tree::new();
+        let noise = tree.add_texture(Texture::noise(Vec3::ZERO, Vec3::ONE, 12.0, 4));
+        let mesh = Mesh::load_obj(
+            include_bytes!("../assets/suzanne_lp.obj"),
+            Material::new_lambertian(Vec3::new(0.3, 0.4, 0.6)).with_bump_map(noise, 0.04),
+        )
+        .unwrap();
+        tree.add_mesh(mesh);
+        let cells = tree.add_texture(Texture::voronoi(Vec3::ZERO, Vec3::ONE, 2.0));
+        let mesh = Mesh::load_obj(
+            include_bytes!("../assets/floor.obj"),
+            Material::new_metal(Vec3::new(0.5, 0.5, 0.6), 0.1).with_bump_map(cells, 0.1),
+        )
+        .unwrap();
+        tree.add_mesh(mesh);
+        tree.build();
+        let camera = Camera::new(
+            Vec3::new(0.0, 2.2, 4.5),
+            Vec3::new(0.0, 0.0, -4.5),
+            5.6,
+            0.0,
+            PI * 0.3,
+        );
+        let renderer = Self::make_renderer(output).await;
+        Self {
+            renderer,
+            camera,
+            tris_bvh: tree,
+        }
+    }
+    pub async fn new_fence(output: RenderOutput) -> Self {
+        let mesh = Mesh::load_obj(
+            include_bytes!("../assets/suzanne_lp.obj"),
+            Material::new_lambertian(Vec3::new(0.3, 0.4, 0.6)),
+        )
+        .unwrap();
+        let mut tree: Tree = mesh.into();
+        // chain-link: diagonal wires on a transparent background
+        let wires = Image::from_fn(64, 64, |x, y| {
+            let d = |a: u32| a.min(64 - a);
+            let wire = d((x + y) % 64) < 4 || d((x + 64 - y) % 64) < 4;
+            if wire {
+                [200, 200, 210, 255]
+            } else {
+                [0, 0, 0, 0]
+            }
+        });
+        let wires = tree.add_image(&wires);
+        let mesh = Mesh::load_obj(
+            include_bytes!("../assets/fence.obj"),
+            Material::new_metal(Vec3::ONE, 0.3)
+                .with_albedo_texture(wires)
+                .with_cutout(0.5),
+        )
+        .unwrap();
+        tree.add_mesh(mesh);
+        let checker = tree.add_texture(
+            Texture::checker(Vec3::new(0.5, 0.5, 0.6), Vec3::new(0.3, 0.3, 0.4), 2.0)
+                .with_alpha(1.0, 0.0),
+        );
+        let mesh = Mesh::load_obj(
+            include_bytes!("../assets/floor.obj"),
+            Material::new_lambertian(Vec3::ONE).with_albedo_texture(checker).with_cutout(0.5),
+        )
+        .unwrap();
+        tree.add_mesh(mesh);
+        tree.build();
+        let camera = Camera::new(
+            Vec3::new(0.0, 2.2, 4.5),
+            Vec3::new(0.0, 0.0, -4.5),
+            5.6,
+            0.0,
+            PI * 0.3,
+        );
+        let renderer = Self::make_renderer(output).await;
+        Self {
+            renderer,
+            camera,
+            tris_bvh: tree,
+        }
+    }
+    pub async fn new_subdivided(output: RenderOutput) -> Self {
+        // the low poly head refined three times, then rippled along its normals
+        let mut mesh = Mesh::load_obj(
+            include_bytes!("../assets/suzanne_lp.obj"),
+            Material::new_metal(Vec3::new(0.8, 0.6, 0.3), 0.2),
+        )
+        .unwrap();
+        mesh.subdivide(3);
+        mesh.displace(|p, _| 0.01 * (p.y * 40.0).sin());
+        let mut tree: Tree = mesh.into();
+        let checker = tree.add_texture(Texture::checker(
+            Vec3::new(0.5, 0.5, 0.6),
+            Vec3::new(0.3, 0.3, 0.4),
+            2.0,
+        ));
+        let mesh = Mesh::load_obj(
+            include_bytes!("../assets/floor.obj"),
+            Material::new_lambertian(Vec3::ONE).with_albedo_texture(checker),
+        )
+        .unwrap();
         tree.add_mesh(mesh);
         tree.build();
         let camera = Camera::new(
@@ -157,11 +440,57 @@ impl SceneTris {
             tris_bvh: tree,
         }
     }
+    pub async fn new_points(output: RenderOutput) -> Self {
+        // a scanned dragon as coloured disks next to a mesh, both lit by each other
+        let mut cloud = PointCloud::load_ply(
+            include_bytes!("../assets/dragon_points.ply"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .expect("bundled point cloud is valid");
+        // behind the sphere rather than through it
+        for p in cloud.positions.iter_mut() {
+            *p -= Vec3::new(1.0, 0.0, 2.5);
+        }
+        let mut tree = Tree::new();
+        tree.add_point_cloud(cloud);
+        let mesh = Mesh::load_obj(
+            include_bytes!("../assets/ico_sphere.obj"),
+            Material::new_metal(Vec3::new(0.8, 0.8, 0.9), 0.05),
+        )
+        .unwrap();
+        tree.add_mesh(mesh);
+        let checker = tree.add_texture(Texture::checker(
+            Vec3::new(0.5, 0.5, 0.6),
+            Vec3::new(0.3, 0.3, 0.4),
+            2.0,
+        ));
+        let mesh = Mesh::load_obj(
+            include_bytes!("../assets/floor.obj"),
+            Material::new_lambertian(Vec3::ONE).with_albedo_texture(checker),
+        )
+        .unwrap();
+        tree.add_mesh(mesh);
+        tree.build();
+        let renderer = Self::make_renderer(output).await;
+        let camera = Camera::new(
+            Vec3::new(0.0, 2.0, 8.0),
+            Vec3::new(0.0, 0.0, -8.0),
+            5.6,
+            0.0,
+            PI * 0.3,
+        );
+        Self {
+            renderer,
+            camera,
+            tris_bvh: tree,
+        }
+    }
     pub async fn new_cube(output: RenderOutput) -> Self {
         let mesh = Mesh::load_obj(
             include_bytes!("../assets/cube2.obj"),
             Material::new_lambertian(Vec3::new(0.5, 0.5, 0.6)),
-        );
+        )
+        .unwrap();
         let mut tree: Tree = mesh.into();
         tree.build();
         let camera = Camera::new(
@@ -178,11 +507,64 @@ impl SceneTris {
             tris_bvh: tree,
         }
     }
+    /// A mesh loaded at run time, scaled to two units, centred and stood on the floor so
+    /// the orbit camera finds it whatever units it was modelled in. The tree is built on
+    /// the GPU by `init`, which fails with `BufferTooLarge` for meshes too large for the
+    /// device's buffers, see `Mesh::decimate` to fit them.
+    pub async fn from_mesh(mesh: Mesh, output: RenderOutput) -> Self {
+        Self {
+            renderer: Self::make_renderer(output).await,
+            camera: mesh_camera(),
+            tris_bvh: mesh_tree(mesh),
+        }
+    }
+    /// `from_mesh` over the mesh in `source`, read from `path`, keeping the built tree in
+    /// the cache under a hash of the source and all else the tree depends on, see
+    /// `bvh::cache`. With a valid cache the source is not even parsed, the tree goes
+    /// straight to the GPU in `init`. Fails with `BufferTooLarge` as `init` does.
+    pub async fn load_mesh(
+        path: &str,
+        source: &[u8],
+        material: Material,
+        output: RenderOutput,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let renderer = Self::make_renderer(output).await;
+        let floor = include_bytes!("../assets/floor.obj");
+        let key = cache::key(&[source, bytemuck::bytes_of(&material), floor]);
+        if let Some(tree) = cache::load(key) {
+            return Ok(Self {
+                renderer,
+                camera: mesh_camera(),
+                tris_bvh: tree,
+            });
+        }
+        let mesh = Mesh::load(path, source, material)?;
+        let mut scene = Self {
+            renderer,
+            camera: mesh_camera(),
+            tris_bvh: mesh_tree(mesh),
+        };
+        scene.build_tree_on_gpu()?;
+        // a cache that cannot be written only costs the next launch its time
+        let _ = cache::save(&scene.tris_bvh, key);
+        Ok(scene)
+    }
+    /// Bounds of the mesh `from_mesh` or `load_mesh` stood on the floor, leaving the floor
+    /// out. `None` once it has no triangles left.
+    pub fn mesh_bounds(&self) -> Option<(Vec3, Vec3)> {
+        // `mesh_tree` adds the mesh first, so its triangles are those of material 0
+        let mesh = self.tris_bvh.triangles.iter().filter(|t| t.material == 0);
+        mesh.flat_map(|t| [t.a, t.b, t.c]).map(Vec4::truncate).fold(None, |bounds, p| {
+            let (min, max) = bounds.unwrap_or((p, p));
+            Some((min.min(p), max.max(p)))
+        })
+    }
     pub async fn new_quad(output: RenderOutput) -> Self {
         let mesh = Mesh::load_obj(
             include_bytes!("../assets/quad.obj"),
             Material::new_lambertian(Vec3::new(0.5, 0.5, 0.6)),
-        );
+        )
+        .unwrap();
         let mut tree: Tree = mesh.into();
         tree.build();
         let camera = Camera::new(
@@ -204,28 +586,323 @@ impl SceneTris {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::scene::render_ppm;
+    use crate::scene::bvh::{tree::links, wide, LEAF};
+    use crate::scene::{render_ppm, render_rgb};
     use crate::scene::Scene;
+    use glam::Vec4;
     use std::io::Write;
 
+    #[test]
+    fn buffers_grow() {
+        let mut scene = pollster::block_on(SceneTris::new_cube(RenderOutput::Headless(64, 64)));
+        assert_eq!(scene.renderer.buffers[1].buffers[5].size(), 128);
+        scene.init().unwrap();
+        let needed = scene.tris_bvh.attributes.len() * size_of::<TriangleAttributes>();
+        let size = scene.renderer.buffers[1].buffers[5].size();
+        assert_eq!(size, (needed as u64).next_power_of_two());
+        // the tree size is a uniform, far smaller than any storage buffer
+        let limit = scene.renderer.max_buffer_size(0);
+        let data = vec![0; limit as usize + 4];
+        let error = scene.renderer.write_buffer(&data, 0).unwrap_err();
+        assert_eq!(error.buffer, 0);
+        assert_eq!(error.size, limit + 4);
+        assert!(error.to_string().starts_with("scene buffer 0 needs"));
+    }
+
+    #[test]
+    fn animate() {
+        let output = || RenderOutput::Headless(64, 64);
+        let mut scene = pollster::block_on(SceneTris::new_cube(output()));
+        let mut reference = pollster::block_on(SceneTris::new_cube(output()));
+        scene.init().unwrap();
+        let mut mesh = Mesh::load_obj(
+            include_bytes!("../assets/cube2.obj"),
+            Material::new_lambertian(Vec3::new(0.5, 0.5, 0.6)),
+        )
+        .unwrap();
+        for _ in 0..4 {
+            mesh.translate(Vec3::new(0.0, 0.3, 0.1));
+            scene.tris_bvh.update_mesh(0, &mesh);
+            scene.tris_bvh.refit();
+            assert!(scene.tris_bvh.changes.triangles.is_some());
+            scene.write_tree_changes();
+            assert_eq!(scene.tris_bvh.changes, Changes::default());
+        }
+        // the partial uploads render the same as uploading the moved mesh whole
+        reference.tris_bvh.update_mesh(0, &mesh);
+        reference.tris_bvh.refit();
+        reference.init().unwrap();
+        scene.draw();
+        reference.draw();
+        assert_eq!(
+            render_ppm(&mut scene.renderer),
+            render_ppm(&mut reference.renderer)
+        );
+    }
+
+    #[test]
+    fn heatmap() {
+        use crate::renderer::FLAG_HEATMAP;
+
+        let quad = Mesh::load_obj(
+            include_bytes!("../assets/quad.obj"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap();
+        // the centre pixel of the heatmap over `copies` quads in the same place
+        let centre = |copies: usize| {
+            let output = RenderOutput::Headless(32, 32);
+            let mut scene = pollster::block_on(SceneTris::new_quad(output));
+            scene.tris_bvh = Tree::new();
+            for _ in 0..copies {
+                scene.tris_bvh.add_mesh(quad.clone());
+            }
+            scene.tris_bvh.build();
+            scene.init().unwrap();
+            scene.set_flags(FLAG_HEATMAP);
+            scene.draw();
+            let ppm = render_ppm(&mut scene.renderer);
+            let values: Vec<u8> = ppm
+                .split_whitespace()
+                .skip(4)
+                .map(|v| v.parse().unwrap())
+                .collect();
+            let i = 3 * (16 * 32 + 16);
+            [values[i], values[i + 1], values[i + 2]]
+        };
+        assert_ne!(centre(1), [255, 0, 255]);
+        // a ray there has to test every copy, more than traversal takes steps
+        assert_eq!(centre(1000), [255, 0, 255]);
+    }
+
+    #[test]
+    fn wide() {
+        let output = || RenderOutput::Headless(64, 48);
+        let render = |scene: &mut SceneTris| {
+            scene.draw();
+            render_ppm(&mut scene.renderer)
+        };
+        let mut binary = pollster::block_on(SceneTris::new_suzane(output()));
+        binary.tris_bvh.width = 2;
+        binary.init().unwrap();
+        let expected = render(&mut binary);
+        for width in wide::WIDTHS {
+            let mut scene = pollster::block_on(SceneTris::new_suzane(output()));
+            scene.tris_bvh.width = width;
+            scene.init().unwrap();
+            assert_eq!(render(&mut scene), expected);
+        }
+    }
+
+    #[test]
+    fn motion_blur() {
+        let quad = Mesh::load_obj(
+            include_bytes!("../assets/quad.obj"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap();
+        let offset = Vec3::new(0.6, 0.0, 0.0);
+        let render = |moving: bool, (open, close): (f32, f32)| {
+            let output = RenderOutput::Headless(32, 32);
+            let mut scene = pollster::block_on(SceneTris::new_quad(output));
+            scene.camera = scene.camera.with_shutter(open, close);
+            scene.tris_bvh = Tree::new();
+            if moving {
+                scene.tris_bvh.add_moving_mesh(quad.clone(), Motion::linear(offset)).unwrap();
+            } else {
+                let mut moved = quad.clone();
+                moved.translate(offset * open);
+                scene.tris_bvh.add_mesh(moved);
+            }
+            scene.init().unwrap();
+            scene.draw();
+            render_ppm(&mut scene.renderer)
+        };
+        // a closed shutter sees the quad where it is at that time, just as if it stood there
+        assert_eq!(render(true, (0.0, 0.0)), render(false, (0.0, 0.0)));
+        assert_eq!(render(true, (1.0, 1.0)), render(false, (1.0, 1.0)));
+        let blurred = render(true, (0.0, 1.0));
+        assert_ne!(blurred, render(false, (0.0, 0.0)));
+        assert_ne!(blurred, render(false, (1.0, 1.0)));
+    }
+
+    /// The first `len` elements of custom buffer `buffer`.
+    fn read_buffer<T: bytemuck::Pod>(renderer: &Renderer, buffer: usize, len: usize) -> Vec<T> {
+        let size = (len * size_of::<T>()) as u64;
+        let device = &renderer.device;
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            size,
+            mapped_at_creation: false,
+            label: None,
+        });
+        let mut encoder = device.create_command_encoder(&Default::default());
+        let source = &renderer.buffers[1].buffers[buffer];
+        encoder.copy_buffer_to_buffer(source, 0, &readback, 0, size);
+        renderer.queue.submit(Some(encoder.finish()));
+        readback.slice(..).map_async(wgpu::MapMode::Read, |_| {});
+        let _ = device.poll(wgpu::MaintainBase::Wait);
+        let data = bytemuck::cast_slice(&readback.slice(..).get_mapped_range()).to_vec();
+        data
+    }
+
+    #[test]
+    fn gpu_tree() {
+        use glam::UVec3;
+
+        let mesh = Mesh::load_obj(
+            include_bytes!("../assets/suzanne.obj"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap();
+        let triangles = mesh.indices.len() / 3;
+        let mut scene = pollster::block_on(SceneTris::from_mesh(
+            mesh,
+            RenderOutput::Headless(64, 64),
+        ));
+        assert!(!scene.tris_bvh.is_built());
+        // the nodes as the GPU left them, not collapsed
+        scene.tris_bvh.width = 2;
+        scene.init().unwrap();
+        let tree = &scene.tris_bvh;
+        assert!(tree.is_built());
+        // suzanne and the floor, each triangle in exactly one slot
+        assert!(tree.triangles.len() > triangles);
+        let mut slots = tree.slots.clone();
+        slots.sort_unstable();
+        assert!(slots.into_iter().eq(0..tree.triangles.len() as u32));
+        // the CPU copy is what the GPU built
+        let nodes: Vec<Node> = read_buffer(&scene.renderer, 1, tree.nodes.len());
+        for (a, b) in nodes.iter().zip(&tree.nodes) {
+            assert_eq!(format!("{:?}", a), format!("{:?}", b));
+        }
+        // and the GPU fitted the nodes as the CPU would over the links it made
+        let mut fitted = Tree::new();
+        fitted.triangles = tree.triangles.clone();
+        fitted.nodes = tree.nodes.clone();
+        fitted.fit_nodes();
+        for (a, b) in fitted.nodes.iter().zip(&tree.nodes) {
+            assert_eq!(format!("{:?}", a), format!("{:?}", b));
+        }
+        let gpu: Vec<Triangle> = read_buffer(&scene.renderer, 2, tree.triangles.len());
+        for (a, b) in gpu.iter().zip(&tree.triangles) {
+            assert_eq!([a.a, a.b, a.c], [b.a, b.b, b.c]);
+            assert_eq!(a.material, b.material);
+            assert!(a.custom.abs_diff_eq(b.custom, 1e-5));
+        }
+        // sorted along the Morton curve, but for rounding on the GPU
+        let centers: Vec<Vec3> = tree
+            .triangles
+            .iter()
+            .map(|t| (t.a + t.b + t.c).truncate() / 3.0)
+            .collect();
+        let min = centers.iter().fold(Vec3::MAX, |a, &c| a.min(c));
+        let max = centers.iter().fold(Vec3::MIN, |a, &c| a.max(c));
+        let expand = |v: u32| (0..10).map(|b| (v >> b & 1) << (3 * b)).sum::<u32>();
+        let codes: Vec<u32> = centers
+            .iter()
+            .map(|&c| {
+                let q = ((c - min) / (max - min) * 1024.0).as_uvec3().min(UVec3::splat(1023));
+                expand(q.x) << 2 | expand(q.y) << 1 | expand(q.z)
+            })
+            .collect();
+        let unsorted = codes.windows(2).filter(|w| w[0] > w[1]).count();
+        assert!(unsorted < codes.len() / 100, "{unsorted}");
+    }
+
+    #[test]
+    fn karras() {
+        use glam::UVec3;
+
+        // tiny triangles on a 4x4x4 lattice, some twice, whose codes are far from rounding
+        let delta = 0.01;
+        let points: Vec<Vec3> = (0..64)
+            .chain([0, 5, 5, 21, 63])
+            .map(|i| Vec3::new((i % 4) as f32, (i / 4 % 4) as f32, (i / 16) as f32))
+            .collect();
+        let m = points.len();
+        let mut source = String::new();
+        for k in 0..m {
+            // shuffled, so the sort has work to do
+            let p = points[k * 31 % m];
+            for v in [p, p + Vec3::X * delta, p + Vec3::Y * delta] {
+                source += &format!("v {} {} {}\n", v.x, v.y, v.z);
+            }
+            source += &format!("f {} {} {}\n", 3 * k + 1, 3 * k + 2, 3 * k + 3);
+        }
+        let mesh = Mesh::load_obj(source.as_bytes(), Material::new_lambertian(Vec3::ONE)).unwrap();
+        let mut scene = pollster::block_on(SceneTris::new_quad(RenderOutput::Headless(16, 16)));
+        scene.tris_bvh = mesh.into();
+        scene.build_tree_on_gpu().unwrap();
+        let tree = &scene.tris_bvh;
+        assert!(tree.is_built());
+        // the codes of the triangles as added, each lattice step a third of the range
+        let code = |k: usize| {
+            let q = (points[k * 31 % m].as_uvec3() * 341).min(UVec3::splat(1023));
+            let expand = |v: u32| (0..10).map(|b| (v >> b & 1) << (3 * b)).sum::<u32>();
+            expand(q.x) << 2 | expand(q.y) << 1 | expand(q.z)
+        };
+        // slots in the stable order of the codes
+        let mut order: Vec<usize> = (0..m).collect();
+        order.sort_by_key(|&k| code(k));
+        for (slot, &k) in order.iter().enumerate() {
+            assert_eq!(tree.slots[k] as usize, slot);
+        }
+        // every node splits its slots where their codes, then slots, first differ
+        let key = |slot: usize| (code(order[slot]) as u64) << 32 | slot as u64;
+        let mut stack = vec![(0, 0, m - 1)];
+        let mut visited = 0;
+        while let Some((j, a, b)) = stack.pop() {
+            visited += 1;
+            let prefix = (key(a) ^ key(b)).leading_zeros();
+            let split = (a..b).filter(|&k| (key(a) ^ key(k)).leading_zeros() > prefix).max();
+            let split = split.unwrap_or(a);
+            assert_eq!(tree.nodes[j].children(), links(a, split, b), "node {j}");
+            let [left, right] = tree.nodes[j].children();
+            if left & LEAF == 0 {
+                stack.push((left as usize, a, split));
+            }
+            if right & LEAF == 0 {
+                stack.push((right as usize, split + 1, b));
+            }
+        }
+        assert_eq!(visited, m - 1);
+    }
+
     #[test]
     fn suzanne() {
         let width = 1024;
         let height = 768;
         let mut scene =
             pollster::block_on(SceneTris::new_suzane(RenderOutput::Headless(width, height)));
-        scene.init();
+        scene.init().unwrap();
         let content = render_ppm(&mut scene.renderer);
         let mut file = std::fs::File::create("suzanne.ppm").unwrap();
         file.write_all(content.as_bytes()).unwrap();
     }
     #[test]
+    fn from_mesh() {
+        let mesh = Mesh::load_file(
+            "src/assets/lucy_lp_20.obj",
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap();
+        let mut scene =
+            pollster::block_on(SceneTris::from_mesh(mesh, RenderOutput::Headless(64, 48)));
+        let (min, max) = scene.mesh_bounds().unwrap();
+        assert!(min.y.abs() < 1e-5);
+        assert!(((max - min).max_element() - 2.0).abs() < 1e-5);
+        assert!((min.x + max.x).abs() < 1e-5 && (min.z + max.z).abs() < 1e-5);
+        scene.tris_bvh = Tree::new();
+        assert_eq!(scene.mesh_bounds(), None);
+    }
+    #[test]
     fn simple_quad() {
         let width = 1024;
         let height = 768;
         let mut scene =
             pollster::block_on(SceneTris::new_quad(RenderOutput::Headless(width, height)));
-        scene.init();
+        scene.init().unwrap();
         let content = render_ppm(&mut scene.renderer);
         let mut file = std::fs::File::create("quad.ppm").unwrap();
         file.write_all(content.as_bytes()).unwrap();
@@ -236,9 +913,132 @@ mod tests {
         let height = 768;
         let mut scene =
             pollster::block_on(SceneTris::new_cube(RenderOutput::Headless(width, height)));
-        scene.init();
+        scene.init().unwrap();
         let content = render_ppm(&mut scene.renderer);
         let mut file = std::fs::File::create("cube.ppm").unwrap();
         file.write_all(content.as_bytes()).unwrap();
     }
+    /// The frame after `samples` draws, as RGB bytes.
+    fn render_frame(scene: &mut SceneTris, samples: u32) -> Vec<u8> {
+        for i in 0..samples {
+            scene.set_time(1000 + i * 10);
+            scene.draw();
+        }
+        render_rgb(&mut scene.renderer)
+    }
+
+    /// The traversal step heatmap of a single draw.
+    fn heatmap_frame(scene: &mut SceneTris) -> Vec<u8> {
+        scene.set_flags(crate::renderer::FLAG_HEATMAP);
+        scene.reset_frame_count();
+        scene.draw();
+        let frame = render_rgb(&mut scene.renderer);
+        scene.set_flags(0);
+        scene.reset_frame_count();
+        frame
+    }
+
+    /// How many pixels differ by more than `tolerance` in some channel.
+    fn differing(a: &[u8], b: &[u8], tolerance: u8) -> usize {
+        a.chunks(3)
+            .zip(b.chunks(3))
+            .filter(|(a, b)| a.iter().zip(b.iter()).any(|(a, b)| a.abs_diff(*b) > tolerance))
+            .count()
+    }
+
+    const WIDTH: u32 = 128;
+    const HEIGHT: u32 = 96;
+    const PIXELS: usize = (WIDTH * HEIGHT) as usize;
+
+    #[test]
+    fn bumpy() {
+        let output = || RenderOutput::Headless(WIDTH, HEIGHT);
+        let mut bumpy = pollster::block_on(SceneTris::new_bumpy(output()));
+        let mut smooth = pollster::block_on(SceneTris::new_bumpy(output()));
+        // the same head and floor with their bumps flattened
+        for i in 0..2 {
+            let material = smooth.tris_bvh.materials[i];
+            smooth.tris_bvh.set_material(i as u32, material.with_bump_map(0, 0.0));
+        }
+        bumpy.init().unwrap();
+        smooth.init().unwrap();
+        // bumps only bend the shading normals, rays hit the same triangles
+        assert_eq!(heatmap_frame(&mut bumpy), heatmap_frame(&mut smooth));
+        let bumpy = render_frame(&mut bumpy, 4);
+        let smooth = render_frame(&mut smooth, 4);
+        assert!(differing(&bumpy, &smooth, 8) > PIXELS / 20);
+        // the sky along the top is seen directly either way
+        let sky = 3 * WIDTH as usize * 4;
+        assert_eq!(bumpy[..sky], smooth[..sky]);
+    }
+
+    #[test]
+    fn subdivided() {
+        let low = Mesh::load_obj(
+            include_bytes!("../assets/suzanne_lp.obj"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap();
+        let mut smooth = low.clone();
+        smooth.subdivide(3);
+        let scene = pollster::block_on(SceneTris::new_subdivided(RenderOutput::Headless(64, 48)));
+        let tree = &scene.tris_bvh;
+        let head = smooth.indices.len() / 3;
+        // each subdivision splits every triangle in four
+        assert_eq!(head, 64 * low.indices.len() / 3);
+        assert_eq!(tree.triangles.iter().filter(|t| t.material == 0).count(), head);
+        // the ripple moves the refined vertices at most 0.01 along their normals
+        let mut largest = 0.0f32;
+        for (k, t) in smooth.indices.chunks_exact(3).enumerate() {
+            let displaced = &tree.triangles[tree.slots[k] as usize];
+            for (i, p) in [displaced.a, displaced.b, displaced.c].into_iter().enumerate() {
+                let original = Vec4::from_array(smooth.vertices[t[i] as usize].position);
+                largest = largest.max((p - original).truncate().length());
+            }
+        }
+        assert!(largest > 0.009 && largest < 0.0101, "{largest}");
+    }
+
+    #[test]
+    fn points() {
+        let output = || RenderOutput::Headless(WIDTH, HEIGHT);
+        let mut plain = pollster::block_on(SceneTris::new_points(output()));
+        let mut glowing = pollster::block_on(SceneTris::new_points(output()));
+        // the point cloud is added first, light it up red
+        let material = glowing.tris_bvh.materials[0];
+        glowing.tris_bvh.set_material(0, material.with_emission(Vec3::new(8.0, 0.0, 0.0)));
+        plain.init().unwrap();
+        glowing.init().unwrap();
+        let plain = render_frame(&mut plain, 4);
+        let glowing = render_frame(&mut glowing, 4);
+        // pixels that saturate red are the disks, seen directly or in the sphere
+        let red: Vec<usize> = (0..PIXELS)
+            .filter(|&i| glowing[3 * i] == 255 && plain[3 * i] < 250)
+            .collect();
+        assert!(red.len() > PIXELS / 50 && red.len() < PIXELS / 4, "{}", red.len());
+        // the dragon stands to the left of the sphere
+        let x = red.iter().map(|i| i % WIDTH as usize).sum::<usize>() / red.len();
+        assert!(x < WIDTH as usize / 2, "{x}");
+    }
+
+    #[test]
+    fn fence() {
+        let output = || RenderOutput::Headless(WIDTH, HEIGHT);
+        let mut cut = pollster::block_on(SceneTris::new_fence(output()));
+        let mut solid = pollster::block_on(SceneTris::new_fence(output()));
+        // the fence and the floor without their holes
+        for i in 1..3 {
+            let material = solid.tris_bvh.materials[i];
+            solid.tris_bvh.set_material(i as u32, material.with_cutout(0.0));
+        }
+        cut.init().unwrap();
+        solid.init().unwrap();
+        let cut = render_frame(&mut cut, 4);
+        let solid = render_frame(&mut solid, 4);
+        // without holes the gaps between the wires and the clear floor cells are black,
+        // with them the rays carry on to the head and the sky
+        let dark = |frame: &[u8]| frame.chunks(3).filter(|p| p.iter().all(|&c| c < 8)).count();
+        assert!(dark(&solid) > PIXELS / 5, "{}", dark(&solid));
+        assert!(dark(&cut) < PIXELS / 100, "{}", dark(&cut));
+    }
 }
@@ -0,0 +1,272 @@
+use glam::Vec3;
+
+/// Shapes are only marched within this distance of the origin, repetition would otherwise
+/// give infinite bounds.
+const FAR: f32 = 1000.0;
+
+/// Signed distance expression, compiled into the sphere shader by `compile`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sdf {
+    Sphere(f32),
+    /// Half extents.
+    Box(Vec3),
+    /// Ring in the XZ plane with major and minor radius.
+    Torus(f32, f32),
+    /// Segment between two points, inflated by a radius.
+    Capsule(Vec3, Vec3, f32),
+    Translate(Vec3, Box<Sdf>),
+    Union(Box<Sdf>, Box<Sdf>),
+    /// Union blended over a distance `k`.
+    SmoothUnion(f32, Box<Sdf>, Box<Sdf>),
+    /// The first shape minus the second.
+    Subtract(Box<Sdf>, Box<Sdf>),
+    Intersect(Box<Sdf>, Box<Sdf>),
+    /// Infinite repetition with the given period, 0 leaves an axis alone.
+    Repeat(Vec3, Box<Sdf>),
+}
+
+impl Sdf {
+    /// Reads a prefix expression such as `(smooth_union 0.2 (sphere 1) (box 1 0.5 1))`.
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let source = source.replace('(', " ( ").replace(')', " ) ");
+        let mut tokens = source.split_whitespace();
+        let sdf = Self::parse_tokens(&mut tokens)?;
+        match tokens.next() {
+            Some(token) => Err(format!("unexpected `{token}` after shape")),
+            None => Ok(sdf),
+        }
+    }
+    fn parse_tokens<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Result<Self, String> {
+        match tokens.next() {
+            Some("(") => {}
+            Some(token) => return Err(format!("expected `(`, found `{token}`")),
+            None => return Err("missing shape".to_string()),
+        }
+        let name = tokens.next().ok_or("missing shape name")?;
+        let number = |tokens: &mut dyn Iterator<Item = &'a str>| {
+            let token = tokens.next().ok_or(format!("missing number in `{name}`"))?;
+            match token.parse::<f32>() {
+                Ok(x) if x.is_finite() => Ok(x),
+                _ => Err(format!("expected a number in `{name}`, found `{token}`")),
+            }
+        };
+        let sdf = match name {
+            "sphere" => Self::Sphere(number(tokens)?),
+            "box" => Self::Box(Vec3::new(number(tokens)?, number(tokens)?, number(tokens)?)),
+            "torus" => Self::Torus(number(tokens)?, number(tokens)?),
+            "capsule" => {
+                let a = Vec3::new(number(tokens)?, number(tokens)?, number(tokens)?);
+                let b = Vec3::new(number(tokens)?, number(tokens)?, number(tokens)?);
+                Self::Capsule(a, b, number(tokens)?)
+            }
+            "translate" => {
+                let offset = Vec3::new(number(tokens)?, number(tokens)?, number(tokens)?);
+                Self::Translate(offset, Box::new(Self::parse_tokens(tokens)?))
+            }
+            "repeat" => {
+                let period = Vec3::new(number(tokens)?, number(tokens)?, number(tokens)?);
+                Self::Repeat(period, Box::new(Self::parse_tokens(tokens)?))
+            }
+            "smooth_union" => {
+                let k = number(tokens)?;
+                let a = Box::new(Self::parse_tokens(tokens)?);
+                Self::SmoothUnion(k, a, Box::new(Self::parse_tokens(tokens)?))
+            }
+            "union" | "subtract" | "intersect" => {
+                let a = Box::new(Self::parse_tokens(tokens)?);
+                let b = Box::new(Self::parse_tokens(tokens)?);
+                match name {
+                    "union" => Self::Union(a, b),
+                    "subtract" => Self::Subtract(a, b),
+                    _ => Self::Intersect(a, b),
+                }
+            }
+            _ => return Err(format!("unknown shape `{name}`")),
+        };
+        match tokens.next() {
+            Some(")") => Ok(sdf),
+            Some(token) => Err(format!("expected `)` to close `{name}`, found `{token}`")),
+            None => Err(format!("missing `)` to close `{name}`")),
+        }
+    }
+    /// Conservative axis aligned bounds of the surface.
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        let (min, max) = match self {
+            Self::Sphere(r) => (Vec3::splat(-r), Vec3::splat(*r)),
+            Self::Box(h) => (-*h, *h),
+            Self::Torus(major, minor) => {
+                let h = Vec3::new(major + minor, *minor, major + minor);
+                (-h, h)
+            }
+            Self::Capsule(a, b, r) => (a.min(*b) - *r, a.max(*b) + *r),
+            Self::Translate(offset, s) => {
+                let (min, max) = s.bounds();
+                (min + *offset, max + *offset)
+            }
+            Self::Union(a, b) => {
+                let (a, b) = (a.bounds(), b.bounds());
+                (a.0.min(b.0), a.1.max(b.1))
+            }
+            Self::SmoothUnion(k, a, b) => {
+                // blending pulls the surface out by at most k/4
+                let (a, b) = (a.bounds(), b.bounds());
+                (a.0.min(b.0) - k * 0.25, a.1.max(b.1) + k * 0.25)
+            }
+            Self::Subtract(a, _) => a.bounds(),
+            Self::Intersect(a, b) => {
+                let (a, b) = (a.bounds(), b.bounds());
+                (a.0.max(b.0), a.1.min(b.1))
+            }
+            Self::Repeat(period, s) => {
+                let (min, max) = s.bounds();
+                let repeated = period.cmpne(Vec3::ZERO);
+                (
+                    Vec3::select(repeated, Vec3::splat(-FAR), min),
+                    Vec3::select(repeated, Vec3::splat(FAR), max),
+                )
+            }
+        };
+        (min.max(Vec3::splat(-FAR)), max.min(Vec3::splat(FAR)))
+    }
+    /// Appends statements computing the distance at point `p` and returns the variable
+    /// holding it.
+    fn emit(&self, p: &str, code: &mut String, next: &mut usize) -> String {
+        let expr = match self {
+            Self::Sphere(r) => format!("length({p}) - {}", float(*r)),
+            Self::Box(h) => format!("sdf_box({p}, {})", vec3(*h)),
+            Self::Torus(major, minor) => {
+                format!("sdf_torus({p}, vec2f({}, {}))", float(*major), float(*minor))
+            }
+            Self::Capsule(a, b, r) => {
+                format!("sdf_capsule({p}, {}, {}, {})", vec3(*a), vec3(*b), float(*r))
+            }
+            Self::Translate(offset, s) => {
+                let q = fresh("p", next);
+                code.push_str(&format!("  let {q} = {p} - {};\n", vec3(*offset)));
+                return s.emit(&q, code, next);
+            }
+            Self::Repeat(period, s) => {
+                let q = fresh("p", next);
+                let axes = [period.x, period.y, period.z]
+                    .iter()
+                    .zip(["x", "y", "z"])
+                    .map(|(&period, axis)| match period {
+                        0.0 => format!("{p}.{axis}"),
+                        _ => {
+                            let period = float(period);
+                            format!("{p}.{axis} - {period}*round({p}.{axis} / {period})")
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                code.push_str(&format!("  let {q} = vec3f({});\n", axes.join(", ")));
+                return s.emit(&q, code, next);
+            }
+            Self::Union(a, b) | Self::Subtract(a, b) | Self::Intersect(a, b) => {
+                let a = a.emit(p, code, next);
+                let b = b.emit(p, code, next);
+                match self {
+                    Self::Union(..) => format!("min({a}, {b})"),
+                    Self::Subtract(..) => format!("max({a}, -{b})"),
+                    _ => format!("max({a}, {b})"),
+                }
+            }
+            Self::SmoothUnion(k, a, b) => {
+                let a = a.emit(p, code, next);
+                let b = b.emit(p, code, next);
+                format!("sdf_smooth_union({a}, {b}, {})", float(*k))
+            }
+        };
+        let d = fresh("d", next);
+        code.push_str(&format!("  let {d} = {expr};\n"));
+        d
+    }
+}
+
+fn fresh(prefix: &str, next: &mut usize) -> String {
+    *next += 1;
+    format!("{prefix}{next}")
+}
+
+fn float(x: f32) -> String {
+    format!("{x:?}")
+}
+
+fn vec3(v: Vec3) -> String {
+    format!("vec3f({}, {}, {})", float(v.x), float(v.y), float(v.z))
+}
+
+const SDF_BEGIN: &str = "// @sdf begin";
+const SDF_END: &str = "// @sdf end";
+
+/// Replaces the placeholder `sdf_distance` in `shader` with one dispatching to `sdfs`,
+/// the index of each shape is its position in the slice.
+pub fn compile(shader: &str, sdfs: &[Sdf]) -> String {
+    let mut code = String::new();
+    for (i, sdf) in sdfs.iter().enumerate() {
+        let mut body = String::new();
+        let d = sdf.emit("p", &mut body, &mut 0);
+        code.push_str(&format!(
+            "fn sdf_{i}(p: vec3f) -> f32 {{\n{body}  return {d};\n}}\n"
+        ));
+    }
+    code.push_str("fn sdf_distance(index: u32, p: vec3f) -> f32 {\n  switch index {\n");
+    for i in 0..sdfs.len() {
+        code.push_str(&format!("    case {i}u: {{\n      return sdf_{i}(p);\n    }}\n"));
+    }
+    code.push_str("    default: {\n      return FLT_MAX;\n    }\n  }\n}\n");
+    let begin = shader.find(SDF_BEGIN).expect("shader has an sdf placeholder") + SDF_BEGIN.len();
+    let end = shader.find(SDF_END).expect("shader has an sdf placeholder");
+    format!("{}\n{code}{}", &shader[..begin], &shader[end..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let sdf = Sdf::parse("(smooth_union 0.5 (sphere 1) (translate 2 0 0 (box 1 2 3)))");
+        assert_eq!(
+            sdf,
+            Ok(Sdf::SmoothUnion(
+                0.5,
+                Box::new(Sdf::Sphere(1.0)),
+                Box::new(Sdf::Translate(
+                    Vec3::new(2.0, 0.0, 0.0),
+                    Box::new(Sdf::Box(Vec3::new(1.0, 2.0, 3.0)))
+                )),
+            ))
+        );
+        assert_eq!(Sdf::parse("(sphere 1"), Err("missing `)` to close `sphere`".into()));
+        assert_eq!(Sdf::parse("(cube 1)"), Err("unknown shape `cube`".into()));
+        assert!(Sdf::parse("(sphere inf)").is_err());
+        assert!(Sdf::parse("(sphere 1) (sphere 2)").is_err());
+    }
+
+    #[test]
+    fn bounds() {
+        let sdf = Sdf::parse("(subtract (translate 0 1 0 (torus 2 0.5)) (sphere 1))").unwrap();
+        assert_eq!(
+            sdf.bounds(),
+            (Vec3::new(-2.5, 0.5, -2.5), Vec3::new(2.5, 1.5, 2.5))
+        );
+        let sdf = Sdf::parse("(intersect (repeat 1 0 1 (sphere 0.3)) (box 4 4 4))").unwrap();
+        assert_eq!(
+            sdf.bounds(),
+            (Vec3::new(-4.0, -0.3, -4.0), Vec3::new(4.0, 0.3, 4.0))
+        );
+    }
+
+    #[test]
+    fn codegen() {
+        let shader = "a\n// @sdf begin\nold\n// @sdf end\nb";
+        let sdf = Sdf::parse("(repeat 2 0 0 (sphere 0.5))").unwrap();
+        let out = compile(shader, &[sdf]);
+        assert!(out.starts_with("a\n// @sdf begin\n"));
+        assert!(out.ends_with("// @sdf end\nb"));
+        assert!(!out.contains("old"));
+        assert!(out.contains("let p1 = vec3f(p.x - 2.0*round(p.x / 2.0), p.y, p.z);"));
+        assert!(out.contains("let d2 = length(p1) - 0.5;"));
+        assert!(out.contains("case 0u: {\n      return sdf_0(p);"));
+    }
+}
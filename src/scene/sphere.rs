@@ -1,4 +1,4 @@
-use crate::scene::Material;
+use crate::scene::{Material, Motion};
 use bytemuck::{Pod, Zeroable};
 use glam::Vec3;
 
@@ -7,15 +7,25 @@ use glam::Vec3;
 pub struct Sphere {
     center: Vec3,
     radius: f32,
+    motion: Motion,
     material: Material,
 }
 
 impl Sphere {
+    pub fn new(center: Vec3, radius: f32, material: Material) -> Self {
+        Self {
+            center,
+            radius,
+            motion: Motion::default(),
+            material,
+        }
+    }
     pub fn new_lambertian(center: Vec3, radius: f32, color: Vec3) -> Self {
         let material = Material::new_lambertian(color);
         Self {
             center,
             radius,
+            motion: Motion::default(),
             material,
         }
     }
@@ -24,6 +34,7 @@ impl Sphere {
         Self {
             center,
             radius,
+            motion: Motion::default(),
             material,
         }
     }
@@ -32,7 +43,18 @@ impl Sphere {
         Self {
             center,
             radius,
+            motion: Motion::default(),
             material,
         }
     }
+    /// Moves the sphere over the frame, see `Motion`.
+    pub fn with_motion(mut self, motion: Motion) -> Self {
+        self.motion = motion;
+        self
+    }
+    /// The box holding the sphere over the whole frame.
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        self.motion
+            .sweep(self.center - self.radius, self.center + self.radius)
+    }
 }
@@ -0,0 +1,202 @@
+use bytemuck::{Pod, Zeroable};
+use glam::{Vec2, Vec3, Vec4};
+use std::ops::Range;
+
+pub const CHECKER: u32 = 1;
+pub const NOISE: u32 = 2;
+pub const MARBLE: u32 = 3;
+pub const VORONOI: u32 = 4;
+pub const GRADIENT: u32 = 5;
+pub const IMAGE: u32 = 6;
+
+/// Sample by world space hit position.
+pub const MAPPING_WORLD: u32 = 0;
+/// Sample by surface UV, `z` is always 0.
+pub const MAPPING_UV: u32 = 1;
+
+/// A procedural pattern blending `color_a` into `color_b`, evaluated in the shader.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable, Default)]
+pub struct Texture {
+    color_a: Vec4,
+    color_b: Vec4,
+    params: Vec4,
+    kind: u32,
+    mapping: u32,
+    _padding: [u32; 2],
+}
+
+impl Texture {
+    fn new(kind: u32, color_a: Vec3, color_b: Vec3, params: Vec4) -> Self {
+        Self {
+            color_a: color_a.extend(1.0),
+            color_b: color_b.extend(1.0),
+            params,
+            kind,
+            mapping: MAPPING_WORLD,
+            _padding: [0; 2],
+        }
+    }
+    /// Alternating cells of size `1/scale`.
+    pub fn checker(color_a: Vec3, color_b: Vec3, scale: f32) -> Self {
+        Self::new(CHECKER, color_a, color_b, Vec4::new(scale, 0.0, 0.0, 0.0))
+    }
+    /// Perlin fBm summed over `octaves`.
+    pub fn noise(color_a: Vec3, color_b: Vec3, scale: f32, octaves: u32) -> Self {
+        Self::new(
+            NOISE,
+            color_a,
+            color_b,
+            Vec4::new(scale, octaves as f32, 0.0, 0.0),
+        )
+    }
+    /// Sine veins along `z` distorted by `turbulence` times a turbulence sum of `octaves`.
+    pub fn marble(color_a: Vec3, color_b: Vec3, scale: f32, turbulence: f32, octaves: u32) -> Self {
+        Self::new(
+            MARBLE,
+            color_a,
+            color_b,
+            Vec4::new(scale, turbulence, octaves as f32, 0.0),
+        )
+    }
+    /// Distance to the nearest jittered feature point, one per cell.
+    pub fn voronoi(color_a: Vec3, color_b: Vec3, scale: f32) -> Self {
+        Self::new(VORONOI, color_a, color_b, Vec4::new(scale, 0.0, 0.0, 0.0))
+    }
+    /// Linear ramp where `dot(p, direction) + offset` goes from 0 to 1.
+    pub fn gradient(color_a: Vec3, color_b: Vec3, direction: Vec3, offset: f32) -> Self {
+        Self::new(GRADIENT, color_a, color_b, direction.extend(offset))
+    }
+    /// Bilinear lookup into `width * height` texels starting at `offset` in the texel buffer,
+    /// use `add_image` on the scene rather than calling this directly.
+    pub fn image(offset: u32, width: u32, height: u32) -> Self {
+        let params = Vec4::new(
+            f32::from_bits(offset),
+            f32::from_bits(width),
+            f32::from_bits(height),
+            0.0,
+        );
+        Self::new(IMAGE, Vec3::ZERO, Vec3::ONE, params).with_mapping(MAPPING_UV)
+    }
+    /// The texels an image texture reads, as a range of the texel buffer.
+    pub(crate) fn texels(&self) -> Option<Range<u64>> {
+        let [offset, width, height, _] = self.params.to_array().map(|p| p.to_bits() as u64);
+        (self.kind == IMAGE).then(|| offset..offset + width * height)
+    }
+    pub fn with_mapping(mut self, mapping: u32) -> Self {
+        self.mapping = mapping;
+        self
+    }
+    /// Alpha blended alongside the colours, read by cutout materials. Images use their own
+    /// alpha channel instead.
+    pub fn with_alpha(mut self, alpha_a: f32, alpha_b: f32) -> Self {
+        self.color_a.w = alpha_a;
+        self.color_b.w = alpha_b;
+        self
+    }
+}
+
+/// Linear RGBA8 pixels, top row first, each packed little endian into a `u32`.
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub texels: Vec<u32>,
+}
+
+impl Image {
+    pub fn from_fn(width: u32, height: u32, f: impl Fn(u32, u32) -> [u8; 4]) -> Self {
+        let texels = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| u32::from_le_bytes(f(x, y)))
+            .collect();
+        Self {
+            width,
+            height,
+            texels,
+        }
+    }
+    /// Decodes an 8 or 16 bit PNG of any colour type. Values are kept as stored, which is
+    /// what normal and height maps want.
+    pub fn load_png(source: &[u8]) -> Result<Self, png::DecodingError> {
+        let mut decoder = png::Decoder::new(source);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info()?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf)?;
+        let channels = info.color_type.samples();
+        let texels = buf[..info.buffer_size()]
+            .chunks_exact(channels)
+            .map(|p| match *p {
+                [l] => [l, l, l, 255],
+                [l, a] => [l, l, l, a],
+                [r, g, b] => [r, g, b, 255],
+                [r, g, b, a] => [r, g, b, a],
+                _ => unreachable!(),
+            })
+            .map(u32::from_le_bytes)
+            .collect();
+        Ok(Self {
+            width: info.width,
+            height: info.height,
+            texels,
+        })
+    }
+    /// Bilinear lookup at `uv` in 0 to 1, wrapping around like the shaders' image textures.
+    pub fn sample(&self, uv: Vec2) -> Vec4 {
+        let (w, h) = (self.width as i32, self.height as i32);
+        let texel = |x: i32, y: i32| {
+            let i = y.rem_euclid(h) * w + x.rem_euclid(w);
+            let rgba = self.texels[i as usize].to_le_bytes();
+            Vec4::from_array(rgba.map(|c| c as f32 / 255.0))
+        };
+        // rows go top to bottom while v goes up
+        let st = Vec2::new(uv.x, 1.0 - uv.y) * Vec2::new(w as f32, h as f32) - 0.5;
+        let i = st.floor();
+        let f = st - i;
+        let (x, y) = (i.x as i32, i.y as i32);
+        let top = texel(x, y).lerp(texel(x + 1, y), f.x);
+        let bottom = texel(x, y + 1).lerp(texel(x + 1, y + 1), f.x);
+        top.lerp(bottom, f.y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        // must match `struct Texture` in the shaders
+        assert_eq!(std::mem::size_of::<Texture>(), 64);
+    }
+
+    #[test]
+    fn mapping() {
+        let texture = Texture::checker(Vec3::ZERO, Vec3::ONE, 2.0);
+        assert_eq!(texture.mapping, MAPPING_WORLD);
+        let texture = texture.with_mapping(MAPPING_UV);
+        assert_eq!(texture.mapping, MAPPING_UV);
+        assert_eq!(texture.kind, CHECKER);
+        let texture = texture.with_alpha(0.0, 1.0);
+        assert_eq!((texture.color_a.w, texture.color_b.w), (0.0, 1.0));
+    }
+
+    #[test]
+    fn png() {
+        let mut source = Vec::new();
+        let mut encoder = png::Encoder::new(&mut source, 2, 1);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[255, 0, 0, 128, 128, 255]).unwrap();
+        writer.finish().unwrap();
+        let image = Image::load_png(&source).unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(image.texels, [0xff0000ff, 0xffff8080]);
+        assert!(Image::load_png(b"not a png").is_err());
+        // texel centres and the wrapped midpoint between the two
+        assert_eq!(image.sample(Vec2::new(0.25, 0.5)), Vec4::new(1.0, 0.0, 0.0, 1.0));
+        assert_eq!(image.sample(Vec2::new(0.75, 0.5)).x, 128.0 / 255.0);
+        assert!((image.sample(Vec2::new(0.0, 0.5)).x - (1.0 + 128.0 / 255.0) / 2.0).abs() < 1e-6);
+    }
+}
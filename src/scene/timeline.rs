@@ -0,0 +1,525 @@
+//! Keyframed animation of a `SceneTris`, rendered headless one frame at a time. A timeline
+//! keys the camera, where meshes are and the colours of their materials at times in
+//! seconds, and values follow a line or a smooth curve between keys. Timelines are built
+//! in code or read from text, one statement per line, `#` starts a comment, angles are in
+//! degrees:
+//!
+//! ```text
+//! fps      FRAMES_PER_SECOND
+//! frames   COUNT
+//! shutter  FRACTION_OF_A_FRAME
+//! camera   TIME FROM(x y z) TO(x y z) FOCAL_LENGTH BLUR FOV [linear|bezier]
+//! move     TIME MESH TRANSLATION(x y z) ROTATION(x y z) SCALE [linear|bezier]
+//! albedo   TIME MESH R G B [linear|bezier]
+//! emission TIME MESH R G B [linear|bezier]
+//! ```
+//!
+//! Meshes are numbered in the order they were added to the tree, which is the index of
+//! their material. `move` places a mesh relative to where it was added: scaled, turned
+//! about the origin by Euler angles in XYZ order, then moved. The interpolation named on a
+//! key runs from that key to the next, linear if left out:
+//!
+//! ```text
+//! frames 48
+//! camera 0 0 2 5  0 1 0  4.6 0 54
+//! camera 2 4 2 2  0 1 0  4.6 0 54 bezier
+//! move   0 0  0 0 0  0 0 0    1
+//! move   2 0  0 0 0  0 360 0  1
+//! ```
+use std::ops::{Add, Mul, Range, Sub};
+
+use glam::{EulerRot, Quat, Vec3, Vec4};
+
+use crate::scene::bvh::{Tree, Triangle, TriangleAttributes};
+use crate::scene::scene_file::Args;
+use crate::scene::{Camera, Material, Motion, ParseError, Scene, SceneTris};
+
+/// How a value goes from one key to the next.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Interpolation {
+    /// At a constant rate.
+    #[default]
+    Linear,
+    /// Along a cubic Bézier whose handles follow the keys on either side, so the value
+    /// passes through keys without a kink.
+    Bezier,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Key<T> {
+    pub time: f32,
+    pub value: T,
+    /// From this key to the next.
+    pub interpolation: Interpolation,
+}
+
+/// Keys of one value, in order of time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Track<T> {
+    keys: Vec<Key<T>>,
+}
+
+impl<T> Default for Track<T> {
+    fn default() -> Self {
+        Self { keys: Vec::new() }
+    }
+}
+
+impl<T> Track<T>
+where
+    T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T>,
+{
+    /// Keys `value` at `time`, after any key already there.
+    pub fn key(&mut self, time: f32, value: T, interpolation: Interpolation) {
+        let i = self.keys.partition_point(|k| k.time <= time);
+        self.keys.insert(
+            i,
+            Key {
+                time,
+                value,
+                interpolation,
+            },
+        );
+    }
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+    /// The value at `time`, held before the first key and after the last.
+    pub fn sample(&self, time: f32) -> Option<T> {
+        let keys = &self.keys;
+        let i = keys.partition_point(|k| k.time <= time);
+        if i == 0 || i == keys.len() {
+            return keys.get(i.saturating_sub(1)).map(|k| k.value);
+        }
+        let (a, b) = (&keys[i - 1], &keys[i]);
+        let u = (time - a.time) / (b.time - a.time);
+        Some(match a.interpolation {
+            Interpolation::Linear => a.value + (b.value - a.value) * u,
+            Interpolation::Bezier => {
+                // handles a third along the Catmull-Rom tangents through the neighbours
+                let before = if i >= 2 { keys[i - 2].value } else { a.value };
+                let after = keys.get(i + 1).map_or(b.value, |k| k.value);
+                let p1 = a.value + (b.value - before) * (1.0 / 6.0);
+                let p2 = b.value - (after - a.value) * (1.0 / 6.0);
+                let v = 1.0 - u;
+                a.value * (v * v * v)
+                    + p1 * (3.0 * v * v * u)
+                    + p2 * (3.0 * v * u * u)
+                    + b.value * (u * u * u)
+            }
+        })
+    }
+}
+
+/// The parameters of `Camera::new`, keyed together by the text format.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CameraKeys {
+    pub eye: Track<Vec3>,
+    pub target: Track<Vec3>,
+    pub focal_length: Track<f32>,
+    pub blur: Track<f32>,
+    /// In degrees.
+    pub fov: Track<f32>,
+}
+
+/// Where a mesh is and the colours of its material. Empty tracks leave it as added.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MeshKeys {
+    pub translation: Track<Vec3>,
+    /// Euler angles in degrees in XYZ order, interpolated one by one so a key can turn
+    /// the mesh more than once round.
+    pub rotation: Track<Vec3>,
+    pub scale: Track<f32>,
+    pub albedo: Track<Vec3>,
+    pub emission: Track<Vec3>,
+}
+
+/// Where `MeshKeys` put a mesh at some time.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Placement {
+    translation: Vec3,
+    rotation: Quat,
+    scale: f32,
+}
+
+impl MeshKeys {
+    fn placement(&self, time: f32) -> Option<Placement> {
+        if self.translation.is_empty() && self.rotation.is_empty() && self.scale.is_empty() {
+            return None;
+        }
+        let [x, y, z] = self.rotation.sample(time).unwrap_or_default().to_array();
+        Some(Placement {
+            translation: self.translation.sample(time).unwrap_or_default(),
+            rotation: Quat::from_euler(
+                EulerRot::XYZ,
+                x.to_radians(),
+                y.to_radians(),
+                z.to_radians(),
+            ),
+            scale: self.scale.sample(time).unwrap_or(1.0),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timeline {
+    pub fps: f32,
+    pub frames: u32,
+    /// How much of each frame the shutter is open for, from its start. Meshes moving
+    /// meanwhile blur, see `Motion`. Half a frame by default, as film cameras do.
+    pub shutter: f32,
+    pub camera: CameraKeys,
+    /// Indexed like the materials of the tree, see `mesh`.
+    pub meshes: Vec<MeshKeys>,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self {
+            fps: 24.0,
+            frames: 1,
+            shutter: 0.5,
+            camera: CameraKeys::default(),
+            meshes: Vec::new(),
+        }
+    }
+}
+
+/// A mesh index, which is a whole number unlike the rest.
+fn mesh_index(args: &mut Args) -> Result<u32, ParseError> {
+    let word = args.word("mesh")?;
+    word.parse()
+        .map_err(|_| args.error(format!("expected a mesh number, found `{word}`")))
+}
+
+/// The interpolation closing a key, linear if there is none.
+fn interpolation(args: &mut Args) -> Result<Interpolation, ParseError> {
+    match args.optional_word() {
+        None | Some("linear") => Ok(Interpolation::Linear),
+        Some("bezier") => Ok(Interpolation::Bezier),
+        Some(word) => Err(args.error(format!("unknown interpolation `{word}`"))),
+    }
+}
+
+impl Timeline {
+    pub fn parse(source: &str) -> Result<Self, ParseError> {
+        let mut timeline = Self::default();
+        for (i, line) in source.lines().enumerate() {
+            let mut args = Args::new(line, i + 1);
+            let Some(keyword) = args.optional_word() else {
+                continue;
+            };
+            match keyword {
+                "fps" => {
+                    timeline.fps = args.f32("frames per second")?;
+                    if timeline.fps <= 0.0 {
+                        return Err(args.error("frames per second must be positive".into()));
+                    }
+                }
+                "frames" => {
+                    let word = args.word("frame count")?;
+                    timeline.frames = word.parse().map_err(|_| {
+                        args.error(format!("expected a frame count, found `{word}`"))
+                    })?;
+                }
+                "shutter" => {
+                    timeline.shutter = args.f32("shutter")?;
+                    if !(0.0..=1.0).contains(&timeline.shutter) {
+                        return Err(args.error("shutter must be from 0 to 1".into()));
+                    }
+                }
+                "camera" => {
+                    let time = args.f32("time")?;
+                    let eye = args.vec3("camera position")?;
+                    let target = args.vec3("camera target")?;
+                    let focal_length = args.f32("focal length")?;
+                    let blur = args.f32("focal blur")?;
+                    let fov = args.f32("field of view")?;
+                    let ease = interpolation(&mut args)?;
+                    let camera = &mut timeline.camera;
+                    camera.eye.key(time, eye, ease);
+                    camera.target.key(time, target, ease);
+                    camera.focal_length.key(time, focal_length, ease);
+                    camera.blur.key(time, blur, ease);
+                    camera.fov.key(time, fov, ease);
+                }
+                "move" => {
+                    let time = args.f32("time")?;
+                    let index = mesh_index(&mut args)?;
+                    let translation = args.vec3("translation")?;
+                    let rotation = args.vec3("rotation")?;
+                    let scale = args.f32("scale")?;
+                    let ease = interpolation(&mut args)?;
+                    let mesh = timeline.mesh(index);
+                    mesh.translation.key(time, translation, ease);
+                    mesh.rotation.key(time, rotation, ease);
+                    mesh.scale.key(time, scale, ease);
+                }
+                "albedo" | "emission" => {
+                    let time = args.f32("time")?;
+                    let index = mesh_index(&mut args)?;
+                    let color = args.vec3("colour")?;
+                    let ease = interpolation(&mut args)?;
+                    let mesh = timeline.mesh(index);
+                    match keyword {
+                        "albedo" => mesh.albedo.key(time, color, ease),
+                        _ => mesh.emission.key(time, color, ease),
+                    }
+                }
+                _ => return Err(args.error(format!("unknown statement `{keyword}`"))),
+            }
+            args.end()?;
+        }
+        Ok(timeline)
+    }
+    /// The keys of mesh `index`, made empty if there are none yet.
+    pub fn mesh(&mut self, index: u32) -> &mut MeshKeys {
+        let index = index as usize;
+        if self.meshes.len() <= index {
+            self.meshes.resize(index + 1, MeshKeys::default());
+        }
+        &mut self.meshes[index]
+    }
+    /// When `frame` starts, in seconds.
+    pub fn time(&self, frame: u32) -> f32 {
+        frame as f32 / self.fps
+    }
+    /// The camera at `time`, if every one of its parameters is keyed.
+    pub fn camera_at(&self, time: f32) -> Option<Camera> {
+        let keys = &self.camera;
+        Some(Camera::new(
+            keys.eye.sample(time)?,
+            keys.target.sample(time)?,
+            keys.focal_length.sample(time)?,
+            keys.blur.sample(time)?,
+            keys.fov.sample(time)?.to_radians(),
+        ))
+    }
+}
+
+/// A `Timeline` played over the tree of a `SceneTris`, which keeps the meshes and
+/// materials as they were added to place and colour them from.
+pub struct Animation {
+    pub timeline: Timeline,
+    /// The triangles in the order they were added.
+    rest: Vec<(Triangle, TriangleAttributes)>,
+    materials: Vec<Material>,
+    /// The triangles of every mesh in `rest`, empty for point clouds.
+    meshes: Vec<Range<usize>>,
+}
+
+impl Animation {
+    /// Plays `timeline` over `tree`, which must be built and not yet posed. Fails if the
+    /// timeline keys a mesh the tree does not have.
+    pub fn new(timeline: Timeline, tree: &Tree) -> Result<Self, String> {
+        let count = tree.materials.len();
+        let keyed = |i: &usize| timeline.meshes[*i] != MeshKeys::default();
+        if let Some(i) = (count..timeline.meshes.len()).find(keyed) {
+            return Err(format!("the timeline keys mesh {i} of only {count}"));
+        }
+        let rest: Vec<_> = (0..tree.triangles.len())
+            .map(|k| tree.slots[k] as usize)
+            .map(|slot| (tree.triangles[slot], tree.attributes[slot]))
+            .collect();
+        let mut meshes = vec![0..0; count];
+        for (k, (t, _)) in rest.iter().enumerate() {
+            let range = &mut meshes[t.material as usize];
+            if range.end == 0 {
+                *range = k..k + 1;
+            } else {
+                range.end = k + 1;
+            }
+        }
+        Ok(Self {
+            timeline,
+            rest,
+            materials: tree.materials.clone(),
+            meshes,
+        })
+    }
+    /// Places and colours the meshes of `tree` as they are when `frame` starts, moving
+    /// those that move while the shutter is open. Meshes set moving after `MAX_MOTIONS`
+    /// others and changes of scale do not blur. The tree is refitted, not yet uploaded.
+    pub fn pose_tree(&self, tree: &mut Tree, frame: u32) {
+        let timeline = &self.timeline;
+        let start = timeline.time(frame);
+        let close = start + timeline.shutter / timeline.fps;
+        for (index, keys) in timeline.meshes.iter().enumerate() {
+            let base = self.materials[index];
+            if !keys.albedo.is_empty() || !keys.emission.is_empty() {
+                let material = match keys.albedo.sample(start) {
+                    Some(albedo) => base.with_albedo(albedo),
+                    None => base,
+                };
+                let material = match keys.emission.sample(start) {
+                    Some(emission) => material.with_emission(emission),
+                    None => material,
+                };
+                tree.set_material(index as u32, material);
+            }
+            let (Some(from), Some(to)) = (keys.placement(start), keys.placement(close)) else {
+                continue;
+            };
+            let range = self.meshes[index].clone();
+            let first = range.start as u32;
+            let triangles = self.rest[range].iter().map(|&(t, a)| place(from, t, a));
+            tree.update_triangles(first, triangles);
+            let (axis, angle) = (to.rotation * from.rotation.inverse()).to_axis_angle();
+            // the short way round, a frame never turns by more than half a turn
+            let angle = if angle > std::f32::consts::PI {
+                angle - std::f32::consts::TAU
+            } else {
+                angle
+            };
+            let translation = to.translation - from.translation;
+            let motion = Motion::new(translation, axis, angle, from.translation);
+            // past the last motion the mesh jumps from frame to frame instead
+            let _ = tree.set_motion(index as u32, motion);
+        }
+        tree.refit();
+    }
+    /// Poses `scene` at `frame` as `pose_tree` does, and its camera if the timeline keys
+    /// one, then uploads what changed.
+    pub fn pose(&self, scene: &mut SceneTris, frame: u32) {
+        let timeline = &self.timeline;
+        let camera = timeline.camera_at(timeline.time(frame)).unwrap_or(scene.camera);
+        scene.camera = match timeline.shutter > 0.0 {
+            true => camera.with_shutter(0.0, 1.0),
+            false => camera.with_shutter(0.0, 0.0),
+        };
+        scene.renderer.set_camera(&scene.camera);
+        self.pose_tree(&mut scene.tris_bvh, frame);
+        scene.write_tree_changes();
+    }
+    /// Poses `scene` at `frame` and renders it from scratch with `samples` paths per
+    /// pixel, to be read with `render_png` or `render_rgb`. The image converges up to 1000
+    /// samples, after which older samples fade out.
+    pub fn draw_frame(&self, scene: &mut SceneTris, frame: u32, samples: u32) {
+        self.pose(scene, frame);
+        scene.reset_frame_count();
+        for sample in 0..samples {
+            scene.set_time(1000 + (frame * samples + sample) * 10);
+            scene.draw();
+        }
+    }
+}
+
+/// Triangle `t` with attributes `a` as `placement` puts it, vertex colours kept.
+fn place(
+    placement: Placement,
+    t: Triangle,
+    a: TriangleAttributes,
+) -> (Triangle, TriangleAttributes) {
+    let Placement {
+        translation,
+        rotation,
+        scale,
+    } = placement;
+    let point = |p: Vec4| (translation + rotation * (p.truncate() * scale)).extend(p.w);
+    let turn = |v: Vec4| (rotation * v.truncate()).extend(v.w);
+    let triangle = Triangle {
+        a: point(t.a),
+        b: point(t.b),
+        c: point(t.c),
+        ..t
+    };
+    let attributes = TriangleAttributes {
+        normals: a.normals.map(turn),
+        tangents: a.tangents.map(turn),
+        ..a
+    };
+    (triangle, attributes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Mesh;
+    use crate::renderer::RenderOutput;
+    use crate::scene::render_ppm;
+
+    #[test]
+    fn sample() {
+        let mut track = Track::default();
+        assert_eq!(track.sample(0.0), None);
+        track.key(2.0, 4.0, Interpolation::Linear);
+        track.key(0.0, 0.0, Interpolation::Linear);
+        assert_eq!(track.sample(-1.0), Some(0.0));
+        assert_eq!(track.sample(1.5), Some(3.0));
+        assert_eq!(track.sample(3.0), Some(4.0));
+        // keys on a line stay on it, otherwise the curve eases through the middle key
+        let mut bezier = Track::default();
+        for (time, value) in [(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (3.0, 3.0)] {
+            bezier.key(time, value, Interpolation::Bezier);
+        }
+        assert!((bezier.sample(1.25).unwrap() - 1.25).abs() < 1e-5);
+        let mut bezier = Track::default();
+        for (time, value) in [(0.0, 0.0), (1.0, 1.0), (2.0, 0.0)] {
+            bezier.key(time, value, Interpolation::Bezier);
+        }
+        let (before, after) = (bezier.sample(0.9).unwrap(), bezier.sample(1.1).unwrap());
+        assert!(before < 1.0 && (before - after).abs() < 1e-5);
+        assert!(before > 0.9);
+    }
+
+    #[test]
+    fn parse() {
+        let timeline = Timeline::parse(
+            "fps 30 # frames per second\n\
+             frames 4\n\
+             shutter 0\n\
+             camera 0 0 1 4 0 0 0 4 0 60\n\
+             camera 1 0 1 4 0 0 0 4 0 30 bezier\n\
+             move 1 2 1 0 0 0 90 0 1 linear\n\
+             albedo 0 0 1 0 0",
+        )
+        .unwrap();
+        assert_eq!((timeline.fps, timeline.frames, timeline.shutter), (30.0, 4, 0.0));
+        assert_eq!(timeline.camera.fov.sample(0.5), Some(45.0));
+        assert_eq!(timeline.meshes.len(), 3);
+        assert_eq!(timeline.meshes[2].translation.sample(0.0), Some(Vec3::X));
+        assert_eq!(timeline.meshes[0].albedo.sample(0.0), Some(Vec3::X));
+        let error = |source| Timeline::parse(source).unwrap_err().to_string();
+        assert_eq!(error("fps 0"), "line 1: frames per second must be positive");
+        assert_eq!(error("\nframes 2.5"), "line 2: expected a frame count, found `2.5`");
+        assert_eq!(error("move 0 -1"), "line 1: expected a mesh number, found `-1`");
+        assert_eq!(error("albedo 0 0 1 1 1 ease"), "line 1: unknown interpolation `ease`");
+        assert_eq!(error("albedo 0 0 1 1 1 linear 2"), "line 1: unexpected `2`");
+        assert_eq!(error("spin 0"), "line 1: unknown statement `spin`");
+    }
+
+    #[test]
+    fn pose() {
+        let quad = Mesh::load_obj(
+            include_bytes!("../assets/quad.obj"),
+            Material::new_lambertian(Vec3::ONE),
+        )
+        .unwrap();
+        let offset = Vec3::new(0.6, 0.0, 0.0);
+        let render = |animated: bool| {
+            let output = RenderOutput::Headless(32, 32);
+            let mut scene = pollster::block_on(SceneTris::new_quad(output));
+            scene.tris_bvh = Tree::new();
+            let mut mesh = quad.clone();
+            if !animated {
+                mesh.translate(offset);
+            }
+            scene.tris_bvh.add_mesh(mesh);
+            scene.init().unwrap();
+            if animated {
+                let mut timeline = Timeline::parse("shutter 0\nmove 0 0 0 0 0 0 0 0 1").unwrap();
+                let time = timeline.time(1);
+                timeline.mesh(0).translation.key(time, offset, Interpolation::Linear);
+                let animation = Animation::new(timeline, &scene.tris_bvh).unwrap();
+                animation.pose(&mut scene, 1);
+            }
+            scene.draw();
+            render_ppm(&mut scene.renderer)
+        };
+        // a frame in, the quad is where it would stand moved by hand
+        assert_eq!(render(true), render(false));
+        let timeline = Timeline::parse("albedo 0 1 1 1 1").unwrap();
+        let error = Animation::new(timeline, &Tree::new()).err();
+        assert_eq!(error.as_deref(), Some("the timeline keys mesh 1 of only 0"));
+    }
+}
@@ -0,0 +1,285 @@
+// Linear BVH construction, see `Lbvh`. Entry points run in this order: `morton`, then
+// `count_digits`, `scan` and `scatter` once per radix digit, `gather`, `hierarchy`,
+// `depths`, and `fit` once per depth of the tree from the bottom.
+const FLT_MAX = 3.40282346638528859812e+38;
+const WORKGROUP = 256u;
+// a child link to a triangle slot rather than a node, see `bvh::node::LEAF`
+const LEAF = 0x80000000u;
+
+struct Params {
+  bounds_min: vec4f,
+  bounds_scale: vec4f,
+  count: u32,
+  blocks: u32,
+  shift: u32,
+  // the depth `fit` fits
+  level: u32,
+}
+struct Node {
+  bound_min: vec3f,
+  left: u32,
+  bound_max: vec3f,
+  right: u32,
+}
+struct Triangle {
+  a: vec4f,
+  b: vec4f,
+  c: vec4f,
+  normal: vec3f,
+  material: u32,
+}
+// copied whole, the layout does not matter here
+struct TriangleAttributes {
+  words: array<vec4u, 8>,
+}
+
+@group(0) @binding(0)
+var<uniform> params: Params;
+@group(0) @binding(1)
+var<storage, read> triangles_in: array<Triangle>;
+@group(0) @binding(2)
+var<storage, read> attributes_in: array<TriangleAttributes>;
+@group(0) @binding(3)
+var<storage, read> keys_in: array<u32>;
+@group(0) @binding(4)
+var<storage, read> values_in: array<u32>;
+@group(0) @binding(5)
+var<storage, read_write> keys_out: array<u32>;
+@group(0) @binding(6)
+var<storage, read_write> values_out: array<u32>;
+// digit counts of every workgroup, digit major, scanned in place into offsets
+@group(0) @binding(7)
+var<storage, read_write> histogram: array<u32>;
+@group(0) @binding(8)
+var<storage, read_write> triangles_out: array<Triangle>;
+@group(0) @binding(9)
+var<storage, read_write> attributes_out: array<TriangleAttributes>;
+@group(0) @binding(10)
+var<storage, read_write> nodes: array<Node>;
+// parent of every node but the root
+@group(0) @binding(11)
+var<storage, read_write> parents: array<u32>;
+@group(0) @binding(12)
+var<storage, read_write> node_depths: array<u32>;
+
+var<workgroup> counts: array<atomic<u32>, 16>;
+var<workgroup> sums: array<u32, WORKGROUP>;
+var<workgroup> digits: array<u32, WORKGROUP>;
+
+// spreads the low ten bits of `v` three apart
+fn expand_bits(v: u32) -> u32 {
+  var x = v & 0x3ffu;
+  x = (x | (x << 16u)) & 0x030000ffu;
+  x = (x | (x << 8u)) & 0x0300f00fu;
+  x = (x | (x << 4u)) & 0x030c30c3u;
+  x = (x | (x << 2u)) & 0x09249249u;
+  return x;
+}
+
+@compute @workgroup_size(WORKGROUP)
+fn morton(@builtin(global_invocation_id) id: vec3u) {
+  let i = id.x;
+  if i >= params.count {
+    return;
+  }
+  let t = triangles_in[i];
+  let center = (t.a.xyz + t.b.xyz + t.c.xyz) / 3.0;
+  let p = clamp((center - params.bounds_min.xyz) * params.bounds_scale.xyz, vec3f(0.0), vec3f(1.0));
+  let q = min(vec3u(p * 1024.0), vec3u(1023u));
+  keys_out[i] = (expand_bits(q.x) << 2u) | (expand_bits(q.y) << 1u) | expand_bits(q.z);
+  values_out[i] = i;
+}
+
+fn digit_of(key: u32) -> u32 {
+  return (key >> params.shift) & 15u;
+}
+
+@compute @workgroup_size(WORKGROUP)
+fn count_digits(@builtin(local_invocation_index) l: u32, @builtin(workgroup_id) w: vec3u) {
+  if l < 16u {
+    atomicStore(&counts[l], 0u);
+  }
+  workgroupBarrier();
+  let i = w.x * WORKGROUP + l;
+  if i < params.count {
+    atomicAdd(&counts[digit_of(keys_in[i])], 1u);
+  }
+  workgroupBarrier();
+  if l < 16u {
+    histogram[l * params.blocks + w.x] = atomicLoad(&counts[l]);
+  }
+}
+
+// exclusive prefix sum of the whole histogram by a single workgroup, each thread
+// summing a run of it serially
+@compute @workgroup_size(WORKGROUP)
+fn scan(@builtin(local_invocation_index) l: u32) {
+  let len = 16u * params.blocks;
+  let run = (len + WORKGROUP - 1u) / WORKGROUP;
+  let start = min(l * run, len);
+  let end = min(start + run, len);
+  var sum = 0u;
+  for (var i = start; i < end; i++) {
+    sum += histogram[i];
+  }
+  sums[l] = sum;
+  workgroupBarrier();
+  for (var d = 1u; d < WORKGROUP; d *= 2u) {
+    var v = 0u;
+    if l >= d {
+      v = sums[l - d];
+    }
+    workgroupBarrier();
+    sums[l] += v;
+    workgroupBarrier();
+  }
+  var offset = sums[l] - sum;
+  for (var i = start; i < end; i++) {
+    let count = histogram[i];
+    histogram[i] = offset;
+    offset += count;
+  }
+}
+
+// stable, an item goes after the items of its workgroup with the same digit before it
+@compute @workgroup_size(WORKGROUP)
+fn scatter(@builtin(local_invocation_index) l: u32, @builtin(workgroup_id) w: vec3u) {
+  let i = w.x * WORKGROUP + l;
+  var digit = 16u;
+  if i < params.count {
+    digit = digit_of(keys_in[i]);
+  }
+  digits[l] = digit;
+  workgroupBarrier();
+  if i >= params.count {
+    return;
+  }
+  var rank = 0u;
+  for (var k = 0u; k < l; k++) {
+    rank += select(0u, 1u, digits[k] == digit);
+  }
+  let j = histogram[digit * params.blocks + w.x] + rank;
+  keys_out[j] = keys_in[i];
+  values_out[j] = values_in[i];
+}
+
+@compute @workgroup_size(WORKGROUP)
+fn gather(@builtin(global_invocation_id) id: vec3u) {
+  let i = id.x;
+  if i >= params.count {
+    return;
+  }
+  let j = values_in[i];
+  var t = triangles_in[j];
+  t.normal = normalize(cross(t.b.xyz - t.a.xyz, t.c.xyz - t.a.xyz));
+  triangles_out[i] = t;
+  attributes_out[i] = attributes_in[j];
+}
+
+// length of the prefix the sorted keys `i` and `j` share, their slots breaking ties, or
+// -1 past either end
+fn delta(i: i32, j: i32) -> i32 {
+  if j < 0 || j >= i32(params.count) {
+    return -1;
+  }
+  let a = keys_in[i];
+  let b = keys_in[j];
+  if a == b {
+    return 32 + i32(countLeadingZeros(u32(i) ^ u32(j)));
+  }
+  return i32(countLeadingZeros(a ^ b));
+}
+
+// links internal node `i` to its children, splitting its slots where their keys first
+// differ, as Karras 2012 does
+@compute @workgroup_size(WORKGROUP)
+fn hierarchy(@builtin(global_invocation_id) id: vec3u) {
+  let i = i32(id.x);
+  if i + 1 >= i32(params.count) {
+    return;
+  }
+  // the slots run from `i` towards the neighbour sharing the longer prefix
+  let d = select(-1, 1, delta(i, i + 1) > delta(i, i - 1));
+  let delta_min = delta(i, i - d);
+  var l_max = 2;
+  while delta(i, i + l_max * d) > delta_min {
+    l_max *= 2;
+  }
+  var l = 0;
+  for (var t = l_max / 2; t >= 1; t /= 2) {
+    if delta(i, i + (l + t) * d) > delta_min {
+      l += t;
+    }
+  }
+  let j = i + l * d;
+  // the split is the last slot sharing more than the node's prefix with `i`
+  let delta_node = delta(i, j);
+  var s = 0;
+  var t = l;
+  loop {
+    t = (t + 1) / 2;
+    if delta(i, i + (s + t) * d) > delta_node {
+      s += t;
+    }
+    if t == 1 {
+      break;
+    }
+  }
+  let split = u32(i + s * d + min(d, 0));
+  let first = u32(min(i, j));
+  let last = u32(max(i, j));
+  var left = split;
+  if split == first {
+    left = LEAF | split;
+  } else {
+    parents[split] = u32(i);
+  }
+  var right = split + 1u;
+  if split + 1u == last {
+    right = LEAF | last;
+  } else {
+    parents[split + 1u] = u32(i);
+  }
+  nodes[i].left = left;
+  nodes[i].right = right;
+}
+
+// the depth of every internal node, climbing to the root
+@compute @workgroup_size(WORKGROUP)
+fn depths(@builtin(global_invocation_id) id: vec3u) {
+  let i = id.x;
+  if i + 1u >= params.count {
+    return;
+  }
+  var depth = 0u;
+  var j = i;
+  while j != 0u {
+    j = parents[j];
+    depth++;
+  }
+  node_depths[i] = depth;
+}
+
+// the bounds of the internal nodes at depth `level` from their children, keeping links
+@compute @workgroup_size(WORKGROUP)
+fn fit(@builtin(global_invocation_id) id: vec3u) {
+  let j = id.x;
+  if j + 1u >= params.count || node_depths[j] != params.level {
+    return;
+  }
+  var lo = vec3f(FLT_MAX);
+  var hi = vec3f(-FLT_MAX);
+  for (var c = 0u; c < 2u; c++) {
+    let child = select(nodes[j].right, nodes[j].left, c == 0u);
+    if (child & LEAF) != 0u {
+      let t = triangles_out[child & ~LEAF];
+      lo = min(lo, min(min(t.a, t.b), t.c).xyz);
+      hi = max(hi, max(max(t.a, t.b), t.c).xyz);
+    } else {
+      lo = min(lo, nodes[child].bound_min);
+      hi = max(hi, nodes[child].bound_max);
+    }
+  }
+  nodes[j].bound_min = lo;
+  nodes[j].bound_max = hi;
+}
@@ -5,10 +5,54 @@ const FLT_MAX = 3.40282e+38;
 const MAT_LAMBERTIAN = 1u;
 const MAT_METAL = 2u;
 const MAT_DIELECTRIC = 3u;
+const MAT_HAIR = 4u;
+const TEX_CHECKER = 1u;
+const TEX_NOISE = 2u;
+const TEX_MARBLE = 3u;
+const TEX_VORONOI = 4u;
+const TEX_GRADIENT = 5u;
+const TEX_IMAGE = 6u;
+const MAPPING_UV = 1u;
+const PRIM_PLANE = 1u;
+const PRIM_BOX = 2u;
+const PRIM_DISK = 3u;
+const PRIM_CYLINDER = 4u;
+const PRIM_QUAD = 5u;
+const PRIM_EPSILON = 0.0001;
+const PRIM_SDF = 6u;
+const SDF_STEPS = 256;
+const SDF_START = 0.001;
+const SDF_HIT_EPSILON = 0.0001;
+const SDF_NORMAL_DELTA = 0.0005;
+const PRIM_CSG = 7u;
+const CSG_SPHERE = 1u;
+const CSG_BOX = 2u;
+const CSG_UNION = 3u;
+const CSG_INTERSECTION = 4u;
+const CSG_DIFFERENCE = 5u;
+const CSG_STACK = 8;
+const CSG_MAX_SPANS = 4u;
+const CURVE_TUBE = 1u;
+const CURVE_RIBBON = 2u;
+// index of refraction of hair keratin
+const HAIR_ETA = 1.55;
 const SKY = vec3f(0.54, 0.86, 0.92);
 const BLUE = vec3f(0.54, 0.7, 0.98);
 const SAMPLE_FRAME = 1000;
 const SAMPLE_PER_FRAME = 1;
+const FLAG_SPECTRAL = 1u;
+const LAMBDA_MIN = 380.0;
+const LAMBDA_MAX = 780.0;
+const RGB_WAVELENGTH = 550.0;
+const RGB_WAVELENGTHS = vec3f(630.0, 532.0, 465.0);
+const CIE_Y_INTEGRAL = 106.857;
+const XYZ_TO_SRGB = mat3x3f(
+  3.2406, -0.9689, 0.0557,
+  -1.5372, 1.8758, -0.2040,
+  -0.4986, 0.0415, 1.0570,
+);
+// linear sRGB of the equal energy white, divided out so flat spectra stay white
+const XYZ_TO_SRGB_WHITE = vec3f(1.2048, 0.9484, 0.9087);
 const BOUNCE_MAX = 10;
 
 @group(0) @binding(0)
@@ -21,8 +65,28 @@ var<uniform> time: u32;
 var<storage, read_write> image: array<f32>;
 @group(0) @binding(4)
 var<uniform> camera: Camera;
+@group(0) @binding(5)
+var<uniform> flags: u32;
 @group(1) @binding(0)
 var<storage> scene: array<Sphere>;
+@group(1) @binding(1)
+var<storage> textures: array<Texture>;
+@group(1) @binding(2)
+var<storage> texels: array<u32>;
+@group(1) @binding(3)
+var<storage> primitives: array<Primitive>;
+@group(1) @binding(4)
+var<storage> csg: array<CsgNode>;
+// node and leaf counts of the curve tree in xy and of the sphere tree in zw, whose
+// nodes follow the curve nodes
+@group(1) @binding(5)
+var<uniform> tree_sizes: vec4u;
+@group(1) @binding(6)
+var<storage> nodes: array<Node>;
+@group(1) @binding(7)
+var<storage> curves: array<Curve>;
+// when in the frame the path being traced sees the scene, see `shutter_time`
+var<private> ray_time: f32;
 
 struct Camera {
   eye: vec4f,
@@ -32,6 +96,7 @@ struct Camera {
   focal_length: f32,
   focal_blur_amount: f32,
   fov: f32,
+  shutter: vec4f,
 }
 struct Ray {
   origin: vec3f,
@@ -45,6 +110,39 @@ struct Light {
 struct Sphere {
   center: vec3f,
   radius: f32,
+  motion: Motion,
+  material: Material,
+}
+// see `Motion`
+struct Motion {
+  translation: vec4f,
+  // axis in xyz, angle over the frame in w
+  rotation: vec4f,
+  pivot: vec4f,
+}
+struct Primitive {
+  a: vec4f,
+  b: vec4f,
+  c: vec4f,
+  kind: u32,
+  material: Material,
+}
+struct CsgNode {
+  a: vec4f,
+  b: vec4f,
+  c: vec4f,
+  kind: u32,
+}
+struct Node {
+  bound_min: vec3f,
+  bound_max: vec3f,
+}
+struct Curve {
+  points: array<vec4f, 4>,
+  normals: array<vec4f, 2>,
+  radius: vec2f,
+  kind: u32,
+  depth: u32,
   material: Material,
 }
 struct HitRecord {
@@ -53,11 +151,28 @@ struct HitRecord {
   t: f32,
   material: Material,
   front_face: bool,
+  uv: vec2f,
+  // direction of the fibre on curves
+  tangent: vec3f,
 }
 struct Material {
     albedo: vec4f,
     params: vec3f,
     id: u32,
+    coating: vec4f,
+    emission: vec4f,
+    textures: vec4u,
+    bump: vec4f,
+    cutout: f32,
+    flags: u32,
+    motion: u32,
+}
+struct Texture {
+  color_a: vec4f,
+  color_b: vec4f,
+  params: vec4f,
+  kind: u32,
+  mapping: u32,
 }
 
 @vertex
@@ -133,8 +248,32 @@ fn make_ray(uv: vec2f, state: ptr<function, u32>) -> Ray {
     direction = focus_point - origin;
     return Ray(origin.xyz, direction.xyz);
 }
+// a random time while the shutter is open, a closed one takes no random number so still
+// images render as they always did
+fn shutter_time(state: ptr<function, u32>) -> f32 {
+  if camera.shutter.y <= camera.shutter.x {
+    return camera.shutter.x;
+  }
+  return mix(camera.shutter.x, camera.shutter.y, rng_float(state));
+}
+fn is_moving(motion: Motion) -> bool {
+  return any(motion.translation.xyz != vec3f(0.0)) || motion.rotation.w != 0.0;
+}
+// the turn of `motion` at `ray_time` as a quaternion
+fn motion_rotation(motion: Motion) -> vec4f {
+  let half = 0.5 * motion.rotation.w * ray_time;
+  return vec4f(motion.rotation.xyz * sin(half), cos(half));
+}
+fn move_point(motion: Motion, p: vec3f) -> vec3f {
+  let pivot = motion.pivot.xyz;
+  return pivot + quat_rotate(motion_rotation(motion), p - pivot) + motion.translation.xyz * ray_time;
+}
 fn intersect_sphere(ray: Ray, sphere: Sphere) -> HitRecord {
-  let center = sphere.center;
+  let moving = is_moving(sphere.motion);
+  var center = sphere.center;
+  if moving {
+    center = move_point(sphere.motion, center);
+  }
   let radius = sphere.radius;
   let oc = ray.origin - center;
   let a = dot(ray.direction, ray.direction);
@@ -142,16 +281,756 @@ fn intersect_sphere(ray: Ray, sphere: Sphere) -> HitRecord {
   let c = dot(oc, oc) - radius*radius;
   let discriminant = b*b - 4*a*c;
   if (discriminant < 0) {
-      return HitRecord(vec3f(), vec3f(), -1, sphere.material, false);
+      return HitRecord(vec3f(), vec3f(), -1, sphere.material, false, vec2f(), vec3f());
   }
   let t = (-b - sqrt(discriminant) ) / (2*a);
   let hit_point = point_on_ray(ray, t);
   var normal = (hit_point - center) / radius;
+  // textures turn with the sphere
+  var local = normal;
+  if moving {
+    let q = motion_rotation(sphere.motion);
+    local = quat_rotate(vec4f(-q.xyz, q.w), normal);
+  }
+  let uv = vec2f(atan2(local.z, local.x) / PI2 + 0.5, acos(clamp(local.y, -1.0, 1.0)) / PI);
   let front_face = dot(ray.direction, normal) < 0;
   if !front_face {
     normal = -normal;
   }
-  return HitRecord(hit_point, normal, t, sphere.material, front_face);
+  return HitRecord(hit_point, normal, t, sphere.material, front_face, uv, vec3f());
+}
+fn no_hit(material: Material) -> HitRecord {
+  return HitRecord(vec3f(), vec3f(), -1, material, false, vec2f(), vec3f());
+}
+fn make_hit(ray: Ray, t: f32, outward: vec3f, material: Material, uv: vec2f) -> HitRecord {
+  let front_face = dot(ray.direction, outward) < 0;
+  let normal = select(-outward, outward, front_face);
+  return HitRecord(point_on_ray(ray, t), normal, t, material, front_face, uv, vec3f());
+}
+fn any_tangent(n: vec3f) -> vec3f {
+  let up = select(vec3f(1.0, 0.0, 0.0), vec3f(0.0, 1.0, 0.0), abs(n.x) > 0.9);
+  return normalize(cross(up, n));
+}
+fn quat_rotate(q: vec4f, v: vec3f) -> vec3f {
+  return v + 2.0 * cross(q.xyz, cross(q.xyz, v) + q.w * v);
+}
+fn intersect_plane(ray: Ray, p: Primitive) -> HitRecord {
+  let n = p.b.xyz;
+  let denom = dot(n, ray.direction);
+  if abs(denom) < EPSILON {
+    return no_hit(p.material);
+  }
+  let t = dot(p.a.xyz - ray.origin, n) / denom;
+  if t < PRIM_EPSILON {
+    return no_hit(p.material);
+  }
+  let d = point_on_ray(ray, t) - p.a.xyz;
+  let tangent = any_tangent(n);
+  let uv = vec2f(dot(d, tangent), dot(d, cross(n, tangent)));
+  return make_hit(ray, t, n, p.material, uv);
+}
+fn intersect_disk(ray: Ray, p: Primitive) -> HitRecord {
+  let n = p.b.xyz;
+  let radius = p.a.w;
+  let denom = dot(n, ray.direction);
+  if abs(denom) < EPSILON {
+    return no_hit(p.material);
+  }
+  let t = dot(p.a.xyz - ray.origin, n) / denom;
+  let d = point_on_ray(ray, t) - p.a.xyz;
+  if t < PRIM_EPSILON || dot(d, d) > radius*radius {
+    return no_hit(p.material);
+  }
+  let tangent = any_tangent(n);
+  let angle = atan2(dot(d, cross(n, tangent)), dot(d, tangent));
+  let uv = vec2f(angle / PI2 + 0.5, length(d) / radius);
+  return make_hit(ray, t, n, p.material, uv);
+}
+fn intersect_quad(ray: Ray, p: Primitive) -> HitRecord {
+  let u = p.b.xyz;
+  let v = p.c.xyz;
+  let n = cross(u, v);
+  let denom = dot(n, ray.direction);
+  if abs(denom) < EPSILON {
+    return no_hit(p.material);
+  }
+  let t = dot(p.a.xyz - ray.origin, n) / denom;
+  if t < PRIM_EPSILON {
+    return no_hit(p.material);
+  }
+  // planar coordinates of the hit along the two edges
+  let w = n / dot(n, n);
+  let d = point_on_ray(ray, t) - p.a.xyz;
+  let alpha = dot(w, cross(d, v));
+  let beta = dot(w, cross(u, d));
+  if alpha < 0.0 || alpha > 1.0 || beta < 0.0 || beta > 1.0 {
+    return no_hit(p.material);
+  }
+  return make_hit(ray, t, normalize(n), p.material, vec2f(alpha, beta));
+}
+fn intersect_box(ray: Ray, p: Primitive) -> HitRecord {
+  // slab test in the box's own frame
+  let inverse = vec4f(-p.c.xyz, p.c.w);
+  let origin = quat_rotate(inverse, ray.origin - p.a.xyz);
+  let direction = quat_rotate(inverse, ray.direction);
+  let half = p.b.xyz;
+  let t1 = (-half - origin) / direction;
+  let t2 = (half - origin) / direction;
+  let near = min(t1, t2);
+  let far = max(t1, t2);
+  let t_near = max(max(near.x, near.y), near.z);
+  let t_far = min(min(far.x, far.y), far.z);
+  if t_near > t_far || t_far < PRIM_EPSILON {
+    return no_hit(p.material);
+  }
+  let t = select(t_far, t_near, t_near > PRIM_EPSILON);
+  // the face hit is the axis where the local point reaches furthest out
+  let local = (origin + direction * t) / half;
+  let a = abs(local);
+  var n = vec3f(0.0, 0.0, sign(local.z));
+  var uv = local.xy;
+  if a.x >= a.y && a.x >= a.z {
+    n = vec3f(sign(local.x), 0.0, 0.0);
+    uv = local.zy;
+  } else if a.y >= a.z {
+    n = vec3f(0.0, sign(local.y), 0.0);
+    uv = local.xz;
+  }
+  return make_hit(ray, t, quat_rotate(p.c, n), p.material, uv * 0.5 + 0.5);
+}
+fn intersect_cylinder(ray: Ray, p: Primitive) -> HitRecord {
+  // capped cone with radius ra at pa and rb at pa + ba, a cylinder when they match
+  let pa = p.a.xyz;
+  let ba = p.b.xyz;
+  let ra = p.a.w;
+  let rb = p.b.w;
+  let oa = ray.origin - pa;
+  let ob = oa - ba;
+  let rd = ray.direction;
+  let m0 = dot(ba, ba);
+  let m1 = dot(oa, ba);
+  let m2 = dot(rd, ba);
+  let m3 = dot(rd, oa);
+  let m5 = dot(oa, oa);
+  let m9 = dot(ob, ba);
+  let axis = ba / sqrt(m0);
+  var t = FLT_MAX;
+  var n = vec3f();
+  var y = 0.0;
+  if abs(m2) > EPSILON {
+    let t_base = -m1 / m2;
+    let q = oa + rd * t_base;
+    if t_base > PRIM_EPSILON && dot(q, q) < ra*ra {
+      t = t_base;
+      n = -axis;
+    }
+    let t_top = -m9 / m2;
+    let r = ob + rd * t_top;
+    if t_top > PRIM_EPSILON && t_top < t && dot(r, r) < rb*rb {
+      t = t_top;
+      n = axis;
+      y = m0;
+    }
+  }
+  let rr = ra - rb;
+  let hy = m0 + rr*rr;
+  let k2 = m0*m0*dot(rd, rd) - m2*m2*hy;
+  let k1 = m0*m0*m3 - m1*m2*hy + m0*ra*rr*m2;
+  let k0 = m0*m0*m5 - m1*m1*hy + m0*ra*(rr*m1*2.0 - m0*ra);
+  let h = k1*k1 - k2*k0;
+  if h >= 0.0 && abs(k2) > EPSILON {
+    let s = sqrt(h);
+    for (var i = 0; i < 2; i++) {
+      let t_side = (-k1 + select(-s, s, i == 1)) / k2;
+      let y_side = m1 + t_side*m2;
+      if t_side > PRIM_EPSILON && t_side < t && y_side >= 0.0 && y_side <= m0 {
+        t = t_side;
+        n = normalize(m0*(m0*(oa + t*rd) + rr*ba*ra) - ba*hy*y_side);
+        y = y_side;
+      }
+    }
+  }
+  if t == FLT_MAX {
+    return no_hit(p.material);
+  }
+  let d = oa + rd * t;
+  let tangent = any_tangent(axis);
+  let angle = atan2(dot(d, cross(axis, tangent)), dot(d, tangent));
+  return make_hit(ray, t, n, p.material, vec2f(angle / PI2 + 0.5, y / m0));
+}
+fn sdf_box(p: vec3f, half: vec3f) -> f32 {
+  let q = abs(p) - half;
+  return length(max(q, vec3f(0.0))) + min(max(q.x, max(q.y, q.z)), 0.0);
+}
+fn sdf_torus(p: vec3f, t: vec2f) -> f32 {
+  let q = vec2f(length(p.xz) - t.x, p.y);
+  return length(q) - t.y;
+}
+fn sdf_capsule(p: vec3f, a: vec3f, b: vec3f, r: f32) -> f32 {
+  let pa = p - a;
+  let ba = b - a;
+  let h = clamp(dot(pa, ba) / dot(ba, ba), 0.0, 1.0);
+  return length(pa - ba*h) - r;
+}
+fn sdf_smooth_union(a: f32, b: f32, k: f32) -> f32 {
+  let h = max(k - abs(a - b), 0.0) / k;
+  return min(a, b) - h*h*k*0.25;
+}
+// @sdf begin
+// replaced by the distance functions of the scene when the shader is built
+fn sdf_distance(index: u32, p: vec3f) -> f32 {
+  return FLT_MAX;
+}
+// @sdf end
+fn sdf_normal(index: u32, p: vec3f) -> vec3f {
+  // tetrahedron of central differences
+  let e = vec2f(1.0, -1.0) * SDF_NORMAL_DELTA;
+  return normalize(
+    e.xyy * sdf_distance(index, p + e.xyy) +
+    e.yyx * sdf_distance(index, p + e.yyx) +
+    e.yxy * sdf_distance(index, p + e.yxy) +
+    e.xxx * sdf_distance(index, p + e.xxx)
+  );
+}
+fn intersect_sdf(ray: Ray, p: Primitive) -> HitRecord {
+  // only march the part of the ray inside the bounds
+  let t1 = (p.a.xyz - ray.origin) / ray.direction;
+  let t2 = (p.b.xyz - ray.origin) / ray.direction;
+  let t_near = max(max(min(t1.x, t2.x), min(t1.y, t2.y)), min(t1.z, t2.z));
+  let t_far = min(min(max(t1.x, t2.x), max(t1.y, t2.y)), max(t1.z, t2.z));
+  if t_near > t_far || t_far < PRIM_EPSILON {
+    return no_hit(p.material);
+  }
+  let index = bitcast<u32>(p.c.x);
+  let scale = length(ray.direction);
+  let direction = ray.direction / scale;
+  // distances along the unit direction from here on
+  var t = max(t_near, 0.0) * scale + SDF_START;
+  let end = t_far * scale;
+  // rays leaving the inside of a dielectric march towards the surface from below
+  let side = sign(sdf_distance(index, ray.origin + direction * t));
+  for (var i = 0; i < SDF_STEPS && t < end; i++) {
+    let point = ray.origin + direction * t;
+    let d = side * sdf_distance(index, point);
+    if d < SDF_HIT_EPSILON {
+      let n = sdf_normal(index, point);
+      return make_hit(ray, t / scale, n, p.material, vec2f());
+    }
+    t += d;
+  }
+  return no_hit(p.material);
+}
+// Where a ray is inside a solid, with the outward normals at both ends.
+struct Span {
+  t0: f32,
+  t1: f32,
+  n0: vec3f,
+  n1: vec3f,
+}
+// Sorted, disjoint spans of a ray through a CSG solid, extra spans are dropped.
+struct Spans {
+  count: u32,
+  spans: array<Span, CSG_MAX_SPANS>,
+}
+fn csg_sphere(ray: Ray, node: CsgNode) -> Spans {
+  var ret: Spans;
+  let oc = ray.origin - node.a.xyz;
+  let a = dot(ray.direction, ray.direction);
+  let b = dot(oc, ray.direction);
+  let c = dot(oc, oc) - node.a.w*node.a.w;
+  let discriminant = b*b - a*c;
+  if discriminant < 0.0 {
+    return ret;
+  }
+  let s = sqrt(discriminant);
+  let t0 = (-b - s) / a;
+  let t1 = (-b + s) / a;
+  let n0 = (oc + ray.direction*t0) / node.a.w;
+  let n1 = (oc + ray.direction*t1) / node.a.w;
+  ret.spans[0] = Span(t0, t1, n0, n1);
+  ret.count = 1u;
+  return ret;
+}
+fn csg_box(ray: Ray, node: CsgNode) -> Spans {
+  var ret: Spans;
+  let inverse = vec4f(-node.c.xyz, node.c.w);
+  let origin = quat_rotate(inverse, ray.origin - node.a.xyz);
+  let direction = quat_rotate(inverse, ray.direction);
+  let half = node.b.xyz;
+  let t1 = (-half - origin) / direction;
+  let t2 = (half - origin) / direction;
+  let near = min(t1, t2);
+  let far = max(t1, t2);
+  let t_near = max(max(near.x, near.y), near.z);
+  let t_far = min(min(far.x, far.y), far.z);
+  if t_near > t_far {
+    return ret;
+  }
+  // the entry face is the slab entered last, the exit face the one left first
+  let entry = vec3f(near == vec3f(t_near));
+  let exit = vec3f(far == vec3f(t_far));
+  let n0 = -sign(direction) * entry;
+  let n1 = sign(direction) * exit;
+  ret.spans[0] = Span(t_near, t_far, quat_rotate(node.c, n0), quat_rotate(node.c, n1));
+  ret.count = 1u;
+  return ret;
+}
+fn csg_inside(op: u32, in_a: bool, in_b: bool) -> bool {
+  switch op {
+    case CSG_UNION: {
+      return in_a || in_b;
+    }
+    case CSG_INTERSECTION: {
+      return in_a && in_b;
+    }
+    default: {
+      return in_a && !in_b;
+    }
+  }
+}
+fn csg_combine(a: Spans, b: Spans, op: u32) -> Spans {
+  // sweep the span boundaries of both operands in order, emitting a boundary
+  // whenever the combined inside state flips
+  var ret: Spans;
+  var i = 0u;
+  var j = 0u;
+  var in_a = false;
+  var in_b = false;
+  var inside = false;
+  while i < 2u*a.count || j < 2u*b.count {
+    var t_a = FLT_MAX;
+    var t_b = FLT_MAX;
+    if i < 2u*a.count {
+      let span = a.spans[i / 2u];
+      t_a = select(span.t1, span.t0, i % 2u == 0u);
+    }
+    if j < 2u*b.count {
+      let span = b.spans[j / 2u];
+      t_b = select(span.t1, span.t0, j % 2u == 0u);
+    }
+    var t = t_a;
+    var n: vec3f;
+    if t_a <= t_b {
+      let span = a.spans[i / 2u];
+      n = select(span.n1, span.n0, i % 2u == 0u);
+      in_a = !in_a;
+      i++;
+    } else {
+      let span = b.spans[j / 2u];
+      n = select(span.n1, span.n0, j % 2u == 0u);
+      // the surface of a subtracted solid faces into it
+      if op == CSG_DIFFERENCE {
+        n = -n;
+      }
+      t = t_b;
+      in_b = !in_b;
+      j++;
+    }
+    let now_inside = csg_inside(op, in_a, in_b);
+    if now_inside == inside {
+      continue;
+    }
+    inside = now_inside;
+    if ret.count == CSG_MAX_SPANS {
+      break;
+    }
+    if inside {
+      ret.spans[ret.count].t0 = t;
+      ret.spans[ret.count].n0 = n;
+    } else {
+      ret.spans[ret.count].t1 = t;
+      ret.spans[ret.count].n1 = n;
+      ret.count++;
+    }
+  }
+  return ret;
+}
+fn intersect_csg(ray: Ray, p: Primitive) -> HitRecord {
+  let t1 = (p.b.xyz - ray.origin) / ray.direction;
+  let t2 = (p.c.xyz - ray.origin) / ray.direction;
+  let t_near = max(max(min(t1.x, t2.x), min(t1.y, t2.y)), min(t1.z, t2.z));
+  let t_far = min(min(max(t1.x, t2.x), max(t1.y, t2.y)), max(t1.z, t2.z));
+  if t_near > t_far || t_far < PRIM_EPSILON {
+    return no_hit(p.material);
+  }
+  // postfix evaluation, leaves push their spans and operators merge the top two
+  var stack: array<Spans, CSG_STACK>;
+  var top = 0u;
+  let first = bitcast<u32>(p.a.x);
+  let count = bitcast<u32>(p.a.y);
+  for (var i = first; i < first + count; i++) {
+    let node = csg[i];
+    switch node.kind {
+      case CSG_SPHERE: {
+        stack[top] = csg_sphere(ray, node);
+        top++;
+      }
+      case CSG_BOX: {
+        stack[top] = csg_box(ray, node);
+        top++;
+      }
+      default: {
+        top--;
+        stack[top - 1u] = csg_combine(stack[top - 1u], stack[top], node.kind);
+      }
+    }
+  }
+  // the first boundary ahead of the ray, leaving the solid when starting inside
+  let spans = stack[0];
+  for (var i = 0u; i < spans.count; i++) {
+    let span = spans.spans[i];
+    if span.t0 > PRIM_EPSILON {
+      return make_hit(ray, span.t0, normalize(span.n0), p.material, vec2f());
+    }
+    if span.t1 > PRIM_EPSILON {
+      return make_hit(ray, span.t1, normalize(span.n1), p.material, vec2f());
+    }
+  }
+  return no_hit(p.material);
+}
+fn intersect_primitive(ray: Ray, p: Primitive) -> HitRecord {
+  switch p.kind {
+    case PRIM_PLANE: {
+      return intersect_plane(ray, p);
+    }
+    case PRIM_BOX: {
+      return intersect_box(ray, p);
+    }
+    case PRIM_DISK: {
+      return intersect_disk(ray, p);
+    }
+    case PRIM_CYLINDER: {
+      return intersect_cylinder(ray, p);
+    }
+    case PRIM_QUAD: {
+      return intersect_quad(ray, p);
+    }
+    case PRIM_SDF: {
+      return intersect_sdf(ray, p);
+    }
+    case PRIM_CSG: {
+      return intersect_csg(ray, p);
+    }
+    default: {
+      return no_hit(p.material);
+    }
+  }
+}
+fn intersect_node(ray: Ray, node: Node, t_max: f32) -> bool {
+  let inv_d = 1.0 / ray.direction;
+  let t0 = (node.bound_min - ray.origin) * inv_d;
+  let t1 = (node.bound_max - ray.origin) * inv_d;
+  let t_near = max(max(min(t0.x, t1.x), min(t0.y, t1.y)), min(t0.z, t1.z));
+  let t_far = min(min(max(t0.x, t1.x), max(t0.y, t1.y)), max(t0.z, t1.z));
+  return t_near <= t_far && t_far >= 0.0 && t_near <= t_max;
+}
+fn bezier(p: array<vec3f, 4>, u: f32) -> vec3f {
+  let s = 1.0 - u;
+  return s*s*s*p[0] + 3.0*s*s*u*p[1] + 3.0*s*u*u*p[2] + u*u*u*p[3];
+}
+fn bezier_tangent(p: array<vec3f, 4>, u: f32) -> vec3f {
+  let s = 1.0 - u;
+  return 3.0 * (s*s*(p[1] - p[0]) + 2.0*s*u*(p[2] - p[1]) + u*u*(p[3] - p[2]));
+}
+fn intersect_curve(ray: Ray, curve: Curve, t_max: f32) -> HitRecord {
+  // in ray space the ray runs down +z from the origin, so the curve is hit wherever
+  // it passes within its radius of the z axis
+  let ray_length = length(ray.direction);
+  let d = ray.direction / ray_length;
+  let x = any_tangent(d);
+  let y = cross(d, x);
+  var world: array<vec3f, 4>;
+  var p: array<vec3f, 4>;
+  for (var i = 0; i < 4; i++) {
+    world[i] = curve.points[i].xyz;
+    let q = world[i] - ray.origin;
+    p[i] = vec3f(dot(q, x), dot(q, y), dot(q, d));
+  }
+  let r_max = max(curve.radius.x, curve.radius.y);
+  let segments = 1u << curve.depth;
+  var best_z = t_max * ray_length;
+  var best_u = -1.0;
+  var p0 = p[0];
+  for (var i = 0u; i < segments; i++) {
+    let u0 = f32(i) / f32(segments);
+    let u1 = f32(i + 1u) / f32(segments);
+    let p1 = bezier(p, u1);
+    let lo = min(p0.xy, p1.xy) - r_max;
+    let hi = max(p0.xy, p1.xy) + r_max;
+    if all(lo <= vec2f(0.0)) && all(hi >= vec2f(0.0)) {
+      // closest point of the segment to the ray, refined on the curve itself
+      let e = p1.xy - p0.xy;
+      let w = clamp(-dot(p0.xy, e) / max(dot(e, e), EPSILON), 0.0, 1.0);
+      let u = mix(u0, u1, w);
+      let pc = bezier(p, u);
+      let radius = mix(curve.radius.x, curve.radius.y, u);
+      var width = radius;
+      if curve.kind == CURVE_RIBBON {
+        // ribbons seen edge on are thin
+        width *= abs(dot(normalize(mix(curve.normals[0].xyz, curve.normals[1].xyz, u)), d));
+      }
+      // a scattered ray starts inside the fibre it leaves, so hits closer than the
+      // radius are that fibre again
+      let z_min = max(radius, PRIM_EPSILON * ray_length);
+      if dot(pc.xy, pc.xy) <= width*width && pc.z > z_min && pc.z < best_z {
+        best_z = pc.z;
+        best_u = u;
+      }
+    }
+    p0 = p1;
+  }
+  if best_u < 0.0 {
+    return no_hit(curve.material);
+  }
+  let t = best_z / ray_length;
+  let u = best_u;
+  let radius = mix(curve.radius.x, curve.radius.y, u);
+  let tangent = normalize(bezier_tangent(world, u));
+  // h runs from -1 to 1 across the fibre as seen along the ray
+  let facing = normalize(-d + tangent * dot(d, tangent));
+  let across = cross(facing, tangent);
+  let h = clamp(dot(point_on_ray(ray, t) - bezier(world, u), across) / radius, -1.0, 1.0);
+  var normal = facing * sqrt(1.0 - h*h) + across * h;
+  if curve.kind == CURVE_RIBBON {
+    normal = normalize(mix(curve.normals[0].xyz, curve.normals[1].xyz, u));
+  }
+  var hit = make_hit(ray, t, normal, curve.material, vec2f(u, h * 0.5 + 0.5));
+  hit.tangent = tangent;
+  return hit;
+}
+fn intersect_curves(ray: Ray, closest: ptr<function, HitRecord>) {
+  // implicit tree, node i has children 2i and 2i + 1 and curve j is leaf n + j
+  let n = tree_sizes.x;
+  let m = tree_sizes.y;
+  var i = 1u;
+  loop {
+    if i < n && intersect_node(ray, nodes[i], (*closest).t) {
+      i *= 2u;
+      continue;
+    }
+    if i >= n {
+      let j = i - n;
+      if j >= m {
+        break;
+      }
+      let hit = intersect_curve(ray, curves[j], (*closest).t);
+      if hit.t > 0 && hit.t < (*closest).t {
+        *closest = hit;
+      }
+    }
+    // next sibling, climbing up past every right child
+    while (i & 1u) == 1u {
+      i /= 2u;
+    }
+    if i == 0u {
+      break;
+    }
+    i++;
+  }
+}
+fn intersect_spheres(ray: Ray, closest: ptr<function, HitRecord>) {
+  // same layout as the curve tree, offset by its nodes
+  let offset = tree_sizes.x;
+  let n = tree_sizes.z;
+  let m = tree_sizes.w;
+  var i = 1u;
+  loop {
+    if i < n && intersect_node(ray, nodes[offset + i], (*closest).t) {
+      i *= 2u;
+      continue;
+    }
+    if i >= n {
+      let j = i - n;
+      if j >= m {
+        break;
+      }
+      let hit = intersect_sphere(ray, scene[j]);
+      if hit.t > 0 && hit.t < (*closest).t {
+        *closest = hit;
+      }
+    }
+    while (i & 1u) == 1u {
+      i /= 2u;
+    }
+    if i == 0u {
+      break;
+    }
+    i++;
+  }
+}
+fn cie_lobe(lambda: f32, mu: f32, sigma_lo: f32, sigma_hi: f32) -> f32 {
+  let sigma = select(sigma_hi, sigma_lo, lambda < mu);
+  let t = (lambda - mu) / sigma;
+  return exp(-0.5 * t * t);
+}
+fn wavelength_to_xyz(lambda: f32) -> vec3f {
+  // multi-lobe fit of the CIE 1931 observer (Wyman, Sloan & Shirley 2013)
+  let x = 1.056 * cie_lobe(lambda, 599.8, 37.9, 31.0)
+    + 0.362 * cie_lobe(lambda, 442.0, 16.0, 26.7)
+    - 0.065 * cie_lobe(lambda, 501.1, 20.4, 26.2);
+  let y = 0.821 * cie_lobe(lambda, 568.8, 46.9, 40.5)
+    + 0.286 * cie_lobe(lambda, 530.9, 16.3, 31.1);
+  let z = 1.217 * cie_lobe(lambda, 437.0, 11.8, 36.0)
+    + 0.681 * cie_lobe(lambda, 459.0, 26.0, 13.8);
+  return vec3f(x, y, z);
+}
+fn sample_wavelengths(state: ptr<function, u32>) -> vec3f {
+  // hero wavelength plus two rotations of it, spread evenly over the visible range
+  let u = rng_float(state);
+  let offsets = fract(vec3f(u, u + 1.0/3.0, u + 2.0/3.0));
+  return LAMBDA_MIN + offsets * (LAMBDA_MAX - LAMBDA_MIN);
+}
+fn rgb_to_spectrum(rgb: vec3f, lambdas: vec3f) -> vec3f {
+  // smooth partition of unity, white upsamples to a flat spectrum
+  let blue = 1.0 - smoothstep(vec3f(480.0), vec3f(510.0), lambdas);
+  let red = smoothstep(vec3f(570.0), vec3f(600.0), lambdas);
+  let green = 1.0 - blue - red;
+  return rgb.r * red + rgb.g * green + rgb.b * blue;
+}
+fn spectrum_to_rgb(values: vec3f, lambdas: vec3f) -> vec3f {
+  var xyz = values.x * wavelength_to_xyz(lambdas.x)
+    + values.y * wavelength_to_xyz(lambdas.y)
+    + values.z * wavelength_to_xyz(lambdas.z);
+  // estimator over 3 uniform samples, scaled so a flat spectrum has Y = 1
+  xyz *= (LAMBDA_MAX - LAMBDA_MIN) / (3.0 * CIE_Y_INTEGRAL);
+  return XYZ_TO_SRGB * xyz / XYZ_TO_SRGB_WHITE;
+}
+fn is_spectral() -> bool {
+  return (flags & FLAG_SPECTRAL) != 0u;
+}
+fn dielectric_ior(material: Material, lambda: f32) -> f32 {
+  // Cauchy's equation with lambda in micrometres
+  let l2 = lambda * lambda * 1e-6;
+  return material.params.x + material.params.y / l2 + material.params.z / (l2 * l2);
+}
+fn is_dispersive(material: Material) -> bool {
+  return material.id == MAT_DIELECTRIC && (material.params.y != 0.0 || material.params.z != 0.0);
+}
+fn hash_cell(cell: vec3i) -> u32 {
+  var h = (u32(cell.x) * 73856093u) ^ (u32(cell.y) * 19349663u) ^ (u32(cell.z) * 83492791u);
+  rng_int(&h);
+  return h;
+}
+fn cell_vec3(cell: vec3i) -> vec3f {
+  // three uniform values in [0, 1) from one cell hash
+  var h = hash_cell(cell);
+  return rng_vec3(&h);
+}
+fn perlin_corner(cell: vec3i, corner: vec3i, f: vec3f) -> f32 {
+  let g = normalize(cell_vec3(cell + corner) * 2.0 - 1.0);
+  return dot(g, f - vec3f(corner));
+}
+fn perlin(p: vec3f) -> f32 {
+  let cell = vec3i(floor(p));
+  let f = fract(p);
+  let u = f*f*f*(f*(f*6.0 - 15.0) + 10.0);
+  let x00 = mix(perlin_corner(cell, vec3i(0, 0, 0), f), perlin_corner(cell, vec3i(1, 0, 0), f), u.x);
+  let x10 = mix(perlin_corner(cell, vec3i(0, 1, 0), f), perlin_corner(cell, vec3i(1, 1, 0), f), u.x);
+  let x01 = mix(perlin_corner(cell, vec3i(0, 0, 1), f), perlin_corner(cell, vec3i(1, 0, 1), f), u.x);
+  let x11 = mix(perlin_corner(cell, vec3i(0, 1, 1), f), perlin_corner(cell, vec3i(1, 1, 1), f), u.x);
+  return mix(mix(x00, x10, u.y), mix(x01, x11, u.y), u.z);
+}
+fn fbm(p: vec3f, octaves: i32) -> f32 {
+  var sum = 0.0;
+  var amplitude = 0.5;
+  var q = p;
+  for (var i = 0; i < octaves; i++) {
+    sum += amplitude * perlin(q);
+    amplitude *= 0.5;
+    q *= 2.0;
+  }
+  return sum;
+}
+fn turbulence(p: vec3f, octaves: i32) -> f32 {
+  var sum = 0.0;
+  var amplitude = 1.0;
+  var q = p;
+  for (var i = 0; i < octaves; i++) {
+    sum += amplitude * abs(perlin(q));
+    amplitude *= 0.5;
+    q *= 2.0;
+  }
+  return sum;
+}
+fn voronoi(p: vec3f) -> f32 {
+  let cell = vec3i(floor(p));
+  var nearest = FLT_MAX;
+  for (var z = -1; z <= 1; z++) {
+    for (var y = -1; y <= 1; y++) {
+      for (var x = -1; x <= 1; x++) {
+        let neighbour = cell + vec3i(x, y, z);
+        let feature = vec3f(neighbour) + cell_vec3(neighbour);
+        nearest = min(nearest, distance(p, feature));
+      }
+    }
+  }
+  return nearest;
+}
+fn image_texel(tex: Texture, x: i32, y: i32) -> vec4f {
+  let offset = bitcast<u32>(tex.params.x);
+  let w = i32(bitcast<u32>(tex.params.y));
+  let h = i32(bitcast<u32>(tex.params.z));
+  let xi = u32(((x % w) + w) % w);
+  let yi = u32(((y % h) + h) % h);
+  return unpack4x8unorm(texels[offset + yi*u32(w) + xi]);
+}
+fn sample_image(tex: Texture, uv: vec2f) -> vec4f {
+  // bilinear with wrap around, image rows go top to bottom while v goes up
+  let size = vec2f(f32(bitcast<u32>(tex.params.y)), f32(bitcast<u32>(tex.params.z)));
+  let st = vec2f(uv.x, 1.0 - uv.y) * size - 0.5;
+  let i = vec2i(floor(st));
+  let f = fract(st);
+  let top = mix(image_texel(tex, i.x, i.y), image_texel(tex, i.x + 1, i.y), f.x);
+  let bottom = mix(image_texel(tex, i.x, i.y + 1), image_texel(tex, i.x + 1, i.y + 1), f.x);
+  return mix(top, bottom, f.y);
+}
+fn sample_texture_rgba(index: u32, point: vec3f, uv: vec2f) -> vec4f {
+  let tex = textures[index - 1u];
+  var p = point;
+  if tex.mapping == MAPPING_UV {
+    p = vec3f(uv, 0.0);
+  }
+  var t = 0.0;
+  switch tex.kind {
+    case TEX_CHECKER: {
+      let c = floor(p * tex.params.x);
+      t = fract((c.x + c.y + c.z) * 0.5) * 2.0;
+    }
+    case TEX_NOISE: {
+      t = fbm(p * tex.params.x, i32(tex.params.y)) * 0.5 + 0.5;
+    }
+    case TEX_MARBLE: {
+      let q = p * tex.params.x;
+      t = 0.5 * (1.0 + sin(q.z + tex.params.y * turbulence(q, i32(tex.params.z))));
+    }
+    case TEX_VORONOI: {
+      t = voronoi(p * tex.params.x);
+    }
+    case TEX_GRADIENT: {
+      t = dot(p, tex.params.xyz) + tex.params.w;
+    }
+    case TEX_IMAGE: {
+      return sample_image(tex, p.xy);
+    }
+    default: {}
+  }
+  return mix(tex.color_a, tex.color_b, clamp(t, 0.0, 1.0));
+}
+fn sample_texture(index: u32, point: vec3f, uv: vec2f) -> vec3f {
+  return sample_texture_rgba(index, point, uv).rgb;
+}
+fn apply_textures(material: Material, point: vec3f, uv: vec2f) -> Material {
+  var ret = material;
+  if material.textures.x != 0u {
+    ret.albedo = vec4f(ret.albedo.rgb * sample_texture(material.textures.x, point, uv), ret.albedo.a);
+  }
+  if material.textures.y != 0u && material.id == MAT_METAL {
+    ret.params.x *= sample_texture(material.textures.y, point, uv).r;
+  }
+  if material.textures.z != 0u {
+    ret.emission = vec4f(ret.emission.rgb * sample_texture(material.textures.z, point, uv), ret.emission.a);
+  }
+  return ret;
 }
 fn reflect(v: vec3f, n: vec3f) -> vec3f {
     return v - 2*dot(v,n)*n;
@@ -169,29 +1048,297 @@ fn reflectance(cosine: f32, ref_idx: f32) -> f32 {
     r0 = r0*r0;
     return r0 + (1-r0)*pow((1 - cosine), 5.0);
 }
-fn scatter(state: ptr<function, u32>, ray: Ray, hit: HitRecord) -> Ray {
+fn fresnel_amplitudes(cos_i: f32, n_i: f32, cos_t: f32, n_t: f32) -> vec2f {
+  let rs = (n_i*cos_i - n_t*cos_t) / (n_i*cos_i + n_t*cos_t);
+  let rp = (n_t*cos_i - n_i*cos_t) / (n_t*cos_i + n_i*cos_t);
+  return vec2f(rs, rp);
+}
+fn airy(r12: f32, r23: vec3f, phase: vec3f) -> vec3f {
+  let interference = 2.0 * r12 * r23 * cos(phase);
+  return (r12*r12 + r23*r23 + interference) / (1.0 + r12*r12*r23*r23 + interference);
+}
+fn has_thin_film(material: Material) -> bool {
+  return material.coating.x > 0.0;
+}
+// Airy reflectance of a film coating.x nm thick with IOR coating.y, lit from a medium
+// with IOR n1. r23s/r23p are the amplitude coefficients of the film/base interface.
+fn thin_film(cos_i: f32, n1: f32, coating: vec4f, cos_film: f32, r23s: vec3f, r23p: vec3f, lambdas: vec3f) -> vec3f {
+  let n2 = coating.y;
+  let r12 = fresnel_amplitudes(cos_i, n1, cos_film, n2);
+  let phase = 2.0 * PI2 * n2 * coating.x * cos_film / lambdas;
+  return 0.5 * (airy(r12.x, r23s, phase) + airy(r12.y, r23p, phase));
+}
+fn film_cosine(cos_i: f32, n_i: f32, n_t: f32) -> f32 {
+  // cosine of the refracted angle, negative on total internal reflection
+  let sin2 = (n_i/n_t) * (n_i/n_t) * (1.0 - cos_i*cos_i);
+  return select(sqrt(1.0 - sin2), -1.0, sin2 >= 1.0);
+}
+fn thin_film_dielectric(cos_i: f32, n1: f32, n3: f32, coating: vec4f, lambdas: vec3f) -> vec3f {
+  let cos_film = film_cosine(cos_i, n1, coating.y);
+  let cos_base = film_cosine(cos_i, n1, n3);
+  if cos_film < 0.0 || cos_base < 0.0 {
+    return vec3f(1.0);
+  }
+  let r23 = fresnel_amplitudes(cos_film, coating.y, cos_base, n3);
+  return thin_film(cos_i, n1, coating, cos_film, vec3f(r23.x), vec3f(r23.y), lambdas);
+}
+fn thin_film_conductor(cos_i: f32, coating: vec4f, f0: vec3f, lambdas: vec3f) -> vec3f {
+  let cos_film = film_cosine(cos_i, 1.0, coating.y);
+  if cos_film < 0.0 {
+    return f0;
+  }
+  // conductors flip the phase on reflection, treat the base as a real -sqrt(F0) amplitude
+  let r23 = -sqrt(f0);
+  return thin_film(cos_i, 1.0, coating, cos_film, r23, r23, lambdas);
+}
+// Hair after Chiang et al. 2016 "A Practical and Controllable Hair and Fur Model for
+// Production Path Tracing", following pbrt. Directions are in the fibre frame, x along
+// the fibre and z towards the viewer. Lobe p is R, TT, TRT and the rest.
+struct HairLobes {
+  ap: array<vec3f, 4>,
+  pdf: array<f32, 4>,
+  // longitudinal variance
+  v: array<f32, 4>,
+  // azimuthal logistic scale
+  s: f32,
+  sin_2k_alpha: vec3f,
+  cos_2k_alpha: vec3f,
+  sin_theta_o: f32,
+  cos_theta_o: f32,
+  phi_o: f32,
+  gamma_o: f32,
+  gamma_t: f32,
+}
+fn hair_i0(x: f32) -> f32 {
+  var value = 0.0;
+  var x2i = 1.0;
+  var factorial = 1.0;
+  var four_i = 1.0;
+  for (var i = 0; i < 10; i++) {
+    if i > 1 {
+      factorial *= f32(i);
+    }
+    value += x2i / (four_i * factorial * factorial);
+    x2i *= x * x;
+    four_i *= 4.0;
+  }
+  return value;
+}
+fn hair_log_i0(x: f32) -> f32 {
+  if x > 12.0 {
+    return x + 0.5 * (-log(PI2) + log(1.0 / x) + 1.0 / (8.0 * x));
+  }
+  return log(hair_i0(x));
+}
+fn hair_mp(cos_theta_i: f32, cos_theta_o: f32, sin_theta_i: f32, sin_theta_o: f32, v: f32) -> f32 {
+  let a = cos_theta_i * cos_theta_o / v;
+  let b = sin_theta_i * sin_theta_o / v;
+  if v <= 0.1 {
+    return exp(hair_log_i0(a) - b - 1.0 / v + 0.6931 + log(1.0 / (2.0 * v)));
+  }
+  return exp(-b) * hair_i0(a) / (sinh(1.0 / v) * 2.0 * v);
+}
+fn logistic_cdf(x: f32, s: f32) -> f32 {
+  return 1.0 / (1.0 + exp(-x / s));
+}
+fn trimmed_logistic(x: f32, s: f32) -> f32 {
+  let e = exp(-abs(x) / s);
+  let logistic = e / (s * (1.0 + e) * (1.0 + e));
+  return logistic / (logistic_cdf(PI, s) - logistic_cdf(-PI, s));
+}
+fn sample_trimmed_logistic(u: f32, s: f32) -> f32 {
+  let k = logistic_cdf(PI, s) - logistic_cdf(-PI, s);
+  let x = -s * log(1.0 / (u * k + logistic_cdf(-PI, s)) - 1.0);
+  return clamp(x, -PI, PI);
+}
+fn hair_phi(p: i32, gamma_o: f32, gamma_t: f32) -> f32 {
+  return 2.0 * f32(p) * gamma_t - 2.0 * gamma_o + f32(p) * PI;
+}
+fn hair_np(phi: f32, p: i32, lobes: HairLobes) -> f32 {
+  let dphi = phi - hair_phi(p, lobes.gamma_o, lobes.gamma_t);
+  return trimmed_logistic(dphi - PI2 * round(dphi / PI2), lobes.s);
+}
+fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+  let c = clamp(cos_i, 0.0, 1.0);
+  let sin_t = sqrt(1.0 - c*c) / eta;
+  if sin_t >= 1.0 {
+    return 1.0;
+  }
+  let cos_t = sqrt(1.0 - sin_t*sin_t);
+  let r_parallel = (eta*c - cos_t) / (eta*c + cos_t);
+  let r_perpendicular = (c - eta*cos_t) / (c + eta*cos_t);
+  return (r_parallel*r_parallel + r_perpendicular*r_perpendicular) * 0.5;
+}
+fn hair_lobes(material: Material, wo: vec3f, h: f32) -> HairLobes {
+  var lobes: HairLobes;
+  let beta_m = material.params.x;
+  let beta_n = material.params.y;
+  let alpha = material.params.z;
+  let sigma_a = material.albedo.rgb;
+  lobes.sin_theta_o = wo.x;
+  lobes.cos_theta_o = max(sqrt(max(0.0, 1.0 - wo.x*wo.x)), EPSILON);
+  lobes.phi_o = atan2(wo.z, wo.y);
+  lobes.gamma_o = asin(h);
+  let v = 0.726*beta_m + 0.812*beta_m*beta_m + 3.7*pow(beta_m, 20.0);
+  lobes.v = array<f32, 4>(v*v, 0.25*v*v, 4.0*v*v, 4.0*v*v);
+  lobes.s = sqrt(PI / 8.0) * (0.265*beta_n + 1.194*beta_n*beta_n + 5.372*pow(beta_n, 22.0));
+  // the cuticle scales tilt R by -2 alpha, TT by alpha and TRT by 4 alpha
+  let sin_a = sin(alpha);
+  let cos_a = sqrt(1.0 - sin_a*sin_a);
+  let sin_2a = 2.0 * cos_a * sin_a;
+  let cos_2a = cos_a*cos_a - sin_a*sin_a;
+  lobes.sin_2k_alpha = vec3f(sin_a, sin_2a, 2.0 * cos_2a * sin_2a);
+  lobes.cos_2k_alpha = vec3f(cos_a, cos_2a, cos_2a*cos_2a - sin_2a*sin_2a);
+  // path of the refracted ray inside the fibre
+  let sin_theta_t = lobes.sin_theta_o / HAIR_ETA;
+  let cos_theta_t = sqrt(1.0 - sin_theta_t*sin_theta_t);
+  let etap = sqrt(HAIR_ETA*HAIR_ETA - lobes.sin_theta_o*lobes.sin_theta_o) / lobes.cos_theta_o;
+  let sin_gamma_t = clamp(h / etap, -1.0, 1.0);
+  let cos_gamma_t = sqrt(1.0 - sin_gamma_t*sin_gamma_t);
+  lobes.gamma_t = asin(sin_gamma_t);
+  let transmittance = exp(-sigma_a * (2.0 * cos_gamma_t / cos_theta_t));
+  let f = fresnel_dielectric(lobes.cos_theta_o * sqrt(1.0 - h*h), HAIR_ETA);
+  lobes.ap[0] = vec3f(f);
+  lobes.ap[1] = (1.0 - f) * (1.0 - f) * transmittance;
+  lobes.ap[2] = lobes.ap[1] * transmittance * f;
+  lobes.ap[3] = lobes.ap[2] * f * transmittance / (1.0 - transmittance * f);
+  // lobes are sampled by their share of the luminance
+  var total = 0.0;
+  for (var p = 0; p < 4; p++) {
+    lobes.pdf[p] = dot(lobes.ap[p], vec3f(0.2126, 0.7152, 0.0722));
+    total += lobes.pdf[p];
+  }
+  for (var p = 0; p < 4; p++) {
+    lobes.pdf[p] /= total;
+  }
+  return lobes;
+}
+// sine and cosine of theta_o turned by the scale tilt of lobe p
+fn hair_tilt(lobes: HairLobes, p: i32) -> vec2f {
+  let so = lobes.sin_theta_o;
+  let co = lobes.cos_theta_o;
+  let s = lobes.sin_2k_alpha;
+  let c = lobes.cos_2k_alpha;
+  switch p {
+    case 0: {
+      return vec2f(so*c.y - co*s.y, abs(co*c.y + so*s.y));
+    }
+    case 1: {
+      return vec2f(so*c.x + co*s.x, abs(co*c.x - so*s.x));
+    }
+    case 2: {
+      return vec2f(so*c.z + co*s.z, abs(co*c.z - so*s.z));
+    }
+    default: {
+      return vec2f(so, co);
+    }
+  }
+}
+// BSDF times the cosine in rgb and the sampling pdf in w
+fn hair_eval(lobes: HairLobes, wi: vec3f) -> vec4f {
+  let sin_theta_i = wi.x;
+  let cos_theta_i = sqrt(max(0.0, 1.0 - wi.x*wi.x));
+  let phi = atan2(wi.z, wi.y) - lobes.phi_o;
+  var f = vec3f(0.0);
+  var pdf = 0.0;
+  for (var p = 0; p < 4; p++) {
+    let o = hair_tilt(lobes, p);
+    let mp = hair_mp(cos_theta_i, o.y, sin_theta_i, o.x, lobes.v[p]);
+    let np = select(1.0 / PI2, hair_np(phi, p, lobes), p < 3);
+    f += mp * np * lobes.ap[p];
+    pdf += mp * np * lobes.pdf[p];
+  }
+  return vec4f(f, pdf);
+}
+fn scatter_hair(state: ptr<function, u32>, ray: Ray, hit: HitRecord, albedo: ptr<function, vec3f>) -> Ray {
+  let x = hit.tangent;
+  let wo_world = -normalize(ray.direction);
+  let z = normalize(wo_world - x * dot(wo_world, x));
+  let y = cross(z, x);
+  let wo = vec3f(dot(wo_world, x), dot(wo_world, y), dot(wo_world, z));
+  let lobes = hair_lobes(hit.material, wo, hit.uv.y * 2.0 - 1.0);
+  var u = rng_float(state);
+  var p = 0;
+  while p < 3 && u >= lobes.pdf[p] {
+    u -= lobes.pdf[p];
+    p++;
+  }
+  // longitudinal angle from the lobe's von Mises-Fisher like distribution
+  let o = hair_tilt(lobes, p);
+  let v = lobes.v[p];
+  let u1 = max(rng_float(state), 0.00001);
+  let cos_theta = 1.0 + v * log(u1 + (1.0 - u1) * exp(-2.0 / v));
+  let sin_theta = sqrt(max(0.0, 1.0 - cos_theta*cos_theta));
+  let cos_phi = cos(PI2 * rng_float(state));
+  let sin_theta_i = clamp(-cos_theta*o.x + sin_theta*cos_phi*o.y, -1.0, 1.0);
+  let cos_theta_i = sqrt(1.0 - sin_theta_i*sin_theta_i);
+  // azimuth around the fibre
+  var dphi = PI2 * rng_float(state);
+  if p < 3 {
+    dphi = hair_phi(p, lobes.gamma_o, lobes.gamma_t) + sample_trimmed_logistic(rng_float(state), lobes.s);
+  }
+  let phi_i = lobes.phi_o + dphi;
+  let wi = vec3f(sin_theta_i, cos_theta_i * cos(phi_i), cos_theta_i * sin(phi_i));
+  let e = hair_eval(lobes, wi);
+  *albedo = select(vec3f(0.0), e.rgb / e.w, e.w > 0.0);
+  return Ray(hit.point, wi.x*x + wi.y*y + wi.z*z);
+}
+// Reflectance of a dielectric over lambdas. Schlick's approximation unless the surface
+// has a thin film, whose interference colours the reflection.
+fn dielectric_reflectance(material: Material, cos_theta: f32, ir: f32, front_face: bool, lambda: f32, lambdas: vec3f) -> vec3f {
+  if !has_thin_film(material) {
+    return vec3f(reflectance(cos_theta, ir));
+  }
+  let n = dielectric_ior(material, lambda);
+  let n1 = select(n, 1.0, front_face);
+  let n3 = select(1.0, n, front_face);
+  return thin_film_dielectric(min(abs(cos_theta), 1.0), n1, n3, material.coating, lambdas);
+}
+fn scatter(state: ptr<function, u32>, ray: Ray, hit: HitRecord, lambdas: vec3f, albedo: ptr<function, vec3f>) -> Ray {
+  *albedo = hit.material.albedo.rgb;
+  if is_spectral() {
+    *albedo = rgb_to_spectrum(*albedo, lambdas);
+  }
+  // dispersion follows the hero wavelength, RGB mode uses a single IOR
+  let lambda = select(RGB_WAVELENGTH, lambdas.x, is_spectral());
   switch hit.material.id {
     case MAT_LAMBERTIAN: {
       let direction = random_on_hemisphere(state, hit.normal);
       return Ray(hit.point, direction);
     }
+    case MAT_HAIR: {
+      let scattered = scatter_hair(state, ray, hit, albedo);
+      if is_spectral() {
+        *albedo = rgb_to_spectrum(*albedo, lambdas);
+      }
+      return scattered;
+    }
     case MAT_METAL: {
       let fuzziness = hit.material.params.x;
       let direction = reflect(normalize(ray.direction), hit.normal) + fuzziness * random_on_hemisphere(state, hit.normal);
+      if has_thin_film(hit.material) {
+        let cos_theta = abs(dot(normalize(ray.direction), hit.normal));
+        *albedo = thin_film_conductor(cos_theta, hit.material.coating, *albedo, lambdas);
+      }
       return Ray(hit.point, normalize(direction));
     }
     case MAT_DIELECTRIC: {
-      var ir = hit.material.params.x;
+      var ir = dielectric_ior(hit.material, lambda);
       if hit.front_face {
         ir = 1.0/ir;
       }
       let cos_theta = min(dot(-ray.direction, hit.normal), 1.0);
       let sin_theta = sqrt(1.0 - cos_theta*cos_theta);
       let cannot_refract = ir * sin_theta > 1.0;
-      if (cannot_refract || reflectance(cos_theta, ir) > fract(rng_float(state))) {
+      // the mean reflectance picks reflect or refract, a film's colour goes into the path weight
+      let r = dielectric_reflectance(hit.material, cos_theta, ir, hit.front_face, lambda, lambdas);
+      let p = (r.x + r.y + r.z) / 3.0;
+      if (cannot_refract || p > fract(rng_float(state))) {
+          *albedo *= r / p;
           let direction = reflect(ray.direction, hit.normal);
           return Ray(hit.point, normalize(direction));
       } else {
+          *albedo *= (1.0 - r) / (1.0 - p);
           let direction = refract(ray.direction, hit.normal, ir);
           return Ray(hit.point, normalize(direction));
       }
@@ -215,31 +1362,58 @@ fn scatter(state: ptr<function, u32>, ray: Ray, hit: HitRecord) -> Ray {
     }
   }
 }
-fn intersect_all_sphere(ray: Ray) -> HitRecord {
+fn intersect_all(ray: Ray) -> HitRecord {
   var closest_hit: HitRecord;
   closest_hit.t = FLT_MAX;
-  for (var i = 0u; i < arrayLength(&scene); i++) {
-    let obj = scene[i];
-    let hit = intersect_sphere(ray, obj);
+  intersect_spheres(ray, &closest_hit);
+  for (var i = 0u; i < arrayLength(&primitives); i++) {
+    // primitives are packed from the start, the rest of the buffer is zeroed
+    if primitives[i].kind == 0u {
+      break;
+    }
+    let hit = intersect_primitive(ray, primitives[i]);
     if hit.t > 0 && hit.t < closest_hit.t {
       closest_hit = hit;
     }
   }
+  intersect_curves(ray, &closest_hit);
   return closest_hit;
 }
 fn trace(ray: Ray, state: ptr<function, u32>) -> vec3f {
+  let spectral = is_spectral();
+  var lambdas = RGB_WAVELENGTHS;
+  if spectral {
+    lambdas = sample_wavelengths(state);
+  }
+  var collapsed = false;
+  var radiance = vec3f(0);
   var attenuation = vec3f(1);
   var current_ray = ray;
   for(var b = 0;b < BOUNCE_MAX; b++) {
-    let hit = intersect_all_sphere(current_ray);
+    var hit = intersect_all(current_ray);
     if abs(hit.t - FLT_MAX) < EPSILON {
       break;
     }
-    current_ray = scatter(state, current_ray, hit);
-    attenuation *= hit.material.albedo.rgb * 0.7;
+    hit.material = apply_textures(hit.material, hit.point, hit.uv);
+    var emission = hit.material.emission.rgb;
+    if spectral {
+      emission = rgb_to_spectrum(emission, lambdas);
+    }
+    radiance += attenuation * emission;
+    if spectral && !collapsed && is_dispersive(hit.material) {
+      // the secondary wavelengths would refract elsewhere, only the hero carries on
+      attenuation *= vec3f(3.0, 0.0, 0.0);
+      collapsed = true;
+    }
+    var albedo = vec3f(1);
+    current_ray = scatter(state, current_ray, hit, lambdas, &albedo);
+    attenuation *= albedo * 0.7;
   }
   let sky = mix(SKY, BLUE, ray.direction.y*0.5 + 0.5);
-  return attenuation * sky;
+  if spectral {
+    return spectrum_to_rgb(radiance + attenuation * rgb_to_spectrum(sky, lambdas), lambdas);
+  }
+  return radiance + attenuation * sky;
 }
 
 
@@ -256,6 +1430,7 @@ fn fs_main(@builtin(position) position: vec4f) -> @location(0) vec4f {
   var uv = position_aa / (vec2f(resolution) - vec2f(1));
   uv = (2 * uv - vec2(1)) * vec2(aspect_ratio, -1);
   let ray = make_ray(uv, &rng_state);
+  ray_time = shutter_time(&rng_state);
   var color = vec3f(0);
   for (var i = 0; i < SAMPLE_PER_FRAME; i += 1) {
     color += trace(ray, &rng_state);
@@ -5,12 +5,44 @@ const FLT_MAX = 3.40282e+38;
 const MAT_LAMBERTIAN = 1u;
 const MAT_METAL = 2u;
 const MAT_DIELECTRIC = 3u;
+const TEX_CHECKER = 1u;
+const TEX_NOISE = 2u;
+const TEX_MARBLE = 3u;
+const TEX_VORONOI = 4u;
+const TEX_GRADIENT = 5u;
+const TEX_IMAGE = 6u;
+const MAPPING_UV = 1u;
+const BUMP_NORMAL_MAP = 1u;
+const MATERIAL_VERTEX_COLORS = 1u;
+const BUMP_DELTA = 0.001;
 const SKY = vec3f(0.54, 0.86, 0.92);
 const BLUE = vec3f(0.54, 0.7, 0.98);
 const RED = vec3f(0.98, 0.2, 0.2);
 const SAMPLE_FRAME = 1000;
 const SAMPLE_PER_FRAME = 1;
+const FLAG_SPECTRAL = 1u;
+const FLAG_HEATMAP = 2u;
+// traversal loops give up after this many steps, see `heatmap`
+const MAX_STEPS = 600;
+const LAMBDA_MIN = 380.0;
+const LAMBDA_MAX = 780.0;
+const RGB_WAVELENGTH = 550.0;
+const RGB_WAVELENGTHS = vec3f(630.0, 532.0, 465.0);
+const CIE_Y_INTEGRAL = 106.857;
+const XYZ_TO_SRGB = mat3x3f(
+  3.2406, -0.9689, 0.0557,
+  -1.5372, 1.8758, -0.2040,
+  -0.4986, 0.0415, 1.0570,
+);
+// linear sRGB of the equal energy white, divided out so flat spectra stay white
+const XYZ_TO_SRGB_WHITE = vec3f(1.2048, 0.9484, 0.9087);
 const BOUNCE_MAX = 5;
+// must match `MAX_MOTIONS`
+const MAX_MOTIONS = 256;
+// a child link to a triangle slot rather than a node, see `bvh::node::LEAF`
+const LEAF = 0x80000000u;
+// nodes a traversal stack holds, deeper than any sorted tree gets
+const MAX_DEPTH = 64u;
 
 @group(0) @binding(0)
 var<uniform> resolution: vec2u;
@@ -22,14 +54,38 @@ var<uniform> time: u32;
 var<storage, read_write> image: array<f32>;
 @group(0) @binding(4)
 var<uniform> camera: Camera;
+@group(0) @binding(5)
+var<uniform> flags: u32;
+// nodes, triangles and children per node
 @group(1) @binding(0)
-var<uniform> bvh_tree_size: vec2u;
+var<uniform> bvh_tree_size: vec4u;
+// `Node`s of the binary tree, links in their w lanes, or nodes of a wide tree, see `wide_hits`
 @group(1) @binding(1)
-var<storage> nodes: array<Node>;
+var<storage> nodes: array<vec4u>;
 @group(1) @binding(2)
 var<storage> triangles: array<Triangle>;
 @group(1) @binding(3)
 var<storage> materials: array<Material>;
+@group(1) @binding(4)
+var<storage> textures: array<Texture>;
+@group(1) @binding(5)
+var<storage> attributes: array<TriangleAttributes>;
+@group(1) @binding(6)
+var<storage> texels: array<u32>;
+@group(1) @binding(7)
+var<uniform> point_tree_size: vec2u;
+@group(1) @binding(8)
+var<storage> point_nodes: array<Node>;
+@group(1) @binding(9)
+var<storage> points: array<Point>;
+// motions of the moving meshes, see `Material::motion`
+@group(1) @binding(10)
+var<uniform> motions: array<Motion, MAX_MOTIONS>;
+// steps taken by the last `intersect_all_node`, and whether a loop ran out of them
+var<private> traversal_steps: i32;
+var<private> traversal_truncated: bool;
+// when in the frame the path being traced sees the scene, see `shutter_time`
+var<private> ray_time: f32;
 
 struct Camera {
   eye: vec4f,
@@ -39,6 +95,7 @@ struct Camera {
   focal_length: f32,
   focal_blur_amount: f32,
   fov: f32,
+  shutter: vec4f,
 }
 struct Ray {
   origin: vec3f,
@@ -55,10 +112,45 @@ struct Triangle {
   normal: vec3f,
   material: u32,
 }
+struct Point {
+  position: vec3f,
+  radius: f32,
+  normal: vec3f,
+  material: u32,
+  color: vec4f,
+}
+struct TriangleAttributes {
+  // xyz is the normal, w the bits of the vertex colour as sRGB RGBA8
+  normals: array<vec4u, 3>,
+  tangents: array<vec4f, 3>,
+  uvs: array<vec2f, 3>,
+}
 struct Material {
     albedo: vec4f,
     params: vec3f,
     id: u32,
+    coating: vec4f,
+    emission: vec4f,
+    textures: vec4u,
+    bump: vec4f,
+    cutout: f32,
+    flags: u32,
+    // one past the index in `motions`, zero for still meshes
+    motion: u32,
+}
+// see `Motion`
+struct Motion {
+  translation: vec4f,
+  // axis in xyz, angle over the frame in w
+  rotation: vec4f,
+  pivot: vec4f,
+}
+struct Texture {
+  color_a: vec4f,
+  color_b: vec4f,
+  params: vec4f,
+  kind: u32,
+  mapping: u32,
 }
 struct HitRecord {
   point: vec3f,
@@ -66,11 +158,14 @@ struct HitRecord {
   t: f32,
   material: Material,
   front_face: bool,
+  uv: vec2f,
+  triangle: u32,
+  barycentric: vec2f,
 }
 
-const DEFAULT_MATERIAL = Material(vec4f(0.0,0.4,0.0,1.0), vec3f(), MAT_LAMBERTIAN);
-const EMPTY_HIT_RECORD = HitRecord(vec3f(), vec3f(), FLT_MAX, DEFAULT_MATERIAL, false);
-const GRAY_MATERIAL = Material(vec4f(0.5,0.5,0.6,1.0), vec3f(), MAT_LAMBERTIAN);
+const DEFAULT_MATERIAL = Material(vec4f(0.0,0.4,0.0,1.0), vec3f(), MAT_LAMBERTIAN, vec4f(), vec4f(), vec4u(), vec4f(), 0.0, 0u, 0u);
+const EMPTY_HIT_RECORD = HitRecord(vec3f(), vec3f(), FLT_MAX, DEFAULT_MATERIAL, false, vec2f(), 0u, vec2f());
+const GRAY_MATERIAL = Material(vec4f(0.5,0.5,0.6,1.0), vec3f(), MAT_LAMBERTIAN, vec4f(), vec4f(), vec4u(), vec4f(), 0.0, 0u, 0u);
 
 @vertex
 fn vs_main(@builtin(vertex_index) vertexIndex: u32) -> @builtin(position) vec4f {
@@ -147,6 +242,33 @@ fn make_ray(uv: vec2f, state: ptr<function, u32>) -> Ray {
   return Ray(origin.xyz, direction.xyz);
 }
 
+// a random time while the shutter is open, a closed one takes no random number so still
+// images render as they always did
+fn shutter_time(state: ptr<function, u32>) -> f32 {
+  if camera.shutter.y <= camera.shutter.x {
+    return camera.shutter.x;
+  }
+  return mix(camera.shutter.x, camera.shutter.y, rng_float(state));
+}
+fn quat_rotate(q: vec4f, v: vec3f) -> vec3f {
+  return v + 2.0 * cross(q.xyz, cross(q.xyz, v) + q.w * v);
+}
+fn is_moving(material: u32) -> bool {
+  return materials[material].motion != 0u;
+}
+fn material_motion(material: u32) -> Motion {
+  return motions[materials[material].motion - 1u];
+}
+// the turn of `motion` at `ray_time` as a quaternion
+fn motion_rotation(motion: Motion) -> vec4f {
+  let half = 0.5 * motion.rotation.w * ray_time;
+  return vec4f(motion.rotation.xyz * sin(half), cos(half));
+}
+fn move_point(motion: Motion, q: vec4f, p: vec3f) -> vec3f {
+  let pivot = motion.pivot.xyz;
+  return pivot + quat_rotate(q, p - pivot) + motion.translation.xyz * ray_time;
+}
+
 fn intersect_node(r: Ray, node: Node) -> bool {
   let inv_d = 1.0 / r.direction;
   let t0 = (node.bound_min.xyz - r.origin) * inv_d;
@@ -158,12 +280,68 @@ fn intersect_node(r: Ray, node: Node) -> bool {
   return tmin_final <= tmax_final && tmax_final >= 0.0;
 }
 
+fn binary_node(i: u32) -> Node {
+  return Node(bitcast<vec3f>(nodes[2u * i].xyz), bitcast<vec3f>(nodes[2u * i + 1u].xyz));
+}
+
+// the links to the children of binary node `i`, see `Tree::nodes`
+fn binary_links(i: u32) -> vec2u {
+  return vec2u(nodes[2u * i].w, nodes[2u * i + 1u].w);
+}
+
+// size of a wide node in words
+fn wide_stride(width: u32) -> u32 {
+  return 1u + (6u * width + 15u) / 16u + width / 4u;
+}
+
+// the link to child `c` of wide node `node`, after its child bounds
+fn wide_link(node: u32, c: u32) -> u32 {
+  let width = bvh_tree_size.z;
+  let base = (node + 1u) * wide_stride(width) - width / 4u;
+  return nodes[base + c / 4u][c % 4u];
+}
+
+// byte `b` of the child bounds of the wide node at word `base`
+fn wide_byte(base: u32, b: u32) -> u32 {
+  return (nodes[base + 1u + b / 16u][(b / 4u) % 4u] >> (8u * (b % 4u))) & 255u;
+}
+
+// children of wide node `node` whose box `ray` hits, as bits, see `bvh::wide` for the
+// layout. Cells are powers of two made from the exponent bits, so decoding is exact.
+fn wide_hits(ray: Ray, node: u32) -> u32 {
+  let width = bvh_tree_size.z;
+  let base = node * wide_stride(width);
+  let header = nodes[base];
+  let origin = bitcast<vec3f>(header.xyz);
+  let cell = bitcast<vec3f>(((vec3u(header.w) >> vec3u(0u, 8u, 16u)) & vec3u(255u)) << vec3u(23u));
+  var hits = 0u;
+  for (var c = 0u; c < width; c++) {
+    let lo = vec3u(wide_byte(base, c), wide_byte(base, width + c), wide_byte(base, 2u * width + c));
+    if lo.x > wide_byte(base, 3u * width + c) {
+      continue;
+    }
+    let hi = vec3u(wide_byte(base, 3u * width + c), wide_byte(base, 4u * width + c), wide_byte(base, 5u * width + c));
+    if intersect_node(ray, Node(origin + vec3f(lo) * cell, origin + vec3f(hi) * cell)) {
+      hits |= 1u << c;
+    }
+  }
+  return hits;
+}
+
 fn intersect_triangle(ray: Ray, i: u32, ret: ptr<function, HitRecord>) {
-  let a = triangles[i].a.xyz;
-  let b = triangles[i].b.xyz;
-  let c = triangles[i].c.xyz;
-  let normal = triangles[i].normal;
+  var a = triangles[i].a.xyz;
+  var b = triangles[i].b.xyz;
+  var c = triangles[i].c.xyz;
+  var normal = triangles[i].normal;
   let material = triangles[i].material;
+  if is_moving(material) {
+    let motion = material_motion(material);
+    let q = motion_rotation(motion);
+    a = move_point(motion, q, a);
+    b = move_point(motion, q, b);
+    c = move_point(motion, q, c);
+    normal = quat_rotate(q, normal);
+  }
   // Moller-Trumbore intersection algorithm
   let edge1 = b - a;
   let edge2 = c - a;
@@ -193,14 +371,378 @@ fn intersect_triangle(ray: Ray, i: u32, ret: ptr<function, HitRecord>) {
   if t < EPSILON || t >= (*ret).t {
     return;
   }
+  if is_cut_out(materials[material], point_on_ray(ray, t), i, vec2f(u, v)) {
+    return;
+  }
 
   (*ret).point = point_on_ray(ray, t);
   (*ret).normal = normal;
   (*ret).t = t;
   (*ret).material = materials[material];
   (*ret).front_face = dot((*ret).normal, ray.direction) > 0;
+  (*ret).triangle = i;
+  (*ret).barycentric = vec2f(u, v);
+}
+
+// Points are disks facing their normal, seen from both sides, or spheres without one.
+fn intersect_point(ray: Ray, i: u32, ret: ptr<function, HitRecord>) {
+  let p = points[i];
+  let oc = ray.origin - p.position;
+  var t = FLT_MAX;
+  var normal = p.normal;
+  if dot(p.normal, p.normal) == 0.0 {
+    let a = dot(ray.direction, ray.direction);
+    let half_b = dot(oc, ray.direction);
+    let c = dot(oc, oc) - p.radius*p.radius;
+    let discriminant = half_b*half_b - a*c;
+    if discriminant < 0.0 {
+      return;
+    }
+    let sqrtd = sqrt(discriminant);
+    t = (-half_b - sqrtd) / a;
+    if t < EPSILON {
+      t = (-half_b + sqrtd) / a;
+    }
+    normal = (oc + t*ray.direction) / p.radius;
+  } else {
+    let denominator = dot(ray.direction, p.normal);
+    if abs(denominator) < EPSILON {
+      return;
+    }
+    t = -dot(oc, p.normal) / denominator;
+    let offset = oc + t*ray.direction;
+    if dot(offset, offset) > p.radius*p.radius {
+      return;
+    }
+    normal = select(p.normal, -p.normal, denominator > 0.0);
+  }
+  if t < EPSILON || t >= (*ret).t {
+    return;
+  }
+  var material = materials[p.material];
+  material.albedo = vec4f(material.albedo.rgb * p.color.rgb, material.albedo.a);
+  (*ret).point = point_on_ray(ray, t);
+  (*ret).normal = normal;
+  (*ret).t = t;
+  (*ret).material = material;
+  (*ret).front_face = dot(normal, ray.direction) > 0;
+  (*ret).uv = vec2f();
+}
+fn intersect_points(ray: Ray, ret: ptr<function, HitRecord>) {
+  var i = 1u;
+  let n = point_tree_size.x;
+  let m = point_tree_size.y;
+  var step = 0;
+  loop {
+    if step == MAX_STEPS {
+      traversal_truncated = true;
+      break;
+    }
+    step++;
+    if i < n && intersect_node(ray, point_nodes[i]) {
+      i *= 2u;
+      continue;
+    }
+    if i >= n {
+      let j = i - n;
+      if j >= m {
+        break;
+      }
+      intersect_point(ray, j, ret);
+    }
+    while (i & 1u) == 1u {
+      i /= 2u;
+    }
+    if i == 0u {
+      break;
+    }
+    i++;
+  }
+  traversal_steps += step;
 }
 
+fn cie_lobe(lambda: f32, mu: f32, sigma_lo: f32, sigma_hi: f32) -> f32 {
+  let sigma = select(sigma_hi, sigma_lo, lambda < mu);
+  let t = (lambda - mu) / sigma;
+  return exp(-0.5 * t * t);
+}
+fn wavelength_to_xyz(lambda: f32) -> vec3f {
+  // multi-lobe fit of the CIE 1931 observer (Wyman, Sloan & Shirley 2013)
+  let x = 1.056 * cie_lobe(lambda, 599.8, 37.9, 31.0)
+    + 0.362 * cie_lobe(lambda, 442.0, 16.0, 26.7)
+    - 0.065 * cie_lobe(lambda, 501.1, 20.4, 26.2);
+  let y = 0.821 * cie_lobe(lambda, 568.8, 46.9, 40.5)
+    + 0.286 * cie_lobe(lambda, 530.9, 16.3, 31.1);
+  let z = 1.217 * cie_lobe(lambda, 437.0, 11.8, 36.0)
+    + 0.681 * cie_lobe(lambda, 459.0, 26.0, 13.8);
+  return vec3f(x, y, z);
+}
+fn sample_wavelengths(state: ptr<function, u32>) -> vec3f {
+  // hero wavelength plus two rotations of it, spread evenly over the visible range
+  let u = rng_float(state);
+  let offsets = fract(vec3f(u, u + 1.0/3.0, u + 2.0/3.0));
+  return LAMBDA_MIN + offsets * (LAMBDA_MAX - LAMBDA_MIN);
+}
+fn rgb_to_spectrum(rgb: vec3f, lambdas: vec3f) -> vec3f {
+  // smooth partition of unity, white upsamples to a flat spectrum
+  let blue = 1.0 - smoothstep(vec3f(480.0), vec3f(510.0), lambdas);
+  let red = smoothstep(vec3f(570.0), vec3f(600.0), lambdas);
+  let green = 1.0 - blue - red;
+  return rgb.r * red + rgb.g * green + rgb.b * blue;
+}
+fn spectrum_to_rgb(values: vec3f, lambdas: vec3f) -> vec3f {
+  var xyz = values.x * wavelength_to_xyz(lambdas.x)
+    + values.y * wavelength_to_xyz(lambdas.y)
+    + values.z * wavelength_to_xyz(lambdas.z);
+  // estimator over 3 uniform samples, scaled so a flat spectrum has Y = 1
+  xyz *= (LAMBDA_MAX - LAMBDA_MIN) / (3.0 * CIE_Y_INTEGRAL);
+  return XYZ_TO_SRGB * xyz / XYZ_TO_SRGB_WHITE;
+}
+fn is_spectral() -> bool {
+  return (flags & FLAG_SPECTRAL) != 0u;
+}
+fn is_heatmap() -> bool {
+  return (flags & FLAG_HEATMAP) != 0u;
+}
+// traversal steps from blue through green to red on a square root scale, so shallow
+// traversals still tell apart, magenta where a loop hit `MAX_STEPS` and missed geometry
+fn heatmap(steps: i32, truncated: bool) -> vec3f {
+  if truncated {
+    return vec3f(1.0, 0.0, 1.0);
+  }
+  let t = sqrt(clamp(f32(steps) / f32(MAX_STEPS), 0.0, 1.0));
+  return vec3f(clamp(2.0 * t - 1.0, 0.0, 1.0), 1.0 - abs(2.0 * t - 1.0), clamp(1.0 - 2.0 * t, 0.0, 1.0));
+}
+fn dielectric_ior(material: Material, lambda: f32) -> f32 {
+  // Cauchy's equation with lambda in micrometres
+  let l2 = lambda * lambda * 1e-6;
+  return material.params.x + material.params.y / l2 + material.params.z / (l2 * l2);
+}
+fn is_dispersive(material: Material) -> bool {
+  return material.id == MAT_DIELECTRIC && (material.params.y != 0.0 || material.params.z != 0.0);
+}
+fn hash_cell(cell: vec3i) -> u32 {
+  var h = (u32(cell.x) * 73856093u) ^ (u32(cell.y) * 19349663u) ^ (u32(cell.z) * 83492791u);
+  rng_int(&h);
+  return h;
+}
+fn cell_vec3(cell: vec3i) -> vec3f {
+  // three uniform values in [0, 1) from one cell hash
+  var h = hash_cell(cell);
+  return rng_vec3(&h);
+}
+fn perlin_corner(cell: vec3i, corner: vec3i, f: vec3f) -> f32 {
+  let g = normalize(cell_vec3(cell + corner) * 2.0 - 1.0);
+  return dot(g, f - vec3f(corner));
+}
+fn perlin(p: vec3f) -> f32 {
+  let cell = vec3i(floor(p));
+  let f = fract(p);
+  let u = f*f*f*(f*(f*6.0 - 15.0) + 10.0);
+  let x00 = mix(perlin_corner(cell, vec3i(0, 0, 0), f), perlin_corner(cell, vec3i(1, 0, 0), f), u.x);
+  let x10 = mix(perlin_corner(cell, vec3i(0, 1, 0), f), perlin_corner(cell, vec3i(1, 1, 0), f), u.x);
+  let x01 = mix(perlin_corner(cell, vec3i(0, 0, 1), f), perlin_corner(cell, vec3i(1, 0, 1), f), u.x);
+  let x11 = mix(perlin_corner(cell, vec3i(0, 1, 1), f), perlin_corner(cell, vec3i(1, 1, 1), f), u.x);
+  return mix(mix(x00, x10, u.y), mix(x01, x11, u.y), u.z);
+}
+fn fbm(p: vec3f, octaves: i32) -> f32 {
+  var sum = 0.0;
+  var amplitude = 0.5;
+  var q = p;
+  for (var i = 0; i < octaves; i++) {
+    sum += amplitude * perlin(q);
+    amplitude *= 0.5;
+    q *= 2.0;
+  }
+  return sum;
+}
+fn turbulence(p: vec3f, octaves: i32) -> f32 {
+  var sum = 0.0;
+  var amplitude = 1.0;
+  var q = p;
+  for (var i = 0; i < octaves; i++) {
+    sum += amplitude * abs(perlin(q));
+    amplitude *= 0.5;
+    q *= 2.0;
+  }
+  return sum;
+}
+fn voronoi(p: vec3f) -> f32 {
+  let cell = vec3i(floor(p));
+  var nearest = FLT_MAX;
+  for (var z = -1; z <= 1; z++) {
+    for (var y = -1; y <= 1; y++) {
+      for (var x = -1; x <= 1; x++) {
+        let neighbour = cell + vec3i(x, y, z);
+        let feature = vec3f(neighbour) + cell_vec3(neighbour);
+        nearest = min(nearest, distance(p, feature));
+      }
+    }
+  }
+  return nearest;
+}
+fn image_texel(tex: Texture, x: i32, y: i32) -> vec4f {
+  let offset = bitcast<u32>(tex.params.x);
+  let w = i32(bitcast<u32>(tex.params.y));
+  let h = i32(bitcast<u32>(tex.params.z));
+  let xi = u32(((x % w) + w) % w);
+  let yi = u32(((y % h) + h) % h);
+  return unpack4x8unorm(texels[offset + yi*u32(w) + xi]);
+}
+fn sample_image(tex: Texture, uv: vec2f) -> vec4f {
+  // bilinear with wrap around, image rows go top to bottom while v goes up
+  let size = vec2f(f32(bitcast<u32>(tex.params.y)), f32(bitcast<u32>(tex.params.z)));
+  let st = vec2f(uv.x, 1.0 - uv.y) * size - 0.5;
+  let i = vec2i(floor(st));
+  let f = fract(st);
+  let top = mix(image_texel(tex, i.x, i.y), image_texel(tex, i.x + 1, i.y), f.x);
+  let bottom = mix(image_texel(tex, i.x, i.y + 1), image_texel(tex, i.x + 1, i.y + 1), f.x);
+  return mix(top, bottom, f.y);
+}
+fn sample_texture_rgba(index: u32, point: vec3f, uv: vec2f) -> vec4f {
+  let tex = textures[index - 1u];
+  var p = point;
+  if tex.mapping == MAPPING_UV {
+    p = vec3f(uv, 0.0);
+  }
+  var t = 0.0;
+  switch tex.kind {
+    case TEX_CHECKER: {
+      let c = floor(p * tex.params.x);
+      t = fract((c.x + c.y + c.z) * 0.5) * 2.0;
+    }
+    case TEX_NOISE: {
+      t = fbm(p * tex.params.x, i32(tex.params.y)) * 0.5 + 0.5;
+    }
+    case TEX_MARBLE: {
+      let q = p * tex.params.x;
+      t = 0.5 * (1.0 + sin(q.z + tex.params.y * turbulence(q, i32(tex.params.z))));
+    }
+    case TEX_VORONOI: {
+      t = voronoi(p * tex.params.x);
+    }
+    case TEX_GRADIENT: {
+      t = dot(p, tex.params.xyz) + tex.params.w;
+    }
+    case TEX_IMAGE: {
+      return sample_image(tex, p.xy);
+    }
+    default: {}
+  }
+  return mix(tex.color_a, tex.color_b, clamp(t, 0.0, 1.0));
+}
+fn sample_texture(index: u32, point: vec3f, uv: vec2f) -> vec3f {
+  return sample_texture_rgba(index, point, uv).rgb;
+}
+fn apply_textures(material: Material, point: vec3f, uv: vec2f) -> Material {
+  var ret = material;
+  if material.textures.x != 0u {
+    ret.albedo = vec4f(ret.albedo.rgb * sample_texture(material.textures.x, point, uv), ret.albedo.a);
+  }
+  if material.textures.y != 0u && material.id == MAT_METAL {
+    ret.params.x *= sample_texture(material.textures.y, point, uv).r;
+  }
+  if material.textures.z != 0u {
+    ret.emission = vec4f(ret.emission.rgb * sample_texture(material.textures.z, point, uv), ret.emission.a);
+  }
+  return ret;
+}
+fn bump_height(index: u32, point: vec3f, uv: vec2f) -> f32 {
+  return sample_texture(index, point, uv).r;
+}
+fn perturb_normal(material: Material, point: vec3f, uv: vec2f, n: vec3f, tangent: vec4f) -> vec3f {
+  let index = material.textures.w;
+  let strength = material.bump.x;
+  let t = normalize(tangent.xyz - n*dot(n, tangent.xyz));
+  let b = tangent.w * cross(n, t);
+  if u32(material.bump.y) == BUMP_NORMAL_MAP {
+    let m = sample_texture(index, point, uv) * 2.0 - 1.0;
+    return normalize(strength * (m.x*t + m.y*b) + m.z*n);
+  }
+  let h = bump_height(index, point, uv);
+  if textures[index - 1u].mapping == MAPPING_UV {
+    let du = bump_height(index, point, uv + vec2f(BUMP_DELTA, 0.0)) - h;
+    let dv = bump_height(index, point, uv + vec2f(0.0, BUMP_DELTA)) - h;
+    return normalize(n - strength * (du*t + dv*b) / BUMP_DELTA);
+  }
+  let gradient = vec3f(
+    bump_height(index, point + vec3f(BUMP_DELTA, 0.0, 0.0), uv) - h,
+    bump_height(index, point + vec3f(0.0, BUMP_DELTA, 0.0), uv) - h,
+    bump_height(index, point + vec3f(0.0, 0.0, BUMP_DELTA), uv) - h,
+  ) / BUMP_DELTA;
+  return normalize(n - strength * (gradient - n*dot(gradient, n)));
+}
+fn triangle_color(attr: TriangleAttributes, barycentric: vec2f) -> vec4f {
+  let w = vec3f(1.0 - barycentric.x - barycentric.y, barycentric);
+  let c = unpack4x8unorm(attr.normals[0].w)*w.x + unpack4x8unorm(attr.normals[1].w)*w.y + unpack4x8unorm(attr.normals[2].w)*w.z;
+  return vec4f(pow(c.rgb, vec3f(2.2)), c.a);
+}
+fn has_vertex_colors(material: Material) -> bool {
+  return (material.flags & MATERIAL_VERTEX_COLORS) != 0u;
+}
+fn triangle_uv(attr: TriangleAttributes, barycentric: vec2f) -> vec2f {
+  let w = vec3f(1.0 - barycentric.x - barycentric.y, barycentric);
+  return attr.uvs[0]*w.x + attr.uvs[1]*w.y + attr.uvs[2]*w.z;
+}
+// Cutout materials treat hits below their alpha threshold as misses, so traversal carries
+// on to whatever is behind the hole.
+fn is_cut_out(material: Material, point: vec3f, triangle: u32, barycentric: vec2f) -> bool {
+  if material.cutout <= 0.0 {
+    return false;
+  }
+  var alpha = material.albedo.a;
+  if has_vertex_colors(material) {
+    alpha *= triangle_color(attributes[triangle], barycentric).a;
+  }
+  if material.textures.x != 0u {
+    let uv = triangle_uv(attributes[triangle], barycentric);
+    alpha *= sample_texture_rgba(material.textures.x, point, uv).a;
+  }
+  return alpha < material.cutout;
+}
+// Fills in uv, the vertex colour for materials using it and, for bump mapped materials,
+// the shading normal once the closest triangle is known. Other materials keep the flat
+// face normal.
+fn shade_triangle(ray: Ray, hit: ptr<function, HitRecord>) {
+  let attr = attributes[(*hit).triangle];
+  let w = vec3f(1.0 - (*hit).barycentric.x - (*hit).barycentric.y, (*hit).barycentric);
+  let geometric = (*hit).normal;
+  (*hit).uv = triangle_uv(attr, (*hit).barycentric);
+  if has_vertex_colors((*hit).material) {
+    let color = triangle_color(attr, (*hit).barycentric).rgb;
+    (*hit).material.albedo = vec4f((*hit).material.albedo.rgb * color, (*hit).material.albedo.a);
+  }
+  if (*hit).material.textures.w == 0u {
+    return;
+  }
+  // tangent space is defined around the interpolated vertex normal, as in MikkTSpace
+  let normals = array(
+    bitcast<vec3f>(attr.normals[0].xyz),
+    bitcast<vec3f>(attr.normals[1].xyz),
+    bitcast<vec3f>(attr.normals[2].xyz),
+  );
+  var n = normalize(normals[0]*w.x + normals[1]*w.y + normals[2]*w.z);
+  var tangent = attr.tangents[0]*w.x + attr.tangents[1]*w.y + attr.tangents[2]*w.z;
+  let material = triangles[(*hit).triangle].material;
+  if is_moving(material) {
+    let q = motion_rotation(material_motion(material));
+    n = quat_rotate(q, n);
+    tangent = vec4f(quat_rotate(q, tangent.xyz), tangent.w);
+  }
+  if dot(n, geometric) < 0.0 {
+    n = -n;
+  }
+  n = perturb_normal((*hit).material, (*hit).point, (*hit).uv, n, vec4f(tangent.xyz, sign(tangent.w)));
+  // a shading normal facing away from the viewer while the surface faces it would let
+  // light through, bend it back until it is just visible
+  let view = -normalize(ray.direction);
+  let side = select(-1.0, 1.0, dot(geometric, view) >= 0.0);
+  let visibility = dot(n, view) * side;
+  if visibility < BUMP_DELTA {
+    n = normalize(n + (BUMP_DELTA - visibility) * side * view);
+  }
+  (*hit).normal = n;
+}
 fn reflect(v: vec3f, n: vec3f) -> vec3f {
     return v - 2*dot(v,n)*n;
 }
@@ -217,9 +759,69 @@ fn reflectance(cosine: f32, ref_idx: f32) -> f32 {
     r0 = r0*r0;
     return r0 + (1-r0)*pow((1 - cosine), 5.0);
 }
+fn fresnel_amplitudes(cos_i: f32, n_i: f32, cos_t: f32, n_t: f32) -> vec2f {
+  let rs = (n_i*cos_i - n_t*cos_t) / (n_i*cos_i + n_t*cos_t);
+  let rp = (n_t*cos_i - n_i*cos_t) / (n_t*cos_i + n_i*cos_t);
+  return vec2f(rs, rp);
+}
+fn airy(r12: f32, r23: vec3f, phase: vec3f) -> vec3f {
+  let interference = 2.0 * r12 * r23 * cos(phase);
+  return (r12*r12 + r23*r23 + interference) / (1.0 + r12*r12*r23*r23 + interference);
+}
+fn has_thin_film(material: Material) -> bool {
+  return material.coating.x > 0.0;
+}
+// Airy reflectance of a film coating.x nm thick with IOR coating.y, lit from a medium
+// with IOR n1. r23s/r23p are the amplitude coefficients of the film/base interface.
+fn thin_film(cos_i: f32, n1: f32, coating: vec4f, cos_film: f32, r23s: vec3f, r23p: vec3f, lambdas: vec3f) -> vec3f {
+  let n2 = coating.y;
+  let r12 = fresnel_amplitudes(cos_i, n1, cos_film, n2);
+  let phase = 2.0 * PI2 * n2 * coating.x * cos_film / lambdas;
+  return 0.5 * (airy(r12.x, r23s, phase) + airy(r12.y, r23p, phase));
+}
+fn film_cosine(cos_i: f32, n_i: f32, n_t: f32) -> f32 {
+  // cosine of the refracted angle, negative on total internal reflection
+  let sin2 = (n_i/n_t) * (n_i/n_t) * (1.0 - cos_i*cos_i);
+  return select(sqrt(1.0 - sin2), -1.0, sin2 >= 1.0);
+}
+fn thin_film_dielectric(cos_i: f32, n1: f32, n3: f32, coating: vec4f, lambdas: vec3f) -> vec3f {
+  let cos_film = film_cosine(cos_i, n1, coating.y);
+  let cos_base = film_cosine(cos_i, n1, n3);
+  if cos_film < 0.0 || cos_base < 0.0 {
+    return vec3f(1.0);
+  }
+  let r23 = fresnel_amplitudes(cos_film, coating.y, cos_base, n3);
+  return thin_film(cos_i, n1, coating, cos_film, vec3f(r23.x), vec3f(r23.y), lambdas);
+}
+fn thin_film_conductor(cos_i: f32, coating: vec4f, f0: vec3f, lambdas: vec3f) -> vec3f {
+  let cos_film = film_cosine(cos_i, 1.0, coating.y);
+  if cos_film < 0.0 {
+    return f0;
+  }
+  // conductors flip the phase on reflection, treat the base as a real -sqrt(F0) amplitude
+  let r23 = -sqrt(f0);
+  return thin_film(cos_i, 1.0, coating, cos_film, r23, r23, lambdas);
+}
+// Reflectance of a dielectric over lambdas. Schlick's approximation unless the surface
+// has a thin film, whose interference colours the reflection.
+fn dielectric_reflectance(material: Material, cos_theta: f32, ir: f32, front_face: bool, lambda: f32, lambdas: vec3f) -> vec3f {
+  if !has_thin_film(material) {
+    return vec3f(reflectance(cos_theta, ir));
+  }
+  let n = dielectric_ior(material, lambda);
+  let n1 = select(n, 1.0, front_face);
+  let n3 = select(1.0, n, front_face);
+  return thin_film_dielectric(min(abs(cos_theta), 1.0), n1, n3, material.coating, lambdas);
+}
 
 // seems like the scatter function doesn't do well with triangles
-fn scatter(state: ptr<function, u32>, ray: Ray, hit: HitRecord) -> Ray {
+fn scatter(state: ptr<function, u32>, ray: Ray, hit: HitRecord, lambdas: vec3f, albedo: ptr<function, vec3f>) -> Ray {
+  *albedo = hit.material.albedo.rgb;
+  if is_spectral() {
+    *albedo = rgb_to_spectrum(*albedo, lambdas);
+  }
+  // dispersion follows the hero wavelength, RGB mode uses a single IOR
+  let lambda = select(RGB_WAVELENGTH, lambdas.x, is_spectral());
   switch hit.material.id {
     case MAT_LAMBERTIAN: {
       let direction = random_on_hemisphere(state, hit.normal);
@@ -228,20 +830,29 @@ fn scatter(state: ptr<function, u32>, ray: Ray, hit: HitRecord) -> Ray {
     case MAT_METAL: {
       let fuzziness = hit.material.params.x;
       let direction = reflect(ray.direction, hit.normal) + fuzziness * random_on_hemisphere(state, hit.normal);
+      if has_thin_film(hit.material) {
+        let cos_theta = abs(dot(normalize(ray.direction), hit.normal));
+        *albedo = thin_film_conductor(cos_theta, hit.material.coating, *albedo, lambdas);
+      }
       return Ray(hit.point, normalize(direction));
     }
     case MAT_DIELECTRIC: {
-      var ir = hit.material.params.x;
+      var ir = dielectric_ior(hit.material, lambda);
       if hit.front_face {
         ir = 1.0/ir;
       }
       let cos_theta = min(dot(-ray.direction, hit.normal), 1.0);
       let sin_theta = sqrt(1.0 - cos_theta*cos_theta);
       let cannot_refract = ir * sin_theta > 1.0;
-      if (cannot_refract || reflectance(cos_theta, ir) > fract(rng_float(state))) {
+      // the mean reflectance picks reflect or refract, a film's colour goes into the path weight
+      let r = dielectric_reflectance(hit.material, cos_theta, ir, hit.front_face, lambda, lambdas);
+      let p = (r.x + r.y + r.z) / 3.0;
+      if (cannot_refract || p > fract(rng_float(state))) {
+          *albedo *= r / p;
           let direction = reflect(ray.direction, hit.normal);
           return Ray(hit.point, normalize(direction));
       } else {
+          *albedo *= (1.0 - r) / (1.0 - p);
           let direction = refract(ray.direction, hit.normal, ir);
           return Ray(hit.point, normalize(direction));
       }
@@ -265,42 +876,114 @@ fn scatter(state: ptr<function, u32>, ray: Ray, hit: HitRecord) -> Ray {
     }
   }
 }
-fn intersect_all_node(ray: Ray) -> HitRecord {
-    var i = 1u;
-    let n = bvh_tree_size.x;
+// the binary tree of `Tree::nodes`, a stack of the links left to visit
+fn intersect_binary(ray: Ray, ret: ptr<function, HitRecord>) {
     let m = bvh_tree_size.y;
-    var ret = EMPTY_HIT_RECORD;
+    var stack: array<u32, MAX_DEPTH>;
+    stack[0] = 0u;
+    var top = 1u;
     var step = 0;
-    while step < 600 {
-        step++;
-
-        if i < n && intersect_node(ray, nodes[i]) {
-            i *= 2u; // go to first child
-            continue;
+    loop {
+        if top == 0u {
+            break;
         }
-
-        if i >= n {
-            let j = i - n;
-            if j >= m {
-                break;
+        if step == MAX_STEPS {
+            traversal_truncated = true;
+            break;
+        }
+        step++;
+        top--;
+        let link = stack[top];
+        if (link & LEAF) != 0u {
+            // empty links are past every slot
+            if (link & ~LEAF) < m {
+                intersect_triangle(ray, link & ~LEAF, ret);
             }
-            intersect_triangle(ray, j, &ret);
+            continue;
         }
-
-        // Move to next sibling or parent
-        while (i & 1u) == 1u {
-            i /= 2u; // return to parent
+        if !intersect_node(ray, binary_node(link)) {
+            continue;
         }
-        if i == 0u {
+        if top + 2u > MAX_DEPTH {
+            traversal_truncated = true;
             break;
         }
-        i++; // go to next sibling
+        // the left child is visited first
+        let children = binary_links(link);
+        stack[top] = children.y;
+        stack[top + 1u] = children.x;
+        top += 2u;
+    }
+    traversal_steps = step;
+}
+
+// the wide tree of `Tree::collapse`, a stack of the nodes and their children left to visit
+fn intersect_wide(ray: Ray, ret: ptr<function, HitRecord>) {
+  let m = bvh_tree_size.y;
+  var stack: array<u32, MAX_DEPTH>;
+  var hits: array<u32, MAX_DEPTH>;
+  stack[0] = 0u;
+  hits[0] = wide_hits(ray, 0u);
+  var top = 1u;
+  var step = 0;
+  loop {
+    if top == 0u {
+      break;
+    }
+    if step == MAX_STEPS {
+      traversal_truncated = true;
+      break;
+    }
+    step++;
+    let left = hits[top - 1u];
+    if left == 0u {
+      top--;
+      continue;
+    }
+    hits[top - 1u] = left & (left - 1u);
+    let link = wide_link(stack[top - 1u], firstTrailingBit(left));
+    if (link & LEAF) != 0u {
+      if (link & ~LEAF) < m {
+        intersect_triangle(ray, link & ~LEAF, ret);
+      }
+      continue;
+    }
+    if top == MAX_DEPTH {
+      traversal_truncated = true;
+      break;
+    }
+    stack[top] = link;
+    hits[top] = wide_hits(ray, link);
+    top++;
+  }
+  traversal_steps = step;
+}
+
+fn intersect_all_node(ray: Ray) -> HitRecord {
+    var ret = EMPTY_HIT_RECORD;
+    traversal_truncated = false;
+    if bvh_tree_size.z > 2u {
+        intersect_wide(ray, &ret);
+    } else {
+        intersect_binary(ray, &ret);
     }
 
+    if ret.t < FLT_MAX {
+        shade_triangle(ray, &ret);
+    }
+    // a closer point replaces the shaded triangle hit
+    intersect_points(ray, &ret);
     return ret;
 }
 
 fn trace(ray: Ray, state: ptr<function, u32>) -> vec3f {
+  let spectral = is_spectral();
+  var lambdas = RGB_WAVELENGTHS;
+  if spectral {
+    lambdas = sample_wavelengths(state);
+  }
+  var collapsed = false;
+  var radiance = vec3f(0);
   var attenuation = vec3f(1);
   var current_ray = ray;
   for(var b = 0;b < BOUNCE_MAX; b++) {
@@ -308,11 +991,26 @@ fn trace(ray: Ray, state: ptr<function, u32>) -> vec3f {
     if abs(hit.t - FLT_MAX) < EPSILON {
       break;
     }
-    current_ray = scatter(state, current_ray, hit);
-    attenuation *= hit.material.albedo.rgb * 0.7;
+    hit.material = apply_textures(hit.material, hit.point, hit.uv);
+    var emission = hit.material.emission.rgb;
+    if spectral {
+      emission = rgb_to_spectrum(emission, lambdas);
+    }
+    radiance += attenuation * emission;
+    if spectral && !collapsed && is_dispersive(hit.material) {
+      // the secondary wavelengths would refract elsewhere, only the hero carries on
+      attenuation *= vec3f(3.0, 0.0, 0.0);
+      collapsed = true;
+    }
+    var albedo = vec3f(1);
+    current_ray = scatter(state, current_ray, hit, lambdas, &albedo);
+    attenuation *= albedo * 0.7;
   }
   let sky = mix(SKY, BLUE, ray.direction.y*0.5 + 0.5);
-  return attenuation * sky;
+  if spectral {
+    return spectrum_to_rgb(radiance + attenuation * rgb_to_spectrum(sky, lambdas), lambdas);
+  }
+  return radiance + attenuation * sky;
 }
 
 
@@ -330,11 +1028,17 @@ fn fs_main(@builtin(position) position: vec4f) -> @location(0) vec4f {
   var uv = position_aa / (vec2f(resolution) - vec2f(1));
   uv = (2 * uv - vec2(1)) * vec2(aspect_ratio, -1);
   let ray = make_ray(uv, &rng_state);
+  ray_time = shutter_time(&rng_state);
   var color = vec3f(0);
-  for (var i = 0; i < SAMPLE_PER_FRAME; i += 1) {
-    color += trace(ray, &rng_state);
+  if is_heatmap() {
+    _ = intersect_all_node(ray);
+    color = heatmap(traversal_steps, traversal_truncated);
+  } else {
+    for (var i = 0; i < SAMPLE_PER_FRAME; i += 1) {
+      color += trace(ray, &rng_state);
+    }
+    color /= f32(SAMPLE_PER_FRAME);
   }
-  color /= f32(SAMPLE_PER_FRAME);
   let x = u32(position.x);
   let y = u32(position.y);
   let i = (y * resolution.x + x)*3;
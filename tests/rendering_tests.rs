@@ -16,7 +16,7 @@ fn test_scene_against_golden(
     golden_name: &str,
 ) -> Result<(), String> {
     // Initialize scene
-    scene.init();
+    scene.init().unwrap();
 
     // Render frames
     for i in 0..TEST_FRAMES {
@@ -51,7 +51,7 @@ fn test_scene_against_golden(
 /// Helper function to generate golden reference image
 fn generate_golden_image(scene: &mut SceneSphere, golden_name: &str) {
     // Initialize scene
-    scene.init();
+    scene.init().unwrap();
 
     // Render frames
     println!("Generating golden image for {}", golden_name);
@@ -75,6 +75,7 @@ fn generate_golden_image(scene: &mut SceneSphere, golden_name: &str) {
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 enum ComparisonError {
     DifferentDimensions,
     PixelCountMismatch,
@@ -429,7 +430,7 @@ fn create_complex_scene() -> SceneSphere {
             } else {
                 let x = i as f32 * 1.2;
                 let z = -5.0 + j as f32 * 1.2;
-                let material_type = (i + j).abs() as i32 % 3;
+                let material_type = (i + j).abs() % 3;
 
                 let sphere = match material_type {
                     0 => Sphere::new_lambertian(
@@ -543,7 +544,7 @@ fn test_rendering_performance() {
             Vec3::new(x, 0.0, z),
             0.4,
             Vec3::new(
-                (i as f32 / 20.0),
+                i as f32 / 20.0,
                 0.5,
                 1.0 - (i as f32 / 20.0),
             ),
@@ -556,7 +557,7 @@ fn test_rendering_performance() {
         Vec3::new(0.5, 0.5, 0.5),
     ));
 
-    scene.init();
+    scene.init().unwrap();
 
     let start = Instant::now();
 